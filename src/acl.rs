@@ -0,0 +1,208 @@
+//! What each client may do, by the token it authenticates with, see
+//! `ServerConfig::acl`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use super::{KvsError, Request, Result};
+
+/// What a token allows, each level including those before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// Get and list keys, and read the server's stats.
+    Read,
+    /// Set and remove keys too.
+    Write,
+    /// Configure, compact, profile and replicate the store too.
+    Admin,
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "write" => Ok(Permission::Write),
+            "admin" => Ok(Permission::Admin),
+            _ => Err(format!(
+                "unknown permission `{}`, expected `read`, `write` or `admin`",
+                s
+            )),
+        }
+    }
+}
+
+/// The permission of a token, on the keys starting with one of `prefixes`,
+/// or every key if there are none.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub permission: Permission,
+    pub prefixes: Vec<String>,
+}
+
+impl Grant {
+    /// Fails unless the grant allows `request`. Requests on the whole store,
+    /// like digests, need a grant on every key.
+    pub(crate) fn check(&self, request: &Request) -> Result<()> {
+        if let Request::Batch(requests) = request {
+            return requests.iter().try_for_each(|request| self.check(request));
+        }
+        let (permission, scope) = required(request);
+        let on_keys = self.prefixes.is_empty()
+            || match scope {
+                Scope::None => true,
+                Scope::Key(key) | Scope::Prefix(key) => self.on_key(key),
+                Scope::Keys(keys) => keys.iter().all(|key| self.on_key(key)),
+                Scope::Store => false,
+            };
+        if self.permission >= permission && on_keys {
+            Ok(())
+        } else {
+            Err(KvsError::Forbidden(request.op()))
+        }
+    }
+
+    fn on_key(&self, key: &str) -> bool {
+        self.prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
+
+/// The keys a request acts on.
+enum Scope<'a> {
+    /// None, as it acts on the server or the connection.
+    None,
+    Key(&'a str),
+    Keys(Vec<&'a str>),
+    /// The keys starting with it.
+    Prefix(&'a str),
+    /// Every key.
+    Store,
+}
+
+/// Returns the permission `request` needs, and the keys it needs it on.
+fn required(request: &Request) -> (Permission, Scope<'_>) {
+    use Permission::*;
+    match request {
+        Request::Get { key }
+        | Request::GetTransformed { key, .. }
+        | Request::GetChunked { key }
+        | Request::Exists { key }
+        | Request::Strlen { key } => (Read, Scope::Key(key)),
+        Request::MultiGet { keys } => {
+            (Read, Scope::Keys(keys.iter().map(String::as_str).collect()))
+        }
+        Request::RemoveMany { keys } => (
+            Write,
+            Scope::Keys(keys.iter().map(String::as_str).collect()),
+        ),
+        Request::Rename { from, to } => (Write, Scope::Keys(vec![from, to])),
+        // Copying reads `from` too, which `Write` includes.
+        Request::Copy { from, to, .. } => (Write, Scope::Keys(vec![from, to])),
+        Request::MultiSet { pairs } => {
+            let keys = pairs.iter().map(|(key, _)| key.as_str()).collect();
+            (Write, Scope::Keys(keys))
+        }
+        Request::Set { key, .. }
+        | Request::SetChunk { key, .. }
+        | Request::Remove { key }
+        | Request::CompareAndSet { key, .. } => (Write, Scope::Key(key)),
+        Request::CountPrefix { prefix }
+        | Request::Scan { prefix, .. }
+        | Request::ScanSnapshot { prefix, .. }
+        | Request::Subscribe { prefix } => (Read, Scope::Prefix(prefix)),
+        Request::RemovePrefix { prefix, .. } => (Write, Scope::Prefix(prefix)),
+        Request::Digest => (Read, Scope::Store),
+        Request::Stats
+        | Request::SegmentStats
+        | Request::Info
+        | Request::Leader
+        | Request::Topology
+        | Request::Select { .. }
+        | Request::Unsubscribe
+        | Request::Multiplex
+        | Request::Begin
+        | Request::Exec
+        | Request::Discard
+        | Request::Ping { .. }
+        | Request::Auth { .. } => (Read, Scope::None),
+        Request::Configure { .. }
+        | Request::Compact { .. }
+        | Request::FreezeWrites { .. }
+        | Request::Thaw
+        | Request::Profile { .. }
+        | Request::Replicate { .. }
+        | Request::Bootstrap
+        | Request::Raft(_)
+        | Request::Forward(_) => (Admin, Scope::Store),
+        Request::Batch(_) => unreachable!("batches are checked request by request"),
+    }
+}
+
+/// Tokens clients authenticate with, and what each allows, see
+/// `ServerConfig::acl`.
+///
+/// Written one token a line, as `<token> <permission> [<prefix>...]`, like
+/// `s3cr3t write app1:`. Lines starting with `#` are comments.
+#[derive(Clone, Default, PartialEq)]
+pub struct Acl {
+    grants: HashMap<String, Grant>,
+}
+
+impl Acl {
+    /// Lets clients authenticating with `token` do what `grant` allows.
+    pub fn insert(&mut self, token: impl Into<String>, grant: Grant) {
+        self.grants.insert(token.into(), grant);
+    }
+
+    /// Returns what `token` allows, if it's known.
+    pub(crate) fn grant(&self, token: &str) -> Result<&Grant> {
+        self.grants.get(token).ok_or(KvsError::Unauthenticated)
+    }
+}
+
+impl FromStr for Acl {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let mut acl = Acl::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (token, permission) = match (parts.next(), parts.next()) {
+                (Some(token), Some(permission)) => (token, permission),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected `<token> <permission> [<prefix>...]`",
+                        i + 1
+                    ))
+                }
+            };
+            let permission = permission
+                .parse()
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            let prefixes = parts.map(String::from).collect();
+            acl.insert(
+                token,
+                Grant {
+                    permission,
+                    prefixes,
+                },
+            );
+        }
+        Ok(acl)
+    }
+}
+
+/// Formats the ACL without its tokens.
+impl fmt::Debug for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.grants.values()).finish()
+    }
+}
@@ -0,0 +1,127 @@
+//! Snapshots of a store in a single file, see `KvStore::export_archive`.
+//!
+//! An archive is laid out as:
+//!
+//! - `MAGIC`
+//! - the bincode-encoded `ArchiveManifest`, listing the files
+//! - the contents of each file, in the order listed
+//! - the SHA-256 of each file, in the same order
+//!
+//! so both writing and reading it take a single pass.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backup::Hashing;
+use crate::manifest::FORMAT_VERSION;
+use crate::{KvsError, Result};
+
+/// Marks the start of an archive.
+const MAGIC: &[u8; 8] = b"kvsarch1";
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveManifest {
+    /// The format version of the store archived.
+    format_version: u32,
+    files: Vec<ArchiveFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveFile {
+    name: String,
+    len: u64,
+}
+
+/// Writes every file in the checkpoint at `checkpoint` to an archive at
+/// `path`, returning its size.
+pub(crate) fn write(checkpoint: &Path, path: &Path) -> Result<u64> {
+    let mut files = Vec::new();
+    for file in fs::read_dir(checkpoint)? {
+        let file = file?;
+        if let Some(name) = file.file_name().to_str() {
+            files.push(ArchiveFile {
+                name: name.to_owned(),
+                len: file.metadata()?.len(),
+            });
+        }
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    let manifest = ArchiveManifest {
+        format_version: FORMAT_VERSION,
+        files,
+    };
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(MAGIC)?;
+    bincode::serialize_into(&mut out, &manifest)?;
+    let mut hashes = Vec::new();
+    for file in &manifest.files {
+        let mut hasher = Hashing::new(File::open(checkpoint.join(&file.name))?);
+        let copied = io::copy(&mut (&mut hasher).take(file.len), &mut out)?;
+        if copied < file.len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        hashes.push(hasher.finish());
+    }
+    for hash in hashes {
+        out.write_all(&hash)?;
+    }
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(file.metadata()?.len())
+}
+
+/// Reads the archive at `path` into the new directory `dir`, checking each
+/// file against its hash.
+pub(crate) fn read(path: &Path, dir: &Path) -> Result<()> {
+    let invalid = |reason: &str| KvsError::InvalidArchive(path.to_path_buf(), reason.to_owned());
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    input
+        .read_exact(&mut magic)
+        .map_err(|_| invalid("not an archive"))?;
+    if &magic != MAGIC {
+        return Err(invalid("not an archive"));
+    }
+    let manifest: ArchiveManifest =
+        bincode::deserialize_from(&mut input).map_err(|_| invalid("the manifest is corrupt"))?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(KvsError::UnsupportedFormat(
+            manifest.format_version,
+            FORMAT_VERSION,
+        ));
+    }
+
+    fs::create_dir_all(dir)?;
+    let mut hashes = Vec::new();
+    for file in &manifest.files {
+        let plain =
+            !file.name.is_empty() && Path::new(&file.name).file_name() == Some(file.name.as_ref());
+        if !plain {
+            return Err(invalid("a file name isn't plain"));
+        }
+        let mut out = Hashing::new(File::create(dir.join(&file.name))?);
+        let copied = io::copy(&mut (&mut input).take(file.len), &mut out)?;
+        if copied < file.len {
+            return Err(invalid("it's truncated"));
+        }
+        out.inner.sync_all()?;
+        hashes.push(out.finish());
+    }
+    for (file, hash) in manifest.files.iter().zip(hashes) {
+        let mut expected = [0; 32];
+        input
+            .read_exact(&mut expected)
+            .map_err(|_| invalid("it's truncated"))?;
+        if expected != hash {
+            return Err(invalid(&format!(
+                "`{}` doesn't match its checksum",
+                file.name
+            )));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! An append-only log of the mutations made to a store, kept apart from its
+//! data logs, see `Options::audit_log`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// A mutation recorded in the audit log, one JSON line each.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: SystemTime,
+    /// Address of the client whose request made the mutation, if it was
+    /// made through a server.
+    pub client: Option<SocketAddr>,
+    /// The operation, like `set`, `remove` or `delete_prefix`.
+    pub op: String,
+    /// The key mutated, or the prefix removed, lossily decoded as UTF-8.
+    pub key: String,
+}
+
+/// Which entries `read_audit_log` returns. Entries must match every
+/// condition given.
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    /// Only entries made at or after this time.
+    pub since: Option<SystemTime>,
+    /// Only entries made before this time.
+    pub until: Option<SystemTime>,
+    /// Only entries of keys starting with this.
+    pub key_prefix: Option<String>,
+    /// Only entries of clients with this address.
+    pub client: Option<IpAddr>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.since.is_none_or(|since| entry.at >= since)
+            && self.until.is_none_or(|until| entry.at < until)
+            && self
+                .key_prefix
+                .as_ref()
+                .is_none_or(|prefix| entry.key.starts_with(prefix.as_str()))
+            && self
+                .client
+                .is_none_or(|ip| entry.client.map(|client| client.ip()) == Some(ip))
+    }
+}
+
+/// The audit log of an open store.
+///
+/// Entries are appended to `audit.log` until it passes `max_size`, when
+/// it's renamed to `audit.<n>.log`, numbered from 1, and a new one started.
+/// Rotated files are never removed.
+pub(crate) struct AuditLog {
+    dir: PathBuf,
+    max_size: u64,
+    current: Mutex<Current>,
+}
+
+struct Current {
+    file: File,
+    len: u64,
+    /// Number of the next rotated file.
+    next: u64,
+}
+
+impl AuditLog {
+    pub(crate) fn open(dir: &Path, max_size: u64) -> Result<AuditLog> {
+        fs::create_dir_all(dir)?;
+        let next = rotated_files(dir)?.last().map_or(1, |(n, _)| n + 1);
+        let file = open_current(dir)?;
+        let len = file.metadata()?.len();
+        Ok(AuditLog {
+            dir: dir.to_path_buf(),
+            max_size,
+            current: Mutex::new(Current { file, len, next }),
+        })
+    }
+
+    /// Appends an entry for `op` on `key` by `client`. Failing to is logged
+    /// rather than failing the mutation, which is made already.
+    pub(crate) fn record(&self, client: Option<SocketAddr>, op: &str, key: &[u8]) {
+        let entry = AuditEntry {
+            at: SystemTime::now(),
+            client,
+            op: op.to_owned(),
+            key: String::from_utf8_lossy(key).into_owned(),
+        };
+        if let Err(e) = self.append(&entry) {
+            warn!("Failed to write the audit log: {}", e);
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry).expect("audit entries are encodable");
+        line.push(b'\n');
+        let mut current = self.current.lock().unwrap();
+        if current.len > 0 && current.len + line.len() as u64 > self.max_size {
+            let rotated = self.dir.join(format!("audit.{}.log", current.next));
+            fs::rename(get_current_path(&self.dir), rotated)?;
+            current.file = open_current(&self.dir)?;
+            current.len = 0;
+            current.next += 1;
+        }
+        current.file.write_all(&line)?;
+        current.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Returns the entries of the audit log in `dir` matching `query`, oldest
+/// first. It may be read while the store is open.
+pub fn read_audit_log(dir: impl AsRef<Path>, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+    let dir = dir.as_ref();
+    let mut paths: Vec<_> = rotated_files(dir)?
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect();
+    paths.push(get_current_path(dir));
+    let mut entries = Vec::new();
+    for path in paths {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            // The last line is partly written if a write was cut off.
+            let entry: AuditEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if query.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns the rotated files in `dir` and their numbers, in order.
+fn rotated_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        let n = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("audit."))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|n| n.parse().ok());
+        if let Some(n) = n {
+            files.push((n, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn open_current(dir: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_current_path(dir))?)
+}
+
+fn get_current_path(dir: &Path) -> PathBuf {
+    dir.join("audit.log")
+}
@@ -0,0 +1,339 @@
+//! Positional file I/O, through io_uring where it's available.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, FutureExt as _};
+
+/// Reads and writes at given positions of files.
+pub(crate) trait IoBackend: Send + Sync {
+    /// Fills `buf` with the bytes of `file` starting at `pos`.
+    fn read_at<'a>(
+        &'a self,
+        file: &'a File,
+        buf: &'a mut [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Writes all of `buf` to `file` starting at `pos`.
+    fn write_at<'a>(
+        &'a self,
+        file: &'a File,
+        buf: &'a [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    fn fsync<'a>(&'a self, file: &'a File) -> BoxFuture<'a, io::Result<()>>;
+
+    fn fdatasync<'a>(&'a self, file: &'a File) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Returns how many reads had to wait for admission, and for how long in total.
+    fn read_waits(&self) -> (u64, Duration) {
+        (0, Duration::default())
+    }
+}
+
+/// Reads in flight at once, matching the io_uring queue depth.
+const QUEUE_DEPTH: usize = 256;
+
+/// Returns io_uring if the `io-uring` feature is enabled and the kernel
+/// supports it, and plain positional reads and writes otherwise.
+///
+/// Either way, at most `QUEUE_DEPTH` reads are in flight at once.
+pub(crate) fn detect() -> Arc<dyn IoBackend> {
+    Arc::new(Admitted::new(detect_unlimited(), QUEUE_DEPTH))
+}
+
+fn detect_unlimited() -> Box<dyn IoBackend> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        let config = rio::Config {
+            depth: QUEUE_DEPTH,
+            ..Default::default()
+        };
+        match config.start() {
+            Ok(rio) => return Box::new(Uring(rio)),
+            Err(e) => log::warn!("io_uring is unavailable, using positional I/O: {}", e),
+        }
+    }
+    Box::new(Positional)
+}
+
+/// Admission control for reads: once the queue is full, further reads wait
+/// for earlier ones to complete, in the order they arrived.
+struct Admitted {
+    inner: Box<dyn IoBackend>,
+    permits: Arc<Permits>,
+    waits: Mutex<(u64, Duration)>,
+}
+
+impl Admitted {
+    fn new(inner: Box<dyn IoBackend>, depth: usize) -> Self {
+        Admitted {
+            inner,
+            permits: Arc::new(Permits {
+                state: Mutex::new(PermitState {
+                    available: depth,
+                    waiters: VecDeque::new(),
+                }),
+            }),
+            waits: Mutex::new((0, Duration::default())),
+        }
+    }
+}
+
+impl IoBackend for Admitted {
+    fn read_at<'a>(
+        &'a self,
+        file: &'a File,
+        buf: &'a mut [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        match self.permits.acquire() {
+            // Submitted right away, so batches of reads are still submitted together.
+            Ok(permit) => {
+                let read = self.inner.read_at(file, buf, pos);
+                async move {
+                    let res = read.await;
+                    drop(permit);
+                    res
+                }
+                .boxed()
+            }
+            Err(admission) => {
+                let queued_at = Instant::now();
+                async move {
+                    let permit = admission
+                        .await
+                        .map_err(|_| io::Error::other("read queue closed"))?;
+                    {
+                        let mut waits = self.waits.lock().unwrap();
+                        waits.0 += 1;
+                        waits.1 += queued_at.elapsed();
+                    }
+                    let res = self.inner.read_at(file, buf, pos).await;
+                    drop(permit);
+                    res
+                }
+                .boxed()
+            }
+        }
+    }
+
+    fn write_at<'a>(
+        &'a self,
+        file: &'a File,
+        buf: &'a [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.write_at(file, buf, pos)
+    }
+
+    fn fsync<'a>(&'a self, file: &'a File) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.fsync(file)
+    }
+
+    fn fdatasync<'a>(&'a self, file: &'a File) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.fdatasync(file)
+    }
+
+    fn read_waits(&self) -> (u64, Duration) {
+        *self.waits.lock().unwrap()
+    }
+}
+
+/// A fair counting semaphore.
+struct Permits {
+    state: Mutex<PermitState>,
+}
+
+struct PermitState {
+    available: usize,
+    /// Where to hand permits to, oldest first.
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// Returns itself to the `Permits` when dropped.
+struct Permit(Option<Arc<Permits>>);
+
+impl Permits {
+    /// Takes a permit now if there's one and no one is waiting, or returns
+    /// where a permit will be handed once it's this caller's turn.
+    fn acquire(self: &Arc<Self>) -> Result<Permit, oneshot::Receiver<Permit>> {
+        let mut state = self.state.lock().unwrap();
+        if state.available > 0 && state.waiters.is_empty() {
+            state.available -= 1;
+            return Ok(Permit(Some(Arc::clone(self))));
+        }
+        let (sender, receiver) = oneshot::channel();
+        state.waiters.push_back(sender);
+        Err(receiver)
+    }
+
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiters.pop_front() {
+            match waiter.send(Permit(Some(Arc::clone(self)))) {
+                Ok(()) => return,
+                // The waiting read was dropped, so the permit goes to the next one.
+                Err(mut permit) => drop(permit.0.take()),
+            }
+        }
+        state.available += 1;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(permits) = self.0.take() {
+            permits.release();
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+struct Uring(rio::Rio);
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl IoBackend for Uring {
+    fn read_at<'a>(
+        &'a self,
+        file: &'a File,
+        buf: &'a mut [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        // The read is submitted now, not when the future is first polled.
+        let completion = self.0.read_at(file, &*buf, pos);
+        async move { completion.await.map(drop) }.boxed()
+    }
+
+    fn write_at<'a>(
+        &'a self,
+        file: &'a File,
+        buf: &'a [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let completion = self.0.write_at(file, buf, pos);
+        async move { completion.await.map(drop) }.boxed()
+    }
+
+    fn fsync<'a>(&'a self, file: &'a File) -> BoxFuture<'a, io::Result<()>> {
+        self.0.fsync(file).boxed()
+    }
+
+    fn fdatasync<'a>(&'a self, file: &'a File) -> BoxFuture<'a, io::Result<()>> {
+        self.0.fdatasync(file).boxed()
+    }
+}
+
+/// Blocking positional reads and writes.
+///
+/// They block the calling task, which costs little while the log files are
+/// in the page cache.
+struct Positional;
+
+impl IoBackend for Positional {
+    fn read_at<'a>(
+        &'a self,
+        file: &'a File,
+        buf: &'a mut [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        future::ready(read_exact_at(file, buf, pos)).boxed()
+    }
+
+    fn write_at<'a>(
+        &'a self,
+        file: &'a File,
+        buf: &'a [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        future::ready(write_all_at(file, buf, pos)).boxed()
+    }
+
+    fn fsync<'a>(&'a self, file: &'a File) -> BoxFuture<'a, io::Result<()>> {
+        future::ready(file.sync_all()).boxed()
+    }
+
+    fn fdatasync<'a>(&'a self, file: &'a File) -> BoxFuture<'a, io::Result<()>> {
+        future::ready(file.sync_data()).boxed()
+    }
+}
+
+/// Alignment of the offsets, lengths and buffers of direct I/O.
+const DIRECT_ALIGN: u64 = 4096;
+
+/// Fills `buf` with the bytes of `file`, opened for direct I/O, starting at
+/// `pos`. The aligned blocks around them are read into a buffer of their
+/// own, blocking the calling task.
+#[cfg(unix)]
+pub(crate) fn read_direct(file: &File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    let start = pos & !(DIRECT_ALIGN - 1);
+    let end = (pos + buf.len() as u64 + DIRECT_ALIGN - 1) & !(DIRECT_ALIGN - 1);
+    let len = (end - start) as usize;
+    let mut raw = vec![0u8; len + DIRECT_ALIGN as usize];
+    let offset = raw.as_ptr().align_offset(DIRECT_ALIGN as usize);
+    let aligned = &mut raw[offset..offset + len];
+    let skip = (pos - start) as usize;
+    let mut filled = 0;
+    while filled < skip + buf.len() {
+        match file.read_at(&mut aligned[filled..], start + filled as u64) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    buf.copy_from_slice(&aligned[skip..skip + buf.len()]);
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) fn read_direct(file: &File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    read_exact_at(file, buf, pos)
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, pos)
+}
+
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, buf: &[u8], pos: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, pos)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut std::mem::take(&mut buf)[n..];
+                pos += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut pos: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, pos)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                pos += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
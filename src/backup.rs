@@ -0,0 +1,331 @@
+//! Backing stores up to targets other than a local directory, such as object
+//! storage, see `KvStore::backup` and `KvStore::restore`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use futures::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::manifest::FORMAT_VERSION;
+use crate::{BackupStats, KvsError, Result};
+
+/// The object listing the others of a backup, put last.
+const MANIFEST: &str = "backup.json";
+
+/// Where `KvStore::backup` puts the files of a store, as named objects.
+pub trait BackupTarget: Send + Sync {
+    /// Stores the `len` bytes `data` reads as object `name`, replacing any
+    /// object of that name. A put cut off midway must leave no object, or
+    /// the one replaced.
+    fn put<'a>(
+        &'a self,
+        name: &'a str,
+        len: u64,
+        data: &'a mut (dyn Read + Send),
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Writes object `name` to `out`, returning false if there's none.
+    fn get<'a>(
+        &'a self,
+        name: &'a str,
+        out: &'a mut (dyn Write + Send),
+    ) -> BoxFuture<'a, Result<bool>>;
+
+    /// Returns the names of the objects.
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>>>;
+
+    /// Removes object `name`, if there is one.
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// A directory to back up to, such as one on a mounted network file
+/// system. Objects are files, written to a temporary file and renamed.
+#[derive(Debug, Clone)]
+pub struct DirTarget {
+    dir: PathBuf,
+}
+
+impl DirTarget {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DirTarget { dir: dir.into() }
+    }
+}
+
+impl BackupTarget for DirTarget {
+    fn put<'a>(
+        &'a self,
+        name: &'a str,
+        len: u64,
+        data: &'a mut (dyn Read + Send),
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let path = self.dir.join(check_name(name)?);
+            fs::create_dir_all(&self.dir)?;
+            let temp = self
+                .dir
+                .join(format!("{}.{:016x}.tmp", name, rand::random::<u64>()));
+            let mut file = File::create(&temp)?;
+            let res = io::copy(&mut data.take(len), &mut file).and_then(|copied| {
+                if copied < len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                file.sync_all()
+            });
+            if let Err(e) = res {
+                let _ = fs::remove_file(&temp);
+                return Err(e.into());
+            }
+            fs::rename(&temp, path)?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get<'a>(
+        &'a self,
+        name: &'a str,
+        out: &'a mut (dyn Write + Send),
+    ) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            let mut file = match File::open(self.dir.join(check_name(name)?)) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e.into()),
+            };
+            io::copy(&mut file, out)?;
+            Ok(true)
+        }
+        .boxed()
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        async move {
+            let files = match fs::read_dir(&self.dir) {
+                Ok(files) => files,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut names = Vec::new();
+            for file in files {
+                if let Some(name) = file?.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+            Ok(names)
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<()>> {
+        async move {
+            match fs::remove_file(self.dir.join(check_name(name)?)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// The objects of a backup, and what they should hold.
+#[derive(Serialize, Deserialize)]
+struct BackupManifest {
+    format_version: u32,
+    /// The checkpoint backed up.
+    checkpoint: String,
+    /// The objects holding the files of the checkpoint, by file name.
+    files: BTreeMap<String, Object>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Object {
+    name: String,
+    len: u64,
+    /// Hex-encoded SHA-256 of the contents.
+    sha256: String,
+}
+
+/// Puts the files of the checkpoint at `path`, named `checkpoint`, to
+/// `target`, and removes objects of earlier backups no longer needed.
+///
+/// Logs put by the previous backup are skipped, as sealed logs never
+/// change. The other files are put as objects named after the checkpoint,
+/// so until the manifest is replaced, last, the previous backup is intact.
+pub(crate) async fn backup(
+    path: &Path,
+    checkpoint: &str,
+    target: &dyn BackupTarget,
+) -> Result<BackupStats> {
+    let previous = read_manifest(target).await?;
+    let objects: HashSet<String> = target.list().await?.into_iter().collect();
+    let mut files = BTreeMap::new();
+    let mut stats = BackupStats {
+        checkpoint: checkpoint.to_owned(),
+        ..BackupStats::default()
+    };
+    let mut names = Vec::new();
+    for file in fs::read_dir(path)? {
+        if let Some(name) = file?.file_name().to_str() {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    for name in names {
+        let file = File::open(path.join(&name))?;
+        let len = file.metadata()?.len();
+        let is_log = name.ends_with(".log");
+        let put = previous
+            .as_ref()
+            .and_then(|previous| previous.files.get(&name))
+            .filter(|object| is_log && object.len == len && objects.contains(&object.name));
+        if let Some(object) = put {
+            files.insert(name, object.clone());
+            continue;
+        }
+        let object_name = if is_log {
+            name.clone()
+        } else {
+            format!("{}.{}", checkpoint, name)
+        };
+        let mut data = Hashing::new(file);
+        target.put(&object_name, len, &mut data).await?;
+        files.insert(name, data.object(object_name));
+        if is_log {
+            stats.logs_copied += 1;
+            stats.bytes_copied += len;
+        }
+    }
+
+    let manifest = BackupManifest {
+        format_version: FORMAT_VERSION,
+        checkpoint: checkpoint.to_owned(),
+        files,
+    };
+    let data = serde_json::to_vec_pretty(&manifest).expect("manifests are encodable");
+    target
+        .put(MANIFEST, data.len() as u64, &mut data.as_slice())
+        .await?;
+    let needed: HashSet<&str> = manifest
+        .files
+        .values()
+        .map(|object| object.name.as_str())
+        .collect();
+    for name in objects {
+        if name != MANIFEST && !needed.contains(name.as_str()) {
+            target.delete(&name).await?;
+            if name.ends_with(".log") {
+                stats.logs_removed += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Gets the backup in `target` into the new directory `dir`, checking
+/// each object against the manifest.
+pub(crate) async fn restore(target: &dyn BackupTarget, dir: &Path) -> Result<()> {
+    let manifest = read_manifest(target)
+        .await?
+        .ok_or_else(|| KvsError::Backup("the target holds no backup".to_owned()))?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(KvsError::UnsupportedFormat(
+            manifest.format_version,
+            FORMAT_VERSION,
+        ));
+    }
+    fs::create_dir_all(dir)?;
+    for (name, object) in &manifest.files {
+        let mut out = Hashing::new(File::create(dir.join(check_name(name)?))?);
+        if !target.get(&object.name, &mut out).await? {
+            return Err(KvsError::Backup(format!(
+                "object `{}` is missing",
+                object.name
+            )));
+        }
+        out.inner.sync_all()?;
+        let got = out.object(object.name.clone());
+        if got.len != object.len || got.sha256 != object.sha256 {
+            return Err(KvsError::Backup(format!(
+                "object `{}` doesn't match its checksum",
+                object.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+async fn read_manifest(target: &dyn BackupTarget) -> Result<Option<BackupManifest>> {
+    let mut data = Vec::new();
+    if !target.get(MANIFEST, &mut data).await? {
+        return Ok(None);
+    }
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| KvsError::Backup(format!("invalid manifest: {}", e)))
+}
+
+/// Fails unless `name` is a plain file name, so objects stay in the
+/// directory they're put in.
+fn check_name(name: &str) -> Result<&str> {
+    if name.is_empty() || Path::new(name).file_name() != Some(name.as_ref()) {
+        return Err(KvsError::Backup(format!("invalid object name `{}`", name)));
+    }
+    Ok(name)
+}
+
+/// Hashes what's read or written through it.
+pub(crate) struct Hashing<T> {
+    pub(crate) inner: T,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<T> Hashing<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Hashing {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Returns the SHA-256 of what passed through.
+    pub(crate) fn finish(self) -> [u8; 32] {
+        self.hasher.result().into()
+    }
+
+    fn object(self, name: String) -> Object {
+        let len = self.len;
+        Object {
+            name,
+            len,
+            sha256: self.finish().iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+impl<T: Read> Read for Hashing<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.input(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Hashing<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.input(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -1,47 +1,16 @@
-use std::net::SocketAddr;
+//! The same as `kvs client`, kept for compatibility.
 
-use async_std::task;
-use structopt::StructOpt;
-
-use kvs::{KvsClient, Result};
-
-#[derive(StructOpt, Debug)]
-struct Opt {
-    #[structopt(short, long, default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
-
-    #[structopt(subcommand)]
-    cmd: Command,
-}
-
-#[derive(StructOpt, Debug)]
-pub enum Command {
-    /// Set the value of a key
-    Set { key: String, value: String },
+#[path = "kvs/client.rs"]
+mod client;
+#[path = "kvs/config.rs"]
+#[allow(dead_code)]
+mod config;
+#[path = "kvs/output.rs"]
+#[allow(dead_code)]
+mod output;
 
-    /// Get the value of a key
-    Get { key: String },
-
-    /// Delete a key
-    Rm { key: String },
-}
+use structopt::StructOpt;
 
 fn main() {
-    let opt = Opt::from_args();
-    if let Err(e) = task::block_on(run(opt)) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
-    }
-}
-
-async fn run(opt: Opt) -> Result<()> {
-    let mut client = KvsClient::new(opt.addr).await?;
-    match opt.cmd {
-        Command::Get { key } => client.get(key).await.map(|value| match value {
-            Some(value) => println!("{}", value),
-            None => println!("Key not found"),
-        }),
-        Command::Set { key, value } => client.set(key, value).await,
-        Command::Rm { key } => client.remove(key).await,
-    }
+    client::main(client::ClientOpt::from_args());
 }
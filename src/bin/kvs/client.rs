@@ -0,0 +1,541 @@
+//! `kvs client`, which sends requests to a server.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use structopt::StructOpt;
+
+use kvs::units::{parse_duration, parse_size};
+use kvs::{ClientConfig, KvsClient, KvsError, OfflineClient, Result, Stats, Transform};
+
+use crate::config::{read_signing_key, read_token};
+use crate::output;
+
+#[derive(StructOpt, Debug)]
+pub struct ClientOpt {
+    #[structopt(short, long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+
+    /// File containing the shared key used to sign requests and responses
+    #[structopt(long, parse(from_os_str))]
+    signing_key_file: Option<PathBuf>,
+
+    /// File containing the token to authenticate with, for servers with
+    /// an ACL
+    #[structopt(long, parse(from_os_str))]
+    token_file: Option<PathBuf>,
+
+    /// Journal writes in this directory while the server is unreachable,
+    /// and replay them once it's back
+    #[structopt(long, parse(from_os_str))]
+    journal: Option<PathBuf>,
+
+    /// Send requests to this namespace of the server instead of the
+    /// default one
+    #[structopt(long, conflicts_with = "journal")]
+    namespace: Option<String>,
+
+    /// Send requests to the leader of the server's cluster, wherever it is
+    #[structopt(long, conflicts_with = "journal")]
+    leader: bool,
+
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Set the value of a key
+    Set { key: String, value: String },
+
+    /// Get the value of a key
+    Get {
+        key: String,
+
+        /// Have the server reply with `length`, `sha256` or `json:<pointer>`
+        /// of the value instead
+        #[structopt(long)]
+        transform: Option<Transform>,
+    },
+
+    /// Get the values of several keys at once, one a line
+    Mget {
+        #[structopt(required = true)]
+        keys: Vec<String>,
+    },
+
+    /// Print whether a key is set, without reading its value
+    Exists { key: String },
+
+    /// Print the length of the value of a key, without reading the value
+    Strlen { key: String },
+
+    /// Delete a key, or every key starting with a prefix
+    Rm {
+        #[structopt(required_unless = "prefix")]
+        key: Option<String>,
+
+        /// Delete all keys starting with this prefix
+        #[structopt(long, conflicts_with = "key")]
+        prefix: Option<String>,
+
+        /// Only print how many keys match the prefix
+        #[structopt(long, requires = "prefix")]
+        dry_run: bool,
+
+        /// Confirm deleting by prefix
+        #[structopt(long, requires = "prefix")]
+        yes: bool,
+    },
+
+    /// Delete several keys, printing how many of them were set
+    Del {
+        #[structopt(required = true)]
+        keys: Vec<String>,
+    },
+
+    /// Rename a key, replacing the value of the new name
+    Rename { from: String, to: String },
+
+    /// Copy the value of a key to another on the server
+    Copy {
+        from: String,
+        to: String,
+
+        /// Replace the value of the other key if it's set
+        #[structopt(long)]
+        overwrite: bool,
+    },
+
+    /// Print the keys starting with a prefix and their values, a page at a time
+    Scan {
+        #[structopt(default_value = "")]
+        prefix: String,
+
+        /// Stop after this many keys
+        #[structopt(long)]
+        limit: Option<u64>,
+
+        /// Print the keys as they were when the scan started, ignoring
+        /// changes made while it runs
+        #[structopt(long)]
+        snapshot: bool,
+    },
+
+    /// Print a digest of every key and value, for comparing servers
+    Digest,
+
+    /// Check the server is alive, printing how long it took to answer
+    Ping {
+        /// Have the server echo this back
+        payload: Option<String>,
+    },
+
+    /// Print a summary of the server: its clients, persistence, keys and
+    /// compaction
+    Info,
+
+    /// Print the nodes keys are sharded across, or the one owning a key
+    Topology {
+        #[structopt(long)]
+        key: Option<String>,
+    },
+
+    /// Print changes to keys starting with a prefix as they're made
+    Watch {
+        #[structopt(default_value = "")]
+        prefix: String,
+    },
+
+    /// Fill the server with random values, for demos and trying things out
+    Seed {
+        /// How many keys to write, like `10000` or `1M`
+        #[structopt(long, parse(try_from_str = parse_size), default_value = "10000")]
+        keys: u64,
+
+        /// Length of each value, like `256` or `4KiB`
+        #[structopt(long, parse(try_from_str = parse_size), default_value = "256")]
+        value_size: u64,
+
+        /// Prepended to the key numbers
+        #[structopt(long, default_value = "seed:")]
+        prefix: String,
+
+        /// How many writes to pipeline at once, at most the server's
+        /// `--max-in-flight`
+        #[structopt(long, default_value = "64")]
+        batch: u64,
+    },
+
+    /// Administrative commands
+    Admin(AdminCommand),
+}
+
+#[derive(StructOpt, Debug)]
+pub enum AdminCommand {
+    /// Analyze server statistics and recommend configuration
+    Tune,
+
+    /// Change an option of the server's store while it runs, e.g.
+    /// `compaction_ratio 0.5` or `sync_interval none`
+    Config { name: String, value: String },
+
+    /// Compact the store's logs now instead of waiting for the threshold
+    Compact {
+        /// Only compact the log with this generation
+        #[structopt(long)]
+        gen: Option<u64>,
+    },
+
+    /// Show the size, dead bytes and age of each log file
+    Segments,
+
+    /// Flush the store and hold writes, for taking a file system snapshot
+    Freeze {
+        /// Let writes go on after this long, even without `admin thaw`
+        #[structopt(long, parse(try_from_str = parse_duration), default_value = "1m")]
+        timeout: Duration,
+    },
+
+    /// Let writes held by `admin freeze` go on
+    Thaw,
+
+    /// Record a CPU flamegraph of the server (needs the `profiling` feature)
+    Profile {
+        /// How long to sample for
+        #[structopt(long, default_value = "10")]
+        seconds: u64,
+
+        /// Where to write the SVG
+        #[structopt(short, long, parse(from_os_str), default_value = "flamegraph.svg")]
+        output: PathBuf,
+    },
+}
+
+/// How many keys the server deletes per request when deleting by prefix.
+const REMOVE_BATCH: u64 = 1000;
+
+pub fn main(opt: ClientOpt) {
+    output::exit_on_error(kvs::block_on(run(opt)));
+}
+
+async fn run(opt: ClientOpt) -> Result<()> {
+    let config = ClientConfig {
+        signing_key: read_signing_key(opt.signing_key_file)?,
+        token: read_token(opt.token_file)?,
+    };
+    if let Some(dir) = opt.journal {
+        return run_offline(opt.addr, config, dir, opt.cmd).await;
+    }
+    let mut client = if opt.leader {
+        KvsClient::connect_leader(opt.addr, config).await?
+    } else {
+        KvsClient::connect(opt.addr, config).await?
+    };
+    if opt.namespace.is_some() {
+        client.select(opt.namespace).await?;
+    }
+    match opt.cmd {
+        Command::Get {
+            key,
+            transform: Some(transform),
+        } => client
+            .get_transformed(key, transform)
+            .await
+            .map(output::value),
+        Command::Get { key, .. } => client.get(key).await.map(output::value),
+        Command::Mget { keys } => client
+            .get_many(keys)
+            .await
+            .map(|values| values.into_iter().for_each(output::value)),
+        Command::Exists { key } => client.exists(key).await.map(|found| println!("{}", found)),
+        Command::Strlen { key } => client.strlen(key).await.map(output::value_len),
+        Command::Set { key, value } => client.set(key, value).await,
+        Command::Rm { key: Some(key), .. } => client.remove(key).await,
+        Command::Rename { from, to } => client.rename(from, to).await,
+        Command::Copy {
+            from,
+            to,
+            overwrite,
+        } => {
+            if !client.copy(from, to.clone(), overwrite).await? {
+                println!("Key {:?} is already set, see --overwrite", to);
+            }
+            Ok(())
+        }
+        Command::Del { keys } => client
+            .remove_many(keys)
+            .await
+            .map(|removed| println!("{} keys removed", removed)),
+        Command::Rm {
+            prefix: Some(prefix),
+            dry_run,
+            yes,
+            ..
+        } => {
+            let count = client.count_prefix(prefix.clone()).await?;
+            println!("{} keys match prefix {:?}", count, prefix);
+            if dry_run || count == 0 {
+                return Ok(());
+            }
+            if !yes {
+                eprintln!("Refusing to delete without --yes");
+                std::process::exit(1);
+            }
+            let mut removed = 0;
+            loop {
+                let n = client.remove_prefix(prefix.clone(), REMOVE_BATCH).await?;
+                if n == 0 {
+                    break;
+                }
+                removed += n;
+                eprintln!("Deleted {}/{} keys", removed, count);
+            }
+            Ok(())
+        }
+        Command::Rm { .. } => unreachable!("structopt requires a key or a prefix"),
+        Command::Scan {
+            prefix,
+            limit,
+            snapshot: true,
+        } => {
+            let mut remaining = limit.unwrap_or(u64::MAX);
+            let mut cursor = None;
+            while remaining > 0 {
+                let page = client
+                    .scan_snapshot(prefix.clone(), cursor, remaining, true)
+                    .await?;
+                remaining = remaining.saturating_sub(page.keys.len() as u64);
+                let values = page.values.unwrap_or_default();
+                for (key, value) in page.keys.iter().zip(&values) {
+                    output::entry(key, value);
+                }
+                cursor = match page.cursor {
+                    Some(cursor) => Some(cursor),
+                    None => break,
+                };
+            }
+            Ok(())
+        }
+        Command::Scan { prefix, limit, .. } => {
+            let mut remaining = limit.unwrap_or(u64::MAX);
+            let mut start_after = None;
+            while remaining > 0 {
+                let page = client
+                    .scan(prefix.clone(), start_after, remaining, u64::MAX)
+                    .await?;
+                remaining = remaining.saturating_sub(page.entries.len() as u64);
+                for (key, value) in page.entries {
+                    output::entry(&key, &value);
+                }
+                start_after = match page.cursor {
+                    Some(cursor) => Some(cursor),
+                    None => break,
+                };
+            }
+            Ok(())
+        }
+        Command::Digest => {
+            println!("{}", output::hex(&client.digest().await?));
+            Ok(())
+        }
+        Command::Ping { payload } => {
+            let payload = payload.unwrap_or_default();
+            let start = Instant::now();
+            let echoed = client.ping(payload.as_bytes()).await?;
+            let elapsed = start.elapsed();
+            if echoed.is_empty() {
+                println!("pong in {:?}", elapsed);
+            } else {
+                println!("pong {} in {:?}", String::from_utf8_lossy(&echoed), elapsed);
+            }
+            Ok(())
+        }
+        Command::Info => {
+            output::info(&client.info().await?);
+            Ok(())
+        }
+        Command::Topology { key } => {
+            let topology = match client.topology().await? {
+                Some(topology) => topology,
+                None => output::fail("the server isn't sharded"),
+            };
+            match key {
+                Some(key) => println!("{}", topology.owner(key.as_bytes())),
+                None => topology
+                    .nodes()
+                    .iter()
+                    .for_each(|node| println!("{}", node)),
+            }
+            Ok(())
+        }
+        Command::Watch { prefix } => {
+            let mut subscription = client.subscribe(prefix).await?;
+            while let Some(event) = subscription.next().await? {
+                output::watch_event(&event);
+            }
+            Ok(())
+        }
+        Command::Seed {
+            keys,
+            value_size,
+            prefix,
+            batch,
+        } => {
+            // Pad key numbers so they sort in the order they're written.
+            let width = keys.saturating_sub(1).to_string().len();
+            let mut rng = rand::thread_rng();
+            let mut written = 0;
+            while written < keys {
+                let pairs = (written..keys.min(written + batch.max(1)))
+                    .map(|i| {
+                        let key = format!("{}{:0width$}", prefix, i, width = width);
+                        let value = (&mut rng)
+                            .sample_iter(&Alphanumeric)
+                            .take(value_size as usize)
+                            .collect();
+                        (key, value)
+                    })
+                    .collect::<Vec<_>>();
+                written += pairs.len() as u64;
+                client.set_many(pairs).await?;
+                eprintln!("Wrote {}/{} keys", written, keys);
+            }
+            Ok(())
+        }
+        Command::Admin(AdminCommand::Tune) => {
+            tune(&client.stats().await?);
+            Ok(())
+        }
+        Command::Admin(AdminCommand::Config { name, value }) => client.configure(name, value).await,
+        Command::Admin(AdminCommand::Compact { gen }) => {
+            output::compaction(&client.compact(gen).await?);
+            Ok(())
+        }
+        Command::Admin(AdminCommand::Segments) => {
+            output::segments(&client.segment_stats().await?);
+            Ok(())
+        }
+        Command::Admin(AdminCommand::Freeze { timeout }) => {
+            client.freeze_writes(timeout).await?;
+            eprintln!(
+                "Writes frozen for up to {:?}, run `admin thaw` after the snapshot",
+                timeout
+            );
+            Ok(())
+        }
+        Command::Admin(AdminCommand::Thaw) => {
+            if !client.thaw().await? {
+                eprintln!("Writes weren't frozen");
+            }
+            Ok(())
+        }
+        Command::Admin(AdminCommand::Profile { seconds, output }) => {
+            eprintln!("Sampling for {} seconds...", seconds);
+            let svg = client.profile(seconds).await?;
+            std::fs::write(&output, svg)?;
+            eprintln!("Wrote {}", output.display());
+            Ok(())
+        }
+    }
+}
+
+async fn run_offline(
+    addr: SocketAddr,
+    config: ClientConfig,
+    dir: PathBuf,
+    cmd: Command,
+) -> Result<()> {
+    let mut client = OfflineClient::open(addr, config, dir).await?;
+    match client.sync().await {
+        Ok(conflicts) => {
+            for c in conflicts {
+                eprintln!(
+                    "Conflict: {:?} was {:?} instead of {:?}, not written {:?}",
+                    c.key, c.actual, c.expected, c.value
+                );
+            }
+        }
+        Err(KvsError::Io(_)) => eprintln!(
+            "Server unreachable, working offline ({} writes pending)",
+            client.pending().await?
+        ),
+        Err(e) => return Err(e),
+    }
+    match cmd {
+        Command::Get {
+            key,
+            transform: None,
+        } => client.get(key).await.map(output::value),
+        Command::Set { key, value } => client.set(key, value).await,
+        Command::Rm { key: Some(key), .. } => client.remove(key).await,
+        _ => {
+            eprintln!("Only get, set and rm of a single key work with --journal");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints configuration recommendations based on `stats`.
+fn tune(stats: &Stats) {
+    println!("Keys:            {}", stats.keys);
+    println!("Log files:       {}", stats.log_files);
+    println!("Live bytes:      {}", stats.live_bytes);
+    println!("Dead bytes:      {}", stats.dead_bytes);
+    println!("Max file size:   {}", stats.max_file_size);
+    println!(
+        "Compaction at:   {} dead bytes per file",
+        stats.compaction_threshold
+    );
+    println!(
+        "Queued reads:    {} (waited {:?} in total)",
+        stats.queued_reads, stats.read_queue_wait
+    );
+    println!();
+
+    let mut recommendations = Vec::new();
+    // The smallest record size that 99% of records don't exceed.
+    let mut seen = 0;
+    let p99 = stats
+        .record_sizes
+        .iter()
+        .enumerate()
+        .find(|&(_, &count)| {
+            seen += count;
+            seen * 100 >= stats.keys * 99
+        })
+        .map_or(0, |(bucket, _)| 1u64 << bucket);
+    if p99 * 100 > stats.max_file_size {
+        recommendations.push(format!(
+            "99% of records are up to {} bytes, so a log file only holds about {} of them. \
+             Raise the max file size to at least {} bytes.",
+            p99,
+            stats.max_file_size / p99,
+            (p99 * 1000).next_power_of_two()
+        ));
+    }
+    let total = stats.live_bytes + stats.dead_bytes;
+    if total > 0 && stats.dead_bytes * 2 > total {
+        recommendations.push(format!(
+            "{}% of the data on disk is dead. Lower the compaction threshold to reclaim it sooner.",
+            stats.dead_bytes * 100 / total
+        ));
+    }
+    if stats.log_files > 1000 {
+        recommendations.push(format!(
+            "The store has {} log files. Raise the max file size to reduce open files.",
+            stats.log_files
+        ));
+    }
+
+    if recommendations.is_empty() {
+        println!("The current configuration suits this data.");
+    }
+    for recommendation in recommendations {
+        println!("* {}", recommendation);
+    }
+    println!("Cache hit rate and fsync latency aren't collected by this server.");
+}
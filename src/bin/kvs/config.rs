@@ -0,0 +1,132 @@
+//! Options shared by the subcommands that open a store or talk to a server.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use kvs::units::{parse_duration, parse_ratio, parse_size};
+use kvs::{Acl, MaintenanceWindow, Options, Result, SigningKey};
+
+/// Options of the store, for the server and the tools opening it directly.
+#[derive(StructOpt, Debug, Clone)]
+pub struct StoreOpt {
+    /// Start a new log file after this size, e.g. `256MB` or `1GiB`
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_file_size: Option<u64>,
+
+    /// Fraction of a log file that must be dead before it is compacted, in (0, 1]
+    #[structopt(long, parse(try_from_str = parse_ratio))]
+    compaction_ratio: Option<f64>,
+
+    /// Sync the active log file this often, e.g. `50ms`
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    sync_interval: Option<Duration>,
+
+    /// Sync the log before acknowledging writes
+    #[structopt(long)]
+    sync_writes: bool,
+
+    /// Only compact during this UTC window, e.g. `mon-fri 01:00-05:00` or
+    /// `* 22:00-02:00` (may be repeated)
+    #[structopt(long = "maintenance-window", number_of_values = 1)]
+    maintenance_windows: Vec<MaintenanceWindow>,
+
+    /// Keep this many previous values of each key
+    #[structopt(long, default_value = "0")]
+    versions: usize,
+
+    /// Spill the keydir to index files once it takes this much memory, e.g. `512MiB`
+    #[structopt(long, parse(try_from_str = parse_size))]
+    keydir_memory: Option<u64>,
+
+    /// Reject keys longer than this, e.g. `1KiB`
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_key_size: Option<u64>,
+
+    /// Reject values longer than this, e.g. `1MiB`
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_value_size: Option<u64>,
+
+    /// Keep every log in one file rather than a file each
+    #[structopt(long)]
+    single_file: bool,
+
+    /// Keep at most this many log files open at once
+    #[structopt(long)]
+    max_open_files: Option<usize>,
+
+    /// Read log files with direct I/O, bypassing the page cache
+    #[structopt(long)]
+    direct_io: bool,
+
+    /// Log every mutation to an audit log in this directory
+    #[structopt(long, parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+
+    /// Rotate the audit log after this size, e.g. `64MiB`
+    #[structopt(long, parse(try_from_str = parse_size))]
+    audit_max_size: Option<u64>,
+}
+
+impl StoreOpt {
+    pub fn options(self) -> std::result::Result<Options, String> {
+        if self.max_file_size == Some(0) {
+            return Err("--max-file-size must be positive".to_owned());
+        }
+        if self.max_open_files == Some(0) {
+            return Err("--max-open-files must be positive".to_owned());
+        }
+        if self.sync_interval == Some(Duration::from_secs(0)) {
+            return Err("--sync-interval must be positive".to_owned());
+        }
+        let options = Options::default();
+        Ok(Options {
+            max_file_size: self.max_file_size.unwrap_or(options.max_file_size),
+            compaction_ratio: self.compaction_ratio.unwrap_or(options.compaction_ratio),
+            sync_interval: self.sync_interval,
+            sync_writes: self.sync_writes,
+            maintenance_windows: self.maintenance_windows,
+            versions: self.versions,
+            keydir_memory: self.keydir_memory,
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+            single_file: self.single_file,
+            max_open_files: self.max_open_files.unwrap_or(options.max_open_files),
+            direct_io: self.direct_io,
+            audit_log: self.audit_log,
+            audit_max_size: self.audit_max_size.unwrap_or(options.audit_max_size),
+            ..options
+        })
+    }
+}
+
+/// Reads the shared key from `--signing-key-file`, if it's given.
+pub fn read_signing_key(path: Option<PathBuf>) -> Result<Option<SigningKey>> {
+    match path {
+        Some(path) => Ok(Some(SigningKey::new(std::fs::read_to_string(path)?.trim()))),
+        None => Ok(None),
+    }
+}
+
+/// Reads the auth token from `--token-file`, if it's given.
+pub fn read_token(path: Option<PathBuf>) -> Result<Option<String>> {
+    match path {
+        Some(path) => Ok(Some(std::fs::read_to_string(path)?.trim().to_owned())),
+        None => Ok(None),
+    }
+}
+
+/// Reads the tokens and what they allow from `--acl-file`, if it's given.
+pub fn read_acl(path: Option<PathBuf>) -> std::result::Result<Option<Acl>, String> {
+    match path {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+            let acl = text
+                .parse()
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            Ok(Some(acl))
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,65 @@
+//! `kvs`, which runs the client, the server and the tools working on a
+//! store's directory as subcommands. `kvs-client` and `kvs-server` are the
+//! same as `kvs client` and `kvs server`.
+
+mod client;
+mod config;
+mod output;
+mod server;
+mod tools;
+
+use structopt::StructOpt;
+
+// Parsed once, so the size of the server's options doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt, Debug)]
+enum Opt {
+    /// Send requests to a server
+    Client(client::ClientOpt),
+
+    /// Serve a store, in the current directory unless given `--dir`
+    Server(server::ServerOpt),
+
+    /// Check a store's logs and keydir for damage
+    Fsck(tools::StoreDirOpt),
+
+    /// Print every key and value of a store
+    Dump(tools::StoreDirOpt),
+
+    /// Compact a store's logs
+    Compact(tools::StoreDirOpt),
+
+    /// Back a store up, copying only what changed since the last backup
+    Backup(tools::BackupOpt),
+
+    /// Export a store to a single archive file
+    Export(tools::ExportOpt),
+
+    /// Create a store from an archive file made by `export`
+    Import(tools::ImportOpt),
+
+    /// Measure how fast a new store writes and reads
+    Bench(tools::BenchOpt),
+
+    /// Copy every key and value of another engine's data into a new store
+    Migrate(tools::MigrateOpt),
+
+    /// Print the entries of an audit log
+    Audit(tools::AuditOpt),
+}
+
+fn main() {
+    match Opt::from_args() {
+        Opt::Client(opt) => client::main(opt),
+        Opt::Server(opt) => server::main(opt),
+        Opt::Fsck(opt) => output::exit_on_error(kvs::block_on(tools::fsck(opt))),
+        Opt::Dump(opt) => output::exit_on_error(kvs::block_on(tools::dump(opt))),
+        Opt::Compact(opt) => output::exit_on_error(kvs::block_on(tools::compact(opt))),
+        Opt::Backup(opt) => output::exit_on_error(kvs::block_on(tools::backup(opt))),
+        Opt::Export(opt) => output::exit_on_error(kvs::block_on(tools::export(opt))),
+        Opt::Import(opt) => output::exit_on_error(kvs::block_on(tools::import(opt))),
+        Opt::Bench(opt) => output::exit_on_error(kvs::block_on(tools::bench(opt))),
+        Opt::Migrate(opt) => output::exit_on_error(kvs::block_on(tools::migrate(opt))),
+        Opt::Audit(opt) => output::exit_on_error(tools::audit(opt)),
+    }
+}
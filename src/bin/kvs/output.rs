@@ -0,0 +1,158 @@
+//! Formatting of what the subcommands print.
+
+use std::time::Duration;
+
+use kvs::{
+    AuditEntry, BackupStats, CompactionStats, Info, Result, SegmentStats, WatchEvent, Watermark,
+};
+
+/// Prints the error and exits unsuccessfully if `res` failed.
+pub fn exit_on_error(res: Result<()>) {
+    if let Err(e) = res {
+        fail(e);
+    }
+}
+
+pub fn fail(error: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", error);
+    std::process::exit(1);
+}
+
+pub fn value(value: Option<String>) {
+    match value {
+        Some(value) => println!("{}", value),
+        None => println!("Key not found"),
+    }
+}
+
+pub fn value_len(len: Option<u64>) {
+    match len {
+        Some(len) => println!("{}", len),
+        None => println!("Key not found"),
+    }
+}
+
+/// Prints a key and its value on one line, as `scan` and `dump` list them.
+pub fn entry(key: &str, value: &str) {
+    println!("{}\t{}", key, value);
+}
+
+/// Prints a change as `set`, the key and the value, or `remove` and the
+/// key, on one line.
+pub fn watch_event(event: &WatchEvent) {
+    match event {
+        WatchEvent::Set { key, value } => println!(
+            "set\t{}\t{}",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(value)
+        ),
+        WatchEvent::Remove { key } => println!("remove\t{}", String::from_utf8_lossy(key)),
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn compaction(stats: &CompactionStats) {
+    println!(
+        "Compacted {} logs, reclaiming {} bytes",
+        stats.logs_compacted, stats.bytes_reclaimed
+    );
+}
+
+pub fn backup(stats: &BackupStats) {
+    println!(
+        "Copied {} logs ({} bytes), removed {}; next backup --since {}",
+        stats.logs_copied, stats.bytes_copied, stats.logs_removed, stats.checkpoint
+    );
+}
+
+pub fn segments(segments: &[SegmentStats]) {
+    println!(
+        "{:>6} {:>12} {:>12} {:>12} {:>6} {:>8} {:>10}",
+        "Gen", "Size", "Live", "Dead", "Dead%", "Records", "Age"
+    );
+    for segment in segments {
+        println!(
+            "{:>6} {:>12} {:>12} {:>12} {:>5.1}% {:>8} {:>9}s",
+            segment.gen,
+            segment.size,
+            segment.live_bytes,
+            segment.dead_bytes,
+            segment.dead_ratio() * 100.0,
+            segment.live_records,
+            segment.age.as_secs()
+        );
+    }
+}
+
+/// Prints each section of `info` under a heading.
+pub fn info(info: &Info) {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_owned());
+    let watermark = |watermark: Option<Watermark>| {
+        optional(watermark.map(|w| format!("change {} (log {}, {} bytes)", w.seq, w.gen, w.len)))
+    };
+    println!("# Server");
+    println!("Version:         {}", info.server.version);
+    println!("Engine:          {}", info.server.engine);
+    println!("Role:            {}", info.server.role);
+    println!("Uptime:          {}s", info.server.uptime.as_secs());
+    println!();
+    println!("# Clients");
+    println!("Connected:       {}", info.clients.connected);
+    println!(
+        "Max connections: {}",
+        optional(info.clients.max_connections.map(|max| max.to_string()))
+    );
+    if let Some(persistence) = &info.persistence {
+        println!();
+        println!("# Persistence");
+        println!("Last change:     {}", persistence.seq);
+        println!("Durable up to:   {}", watermark(persistence.durable));
+        println!("Recovered to:    {}", watermark(persistence.recovered));
+        println!(
+            "Sync interval:   {}",
+            optional(persistence.sync_interval.map(|i| format!("{:?}", i)))
+        );
+    }
+    println!();
+    println!("# Keyspace");
+    println!("Keys:            {}", info.keyspace.keys);
+    if let Some(live_bytes) = info.keyspace.live_bytes {
+        println!("Live bytes:      {}", live_bytes);
+    }
+    if let Some(compaction) = &info.compaction {
+        println!();
+        println!("# Compaction");
+        println!("Dead bytes:      {}", compaction.dead_bytes);
+        println!("Log files:       {}", compaction.log_files);
+        println!("Max file size:   {}", compaction.max_file_size);
+        println!("Threshold:       {}", compaction.compaction_threshold);
+    }
+}
+
+/// Prints how many operations per second `op` ran, `n` times in `elapsed`.
+pub fn throughput(op: &str, n: u64, elapsed: Duration) {
+    println!(
+        "{:<6} {:>10.0} ops/s ({} in {:?})",
+        op,
+        n as f64 / elapsed.as_secs_f64(),
+        n,
+        elapsed
+    );
+}
+
+pub fn audit_entry(entry: &AuditEntry) {
+    let client = match entry.client {
+        Some(client) => client.to_string(),
+        None => "-".to_owned(),
+    };
+    println!(
+        "{}\t{}\t{}\t{}",
+        humantime::format_rfc3339_millis(entry.at),
+        client,
+        entry.op,
+        entry.key
+    );
+}
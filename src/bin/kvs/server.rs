@@ -0,0 +1,412 @@
+//! `kvs server`, which serves the store in a directory, the current one by
+//! default.
+
+#[cfg(target_os = "linux")]
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either, Future};
+use futures::stream::{Stream, StreamExt};
+#[cfg(target_os = "linux")]
+use futures::FutureExt;
+use kvs::units::{parse_duration, parse_size};
+use kvs::{Chaos, Cluster, EngineKind, KvsServer, Protocol, Redaction, ServerConfig, Sharding};
+use log::{info, warn, LevelFilter};
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+
+use crate::config::{read_acl, read_signing_key, StoreOpt};
+use crate::output;
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct ServerOpt {
+    /// Address to listen
+    #[structopt(short, long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+
+    /// Directory to keep the data in, created if it doesn't exist
+    #[structopt(long, parse(from_os_str), default_value = ".")]
+    dir: PathBuf,
+
+    /// File of settings overriding the flags, one `name = value` a line,
+    /// read again on SIGHUP: `log_level`, `signing_key_file`, `acl_file`,
+    /// `idle_timeout`, `max_connections`, `max_in_flight`, `max_scan_keys`,
+    /// `max_scan_bytes`, `max_frame_size`, `max_chunked_value_size`, and the
+    /// store options of `kvs client admin config`
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Engine to serve keys from, which must be the one the directory's
+    /// data was written by
+    #[structopt(long, possible_values = &["kvs", "sled"], default_value = "kvs")]
+    engine: EngineKind,
+
+    /// Protocol clients speak: `resp` serves redis-cli and Redis client
+    /// libraries, with GET, SET, MGET, MSET, DEL, EXISTS, STRLEN, RENAME, COPY
+    /// and PING
+    #[structopt(long, possible_values = &["kvs", "resp"], default_value = "kvs")]
+    protocol: Protocol,
+
+    /// File containing the shared key used to sign requests and responses
+    #[structopt(long, parse(from_os_str))]
+    signing_key_file: Option<PathBuf>,
+
+    /// File of the tokens clients authenticate with, one a line as `<token>
+    /// <read|write|admin> [<prefix>...]`, allowing what the permission does
+    /// on keys starting with the prefixes, or every key without any
+    #[structopt(long, parse(from_os_str))]
+    acl_file: Option<PathBuf>,
+
+    /// Never log values of keys starting with this prefix (may be repeated)
+    #[structopt(long = "redact-prefix", number_of_values = 1)]
+    redact_prefixes: Vec<String>,
+
+    #[structopt(flatten)]
+    store: StoreOpt,
+
+    /// Close connections idle for this long, e.g. `5m`
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    idle_timeout: Option<Duration>,
+
+    /// Refuse connections beyond this many open, telling clients the server
+    /// is busy
+    #[structopt(long, default_value = "1024")]
+    max_connections: usize,
+
+    /// Reject requests beyond this many in flight on one connection
+    #[structopt(long, default_value = "64")]
+    max_in_flight: usize,
+
+    /// Serve connections on this many tasks, queueing those beyond, rather
+    /// than on a task each
+    #[structopt(long)]
+    workers: Option<usize>,
+
+    /// Refuse connections beyond this many waiting for a worker, telling
+    /// clients the server is busy. Defaults to the number of workers
+    #[structopt(long, requires = "workers")]
+    worker_queue: Option<usize>,
+
+    /// Most keys returned by one scan request
+    #[structopt(long, default_value = "1000")]
+    max_scan_keys: usize,
+
+    /// Most bytes of keys and values returned by one scan request, e.g. `1MiB`
+    #[structopt(long, parse(try_from_str = parse_size), default_value = "1MiB")]
+    max_scan_bytes: u64,
+
+    /// Longest request taken, e.g. `64MiB`. Longer ones are refused, closing
+    /// the connection
+    #[structopt(long, parse(try_from_str = parse_size), default_value = "64MiB")]
+    max_frame_size: u64,
+
+    /// Longest value set in chunks, e.g. `64MiB`, as it's held in memory
+    /// whole until its last chunk
+    #[structopt(long, parse(try_from_str = parse_size), default_value = "64MiB")]
+    max_chunked_value_size: u64,
+
+    /// Serve keys starting with a prefix from the memory engine, e.g.
+    /// `cache:=memory` (may be repeated)
+    #[structopt(long = "route", number_of_values = 1, parse(try_from_str = parse_route))]
+    routes: Vec<(String, EngineKind)>,
+
+    /// Append every request received to this file, to replay it later
+    #[structopt(long, parse(from_os_str))]
+    record_session: Option<PathBuf>,
+
+    /// Replace values in the recorded session, keeping their lengths
+    #[structopt(long, requires = "record-session")]
+    scrub_values: bool,
+
+    /// Also speak the memcached text protocol on this address
+    #[structopt(long)]
+    memcached_addr: Option<SocketAddr>,
+
+    /// Also serve a JSON API over HTTP on this address, at `/keys/{key}`
+    /// and `/stats`
+    #[structopt(long)]
+    http_addr: Option<SocketAddr>,
+
+    /// Replicate the primary server at this address, serving reads only.
+    /// The data directory is emptied once the primary is reached
+    #[structopt(long)]
+    replica_of: Option<SocketAddr>,
+
+    /// Address of a node of the Raft cluster to run in, this one included,
+    /// in the same order on every node (may be repeated). Only the leader
+    /// serves reads and writes
+    #[structopt(long = "cluster-node", number_of_values = 1, requires = "node-id")]
+    cluster_nodes: Vec<SocketAddr>,
+
+    /// Address of a node to shard keys across, this one included (may be
+    /// repeated). Keys other nodes own are forwarded to them
+    #[structopt(
+        long = "shard-node",
+        number_of_values = 1,
+        requires = "node-id",
+        conflicts_with_all = &["cluster-nodes", "replica-of"]
+    )]
+    shard_nodes: Vec<SocketAddr>,
+
+    /// Position of this node among the `--cluster-node` or `--shard-node`
+    /// addresses, from 0
+    #[structopt(long)]
+    node_id: Option<usize>,
+
+    /// Serve a GraphQL endpoint at `/graphql` on this address
+    #[cfg(feature = "graphql")]
+    #[structopt(long)]
+    graphql_addr: Option<SocketAddr>,
+
+    /// Delay requests of an operation, e.g. `get=50ms` or `*=10ms`
+    #[structopt(long, hidden = true, number_of_values = 1, parse(try_from_str = parse_latency))]
+    chaos_latency: Vec<(String, Duration)>,
+
+    /// Fail a fraction of requests of an operation, e.g. `set=0.1`
+    #[structopt(long, hidden = true, number_of_values = 1, parse(try_from_str = parse_op_value))]
+    chaos_error_rate: Vec<(String, f64)>,
+}
+
+fn parse_op_value<T: std::str::FromStr>(s: &str) -> std::result::Result<(String, T), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(op), Some(value)) => match value.parse() {
+            Ok(value) => Ok((op.to_owned(), value)),
+            Err(_) => Err(format!("invalid value in `{}`", s)),
+        },
+        _ => Err(format!("expected `op=value`, got `{}`", s)),
+    }
+}
+
+fn parse_route(s: &str) -> std::result::Result<(String, EngineKind), String> {
+    let mut parts = s.rsplitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(engine), Some(prefix)) => Ok((prefix.to_owned(), engine.parse()?)),
+        _ => Err(format!("expected `prefix=engine`, got `{}`", s)),
+    }
+}
+
+fn parse_latency(s: &str) -> std::result::Result<(String, Duration), String> {
+    let (op, value): (String, String) = parse_op_value(s)?;
+    Ok((op, parse_duration(&value)?))
+}
+
+/// Returns a future completing once the process receives SIGINT or
+/// SIGTERM, exiting at once on a second one, and a stream yielding every
+/// SIGHUP.
+///
+/// The signals are blocked and taken by a thread of their own with
+/// `sigwait`, so this must be called before any other thread is started,
+/// which would otherwise take them.
+#[cfg(target_os = "linux")]
+fn signals() -> (impl Future<Output = ()>, impl Stream<Item = ()>) {
+    let (sender, receiver) = oneshot::channel();
+    let (hangups, hangups_received) = mpsc::unbounded();
+    // Safety: the set is initialized by `sigemptyset` before it's used.
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGHUP);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        thread::spawn(move || {
+            let mut sender = Some(sender);
+            loop {
+                let mut signal = 0;
+                libc::sigwait(&signals, &mut signal);
+                if signal == libc::SIGHUP {
+                    let _ = hangups.unbounded_send(());
+                    continue;
+                }
+                match sender.take() {
+                    Some(sender) => {
+                        info!("Received signal {}, shutting down", signal);
+                        let _ = sender.send(());
+                    }
+                    None => std::process::exit(128 + signal),
+                }
+            }
+        });
+    }
+    (receiver.map(|_| ()), hangups_received)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn signals() -> (impl Future<Output = ()>, impl Stream<Item = ()>) {
+    (futures::future::pending(), futures::stream::pending())
+}
+
+/// Returns the config `opt` describes, with the settings of its config
+/// file, and the log level set there.
+fn server_config(opt: ServerOpt) -> Result<(ServerConfig, Option<LevelFilter>), String> {
+    let mut chaos = Chaos::default();
+    for (op, latency) in opt.chaos_latency {
+        chaos.add_latency(op, latency);
+    }
+    for (op, rate) in opt.chaos_error_rate {
+        chaos.add_error_rate(op, rate);
+    }
+    let store = opt.store.options()?;
+    let signing_key = read_signing_key(opt.signing_key_file).map_err(|e| e.to_string())?;
+    let mut config = ServerConfig {
+        signing_key,
+        acl: read_acl(opt.acl_file)?,
+        redaction: Redaction::new(opt.redact_prefixes),
+        chaos,
+        dir: Some(opt.dir),
+        engine: opt.engine,
+        store,
+        routes: opt.routes,
+        protocol: opt.protocol,
+        idle_timeout: opt.idle_timeout,
+        max_connections: Some(opt.max_connections),
+        max_in_flight: Some(opt.max_in_flight),
+        workers: opt.workers,
+        worker_queue: opt.worker_queue,
+        max_scan_keys: Some(opt.max_scan_keys),
+        max_scan_bytes: Some(opt.max_scan_bytes),
+        max_frame_size: Some(opt.max_frame_size),
+        max_chunked_value_size: Some(opt.max_chunked_value_size),
+        record_session: opt.record_session,
+        scrub_recorded_values: opt.scrub_values,
+        memcached_addr: opt.memcached_addr,
+        http_addr: opt.http_addr,
+        #[cfg(feature = "graphql")]
+        graphql_addr: opt.graphql_addr,
+        replica_of: opt.replica_of,
+        cluster: None,
+        sharding: None,
+    };
+    match opt.node_id {
+        Some(id) if !opt.cluster_nodes.is_empty() => {
+            config.cluster = Some(Cluster {
+                nodes: opt.cluster_nodes,
+                id,
+                max_log_entries: None,
+            })
+        }
+        Some(id) if !opt.shard_nodes.is_empty() => {
+            config.sharding = Some(Sharding {
+                nodes: opt.shard_nodes,
+                id,
+            })
+        }
+        Some(_) => return Err("--node-id needs --cluster-node or --shard-node".to_owned()),
+        None => {}
+    }
+    let log_level = match opt.config {
+        Some(path) => read_config_file(&path, &mut config)
+            .map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => None,
+    };
+    Ok((config, log_level))
+}
+
+/// Applies the settings in the config file at `path` to `config`,
+/// returning the log level if it's set.
+fn read_config_file(path: &Path, config: &mut ServerConfig) -> Result<Option<LevelFilter>, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut log_level = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let res = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => match (name.trim(), value.trim()) {
+                ("log_level", value) => value
+                    .parse()
+                    .map(|level| log_level = Some(level))
+                    .map_err(|_| format!("unknown log level `{}`", value)),
+                ("signing_key_file", value) => read_signing_key(Some(value.into()))
+                    .map(|key| config.signing_key = key)
+                    .map_err(|e| e.to_string()),
+                ("acl_file", value) => read_acl(Some(value.into())).map(|acl| config.acl = acl),
+                (name, value) => config.set(name, value),
+            },
+            _ => Err(format!("expected `name = value`, got `{}`", line)),
+        };
+        res.map_err(|e| format!("line {}: {}", i + 1, e))?;
+    }
+    Ok(log_level)
+}
+
+/// Logs at `level`, or at `info` if it's `None`, unless `RUST_LOG` is set,
+/// which then decides alone.
+fn set_log_level(level: Option<LevelFilter>) {
+    if env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(level.unwrap_or(LevelFilter::Info));
+    }
+}
+
+pub fn main(opt: ServerOpt) {
+    let (shutdown, hangups) = signals();
+    let (config, log_level) = server_config(opt.clone()).unwrap_or_else(|e| output::fail(e));
+    // Everything passes the logger, so the level can be changed later.
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("trace"));
+    set_log_level(log_level);
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Data directory: {}", opt.dir.display());
+    info!("Engine: {}", opt.engine);
+    info!("Listening on {} ({})", opt.addr, opt.protocol);
+    if let Some(addr) = opt.memcached_addr {
+        info!("Listening on {} (memcached)", addr);
+    }
+    if let Some(addr) = opt.http_addr {
+        info!("Listening on {} (HTTP)", addr);
+    }
+    if let Some(primary) = opt.replica_of {
+        info!("Replica of {}", primary);
+    }
+    match opt.node_id {
+        Some(id) if !opt.cluster_nodes.is_empty() => {
+            info!("Node {} of a cluster of {}", id, opt.cluster_nodes.len())
+        }
+        Some(id) => info!("Node {} of {} sharing the keys", id, opt.shard_nodes.len()),
+        None => {}
+    }
+
+    let addr = opt.addr;
+    let reloads = hangups.filter_map(move |()| {
+        let reloaded = match server_config(opt.clone()) {
+            Ok((config, log_level)) => {
+                set_log_level(log_level);
+                Some(config)
+            }
+            Err(e) => {
+                warn!("Not reloading the configuration: {}", e);
+                None
+            }
+        };
+        future::ready(reloaded)
+    });
+    output::exit_on_error(kvs::block_on(serve(addr, config, shutdown, reloads)));
+}
+
+/// Runs a server until `shutdown` completes, reloading it with every config
+/// `reloads` yields.
+async fn serve(
+    addr: SocketAddr,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+    reloads: impl Stream<Item = ServerConfig>,
+) -> kvs::Result<()> {
+    let server = KvsServer::bind(addr, config).await?;
+    let reloading = reloads.for_each(|config| server.reload(config));
+    let stopping = async {
+        shutdown.await;
+        server.shutdown();
+    };
+    let controlling = future::join(reloading, stopping);
+    let res = match future::select(Box::pin(server.run()), Box::pin(controlling)).await {
+        Either::Left((res, _)) => res,
+        Either::Right((_, running)) => running.await,
+    };
+    res
+}
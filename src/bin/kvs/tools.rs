@@ -0,0 +1,271 @@
+//! Subcommands working on a store's directory directly, while no server
+//! has it open.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime};
+
+use futures::StreamExt;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use structopt::StructOpt;
+
+use kvs::units::parse_size;
+use kvs::{AuditQuery, KvStore, Options, Result};
+
+use crate::config::StoreOpt;
+use crate::output;
+
+#[derive(StructOpt, Debug)]
+pub struct StoreDirOpt {
+    /// Directory of the store
+    #[structopt(parse(from_os_str), default_value = ".")]
+    dir: PathBuf,
+
+    #[structopt(flatten)]
+    store: StoreOpt,
+}
+
+impl StoreDirOpt {
+    /// Opens the store, which must exist already.
+    async fn open(self) -> Result<KvStore> {
+        let options = self.store.options().unwrap_or_else(|e| output::fail(e));
+        let options = Options {
+            create_if_missing: false,
+            ..options
+        };
+        KvStore::open_with_options(self.dir, options).await
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub struct BenchOpt {
+    /// Directory to create the store in, which mustn't exist yet
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    /// How many keys to write and read, like `10000` or `1M`
+    #[structopt(long, parse(try_from_str = parse_size), default_value = "10000")]
+    keys: u64,
+
+    /// Length of each value, like `256` or `4KiB`
+    #[structopt(long, parse(try_from_str = parse_size), default_value = "256")]
+    value_size: u64,
+
+    #[structopt(flatten)]
+    store: StoreOpt,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct BackupOpt {
+    /// Directory of the store
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    /// Directory to back the store up to
+    #[structopt(parse(from_os_str))]
+    dest: PathBuf,
+
+    /// Checkpoint of the previous backup to the directory, which the last
+    /// backup printed
+    #[structopt(long)]
+    since: Option<String>,
+
+    #[structopt(flatten)]
+    store: StoreOpt,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct ExportOpt {
+    /// Directory of the store
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    /// File to write the archive to
+    #[structopt(parse(from_os_str))]
+    archive: PathBuf,
+
+    #[structopt(flatten)]
+    store: StoreOpt,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct ImportOpt {
+    /// Archive to import
+    #[structopt(parse(from_os_str))]
+    archive: PathBuf,
+
+    /// Directory to create the store in, which mustn't hold one yet
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct MigrateOpt {
+    /// Engine whose data to copy
+    #[structopt(long, possible_values = &["sled"])]
+    from: String,
+
+    /// Data directory of the engine
+    #[structopt(parse(from_os_str))]
+    source: PathBuf,
+
+    /// Directory to create the store in, which mustn't hold one yet
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    #[structopt(flatten)]
+    store: StoreOpt,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct AuditOpt {
+    /// Directory of the audit log
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    /// Only entries made at or after this time, e.g. `2020-02-01T00:00:00Z`
+    #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak))]
+    since: Option<SystemTime>,
+
+    /// Only entries made before this time
+    #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak))]
+    until: Option<SystemTime>,
+
+    /// Only entries of keys starting with this
+    #[structopt(long)]
+    prefix: Option<String>,
+
+    /// Only entries of clients with this IP address
+    #[structopt(long)]
+    client: Option<IpAddr>,
+}
+
+/// Verifies the store, exiting unsuccessfully if it's damaged.
+pub async fn fsck(opt: StoreDirOpt) -> Result<()> {
+    let store = opt.open().await?;
+    let report = store.verify().await?;
+    store.close().await?;
+    println!("{}", report);
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+pub async fn dump(opt: StoreDirOpt) -> Result<()> {
+    let store = opt.open().await?;
+    let mut entries = store.scan(..).await?;
+    while let Some(entry) = entries.next().await {
+        let (key, value) = entry?;
+        output::entry(
+            &String::from_utf8_lossy(&key),
+            &String::from_utf8_lossy(&value),
+        );
+    }
+    drop(entries);
+    store.close().await
+}
+
+pub async fn compact(opt: StoreDirOpt) -> Result<()> {
+    let store = opt.open().await?;
+    output::compaction(&store.compact().await?);
+    store.close().await
+}
+
+pub async fn backup(opt: BackupOpt) -> Result<()> {
+    let store = StoreDirOpt {
+        dir: opt.dir,
+        store: opt.store,
+    }
+    .open()
+    .await?;
+    let stats = store
+        .backup_incremental(&opt.dest, opt.since.as_deref())
+        .await?;
+    store.close().await?;
+    output::backup(&stats);
+    Ok(())
+}
+
+pub async fn export(opt: ExportOpt) -> Result<()> {
+    let store = StoreDirOpt {
+        dir: opt.dir,
+        store: opt.store,
+    }
+    .open()
+    .await?;
+    let len = store.export_archive(&opt.archive).await?;
+    store.close().await?;
+    println!("Exported {} bytes to {}", len, opt.archive.display());
+    Ok(())
+}
+
+pub async fn import(opt: ImportOpt) -> Result<()> {
+    KvStore::import_archive(&opt.archive, &opt.dir).await?;
+    println!(
+        "Imported {} into {}",
+        opt.archive.display(),
+        opt.dir.display()
+    );
+    Ok(())
+}
+
+/// Writes random values to a new store, then reads them back in random order.
+pub async fn bench(opt: BenchOpt) -> Result<()> {
+    if opt.dir.exists() {
+        output::fail(format!("{} already exists", opt.dir.display()));
+    }
+    fs::create_dir_all(&opt.dir)?;
+    let options = opt.store.options().unwrap_or_else(|e| output::fail(e));
+    let store = KvStore::open_with_options(&opt.dir, options).await?;
+    let mut rng = rand::thread_rng();
+    let value: String = (&mut rng)
+        .sample_iter(&Alphanumeric)
+        .take(opt.value_size as usize)
+        .collect();
+
+    let start = Instant::now();
+    for i in 0..opt.keys {
+        store.set(format!("bench:{}", i), &value).await?;
+    }
+    output::throughput("set", opt.keys, start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..opt.keys {
+        let i = rng.gen_range(0, opt.keys);
+        store.get(format!("bench:{}", i)).await?;
+    }
+    output::throughput("get", opt.keys, start.elapsed());
+    store.close().await
+}
+
+pub async fn migrate(opt: MigrateOpt) -> Result<()> {
+    let options = opt.store.options().unwrap_or_else(|e| output::fail(e));
+    let options = Options {
+        error_if_exists: true,
+        ..options
+    };
+    let store = KvStore::open_with_options(&opt.dir, options).await?;
+    let copied = match opt.from.as_str() {
+        "sled" => kvs::migrate_from_sled(&opt.source, &store).await?,
+        _ => unreachable!("structopt checks the engine"),
+    };
+    store.close().await?;
+    println!("Copied {} keys from {}", copied, opt.source.display());
+    Ok(())
+}
+
+pub fn audit(opt: AuditOpt) -> Result<()> {
+    let query = AuditQuery {
+        since: opt.since,
+        until: opt.until,
+        key_prefix: opt.prefix,
+        client: opt.client,
+    };
+    for entry in kvs::read_audit_log(&opt.dir, &query)? {
+        output::audit_entry(&entry);
+    }
+    Ok(())
+}
@@ -0,0 +1,542 @@
+//! Blocking wrappers of `KvStore` and `KvsClient`, for programs that don't
+//! run an async runtime.
+//!
+//! Each call drives the async version to completion with `block_on`, so
+//! these must not be used from within an async context.
+
+use std::net::SocketAddr;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::executor::block_on_stream;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::rt::{block_on, ToSocketAddrs};
+use super::{
+    BackupStats, BackupTarget, BatchOp, Capabilities, ClientConfig, CompactionStats, Info,
+    Metadata, Options, Result, ScanPage, SegmentStats, SnapshotPage, Stats, Topology, Transform,
+    VerifyReport, WatchEvent,
+};
+
+/// A blocking `KvStore`. Cloning it is cheap, and clones share the store.
+#[derive(Clone)]
+pub struct KvStore {
+    inner: super::KvStore,
+}
+
+impl KvStore {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(dir, Options::default())
+    }
+
+    pub fn open_with_options(dir: impl Into<PathBuf>, options: Options) -> Result<Self> {
+        let inner = block_on(super::KvStore::open_with_options(dir, options))?;
+        Ok(KvStore { inner })
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::open_in_memory_with_options(Options::default())
+    }
+
+    pub fn open_in_memory_with_options(options: Options) -> Result<Self> {
+        let inner = block_on(super::KvStore::open_in_memory_with_options(options))?;
+        Ok(KvStore { inner })
+    }
+
+    /// See `KvStore::open_checkpoint`.
+    pub fn open_checkpoint(dir: impl Into<PathBuf>, name: &str) -> Result<Self> {
+        let inner = block_on(super::KvStore::open_checkpoint(dir, name))?;
+        Ok(KvStore { inner })
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        block_on(self.inner.get(key))
+    }
+
+    /// Returns the value of `key` along with its metadata.
+    pub fn get_with_metadata(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, Metadata)>> {
+        block_on(self.inner.get_with_metadata(key))
+    }
+
+    /// See `KvStore::get_version`.
+    pub fn get_version(&self, key: impl AsRef<[u8]>, n: usize) -> Result<Option<Vec<u8>>> {
+        block_on(self.inner.get_version(key, n))
+    }
+
+    /// See `KvStore::history`.
+    pub fn history(&self, key: impl AsRef<[u8]>) -> Result<Vec<Vec<u8>>> {
+        block_on(self.inner.history(key))
+    }
+
+    /// Gets the values of several keys at once, in the order of `keys`.
+    pub fn multi_get<I, K>(&self, keys: I) -> Result<Vec<Option<Vec<u8>>>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        block_on(self.inner.multi_get(keys))
+    }
+
+    /// Returns the keys within `range` in ascending order.
+    pub fn keys<R>(&self, range: R) -> Result<Vec<Vec<u8>>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        block_on(async { Ok(self.inner.keys(range).await?.collect().await) })
+    }
+
+    /// Returns the keys and values within `range` in ascending order, as of
+    /// when this is called. See `KvStore::scan`.
+    pub fn scan<R>(&self, range: R) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        Ok(block_on_stream(block_on(self.inner.scan(range))?))
+    }
+
+    /// Returns the keys and values within `range` in descending order, as of
+    /// when this is called. See `KvStore::scan_rev`.
+    pub fn scan_rev<R>(&self, range: R) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        Ok(block_on_stream(block_on(self.inner.scan_rev(range))?))
+    }
+
+    /// Returns the keys starting with `prefix` in ascending order.
+    pub fn keys_with_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<Vec<Vec<u8>>> {
+        block_on(async { Ok(self.inner.keys_with_prefix(prefix).await?.collect().await) })
+    }
+
+    pub fn set(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        block_on(self.inner.set(key, value))
+    }
+
+    /// Sets `key` to `value` and its flags to `flags`.
+    pub fn set_with_flags(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        flags: u8,
+    ) -> Result<()> {
+        block_on(self.inner.set_with_flags(key, value, flags))
+    }
+
+    /// See `KvStore::set_typed`.
+    pub fn set_typed<T: Serialize + ?Sized>(&self, key: impl AsRef<[u8]>, value: &T) -> Result<()> {
+        block_on(self.inner.set_typed(key, value))
+    }
+
+    /// See `KvStore::get_typed`.
+    pub fn get_typed<T: DeserializeOwned>(&self, key: impl AsRef<[u8]>) -> Result<Option<T>> {
+        block_on(self.inner.get_typed(key))
+    }
+
+    /// Sets `key` to `value` only if it doesn't exist. Returns whether it was set.
+    pub fn set_nx(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool> {
+        block_on(self.inner.set_nx(key, value))
+    }
+
+    /// Sets `key` to `value` only if it exists. Returns whether it was set.
+    pub fn set_xx(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool> {
+        block_on(self.inner.set_xx(key, value))
+    }
+
+    pub fn remove(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        block_on(self.inner.remove(key))
+    }
+
+    /// See `KvStore::compare_and_set`.
+    pub fn compare_and_set(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        value: Option<&[u8]>,
+    ) -> Result<bool> {
+        block_on(self.inner.compare_and_set(key, expected, value))
+    }
+
+    /// See `KvStore::compare_and_set_flags`.
+    pub fn compare_and_set_flags(
+        &self,
+        key: impl AsRef<[u8]>,
+        mask: u8,
+        expected: u8,
+        flags: u8,
+    ) -> Result<bool> {
+        block_on(self.inner.compare_and_set_flags(key, mask, expected, flags))
+    }
+
+    /// See `KvStore::estimate_count`.
+    pub fn estimate_count(&self, prefix: impl AsRef<[u8]>) -> Result<u64> {
+        block_on(self.inner.estimate_count(prefix))
+    }
+
+    /// Removes the given keys, returning how many existed.
+    pub fn remove_many<I, K>(&self, keys: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        block_on(self.inner.remove_many(keys))
+    }
+
+    /// Removes every key starting with `prefix`, returning how many were removed.
+    pub fn delete_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<usize> {
+        block_on(self.inner.delete_prefix(prefix))
+    }
+
+    /// Returns an iterator over changes made to keys starting with `prefix`.
+    ///
+    /// `next` blocks until the next change is made.
+    pub fn watch(&self, prefix: impl Into<Vec<u8>>) -> impl Iterator<Item = WatchEvent> {
+        block_on_stream(Box::pin(self.inner.watch(prefix)))
+    }
+
+    /// See `KvStore::flush`.
+    pub fn flush(&self) -> Result<()> {
+        block_on(self.inner.flush())
+    }
+
+    /// See `KvStore::checkpoint`.
+    pub fn checkpoint(&self, name: &str) -> Result<()> {
+        block_on(self.inner.checkpoint(name))
+    }
+
+    /// See `KvStore::backup_incremental`.
+    pub fn backup_incremental(
+        &self,
+        dest: impl AsRef<Path>,
+        since_checkpoint: Option<&str>,
+    ) -> Result<BackupStats> {
+        block_on(self.inner.backup_incremental(dest, since_checkpoint))
+    }
+
+    /// See `KvStore::backup`.
+    pub fn backup(&self, target: &dyn BackupTarget) -> Result<BackupStats> {
+        block_on(self.inner.backup(target))
+    }
+
+    /// See `KvStore::restore`.
+    pub fn restore(target: &dyn BackupTarget, dir: impl AsRef<Path>) -> Result<()> {
+        block_on(super::KvStore::restore(target, dir))
+    }
+
+    /// See `KvStore::export_archive`.
+    pub fn export_archive(&self, path: impl AsRef<Path>) -> Result<u64> {
+        block_on(self.inner.export_archive(path))
+    }
+
+    /// See `KvStore::import_archive`.
+    pub fn import_archive(path: impl AsRef<Path>, dir: impl AsRef<Path>) -> Result<()> {
+        block_on(super::KvStore::import_archive(path, dir))
+    }
+
+    /// See `KvStore::close`.
+    pub fn close(self) -> Result<()> {
+        block_on(self.inner.close())
+    }
+
+    /// Returns a Merkle root of every key and value, see `KvStore::digest`.
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        block_on(self.inner.digest())
+    }
+
+    /// See `KvStore::compact`.
+    pub fn compact(&self) -> Result<CompactionStats> {
+        block_on(self.inner.compact())
+    }
+
+    /// See `KvStore::compact_gen`.
+    pub fn compact_gen(&self, gen: u64) -> Result<CompactionStats> {
+        block_on(self.inner.compact_gen(gen))
+    }
+
+    /// Returns the options the store currently runs with.
+    pub fn options(&self) -> Options {
+        self.inner.options()
+    }
+
+    /// See `KvStore::reconfigure`.
+    pub fn reconfigure(&self, options: Options) {
+        self.inner.reconfigure(options)
+    }
+
+    /// See `KvStore::set_option`.
+    pub fn set_option(&self, name: &str, value: &str) -> Result<()> {
+        self.inner.set_option(name, value)
+    }
+
+    /// See `KvStore::verify`.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        block_on(self.inner.verify())
+    }
+
+    /// See `KvStore::freeze_writes`.
+    pub fn freeze_writes(&self, timeout: Duration) -> Result<()> {
+        block_on(self.inner.freeze_writes(timeout))
+    }
+
+    /// See `KvStore::thaw`.
+    pub fn thaw(&self) -> bool {
+        self.inner.thaw()
+    }
+
+    /// See `KvStore::segment_stats`.
+    pub fn segment_stats(&self) -> Result<Vec<SegmentStats>> {
+        block_on(self.inner.segment_stats())
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        block_on(self.inner.stats())
+    }
+
+    /// Returns the async store this wraps.
+    pub fn as_async(&self) -> &super::KvStore {
+        &self.inner
+    }
+}
+
+/// A blocking `KvsClient`.
+pub struct KvsClient {
+    inner: super::KvsClient,
+}
+
+impl KvsClient {
+    pub fn new(addr: impl ToSocketAddrs) -> Result<Self> {
+        Self::connect(addr, ClientConfig::default())
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs, config: ClientConfig) -> Result<Self> {
+        let inner = block_on(super::KvsClient::connect(addr, config))?;
+        Ok(KvsClient { inner })
+    }
+
+    /// See `KvsClient::connect_leader`.
+    pub fn connect_leader(addr: SocketAddr, config: ClientConfig) -> Result<Self> {
+        let inner = block_on(super::KvsClient::connect_leader(addr, config))?;
+        Ok(KvsClient { inner })
+    }
+
+    /// Returns what the server supports, as it reported on connecting.
+    pub fn capabilities(&self) -> &Capabilities {
+        self.inner.capabilities()
+    }
+
+    /// See `KvsClient::auth`.
+    pub fn auth(&mut self, token: String) -> Result<()> {
+        block_on(self.inner.auth(token))
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        block_on(self.inner.set(key, value))
+    }
+
+    /// See `KvsClient::set_many`.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        block_on(self.inner.set_many(pairs))
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        block_on(self.inner.get(key))
+    }
+
+    /// See `KvsClient::get_many`.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        block_on(self.inner.get_many(keys))
+    }
+
+    /// See `KvsClient::exists`.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        block_on(self.inner.exists(key))
+    }
+
+    /// See `KvsClient::strlen`.
+    pub fn strlen(&mut self, key: String) -> Result<Option<u64>> {
+        block_on(self.inner.strlen(key))
+    }
+
+    /// See `KvsClient::get_transformed`.
+    pub fn get_transformed(&mut self, key: String, transform: Transform) -> Result<Option<String>> {
+        block_on(self.inner.get_transformed(key, transform))
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        block_on(self.inner.remove(key))
+    }
+
+    /// See `KvsClient::remove_many`.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<u64> {
+        block_on(self.inner.remove_many(keys))
+    }
+
+    /// See `KvsClient::rename`.
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        block_on(self.inner.rename(from, to))
+    }
+
+    /// See `KvsClient::begin`.
+    pub fn begin(&mut self) -> Result<()> {
+        block_on(self.inner.begin())
+    }
+
+    /// See `KvsClient::exec`.
+    pub fn exec(&mut self) -> Result<()> {
+        block_on(self.inner.exec())
+    }
+
+    /// See `KvsClient::discard`.
+    pub fn discard(&mut self) -> Result<()> {
+        block_on(self.inner.discard())
+    }
+
+    /// See `KvsClient::copy`.
+    pub fn copy(&mut self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        block_on(self.inner.copy(from, to, overwrite))
+    }
+
+    /// Returns how many keys start with `prefix`.
+    pub fn count_prefix(&mut self, prefix: String) -> Result<u64> {
+        block_on(self.inner.count_prefix(prefix))
+    }
+
+    /// Removes up to `limit` keys starting with `prefix` on the server,
+    /// returning how many were removed.
+    pub fn remove_prefix(&mut self, prefix: String, limit: u64) -> Result<u64> {
+        block_on(self.inner.remove_prefix(prefix, limit))
+    }
+
+    /// See `KvsClient::scan`.
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        start_after: Option<String>,
+        max_keys: u64,
+        max_bytes: u64,
+    ) -> Result<ScanPage> {
+        block_on(self.inner.scan(prefix, start_after, max_keys, max_bytes))
+    }
+
+    /// See `KvsClient::topology`.
+    pub fn topology(&mut self) -> Result<Option<Topology>> {
+        block_on(self.inner.topology())
+    }
+
+    /// See `KvsClient::leader`.
+    pub fn leader(&mut self) -> Result<Option<SocketAddr>> {
+        block_on(self.inner.leader())
+    }
+
+    /// See `KvsClient::scan_snapshot`.
+    pub fn scan_snapshot(
+        &mut self,
+        prefix: String,
+        cursor: Option<u64>,
+        limit: u64,
+        values: bool,
+    ) -> Result<SnapshotPage> {
+        block_on(self.inner.scan_snapshot(prefix, cursor, limit, values))
+    }
+
+    /// See `KvsClient::compare_and_set`.
+    pub fn compare_and_set(
+        &mut self,
+        id: u64,
+        key: String,
+        expected: Option<Option<String>>,
+        value: Option<String>,
+    ) -> Result<bool> {
+        block_on(self.inner.compare_and_set(id, key, expected, value))
+    }
+
+    /// See `KvsClient::configure`.
+    pub fn configure(&mut self, name: String, value: String) -> Result<()> {
+        block_on(self.inner.configure(name, value))
+    }
+
+    /// See `KvsClient::compact`.
+    pub fn compact(&mut self, gen: Option<u64>) -> Result<CompactionStats> {
+        block_on(self.inner.compact(gen))
+    }
+
+    /// See `KvsClient::segment_stats`.
+    pub fn segment_stats(&mut self) -> Result<Vec<SegmentStats>> {
+        block_on(self.inner.segment_stats())
+    }
+
+    /// See `KvsClient::freeze_writes`.
+    pub fn freeze_writes(&mut self, timeout: Duration) -> Result<()> {
+        block_on(self.inner.freeze_writes(timeout))
+    }
+
+    /// See `KvsClient::thaw`.
+    pub fn thaw(&mut self) -> Result<bool> {
+        block_on(self.inner.thaw())
+    }
+
+    /// See `KvsClient::batch`.
+    pub fn batch(&mut self, ops: Vec<BatchOp>) -> Result<Vec<Result<Option<String>>>> {
+        block_on(self.inner.batch(ops))
+    }
+
+    /// See `KvsClient::subscribe`.
+    pub fn subscribe(self, prefix: String) -> Result<Subscription> {
+        let inner = block_on(self.inner.subscribe(prefix))?;
+        Ok(Subscription { inner })
+    }
+
+    /// See `KvsClient::select`.
+    pub fn select(&mut self, namespace: Option<String>) -> Result<()> {
+        block_on(self.inner.select(namespace))
+    }
+
+    /// Samples the server's CPU usage for `seconds`, returning a flamegraph SVG.
+    pub fn profile(&mut self, seconds: u64) -> Result<Vec<u8>> {
+        block_on(self.inner.profile(seconds))
+    }
+
+    /// Returns the digest of the server's store.
+    pub fn digest(&mut self) -> Result<[u8; 32]> {
+        block_on(self.inner.digest())
+    }
+
+    pub fn stats(&mut self) -> Result<Stats> {
+        block_on(self.inner.stats())
+    }
+
+    /// See `KvsClient::ping`.
+    pub fn ping(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        block_on(self.inner.ping(payload))
+    }
+
+    /// See `KvsClient::info`.
+    pub fn info(&mut self) -> Result<Info> {
+        block_on(self.inner.info())
+    }
+}
+
+/// A blocking `Subscription`.
+pub struct Subscription {
+    inner: super::Subscription,
+}
+
+impl Subscription {
+    /// See `Subscription::unsubscribe`.
+    pub fn unsubscribe(self) -> Result<KvsClient> {
+        let inner = block_on(self.inner.unsubscribe())?;
+        Ok(KvsClient { inner })
+    }
+}
+
+/// Yields changes until the server ends the subscription, see
+/// `Subscription::next`.
+impl Iterator for Subscription {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Result<WatchEvent>> {
+        block_on(self.inner.next()).transpose()
+    }
+}
@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{rt, KvsError, Result};
+
+/// Faults injected into request handling, per operation type.
+///
+/// Meant for staging servers, so applications can exercise their timeout
+/// and retry handling. Rules for the operation `*` apply to every request.
+#[derive(Debug, Default, Clone)]
+pub struct Chaos {
+    latency: HashMap<String, Duration>,
+    error_rate: HashMap<String, f64>,
+}
+
+impl Chaos {
+    /// Delays every `op` request by `latency`.
+    pub fn add_latency(&mut self, op: impl Into<String>, latency: Duration) {
+        self.latency.insert(op.into(), latency);
+    }
+
+    /// Fails a `rate` fraction of `op` requests.
+    pub fn add_error_rate(&mut self, op: impl Into<String>, rate: f64) {
+        self.error_rate.insert(op.into(), rate);
+    }
+
+    /// Applies the rules for `op`, returning an error if the request should fail.
+    pub(crate) async fn inject(&self, op: &str) -> Result<()> {
+        if let Some(latency) = lookup(&self.latency, op) {
+            rt::sleep(*latency).await;
+        }
+        match lookup(&self.error_rate, op) {
+            Some(&rate) if rand::random::<f64>() < rate => Err(KvsError::InjectedFault),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn lookup<'a, T>(rules: &'a HashMap<String, T>, op: &str) -> Option<&'a T> {
+    rules.get(op).or_else(|| rules.get("*"))
+}
@@ -1,34 +1,643 @@
-use async_std::net::{TcpStream, ToSocketAddrs};
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
 
-use super::{receive, send, KvsError, Request, Result};
+use serde::de::DeserializeOwned;
 
-type Response = std::result::Result<Option<String>, String>;
+use super::multiplex::MultiplexedClient;
+use super::raft::{Message, Reply};
+use super::rt::{self, ToSocketAddrs};
+use super::shard::Forward;
+use super::{
+    Capabilities, Change, Chunk, CompactionStats, Connection, Info, KvsError, Request, Result,
+    ScanPage, SegmentStats, ServerError, SigningKey, SnapshotPage, Stats, Topology, Transform,
+    WatchEvent, VALUE_CHUNK_BYTES,
+};
+
+pub(crate) type Response<T> = std::result::Result<T, ServerError>;
+
+/// An operation sent in a batch, see `KvsClient::batch`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    Get { key: String },
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// Changes pushed by a server, see `KvsClient::subscribe`.
+pub struct Subscription {
+    client: KvsClient,
+    /// Whether the server sent the last event.
+    ended: bool,
+}
+
+impl Subscription {
+    /// Waits for the next change. Returns `None` once the server ended the
+    /// subscription, as it shut down.
+    pub async fn next(&mut self) -> Result<Option<WatchEvent>> {
+        if self.ended {
+            return Ok(None);
+        }
+        let event: Option<WatchEvent> = self.client.conn.receive().await?;
+        self.ended = event.is_none();
+        Ok(event)
+    }
+
+    /// Ends the subscription, dropping changes not received yet, and
+    /// returns the client.
+    pub async fn unsubscribe(mut self) -> Result<KvsClient> {
+        if !self.ended {
+            self.client.conn.send(&Request::Unsubscribe).await?;
+            while self.next().await?.is_some() {}
+        }
+        Ok(self.client)
+    }
+}
+
+/// Changes streamed by a primary to its replica, see `KvsClient::replicate`.
+pub(crate) struct Replication {
+    client: KvsClient,
+    /// Whether the server sent the last change.
+    ended: bool,
+}
+
+impl Replication {
+    /// Waits for the next change. Returns `None` once the server ended the
+    /// replication, as it shut down.
+    pub(crate) async fn next(&mut self) -> Result<Option<Change>> {
+        if self.ended {
+            return Ok(None);
+        }
+        let change: Option<Change> = self.client.conn.receive().await?;
+        self.ended = change.is_none();
+        Ok(change)
+    }
+}
+
+/// Options for connecting to a `kvs-server`.
+#[derive(Debug, Default, Clone)]
+pub struct ClientConfig {
+    /// Sign requests and verify responses with this key.
+    pub signing_key: Option<SigningKey>,
+    /// Authenticate with this token on connecting, see `KvsClient::auth`.
+    pub token: Option<String>,
+}
 
 pub struct KvsClient {
-    stream: TcpStream,
+    conn: Connection,
+    capabilities: Capabilities,
 }
 
 impl KvsClient {
     pub async fn new(addr: impl ToSocketAddrs) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
-        Ok(KvsClient { stream })
+        Self::connect(addr, ClientConfig::default()).await
     }
 
+    pub async fn connect(addr: impl ToSocketAddrs, config: ClientConfig) -> Result<Self> {
+        let stream = rt::connect(addr).await?;
+        let (conn, capabilities) = Connection::connect(stream, config.signing_key.as_ref()).await?;
+        let mut client = KvsClient { conn, capabilities };
+        if let Some(token) = config.token {
+            client.auth(token).await?;
+        }
+        Ok(client)
+    }
+
+    /// Connects to the server at `addr`, or to the leader of its cluster if
+    /// that's another node, see `leader`.
+    pub async fn connect_leader(addr: SocketAddr, config: ClientConfig) -> Result<Self> {
+        let mut client = Self::connect(addr, config.clone()).await?;
+        if !client.capabilities.supports("leader") {
+            return Ok(client);
+        }
+        match client.leader().await? {
+            Some(leader) if leader != addr => Self::connect(leader, config).await,
+            _ => Ok(client),
+        }
+    }
+
+    /// Returns what the server supports, as it reported on connecting.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Authenticates the connection's later requests with `token`, allowing
+    /// what the server's ACL lets it, see `ServerConfig::acl`. Fails with
+    /// `ServerError::AuthRequired` if the server doesn't know the token.
+    pub async fn auth(&mut self, token: String) -> Result<()> {
+        self.require("auth")?;
+        self.conn.send(&Request::Auth { token }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Fails with `ServerTooOld` unless the server handles `op` requests.
+    fn require(&self, op: &'static str) -> Result<()> {
+        if self.capabilities.supports(op) {
+            Ok(())
+        } else {
+            Err(KvsError::ServerTooOld(op))
+        }
+    }
+
+    /// Sets `key` to `value`, sent in chunks if it's longer than one, and
+    /// the server takes them.
     pub async fn set(&mut self, key: String, value: String) -> Result<()> {
-        send(&mut self.stream, &Request::Set { key, value }).await?;
-        let resp: Response = bincode::deserialize(&receive(&mut self.stream).await?)?;
-        resp.map(|_| ()).map_err(KvsError::Server)
+        if value.len() > VALUE_CHUNK_BYTES && self.capabilities.supports("set_chunk") {
+            return self.set_chunked(key, value).await;
+        }
+        self.conn.send(&Request::Set { key, value }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Sends `value` in chunks, every one before waiting for the replies.
+    async fn set_chunked(&mut self, key: String, value: String) -> Result<()> {
+        let count = value.len().div_ceil(VALUE_CHUNK_BYTES);
+        for (i, data) in value.as_bytes().chunks(VALUE_CHUNK_BYTES).enumerate() {
+            let chunk = Request::SetChunk {
+                key: key.clone(),
+                data: data.to_vec(),
+                more: i + 1 < count,
+            };
+            self.conn.send(&chunk).await?;
+        }
+        let mut res = Ok(());
+        for _ in 0..count {
+            let resp: Response<()> = self.conn.receive().await?;
+            if res.is_ok() {
+                res = resp.map_err(KvsError::Server);
+            }
+        }
+        res
+    }
+
+    /// Sets several keys, in one request written with a single write if
+    /// they fit in a chunk and the server takes it. Otherwise every request
+    /// is sent before waiting for the replies. Fails with the first error,
+    /// once all replies are received.
+    pub async fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let len: usize = pairs
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        if len <= VALUE_CHUNK_BYTES && self.capabilities.supports("multi_set") {
+            self.conn.send(&Request::MultiSet { pairs }).await?;
+            let resp: Response<()> = self.conn.receive().await?;
+            return resp.map_err(KvsError::Server);
+        }
+        let count = pairs.len();
+        for (key, value) in pairs {
+            self.conn.send(&Request::Set { key, value }).await?;
+        }
+        let mut res = Ok(());
+        for _ in 0..count {
+            let resp: Response<()> = self.conn.receive().await?;
+            if res.is_ok() {
+                res = resp.map_err(KvsError::Server);
+            }
+        }
+        res
     }
 
+    /// Gets the value of `key`, received in chunks if the server sends
+    /// them.
     pub async fn get(&mut self, key: String) -> Result<Option<String>> {
-        send(&mut self.stream, &Request::Get { key }).await?;
-        let resp: Response = bincode::deserialize(&receive(&mut self.stream).await?)?;
+        if self.capabilities.supports("get_chunked") {
+            return self.get_chunked(key).await;
+        }
+        self.conn.send(&Request::Get { key }).await?;
+        let resp: Response<Option<String>> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    async fn get_chunked(&mut self, key: String) -> Result<Option<String>> {
+        self.conn.send(&Request::GetChunked { key }).await?;
+        let resp: Response<Option<Chunk>> = self.conn.receive().await?;
+        let mut chunk = match resp.map_err(KvsError::Server)? {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+        let mut value = std::mem::take(&mut chunk.data);
+        while chunk.more {
+            chunk = self.conn.receive().await?;
+            value.extend_from_slice(&chunk.data);
+        }
+        String::from_utf8(value)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+
+    /// Gets the values of several keys in one request, in the order of
+    /// `keys`. The server reads them together.
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.require("multi_get")?;
+        self.conn.send(&Request::MultiGet { keys }).await?;
+        let resp: Response<Vec<Option<String>>> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns whether `key` is set. The server doesn't read its value.
+    pub async fn exists(&mut self, key: String) -> Result<bool> {
+        self.require("exists")?;
+        self.conn.send(&Request::Exists { key }).await?;
+        let resp: Response<bool> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns the length of the value of `key`, if it's set. The server
+    /// doesn't read the value.
+    pub async fn strlen(&mut self, key: String) -> Result<Option<u64>> {
+        self.require("strlen")?;
+        self.conn.send(&Request::Strlen { key }).await?;
+        let resp: Response<Option<u64>> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Sends `ops` in one request, for the server to apply one after
+    /// another. Returns the result of each, in order: the value for a get,
+    /// and `None` otherwise.
+    ///
+    /// The batch isn't atomic: requests from other clients may be handled
+    /// between its operations, and those after a failed one still apply.
+    pub async fn batch(&mut self, ops: Vec<BatchOp>) -> Result<Vec<Result<Option<String>>>> {
+        self.require("batch")?;
+        let requests = ops
+            .iter()
+            .map(|op| match op.clone() {
+                BatchOp::Get { key } => Request::Get { key },
+                BatchOp::Set { key, value } => Request::Set { key, value },
+                BatchOp::Remove { key } => Request::Remove { key },
+            })
+            .collect();
+        self.conn.send(&Request::Batch(requests)).await?;
+        let resp: Response<Vec<Vec<u8>>> = self.conn.receive().await?;
+        let replies = resp.map_err(KvsError::Server)?;
+        ops.iter()
+            .zip(replies)
+            .map(|(op, reply)| {
+                let resp = match op {
+                    BatchOp::Get { .. } => {
+                        bincode::deserialize::<Response<Option<String>>>(&reply)?
+                    }
+                    _ => bincode::deserialize::<Response<()>>(&reply)?.map(|()| None),
+                };
+                Ok(resp.map_err(KvsError::Server))
+            })
+            .collect()
+    }
+
+    /// Gets the value of `key` with `transform` applied on the server.
+    pub async fn get_transformed(
+        &mut self,
+        key: String,
+        transform: Transform,
+    ) -> Result<Option<String>> {
+        self.require("get_transformed")?;
+        self.conn
+            .send(&Request::GetTransformed { key, transform })
+            .await?;
+        let resp: Response<Option<String>> = self.conn.receive().await?;
         resp.map_err(KvsError::Server)
     }
 
     pub async fn remove(&mut self, key: String) -> Result<()> {
-        send(&mut self.stream, &Request::Remove { key }).await?;
-        let resp: Response = bincode::deserialize(&receive(&mut self.stream).await?)?;
-        resp.map(|_| ()).map_err(KvsError::Server)
+        self.conn.send(&Request::Remove { key }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Removes `keys` in one request, returning how many of them were set.
+    /// Keys that aren't set are skipped.
+    pub async fn remove_many(&mut self, keys: Vec<String>) -> Result<u64> {
+        self.require("remove_many")?;
+        self.conn.send(&Request::RemoveMany { keys }).await?;
+        let resp: Response<u64> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Moves the value of `from` to `to`, replacing any value `to` had.
+    pub async fn rename(&mut self, from: String, to: String) -> Result<()> {
+        self.require("rename")?;
+        self.conn.send(&Request::Rename { from, to }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Sets `to` to the value of `from` on the server, without receiving the
+    /// value, unless `to` is set and not `overwrite`. Returns whether it was
+    /// set.
+    pub async fn copy(&mut self, from: String, to: String, overwrite: bool) -> Result<bool> {
+        self.require("copy")?;
+        let copy = Request::Copy {
+            from,
+            to,
+            overwrite,
+        };
+        self.conn.send(&copy).await?;
+        let resp: Response<bool> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Starts a transaction: later calls to `set` and `remove` are queued
+    /// on the server until `exec` or `discard`, and other requests fail.
+    pub async fn begin(&mut self) -> Result<()> {
+        self.require("begin")?;
+        self.conn.send(&Request::Begin).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Applies the writes queued since `begin` all at once or not at all,
+    /// and ends the transaction. Fails, writing nothing, if a key removed
+    /// isn't set by then.
+    pub async fn exec(&mut self) -> Result<()> {
+        self.conn.send(&Request::Exec).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Ends the transaction, dropping the writes queued since `begin`.
+    pub async fn discard(&mut self) -> Result<()> {
+        self.conn.send(&Request::Discard).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns how many keys start with `prefix`.
+    pub async fn count_prefix(&mut self, prefix: String) -> Result<u64> {
+        self.conn.send(&Request::CountPrefix { prefix }).await?;
+        let resp: Response<u64> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Removes up to `limit` keys starting with `prefix` on the server,
+    /// returning how many were removed.
+    pub async fn remove_prefix(&mut self, prefix: String, limit: u64) -> Result<u64> {
+        self.conn
+            .send(&Request::RemovePrefix { prefix, limit })
+            .await?;
+        let resp: Response<u64> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns keys starting with `prefix` after `start_after` in ascending
+    /// order, with their values, up to `max_keys` keys and `max_bytes` bytes.
+    ///
+    /// The server may return fewer, down to its own limits. Pass the
+    /// returned cursor as `start_after` to get the next page.
+    pub async fn scan(
+        &mut self,
+        prefix: String,
+        start_after: Option<String>,
+        max_keys: u64,
+        max_bytes: u64,
+    ) -> Result<ScanPage> {
+        self.require("scan")?;
+        self.conn
+            .send(&Request::Scan {
+                prefix,
+                start_after,
+                max_keys,
+                max_bytes,
+            })
+            .await?;
+        let resp: Response<ScanPage> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Sets `key` to `value`, or removes it if `value` is `None`, if its
+    /// current value is `expected`. Returns whether the write was made.
+    ///
+    /// `id` identifies the write, so retrying it is safe.
+    pub async fn compare_and_set(
+        &mut self,
+        id: u64,
+        key: String,
+        expected: Option<Option<String>>,
+        value: Option<String>,
+    ) -> Result<bool> {
+        self.conn
+            .send(&Request::CompareAndSet {
+                id,
+                key,
+                expected,
+                value,
+            })
+            .await?;
+        let resp: Response<bool> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Sets an option of the server's store while it runs, see `Options::set`.
+    pub async fn configure(&mut self, name: String, value: String) -> Result<()> {
+        self.require("configure")?;
+        self.conn.send(&Request::Configure { name, value }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Compacts log `gen` of the server's store, or every log with dead
+    /// bytes, see `KvStore::compact`.
+    pub async fn compact(&mut self, gen: Option<u64>) -> Result<CompactionStats> {
+        self.require("compact")?;
+        self.conn.send(&Request::Compact { gen }).await?;
+        let resp: Response<CompactionStats> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns a summary of every log file of the server's store, see
+    /// `KvStore::segment_stats`.
+    pub async fn segment_stats(&mut self) -> Result<Vec<SegmentStats>> {
+        self.require("segment_stats")?;
+        self.conn.send(&Request::SegmentStats).await?;
+        let resp: Response<Vec<SegmentStats>> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Flushes the server's store and holds its writes until `thaw` or
+    /// `timeout`, see `KvStore::freeze_writes`.
+    pub async fn freeze_writes(&mut self, timeout: Duration) -> Result<()> {
+        self.require("freeze_writes")?;
+        self.conn.send(&Request::FreezeWrites { timeout }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Lets the server's writes go on, returning whether they were frozen.
+    pub async fn thaw(&mut self) -> Result<bool> {
+        self.require("thaw")?;
+        self.conn.send(&Request::Thaw).await?;
+        let resp: Response<bool> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Sends later requests to namespace `namespace` of the server, created
+    /// if it's new, or to the default one if `None`. Namespaces hold keys of
+    /// their own, and need the server's kvs engine.
+    pub async fn select(&mut self, namespace: Option<String>) -> Result<()> {
+        self.require("select")?;
+        self.conn.send(&Request::Select { namespace }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns up to `limit` keys starting with `prefix`, and their values
+    /// if `values`, in ascending order from a snapshot of the server's store.
+    ///
+    /// The snapshot is taken by the first page, and later pages see the
+    /// same keys. Pass the returned cursor to get the next page, whose
+    /// `prefix` is ignored; it's only valid on this connection. The server
+    /// may return fewer keys, down to its own limits, and keeps a few scans
+    /// open per connection, dropping the least recently continued.
+    pub async fn scan_snapshot(
+        &mut self,
+        prefix: String,
+        cursor: Option<u64>,
+        limit: u64,
+        values: bool,
+    ) -> Result<SnapshotPage> {
+        self.require("scan_snapshot")?;
+        self.conn
+            .send(&Request::ScanSnapshot {
+                cursor,
+                prefix,
+                limit,
+                values,
+            })
+            .await?;
+        let resp: Response<SnapshotPage> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Subscribes to changes to keys starting with `prefix` in the server's
+    /// store, see `KvStore::watch`. The connection only receives changes
+    /// until `Subscription::unsubscribe` returns it.
+    pub async fn subscribe(mut self, prefix: String) -> Result<Subscription> {
+        self.require("subscribe")?;
+        self.conn.send(&Request::Subscribe { prefix }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)?;
+        Ok(Subscription {
+            client: self,
+            ended: false,
+        })
+    }
+
+    /// Switches the connection to sending requests from many tasks at once,
+    /// each answered as the server handles it, see `MultiplexedClient`.
+    pub async fn multiplex(self) -> Result<MultiplexedClient> {
+        self.require("multiplex")?;
+        MultiplexedClient::new(self.conn, self.capabilities).await
+    }
+
+    /// Streams the changes made to the server's store with sequence numbers
+    /// above `since`, as a replica does. The connection only receives
+    /// changes from then on.
+    pub(crate) async fn replicate(mut self, since: u64) -> Result<Replication> {
+        self.require("replicate")?;
+        self.conn.send(&Request::Replicate { since }).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)?;
+        Ok(Replication {
+            client: self,
+            ended: false,
+        })
+    }
+
+    /// Saves an archive of the server's store, taken now, to a new file at
+    /// `path`, see `KvStore::export_archive`. The server streams it in
+    /// chunks.
+    pub(crate) async fn bootstrap(&mut self, path: &Path) -> Result<()> {
+        self.require("bootstrap")?;
+        self.conn.send(&Request::Bootstrap).await?;
+        let resp: Response<()> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)?;
+        let mut file = File::create(path)?;
+        while let Some(chunk) = self.conn.receive::<Option<Vec<u8>>>().await? {
+            file.write_all(&chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the leader of the server's cluster, which
+    /// serves the reads and writes, or `None` if it isn't known yet or the
+    /// server isn't in a cluster.
+    pub async fn leader(&mut self) -> Result<Option<SocketAddr>> {
+        self.require("leader")?;
+        self.conn.send(&Request::Leader).await?;
+        let resp: Response<Option<SocketAddr>> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns which node of the server's sharding owns each key, or `None`
+    /// if the server isn't sharded. Requests for a key are best sent to its
+    /// owner, as other nodes forward them there.
+    pub async fn topology(&mut self) -> Result<Option<Topology>> {
+        self.require("topology")?;
+        self.conn.send(&Request::Topology).await?;
+        let resp: Response<Option<Vec<SocketAddr>>> = self.conn.receive().await?;
+        Ok(resp.map_err(KvsError::Server)?.map(Topology::new))
+    }
+
+    /// Sends `op` to the server as another node of its sharding.
+    pub(crate) async fn forward<T: DeserializeOwned>(&mut self, op: Forward) -> Result<T> {
+        self.require("forward")?;
+        self.conn.send(&Request::Forward(op)).await?;
+        let resp: Response<T> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Sends `message` to the server as another node of its cluster.
+    pub(crate) async fn raft(&mut self, message: Message) -> Result<Reply> {
+        self.require("raft")?;
+        self.conn.send(&Request::Raft(message)).await?;
+        let resp: Response<Reply> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Samples the server's CPU usage for `seconds`, returning a flamegraph SVG.
+    pub async fn profile(&mut self, seconds: u64) -> Result<Vec<u8>> {
+        self.conn.send(&Request::Profile { seconds }).await?;
+        let resp: Response<Vec<u8>> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns the digest of the server's store, see `KvStore::digest`.
+    pub async fn digest(&mut self) -> Result<[u8; 32]> {
+        self.conn.send(&Request::Digest).await?;
+        let resp: Response<[u8; 32]> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    pub async fn stats(&mut self) -> Result<Stats> {
+        self.conn.send(&Request::Stats).await?;
+        let resp: Response<Stats> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Sends `payload` to the server, which returns it at once, to check the
+    /// connection is alive. Answered even when too many requests are in
+    /// flight, but only after the replies to earlier requests.
+    pub async fn ping(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        self.require("ping")?;
+        self.conn
+            .send(&Request::Ping {
+                payload: payload.to_vec(),
+            })
+            .await?;
+        let resp: Response<Vec<u8>> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
+    }
+
+    /// Returns a summary of the server: its version and role, its clients,
+    /// and the persistence, keys and compaction of its store.
+    pub async fn info(&mut self) -> Result<Info> {
+        self.require("info")?;
+        self.conn.send(&Request::Info).await?;
+        let resp: Response<Info> = self.conn.receive().await?;
+        resp.map_err(KvsError::Server)
     }
 }
@@ -0,0 +1,171 @@
+//! Codecs a `CodecStore` passes keys and values through, so layers like
+//! compression, encryption or serde encoding don't need changes to the
+//! store itself.
+
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use futures::stream::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{KvStore, Result};
+
+/// Encodes the keys of a `CodecStore`.
+///
+/// Scans return keys in the order of their encodings, so a codec keeping
+/// the order of its keys keeps scans of them in order too.
+pub trait KeyCodec: Send + Sync + 'static {
+    type Key;
+
+    fn encode_key(&self, key: &Self::Key) -> Result<Vec<u8>>;
+
+    fn decode_key(&self, bytes: Vec<u8>) -> Result<Self::Key>;
+}
+
+/// Encodes the values of a `CodecStore`.
+pub trait ValueCodec: Send + Sync + 'static {
+    type Value;
+
+    fn encode_value(&self, value: &Self::Value) -> Result<Vec<u8>>;
+
+    fn decode_value(&self, bytes: Vec<u8>) -> Result<Self::Value>;
+}
+
+/// Stores bytes as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl KeyCodec for Raw {
+    type Key = Vec<u8>;
+
+    fn encode_key(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(key.clone())
+    }
+
+    fn decode_key(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bytes)
+    }
+}
+
+impl ValueCodec for Raw {
+    type Value = Vec<u8>;
+
+    fn encode_value(&self, value: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.clone())
+    }
+
+    fn decode_value(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bytes)
+    }
+}
+
+/// Encodes values of type `T` with bincode, like `KvStore::set_typed`.
+///
+/// As a key codec it doesn't keep the order of keys, except of strings.
+pub struct Bincode<T>(PhantomData<fn() -> T>);
+
+impl<T> Default for Bincode<T> {
+    fn default() -> Self {
+        Bincode(PhantomData)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + 'static> KeyCodec for Bincode<T> {
+    type Key = T;
+
+    fn encode_key(&self, key: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(key)?)
+    }
+
+    fn decode_key(&self, bytes: Vec<u8>) -> Result<T> {
+        Ok(bincode::deserialize(&bytes)?)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + 'static> ValueCodec for Bincode<T> {
+    type Value = T;
+
+    fn encode_value(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode_value(&self, bytes: Vec<u8>) -> Result<T> {
+        Ok(bincode::deserialize(&bytes)?)
+    }
+}
+
+/// A `KvStore` whose keys and values are encoded by codecs, see
+/// `KvStore::with_codecs`. Cloning it is cheap, and clones share the store.
+pub struct CodecStore<K = Raw, V = Raw> {
+    store: KvStore,
+    keys: Arc<K>,
+    values: Arc<V>,
+}
+
+impl<K, V> Clone for CodecStore<K, V> {
+    fn clone(&self) -> Self {
+        CodecStore {
+            store: self.store.clone(),
+            keys: Arc::clone(&self.keys),
+            values: Arc::clone(&self.values),
+        }
+    }
+}
+
+impl<K: KeyCodec, V: ValueCodec> CodecStore<K, V> {
+    pub(crate) fn new(store: KvStore, keys: K, values: V) -> Self {
+        CodecStore {
+            store,
+            keys: Arc::new(keys),
+            values: Arc::new(values),
+        }
+    }
+
+    /// The store, which reads and writes the encoded keys and values.
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+
+    pub async fn get(&self, key: &K::Key) -> Result<Option<V::Value>> {
+        match self.store.get(self.keys.encode_key(key)?).await? {
+            Some(value) => Ok(Some(self.values.decode_value(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set(&self, key: &K::Key, value: &V::Value) -> Result<()> {
+        let key = self.keys.encode_key(key)?;
+        self.store.set(key, self.values.encode_value(value)?).await
+    }
+
+    pub async fn remove(&self, key: &K::Key) -> Result<()> {
+        self.store.remove(self.keys.encode_key(key)?).await
+    }
+
+    /// Returns the keys and values whose encoded keys are in the range of
+    /// the encodings of `range`, see `KvStore::scan`.
+    pub async fn scan<R>(
+        &self,
+        range: R,
+    ) -> Result<impl Stream<Item = Result<(K::Key, V::Value)>> + Unpin>
+    where
+        R: RangeBounds<K::Key>,
+    {
+        let encode = |bound: Bound<&K::Key>| -> Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(key) => Bound::Included(self.keys.encode_key(key)?),
+                Bound::Excluded(key) => Bound::Excluded(self.keys.encode_key(key)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+        let range = (encode(range.start_bound())?, encode(range.end_bound())?);
+        let keys = Arc::clone(&self.keys);
+        let values = Arc::clone(&self.values);
+        Ok(self.store.scan(range).await?.map(move |pair| {
+            let (key, value) = pair?;
+            Ok((keys.decode_key(key)?, values.decode_value(value)?))
+        }))
+    }
+}
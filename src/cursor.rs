@@ -0,0 +1,107 @@
+//! Scans continued across requests, so a client can page through a
+//! snapshot of many keys, see `KvsClient::scan_snapshot`.
+
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+
+use futures::lock::Mutex;
+use futures::stream::{BoxStream, Peekable, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use super::kvs::prefix_range;
+use super::{KvStore, KvsError, Result};
+
+/// Most scans kept open on one connection. Opening another drops the one
+/// least recently continued.
+const MAX_CURSORS: usize = 16;
+
+/// A page of keys returned by `KvsClient::scan_snapshot`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotPage {
+    pub keys: Vec<String>,
+    /// The values of `keys`, in the same order, if they were asked for.
+    pub values: Option<Vec<String>>,
+    /// Continues the scan after this page, if more keys may follow.
+    pub cursor: Option<u64>,
+}
+
+type Scan = Peekable<BoxStream<'static, Result<(Vec<u8>, Vec<u8>)>>>;
+
+/// The scans a connection keeps open, by cursor. Each keeps the log files
+/// it reads from until it ends or is dropped.
+#[derive(Default)]
+pub(crate) struct Cursors {
+    scans: Mutex<(HashMap<u64, Scan>, VecDeque<u64>)>,
+}
+
+impl Cursors {
+    /// Returns the next page of the scan `cursor` continues, or of a new
+    /// scan of the keys of `kvs` starting with `prefix`. The page ends before
+    /// `max_keys` keys or `max_bytes` bytes are exceeded, though it holds at
+    /// least one key.
+    pub(crate) async fn page(
+        &self,
+        kvs: &KvStore,
+        cursor: Option<u64>,
+        prefix: &str,
+        values: bool,
+        max_keys: usize,
+        max_bytes: u64,
+    ) -> Result<SnapshotPage> {
+        let mut scan = match cursor {
+            Some(cursor) => self.take(cursor).await?,
+            None => {
+                let range = prefix_range(prefix.as_bytes());
+                kvs.scan(range).await?.boxed().peekable()
+            }
+        };
+        let mut page = SnapshotPage {
+            values: if values { Some(Vec::new()) } else { None },
+            ..SnapshotPage::default()
+        };
+        let mut bytes = 0;
+        let more = loop {
+            let len = match Pin::new(&mut scan).peek().await {
+                Some(Ok((key, value))) => (key.len() + if values { value.len() } else { 0 }) as u64,
+                // Returned as the entry is taken.
+                Some(Err(_)) => 0,
+                None => break false,
+            };
+            if !page.keys.is_empty() && (page.keys.len() >= max_keys || bytes + len > max_bytes) {
+                break true;
+            }
+            if let Some(entry) = scan.next().await {
+                let (key, value) = entry?;
+                bytes += len;
+                page.keys.push(String::from_utf8_lossy(&key).into_owned());
+                if let Some(values) = &mut page.values {
+                    values.push(String::from_utf8_lossy(&value).into_owned());
+                }
+            }
+        };
+        if more {
+            let cursor = cursor.unwrap_or_else(rand::random);
+            self.put(cursor, scan).await;
+            page.cursor = Some(cursor);
+        }
+        Ok(page)
+    }
+
+    async fn take(&self, cursor: u64) -> Result<Scan> {
+        let mut scans = self.scans.lock().await;
+        let (open, order) = &mut *scans;
+        order.retain(|&open| open != cursor);
+        open.remove(&cursor).ok_or(KvsError::UnknownCursor(cursor))
+    }
+
+    async fn put(&self, cursor: u64, scan: Scan) {
+        let mut scans = self.scans.lock().await;
+        let (open, order) = &mut *scans;
+        if order.len() == MAX_CURSORS {
+            let oldest = order.pop_front().unwrap();
+            open.remove(&oldest);
+        }
+        order.push_back(cursor);
+        open.insert(cursor, scan);
+    }
+}
@@ -0,0 +1,52 @@
+//! Merkle roots of key-value pairs, for comparing stores.
+
+use sha2::{Digest, Sha256};
+
+/// Builds the root of a binary Merkle tree over pairs added in key order.
+///
+/// Only the roots of complete subtrees are kept, so memory grows with the
+/// log of the number of pairs.
+#[derive(Default)]
+pub(crate) struct MerkleRoot {
+    /// Roots of complete subtrees with their heights, the highest first.
+    stack: Vec<(u32, [u8; 32])>,
+}
+
+impl MerkleRoot {
+    pub(crate) fn add(&mut self, key: &[u8], value: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.input([0]);
+        hasher.input((key.len() as u64).to_be_bytes());
+        hasher.input(key);
+        hasher.input(value);
+        let mut node = (0, hasher.result().into());
+        while let Some(&(height, left)) = self.stack.last() {
+            if height != node.0 {
+                break;
+            }
+            self.stack.pop();
+            node = (height + 1, join(&left, &node.1));
+        }
+        self.stack.push(node);
+    }
+
+    /// Returns the root, which is all zeros if no pairs were added.
+    pub(crate) fn finish(mut self) -> [u8; 32] {
+        let mut root = match self.stack.pop() {
+            Some((_, root)) => root,
+            None => return [0; 32],
+        };
+        while let Some((_, left)) = self.stack.pop() {
+            root = join(&left, &root);
+        }
+        root
+    }
+}
+
+fn join(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input([1]);
+    hasher.input(left);
+    hasher.input(right);
+    hasher.result().into()
+}
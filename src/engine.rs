@@ -0,0 +1,559 @@
+//! Storage engines a server can serve keys from, and routing between them.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::future::{self, BoxFuture, FutureExt as _};
+use futures::StreamExt;
+
+use super::kvs::store_exists;
+#[cfg(feature = "sled")]
+use super::SledEngine;
+use super::{KvStore, KvsError, MemoryEngine, Result};
+
+/// The operations a server needs from the engine storing its keys.
+pub trait KvsEngine: Send + Sync {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Vec<u8>>>>;
+
+    fn set<'a>(&'a self, key: &'a [u8], value: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    /// Removes `key`, failing with `KeyNotFound` if it doesn't exist.
+    fn remove<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    /// Returns the keys starting with `prefix` in ascending order.
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> BoxFuture<'a, Result<Vec<Vec<u8>>>>;
+
+    /// Sets `key` to `value`, or removes it if `value` is `None`, but only if
+    /// its current value is `expected`. Returns whether the write was made.
+    fn compare_and_set<'a>(
+        &'a self,
+        key: &'a [u8],
+        expected: Option<&'a [u8]>,
+        value: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<bool>>;
+
+    /// Makes the writes so far durable, if the engine keeps any.
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    /// Returns whether `key` is set.
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool>> {
+        async move { Ok(self.get(key).await?.is_some()) }.boxed()
+    }
+
+    /// Returns the length of the value of `key`, if it's set.
+    fn value_len<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<u64>>> {
+        async move { Ok(self.get(key).await?.map(|value| value.len() as u64)) }.boxed()
+    }
+
+    /// Gets the values of several keys, in the order of `keys`.
+    fn multi_get<'a>(&'a self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Result<Vec<Option<Vec<u8>>>>> {
+        async move {
+            let mut values = Vec::with_capacity(keys.len());
+            for key in keys {
+                values.push(self.get(key).await?);
+            }
+            Ok(values)
+        }
+        .boxed()
+    }
+
+    /// Sets each key to its value, in order.
+    fn set_many<'a>(&'a self, pairs: &'a [(Vec<u8>, Vec<u8>)]) -> BoxFuture<'a, Result<()>> {
+        async move {
+            for (key, value) in pairs {
+                self.set(key, value).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Moves the value of `from` to `to`, replacing any value `to` had.
+    /// Fails with `KeyNotFound` if `from` isn't set.
+    ///
+    /// Engines without an atomic rename set `to` before removing `from`, so
+    /// a failure between the two leaves both set rather than neither.
+    fn rename<'a>(&'a self, from: &'a [u8], to: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let value = self.get(from).await?.ok_or(KvsError::KeyNotFound)?;
+            if from != to {
+                self.set(to, &value).await?;
+                self.remove(from).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Sets `to` to the value of `from`, unless `to` is set and not
+    /// `overwrite`. Returns whether it was set. Fails with `KeyNotFound` if
+    /// `from` isn't set.
+    fn copy<'a>(
+        &'a self,
+        from: &'a [u8],
+        to: &'a [u8],
+        overwrite: bool,
+    ) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            let value = self.get(from).await?.ok_or(KvsError::KeyNotFound)?;
+            if overwrite {
+                self.set(to, &value).await?;
+                Ok(true)
+            } else {
+                self.compare_and_set(to, None, Some(&value)).await
+            }
+        }
+        .boxed()
+    }
+
+    /// Applies `writes` all at once or not at all, each setting a key to
+    /// `Some(value)` or removing it with `None`. Fails with `KeyNotFound`,
+    /// writing nothing, if a key removed isn't set by then.
+    ///
+    /// Engines that can't make several writes atomically fail with
+    /// `EngineUnsupported`.
+    fn transact<'a>(
+        &'a self,
+        _writes: &'a [(Vec<u8>, Option<Vec<u8>>)],
+    ) -> BoxFuture<'a, Result<()>> {
+        future::ready(Err(KvsError::EngineUnsupported("transactions"))).boxed()
+    }
+
+    /// Removes the given keys, returning how many existed.
+    fn remove_many<'a>(&'a self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Result<usize>> {
+        async move {
+            let mut removed = 0;
+            for key in keys {
+                match self.remove(key).await {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(removed)
+        }
+        .boxed()
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        KvStore::get(self, key).boxed()
+    }
+
+    fn set<'a>(&'a self, key: &'a [u8], value: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        KvStore::set(self, key, value).boxed()
+    }
+
+    fn remove<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        KvStore::remove(self, key).boxed()
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> BoxFuture<'a, Result<Vec<Vec<u8>>>> {
+        async move {
+            Ok(KvStore::keys_with_prefix(self, prefix)
+                .await?
+                .collect()
+                .await)
+        }
+        .boxed()
+    }
+
+    fn compare_and_set<'a>(
+        &'a self,
+        key: &'a [u8],
+        expected: Option<&'a [u8]>,
+        value: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        KvStore::compare_and_set(self, key, expected, value).boxed()
+    }
+
+    fn multi_get<'a>(&'a self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Result<Vec<Option<Vec<u8>>>>> {
+        KvStore::multi_get(self, keys).boxed()
+    }
+
+    fn set_many<'a>(&'a self, pairs: &'a [(Vec<u8>, Vec<u8>)]) -> BoxFuture<'a, Result<()>> {
+        KvStore::set_many(self, pairs.iter().map(|(key, value)| (key, value))).boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a [u8], to: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        KvStore::rename(self, from, to).boxed()
+    }
+
+    fn copy<'a>(
+        &'a self,
+        from: &'a [u8],
+        to: &'a [u8],
+        overwrite: bool,
+    ) -> BoxFuture<'a, Result<bool>> {
+        KvStore::copy(self, from, to, overwrite).boxed()
+    }
+
+    fn transact<'a>(
+        &'a self,
+        writes: &'a [(Vec<u8>, Option<Vec<u8>>)],
+    ) -> BoxFuture<'a, Result<()>> {
+        KvStore::transact(self, writes).boxed()
+    }
+
+    fn remove_many<'a>(&'a self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Result<usize>> {
+        KvStore::remove_many(self, keys).boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        KvStore::flush(self).boxed()
+    }
+
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool>> {
+        KvStore::contains_key(self, key).boxed()
+    }
+
+    fn value_len<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<u64>>> {
+        KvStore::value_len(self, key).boxed()
+    }
+}
+
+/// Serves reads from an engine, failing writes with `KvsError::Replica`, as
+/// a replica of `primary` does.
+pub(crate) struct ReplicaEngine {
+    inner: Arc<dyn KvsEngine>,
+    primary: SocketAddr,
+}
+
+impl ReplicaEngine {
+    pub(crate) fn new(inner: Arc<dyn KvsEngine>, primary: SocketAddr) -> Self {
+        ReplicaEngine { inner, primary }
+    }
+
+    fn refuse<'a, T: Send + 'a>(&self) -> BoxFuture<'a, Result<T>> {
+        future::ready(Err(KvsError::Replica(self.primary))).boxed()
+    }
+}
+
+impl KvsEngine for ReplicaEngine {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        self.inner.get(key)
+    }
+
+    fn set<'a>(&'a self, _key: &'a [u8], _value: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.refuse()
+    }
+
+    fn remove<'a>(&'a self, _key: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.refuse()
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> BoxFuture<'a, Result<Vec<Vec<u8>>>> {
+        self.inner.keys_with_prefix(prefix)
+    }
+
+    fn compare_and_set<'a>(
+        &'a self,
+        _key: &'a [u8],
+        _expected: Option<&'a [u8]>,
+        _value: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        self.refuse()
+    }
+
+    fn multi_get<'a>(&'a self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Result<Vec<Option<Vec<u8>>>>> {
+        self.inner.multi_get(keys)
+    }
+
+    fn set_many<'a>(&'a self, _pairs: &'a [(Vec<u8>, Vec<u8>)]) -> BoxFuture<'a, Result<()>> {
+        self.refuse()
+    }
+
+    fn rename<'a>(&'a self, _from: &'a [u8], _to: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.refuse()
+    }
+
+    fn copy<'a>(
+        &'a self,
+        _from: &'a [u8],
+        _to: &'a [u8],
+        _overwrite: bool,
+    ) -> BoxFuture<'a, Result<bool>> {
+        self.refuse()
+    }
+
+    fn transact<'a>(
+        &'a self,
+        _writes: &'a [(Vec<u8>, Option<Vec<u8>>)],
+    ) -> BoxFuture<'a, Result<()>> {
+        self.refuse()
+    }
+
+    fn remove_many<'a>(&'a self, _keys: &'a [Vec<u8>]) -> BoxFuture<'a, Result<usize>> {
+        self.refuse()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        self.inner.flush()
+    }
+
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool>> {
+        self.inner.contains_key(key)
+    }
+
+    fn value_len<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<u64>>> {
+        self.inner.value_len(key)
+    }
+}
+
+/// Serves keys from different engines by prefix.
+///
+/// A key is served by the engine of the longest route prefix it starts with,
+/// or by the default engine if it matches no route.
+pub struct RoutingEngine {
+    default: Arc<dyn KvsEngine>,
+    routes: Vec<(Vec<u8>, Arc<dyn KvsEngine>)>,
+}
+
+impl RoutingEngine {
+    pub fn new(default: Arc<dyn KvsEngine>) -> Self {
+        RoutingEngine {
+            default,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Serves keys starting with `prefix` from `engine`.
+    pub fn route(mut self, prefix: impl Into<Vec<u8>>, engine: Arc<dyn KvsEngine>) -> Self {
+        self.routes.push((prefix.into(), engine));
+        self
+    }
+
+    /// Returns the index of the route serving `key`, if any.
+    fn route_of(&self, key: &[u8]) -> Option<usize> {
+        (0..self.routes.len())
+            .filter(|&i| key.starts_with(&self.routes[i].0))
+            .max_by_key(|&i| self.routes[i].0.len())
+    }
+
+    fn engine(&self, key: &[u8]) -> &dyn KvsEngine {
+        match self.route_of(key) {
+            Some(i) => &*self.routes[i].1,
+            None => &*self.default,
+        }
+    }
+}
+
+impl KvsEngine for RoutingEngine {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        self.engine(key).get(key)
+    }
+
+    fn set<'a>(&'a self, key: &'a [u8], value: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.engine(key).set(key, value)
+    }
+
+    fn remove<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.engine(key).remove(key)
+    }
+
+    /// Merges the keys of every engine that may serve keys starting with
+    /// `prefix`. Keys an engine holds but doesn't serve are left out.
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> BoxFuture<'a, Result<Vec<Vec<u8>>>> {
+        async move {
+            let mut keys: Vec<_> = self
+                .default
+                .keys_with_prefix(prefix)
+                .await?
+                .into_iter()
+                .filter(|key| self.route_of(key).is_none())
+                .collect();
+            for (i, (route, engine)) in self.routes.iter().enumerate() {
+                let prefix = if route.starts_with(prefix) {
+                    &route[..]
+                } else if prefix.starts_with(route) {
+                    prefix
+                } else {
+                    continue;
+                };
+                let served = engine.keys_with_prefix(prefix).await?.into_iter();
+                keys.extend(served.filter(|key| self.route_of(key) == Some(i)));
+            }
+            keys.sort();
+            Ok(keys)
+        }
+        .boxed()
+    }
+
+    fn compare_and_set<'a>(
+        &'a self,
+        key: &'a [u8],
+        expected: Option<&'a [u8]>,
+        value: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        self.engine(key).compare_and_set(key, expected, value)
+    }
+
+    /// Applies the writes with the engine serving every key written, which
+    /// fails if they're served by several.
+    fn transact<'a>(
+        &'a self,
+        writes: &'a [(Vec<u8>, Option<Vec<u8>>)],
+    ) -> BoxFuture<'a, Result<()>> {
+        let mut routes = writes.iter().map(|(key, _)| self.route_of(key));
+        match routes.next() {
+            Some(first) if routes.all(|route| route == first) => {
+                self.engine(&writes[0].0).transact(writes)
+            }
+            Some(_) => future::ready(Err(KvsError::EngineUnsupported(
+                "transactions across routes",
+            )))
+            .boxed(),
+            None => future::ready(Ok(())).boxed(),
+        }
+    }
+
+    /// Renames within the engine serving both keys, atomically if it does.
+    /// Across engines, `to` is set before `from` is removed.
+    fn rename<'a>(&'a self, from: &'a [u8], to: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        if self.route_of(from) == self.route_of(to) {
+            return self.engine(from).rename(from, to);
+        }
+        async move {
+            let value = self.get(from).await?.ok_or(KvsError::KeyNotFound)?;
+            self.set(to, &value).await?;
+            self.remove(from).await
+        }
+        .boxed()
+    }
+
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool>> {
+        self.engine(key).contains_key(key)
+    }
+
+    fn value_len<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<u64>>> {
+        self.engine(key).value_len(key)
+    }
+}
+
+/// The engines a `kvs-server` can serve keys from, by default or for the
+/// keys of a route.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EngineKind {
+    /// The log-structured store in the server's directory.
+    #[default]
+    Kvs,
+    /// A `MemoryEngine`, shared by every route to it.
+    Memory,
+    /// A sled database in the server's directory, with the `sled` feature.
+    Sled,
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "memory" => Ok(EngineKind::Memory),
+            "sled" => Ok(EngineKind::Sled),
+            _ => Err(format!(
+                "unknown engine `{}`, expected `kvs`, `memory` or `sled`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EngineKind::Kvs => "kvs",
+            EngineKind::Memory => "memory",
+            EngineKind::Sled => "sled",
+        })
+    }
+}
+
+/// Checks that the data in `dir`, if any, was written by `kind`, and
+/// records `kind` in the `ENGINE` file for the next start.
+///
+/// Directories from before the file was written are recognized by their
+/// contents: a sled database has a `conf` file.
+pub(crate) fn check_recorded(dir: &Path, kind: EngineKind) -> Result<()> {
+    let path = dir.join("ENGINE");
+    let recorded = match fs::read_to_string(&path) {
+        Ok(recorded) => Some(recorded.trim().parse().map_err(KvsError::Config)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if dir.join("conf").exists() {
+                Some(EngineKind::Sled)
+            } else if store_exists(dir)? {
+                Some(EngineKind::Kvs)
+            } else {
+                None
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match recorded {
+        Some(recorded) if recorded != kind => Err(KvsError::WrongEngine(recorded, kind)),
+        Some(_) if path.exists() => Ok(()),
+        _ => {
+            fs::create_dir_all(dir)?;
+            fs::write(&path, format!("{}\n", kind))?;
+            Ok(())
+        }
+    }
+}
+
+/// Opens the sled database in `dir` as an engine.
+#[cfg(feature = "sled")]
+pub(crate) fn open_sled(dir: &Path) -> Result<Arc<dyn KvsEngine>> {
+    Ok(Arc::new(SledEngine::open(dir)?))
+}
+
+#[cfg(not(feature = "sled"))]
+pub(crate) fn open_sled(_dir: &Path) -> Result<Arc<dyn KvsEngine>> {
+    Err(KvsError::Config(
+        "kvs was built without the `sled` feature".to_owned(),
+    ))
+}
+
+/// Fails unless every route is to `memory` or `default`, the engine in the
+/// server's directory.
+pub(crate) fn check_routes(default: EngineKind, routes: &[(String, EngineKind)]) -> Result<()> {
+    for (prefix, kind) in routes {
+        if *kind != EngineKind::Memory && *kind != default {
+            return Err(KvsError::Config(format!(
+                "keys starting with `{}` are routed to the {} engine, but the server uses {}",
+                prefix, kind, default
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Builds the engine serving `routes`, with keys matching no route served by
+/// `default` and the memory routes by `memory`. Other routes are to
+/// `default`, see `check_routes`.
+pub(crate) fn routed(
+    default: Arc<dyn KvsEngine>,
+    memory: &MemoryEngine,
+    routes: &[(String, EngineKind)],
+) -> Arc<dyn KvsEngine> {
+    if routes.is_empty() {
+        return default;
+    }
+    let memory: Arc<dyn KvsEngine> = Arc::new(memory.clone());
+    let mut engine = RoutingEngine::new(Arc::clone(&default));
+    for (prefix, kind) in routes {
+        let target = match kind {
+            EngineKind::Memory => Arc::clone(&memory),
+            _ => Arc::clone(&default),
+        };
+        engine = engine.route(prefix.as_bytes(), target);
+    }
+    Arc::new(engine)
+}
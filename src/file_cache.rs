@@ -0,0 +1,155 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use log::warn;
+
+/// Log files opened on demand, keeping at most `limit` of them open at once.
+///
+/// Once a file is opened past the limit, the least recently used other one
+/// is closed, and opened again when it's next used. Reads in flight hold on
+/// to the file they read from, so closing it never fails them.
+///
+/// With direct I/O, files are read through a second handle opened with
+/// `O_DIRECT`, while writes stay buffered.
+pub(crate) struct FileCache {
+    limit: usize,
+    /// Turned off if a file system turns out not to support it.
+    direct: AtomicBool,
+    open: Mutex<Vec<Weak<CachedFile>>>,
+    clock: AtomicU64,
+}
+
+/// A file of a `FileCache`.
+pub(crate) struct CachedFile {
+    path: PathBuf,
+    file: Mutex<Option<Arc<File>>>,
+    direct: Mutex<Option<Arc<File>>>,
+    last_used: AtomicU64,
+    cache: Arc<FileCache>,
+}
+
+impl FileCache {
+    pub(crate) fn new(limit: usize, direct: bool) -> Arc<FileCache> {
+        Arc::new(FileCache {
+            limit: limit.max(1),
+            direct: AtomicBool::new(direct),
+            open: Mutex::new(Vec::new()),
+            clock: AtomicU64::new(0),
+        })
+    }
+
+    /// Opens the file at `path` for reading and writing, creating it if it
+    /// doesn't exist.
+    pub(crate) fn open(self: &Arc<Self>, path: PathBuf) -> io::Result<Arc<CachedFile>> {
+        let file = Arc::new(CachedFile {
+            file: Mutex::new(Some(Arc::new(open_file(&path)?))),
+            direct: Mutex::new(None),
+            path,
+            last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+            cache: Arc::clone(self),
+        });
+        self.opened(&file);
+        Ok(file)
+    }
+
+    /// Counts `file` as open, closing others if it's past the limit.
+    fn opened(&self, file: &Arc<CachedFile>) {
+        let mut open = self.open.lock().unwrap();
+        open.retain(|open| open.strong_count() > 0);
+        if !open.iter().any(|open| open.as_ptr() == Arc::as_ptr(file)) {
+            open.push(Arc::downgrade(file));
+        }
+        while open.len() > self.limit {
+            let victim = open
+                .iter()
+                .enumerate()
+                .filter_map(|(i, open)| open.upgrade().map(|open| (i, open)))
+                .filter(|(_, open)| !Arc::ptr_eq(open, file))
+                .min_by_key(|(_, open)| open.last_used.load(Ordering::Relaxed));
+            match victim {
+                Some((i, victim)) => {
+                    open.swap_remove(i);
+                    *victim.file.lock().unwrap() = None;
+                    *victim.direct.lock().unwrap() = None;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl CachedFile {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the file, opening it again if it was closed.
+    pub(crate) fn get(self: &Arc<Self>) -> io::Result<Arc<File>> {
+        self.get_or_open(&self.file, open_file)
+    }
+
+    /// Returns the file to read by, and whether it's opened for direct I/O.
+    pub(crate) fn reader(self: &Arc<Self>) -> io::Result<(Arc<File>, bool)> {
+        if !self.cache.direct.load(Ordering::Relaxed) {
+            return Ok((self.get()?, false));
+        }
+        match self.get_or_open(&self.direct, open_direct) {
+            Ok(file) => Ok((file, true)),
+            Err(e) => {
+                if self.cache.direct.swap(false, Ordering::Relaxed) {
+                    warn!(
+                        "Direct I/O is unavailable, reading through the page cache: {}",
+                        e
+                    );
+                }
+                Ok((self.get()?, false))
+            }
+        }
+    }
+
+    fn get_or_open(
+        self: &Arc<Self>,
+        slot: &Mutex<Option<Arc<File>>>,
+        open: fn(&Path) -> io::Result<File>,
+    ) -> io::Result<Arc<File>> {
+        let now = self.cache.clock.fetch_add(1, Ordering::Relaxed);
+        self.last_used.store(now, Ordering::Relaxed);
+        if let Some(file) = &*slot.lock().unwrap() {
+            return Ok(Arc::clone(file));
+        }
+        let file = Arc::new(open(&self.path)?);
+        *slot.lock().unwrap() = Some(Arc::clone(&file));
+        // Not while holding the lock of this file, which closing it takes.
+        self.cache.opened(self);
+        Ok(file)
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_: &Path) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "direct I/O is only supported on Linux",
+    ))
+}
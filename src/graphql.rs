@@ -0,0 +1,196 @@
+//! A GraphQL endpoint over the store, for prototyping and internal tools.
+//!
+//! `POST /graphql` runs a query or mutation. Subscriptions are streamed as
+//! server-sent events when the request accepts `text/event-stream`.
+//! `GET /graphql` serves GraphQL Playground.
+
+use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
+use async_graphql::{Context, Object, Schema, SimpleObject, Subscription};
+use futures::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use futures::{Stream, StreamExt};
+use log::{debug, warn};
+
+use super::http::{respond, split_header};
+use super::rt::{self, TcpStream, ToSocketAddrs};
+use super::{KvStore, KvsError, Result, WatchEvent};
+
+type KvsSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+/// Most keys returned by one `keys` query.
+const MAX_KEYS: usize = 1000;
+
+struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// The value of `key`, if it exists.
+    async fn get(&self, ctx: &Context<'_>, key: String) -> async_graphql::Result<Option<String>> {
+        let kvs = ctx.data_unchecked::<KvStore>();
+        Ok(kvs.get(key).await?.map(lossy))
+    }
+
+    /// Keys starting with `prefix` in ascending order.
+    async fn keys(
+        &self,
+        ctx: &Context<'_>,
+        prefix: String,
+        #[graphql(default = 100)] limit: usize,
+    ) -> async_graphql::Result<Vec<String>> {
+        let kvs = ctx.data_unchecked::<KvStore>();
+        let mut keys = kvs
+            .keys_with_prefix(prefix)
+            .await?
+            .take(limit.min(MAX_KEYS));
+        let mut res = Vec::new();
+        while let Some(key) = keys.next().await {
+            res.push(lossy(key));
+        }
+        Ok(res)
+    }
+}
+
+struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn set(
+        &self,
+        ctx: &Context<'_>,
+        key: String,
+        value: String,
+    ) -> async_graphql::Result<bool> {
+        ctx.data_unchecked::<KvStore>().set(key, value).await?;
+        Ok(true)
+    }
+
+    /// Removes `key`, returning whether it existed.
+    async fn remove(&self, ctx: &Context<'_>, key: String) -> async_graphql::Result<bool> {
+        match ctx.data_unchecked::<KvStore>().remove(key).await {
+            Ok(()) => Ok(true),
+            Err(KvsError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A change to a key. `value` is null if the key was removed.
+#[derive(SimpleObject)]
+struct Change {
+    key: String,
+    value: Option<String>,
+}
+
+struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Changes to keys starting with `prefix`, see `KvStore::watch`.
+    async fn watch(&self, ctx: &Context<'_>, prefix: String) -> impl Stream<Item = Change> {
+        ctx.data_unchecked::<KvStore>()
+            .watch(prefix)
+            .map(|event| match event {
+                WatchEvent::Set { key, value } => Change {
+                    key: lossy(key),
+                    value: Some(lossy(value)),
+                },
+                WatchEvent::Remove { key } => Change {
+                    key: lossy(key),
+                    value: None,
+                },
+            })
+    }
+}
+
+fn lossy(bytes: Vec<u8>) -> String {
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Serves the GraphQL endpoint on `addr` until an error occurs.
+pub async fn serve_graphql(addr: impl ToSocketAddrs, kvs: KvStore) -> Result<()> {
+    let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(kvs)
+        .finish();
+    let listener = rt::TcpListener::bind(addr).await?;
+
+    loop {
+        let (stream, peer) = rt::accept(&listener).await?;
+        let schema = schema.clone();
+        rt::spawn(async move {
+            if let Err(e) = handle(stream, schema).await {
+                warn!("Error serving GraphQL to {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answers a single HTTP request, then closes the connection.
+async fn handle(stream: TcpStream, schema: KvsSchema) -> Result<()> {
+    let (reader, mut stream) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut content_length = 0;
+    let mut event_stream = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = split_header(&line) {
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "accept" => event_stream = value.contains("text/event-stream"),
+                _ => {}
+            }
+        }
+    }
+    debug!("GraphQL: {}", request_line.trim());
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/graphql")) => {
+            let page = playground_source(GraphQLPlaygroundConfig::new("/graphql"));
+            respond(&mut stream, "200 OK", "text/html", &page).await
+        }
+        (Some("POST"), Some("/graphql")) => {
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).await?;
+            let request: async_graphql::Request = match serde_json::from_slice(&body) {
+                Ok(request) => request,
+                Err(e) => {
+                    return respond(&mut stream, "400 Bad Request", "text/plain", &e.to_string())
+                        .await
+                }
+            };
+            if event_stream {
+                stream_events(&mut stream, schema.execute_stream(request)).await
+            } else {
+                let response = serde_json::to_string(&schema.execute(request).await).unwrap();
+                respond(&mut stream, "200 OK", "application/json", &response).await
+            }
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "not found").await,
+    }
+}
+
+/// Writes each response as a server-sent event until the stream ends or
+/// the client disconnects.
+async fn stream_events(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut responses: impl Stream<Item = async_graphql::Response> + Unpin,
+) -> Result<()> {
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    while let Some(response) = responses.next().await {
+        let data = serde_json::to_string(&response).unwrap();
+        stream
+            .write_all(format!("event: next\ndata: {}\n\n", data).as_bytes())
+            .await?;
+    }
+    stream.write_all(b"event: complete\ndata:\n\n").await?;
+    Ok(())
+}
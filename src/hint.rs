@@ -0,0 +1,142 @@
+use std::convert::TryFrom;
+use std::io::Cursor;
+use std::sync::{Arc, OnceLock};
+
+use log::warn;
+use memmap::Mmap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::kvs::LogPos;
+use crate::{KvsError, Result};
+
+/// Entries of a keydir file are split into buckets by the first byte of
+/// their key, with one more for the empty key.
+const BUCKETS: usize = 257;
+
+type Entry = (Vec<u8>, LogPos);
+
+fn bucket_of(key: &[u8]) -> usize {
+    key.first().map_or(0, |&byte| byte as usize + 1)
+}
+
+/// Where a bucket is, from the end of the bucket table, and its SHA-256.
+#[derive(Serialize, Deserialize)]
+struct Bucket {
+    offset: u64,
+    len: u64,
+    digest: [u8; 32],
+}
+
+/// Encodes a keydir file: `header`, then `entries`, which must be sorted,
+/// split into buckets each verified on its own as it's loaded.
+pub(crate) fn encode<H: Serialize>(
+    header: &H,
+    entries: impl Iterator<Item = Entry>,
+) -> Result<Vec<u8>> {
+    let mut buckets = vec![Vec::new(); BUCKETS];
+    for (key, pos) in entries {
+        buckets[bucket_of(&key)].push((key, pos));
+    }
+    let mut table = Vec::with_capacity(BUCKETS);
+    let mut bodies = Vec::new();
+    for entries in buckets {
+        let body = bincode::serialize(&entries)?;
+        table.push(Bucket {
+            offset: bodies.len() as u64,
+            len: body.len() as u64,
+            digest: Sha256::digest(&body).into(),
+        });
+        bodies.extend(body);
+    }
+    let mut data = bincode::serialize(header)?;
+    data.extend(bincode::serialize(&table)?);
+    data.extend(bodies);
+    Ok(data)
+}
+
+/// A mapped keydir file, whose buckets are decoded and verified the first
+/// time a key in them is looked up.
+pub(crate) struct Hint {
+    map: Mmap,
+    /// Offset of the first bucket.
+    start: usize,
+    table: Vec<Bucket>,
+    /// The entries of each bucket loaded so far, or `None` in place of one
+    /// that's invalid.
+    loaded: Vec<OnceLock<Option<Arc<Vec<Entry>>>>>,
+}
+
+impl Hint {
+    /// Reads the header and the bucket table of a keydir file.
+    pub(crate) fn open<H: DeserializeOwned>(map: Mmap) -> Result<(H, Hint)> {
+        let mut cursor = Cursor::new(&map[..]);
+        let header = bincode::config()
+            .limit(map.len() as u64)
+            .deserialize_from(&mut cursor)?;
+        let table: Vec<Bucket> = bincode::config()
+            .limit(map.len() as u64)
+            .deserialize_from(&mut cursor)?;
+        if table.len() != BUCKETS {
+            return Err(KvsError::Load(format!(
+                "expected {} buckets, found {}",
+                BUCKETS,
+                table.len()
+            )));
+        }
+        let start = cursor.position() as usize;
+        let hint = Hint {
+            map,
+            start,
+            table,
+            loaded: (0..BUCKETS).map(|_| OnceLock::new()).collect(),
+        };
+        Ok((header, hint))
+    }
+
+    /// Returns the position of `key`, or `None` if it has none. Fails if
+    /// the bucket holding it is invalid.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<LogPos>> {
+        let entries = self.bucket(bucket_of(key))?;
+        let found = entries.binary_search_by(|(other, _)| other.as_slice().cmp(key));
+        Ok(found.ok().map(|i| entries[i].1))
+    }
+
+    /// Returns every entry in key order, once each bucket is verified.
+    pub(crate) fn entries(&self) -> Result<Vec<Arc<Vec<Entry>>>> {
+        (0..BUCKETS).map(|i| self.bucket(i)).collect()
+    }
+
+    fn bucket(&self, i: usize) -> Result<Arc<Vec<Entry>>> {
+        let loaded = self.loaded[i].get_or_init(|| match self.decode(i) {
+            Ok(entries) => Some(Arc::new(entries)),
+            Err(e) => {
+                warn!("Invalid bucket {} of the keydir file: {}", i, e);
+                None
+            }
+        });
+        loaded
+            .clone()
+            .ok_or_else(|| KvsError::Load(format!("bucket {} of the keydir file is invalid", i)))
+    }
+
+    fn decode(&self, i: usize) -> Result<Vec<Entry>> {
+        let bucket = &self.table[i];
+        let data = usize::try_from(bucket.offset)
+            .ok()
+            .and_then(|offset| self.start.checked_add(offset))
+            .and_then(|start| Some(start..start.checked_add(usize::try_from(bucket.len).ok()?)?))
+            .and_then(|range| self.map.get(range))
+            .ok_or(KvsError::Corrupted)?;
+        if Sha256::digest(data).as_slice() != bucket.digest {
+            return Err(KvsError::Corrupted);
+        }
+        let entries: Vec<Entry> = bincode::config().limit(bucket.len).deserialize(data)?;
+        let sorted = entries.windows(2).all(|pair| pair[0].0 < pair[1].0);
+        if !sorted || entries.iter().any(|(key, _)| bucket_of(key) != i) {
+            return Err(KvsError::Corrupted);
+        }
+        Ok(entries)
+    }
+}
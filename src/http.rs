@@ -0,0 +1,244 @@
+//! A JSON API over HTTP, for scripts and browsers, see
+//! `ServerConfig::http_addr`.
+//!
+//! - `GET /keys/{key}` returns `{"key": .., "value": ..}`
+//! - `PUT /keys/{key}` sets the value in a `{"value": ..}` body
+//! - `DELETE /keys/{key}` removes the key
+//! - `GET /stats` returns the store's `Stats`
+//!
+//! Keys are percent-decoded, and values are strings, decoded lossily as
+//! UTF-8. Errors are returned as `{"error": ..}`. Each connection is closed
+//! after one request.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::{self, Either, FutureExt as _};
+use futures::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use log::debug;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::rt::{self, TcpStream};
+use super::server::Shutdown;
+use super::{KvStore, KvsEngine, KvsError, Result, ServerConfig};
+
+/// Longest request body accepted.
+const MAX_BODY_LEN: usize = 16 << 20;
+
+/// Sent to connections refused for `ServerConfig::max_connections`.
+pub(crate) const BUSY: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+    Content-Type: application/json\r\nContent-Length: 45\r\nConnection: close\r\n\r\n\
+    {\"error\":\"server busy: too many connections\"}";
+
+/// The head of a request.
+struct Head {
+    method: String,
+    target: String,
+    content_length: usize,
+}
+
+#[derive(Deserialize)]
+struct SetBody {
+    value: String,
+}
+
+/// Answers the request of one connection, then closes it.
+pub(crate) async fn serve(
+    stream: TcpStream,
+    kvs: Option<KvStore>,
+    engine: Arc<dyn KvsEngine>,
+    config: Arc<ServerConfig>,
+    shutdown: Shutdown,
+    peer: SocketAddr,
+) -> Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let receiving = match config.idle_timeout {
+        Some(idle_timeout) => rt::timeout(idle_timeout, read_head(&mut reader)).boxed(),
+        None => read_head(&mut reader).map(Some).boxed(),
+    };
+    let head = match future::select(receiving, shutdown).await {
+        Either::Left((Some(head), _)) => match head? {
+            Some(head) => head,
+            None => return Ok(()),
+        },
+        Either::Left((None, _)) => {
+            debug!("{}: closing idle connection", peer);
+            return Ok(());
+        }
+        Either::Right(_) => {
+            debug!("{}: closing for shutdown", peer);
+            return Ok(());
+        }
+    };
+    debug!("{}: HTTP {} {}", peer, head.method, head.target);
+    if head.content_length > MAX_BODY_LEN {
+        let body = error_body("the body is too large");
+        return respond(
+            &mut writer,
+            "413 Payload Too Large",
+            "application/json",
+            &body,
+        )
+        .await;
+    }
+    let mut body = vec![0; head.content_length];
+    reader.read_exact(&mut body).await?;
+
+    let (status, body) = handle(&head, &body, kvs, &*engine, &config).await;
+    respond(&mut writer, status, "application/json", &body).await
+}
+
+/// Reads the request line and headers, or returns `None` if the client
+/// closed the connection first.
+async fn read_head(reader: &mut BufReader<impl AsyncRead + Unpin>) -> Result<Option<Head>> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+    let mut head = Head {
+        method: parts.next().unwrap_or_default().to_owned(),
+        target: parts.next().unwrap_or_default().to_owned(),
+        content_length: 0,
+    };
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = split_header(&line) {
+            if name.eq_ignore_ascii_case("content-length") {
+                head.content_length = value.parse().unwrap_or(0);
+            }
+        }
+    }
+    Ok(Some(head))
+}
+
+/// Returns the status and body of the response to a request.
+async fn handle(
+    head: &Head,
+    body: &[u8],
+    kvs: Option<KvStore>,
+    engine: &dyn KvsEngine,
+    config: &ServerConfig,
+) -> (&'static str, String) {
+    let path = head.target.split('?').next().unwrap_or_default();
+    if path == "/stats" {
+        if head.method != "GET" {
+            return method_not_allowed();
+        }
+        let res = match kvs {
+            Some(kvs) => kvs.stats().await,
+            None => Err(KvsError::EngineUnsupported("stats")),
+        };
+        return match res {
+            Ok(stats) => ("200 OK", serde_json::to_string(&stats).unwrap()),
+            Err(e) => error(e),
+        };
+    }
+    let key = match path.strip_prefix("/keys/") {
+        Some(key) => match percent_decode(key) {
+            Some(key) if !key.is_empty() => key,
+            _ => return ("400 Bad Request", error_body("invalid key")),
+        },
+        None => return ("404 Not Found", error_body("not found")),
+    };
+    let op = match head.method.as_str() {
+        "GET" => "get",
+        "PUT" => "set",
+        "DELETE" => "remove",
+        _ => return method_not_allowed(),
+    };
+    if let Err(e) = config.chaos.inject(op).await {
+        return error(e);
+    }
+    match op {
+        "get" => match engine.get(&key).await {
+            Ok(Some(value)) => {
+                let body = json!({
+                    "key": String::from_utf8_lossy(&key),
+                    "value": String::from_utf8_lossy(&value),
+                });
+                ("200 OK", body.to_string())
+            }
+            Ok(None) => error(KvsError::KeyNotFound),
+            Err(e) => error(e),
+        },
+        "set" => {
+            let body: SetBody = match serde_json::from_slice(body) {
+                Ok(body) => body,
+                Err(e) => return ("400 Bad Request", error_body(&e.to_string())),
+            };
+            match engine.set(&key, body.value.as_bytes()).await {
+                Ok(()) => ("204 No Content", String::new()),
+                Err(e) => error(e),
+            }
+        }
+        _ => match engine.remove(&key).await {
+            Ok(()) => ("204 No Content", String::new()),
+            Err(e) => error(e),
+        },
+    }
+}
+
+fn method_not_allowed() -> (&'static str, String) {
+    ("405 Method Not Allowed", error_body("method not allowed"))
+}
+
+fn error(e: KvsError) -> (&'static str, String) {
+    let status = match e {
+        KvsError::KeyNotFound => "404 Not Found",
+        KvsError::TooLarge(..) => "413 Payload Too Large",
+        KvsError::EngineUnsupported(_) => "501 Not Implemented",
+        _ => "500 Internal Server Error",
+    };
+    (status, error_body(&e.to_string()))
+}
+
+fn error_body(message: &str) -> String {
+    json!({ "error": message }).to_string()
+}
+
+/// Decodes `%XX` escapes, returning `None` if one is malformed.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    Some(decoded)
+}
+
+pub(crate) fn split_header(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.splitn(2, ':');
+    Some((parts.next()?.trim(), parts.next()?.trim()))
+}
+
+pub(crate) async fn respond(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    Ok(())
+}
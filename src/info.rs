@@ -0,0 +1,65 @@
+//! What a server reports about itself, see `KvsClient::info`.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::Watermark;
+
+/// A summary of a server, in sections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Info {
+    pub server: ServerInfo,
+    pub clients: ClientsInfo,
+    /// `None` unless the server uses the kvs engine.
+    pub persistence: Option<PersistenceInfo>,
+    pub keyspace: KeyspaceInfo,
+    /// `None` unless the server uses the kvs engine.
+    pub compaction: Option<CompactionInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    pub engine: String,
+    /// How the server shares its keys, like `standalone` or `replica of
+    /// 127.0.0.1:4000`.
+    pub role: String,
+    /// Time since the server started.
+    pub uptime: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientsInfo {
+    /// Connections open on every listener.
+    pub connected: u64,
+    pub max_connections: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersistenceInfo {
+    /// The sequence number of the last write.
+    pub seq: u64,
+    /// How far the logs are known to be synced, see
+    /// `KvStore::durable_watermark`.
+    pub durable: Option<Watermark>,
+    /// See `KvStore::recovery_watermark`.
+    pub recovered: Option<Watermark>,
+    pub sync_interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyspaceInfo {
+    pub keys: u64,
+    /// Bytes of records holding current values, with the kvs engine.
+    pub live_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompactionInfo {
+    /// Bytes of overwritten records and tombstones not compacted yet.
+    pub dead_bytes: u64,
+    pub log_files: u64,
+    pub max_file_size: u64,
+    pub compaction_threshold: u64,
+}
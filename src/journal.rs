@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use futures::{future, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use super::{ClientConfig, KvStore, KvsClient, KvsError, Options, Result};
+
+/// Logs of the journal are this long, so it takes values as long as the
+/// server does by default.
+const JOURNAL_FILE_SIZE: u64 = 64 << 20;
+
+/// Keys whose last seen value is kept. Beyond this many, those seen the
+/// longest ago are forgotten.
+const MAX_SEEN_KEYS: usize = 10_000;
+
+/// A write made while the server was unreachable.
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    /// Idempotency key sent with the write when it's replayed.
+    id: u64,
+    key: String,
+    /// The value the key had as far as the client knew, if it knew.
+    expected: Option<Option<String>>,
+    value: Option<String>,
+}
+
+/// A journaled write the server rejected, because the key changed on the
+/// server since the client last saw it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub key: String,
+    /// The value the client expected the key to have.
+    pub expected: Option<String>,
+    /// The value the key had when the write was replayed.
+    pub actual: Option<String>,
+    /// The value the client wrote, or `None` for a removal.
+    pub value: Option<String>,
+}
+
+/// A client that keeps working while the server is unreachable.
+///
+/// Writes that can't reach the server are journaled in a local store and
+/// replayed by `sync`. Each replayed write only applies if the key still has
+/// the value the client last saw, and conflicts are reported otherwise.
+/// Reads are answered from the last values seen while offline, of up to
+/// `MAX_SEEN_KEYS` keys.
+pub struct OfflineClient {
+    addr: SocketAddr,
+    config: ClientConfig,
+    client: Option<KvsClient>,
+    /// Journaled writes under `op/<seq>`, and the last value seen of each
+    /// key under `seen/<key>`, with when it was seen.
+    journal: KvStore,
+    next_seq: u64,
+    /// The keys with a value seen, by when they were last seen.
+    seen_order: BTreeMap<u64, String>,
+    next_seen: u64,
+}
+
+impl OfflineClient {
+    /// Opens the journal in `dir`. The server isn't contacted until `sync`.
+    pub async fn open(
+        addr: SocketAddr,
+        config: ClientConfig,
+        dir: impl Into<PathBuf>,
+    ) -> Result<Self> {
+        let options = Options {
+            max_file_size: JOURNAL_FILE_SIZE,
+            ..Options::default()
+        };
+        let journal = KvStore::open_with_options(dir, options).await?;
+        let mut next_seq = 0;
+        let mut ops = journal.keys_with_prefix("op/").await?;
+        while let Some(key) = ops.next().await {
+            next_seq = seq_of(&key) + 1;
+        }
+        let mut client = OfflineClient {
+            addr,
+            config,
+            client: None,
+            journal,
+            next_seq,
+            seen_order: BTreeMap::new(),
+            next_seen: 0,
+        };
+        let mut seen = client.journal.keys_with_prefix("seen/").await?;
+        while let Some(key) = seen.next().await {
+            let key = String::from_utf8_lossy(&key["seen/".len()..]).into_owned();
+            if let Some((when, _)) = client.seen_entry(&key).await? {
+                client.seen_order.insert(when, key);
+                client.next_seen = client.next_seen.max(when + 1);
+            }
+        }
+        Ok(client)
+    }
+
+    /// Returns how many writes are waiting to be replayed.
+    pub async fn pending(&self) -> Result<u64> {
+        let ops = self.journal.keys_with_prefix("op/").await?;
+        Ok(ops.fold(0, |n, _| future::ready(n + 1)).await)
+    }
+
+    /// Connects to the server and replays the journal in order.
+    ///
+    /// Fails with an io error if the server is still unreachable, in which
+    /// case the rest of the journal is kept for the next attempt.
+    pub async fn sync(&mut self) -> Result<Vec<Conflict>> {
+        if self.client.is_none() {
+            let client = KvsClient::connect(self.addr, self.config.clone()).await?;
+            self.client = Some(client);
+        }
+        let mut conflicts = Vec::new();
+        let mut ops = self.journal.keys_with_prefix("op/").await?;
+        while let Some(op) = ops.next().await {
+            let entry: Entry = bincode::deserialize(&self.journal.get(&op).await?.unwrap())?;
+            let client = self.client.as_mut().unwrap();
+            let res = client
+                .compare_and_set(
+                    entry.id,
+                    entry.key.clone(),
+                    entry.expected.clone(),
+                    entry.value.clone(),
+                )
+                .await;
+            let applied = match res {
+                Ok(applied) => applied,
+                Err(e) => return Err(self.disconnect(e)),
+            };
+            if !applied {
+                let actual = match client.get(entry.key.clone()).await {
+                    Ok(actual) => actual,
+                    Err(e) => return Err(self.disconnect(e)),
+                };
+                self.see(&entry.key, &actual).await?;
+                conflicts.push(Conflict {
+                    key: entry.key,
+                    expected: entry.expected.unwrap_or_default(),
+                    actual,
+                    value: entry.value,
+                });
+            }
+            self.journal.remove(&op).await?;
+        }
+        Ok(conflicts)
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(client) = &mut self.client {
+            match client.get(key.clone()).await {
+                Ok(value) => {
+                    self.see(&key, &value).await?;
+                    return Ok(value);
+                }
+                Err(KvsError::Io(_)) => self.client = None,
+                Err(e) => return Err(e),
+            }
+        }
+        self.seen(&key).await?.ok_or(KvsError::Offline)
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(key, Some(value)).await
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.write(key, None).await
+    }
+
+    async fn write(&mut self, key: String, value: Option<String>) -> Result<()> {
+        if let Some(client) = &mut self.client {
+            let res = match &value {
+                Some(value) => client.set(key.clone(), value.clone()).await,
+                None => client.remove(key.clone()).await,
+            };
+            match res {
+                Ok(()) => return self.see(&key, &value).await,
+                Err(KvsError::Io(_)) => self.client = None,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let expected = self.seen(&key).await?;
+        if value.is_none() && expected == Some(None) {
+            return Err(KvsError::KeyNotFound);
+        }
+        let entry = Entry {
+            id: rand::random(),
+            key,
+            expected,
+            value,
+        };
+        let op = format!("op/{:020}", self.next_seq);
+        self.journal.set(op, bincode::serialize(&entry)?).await?;
+        self.next_seq += 1;
+        // Later writes to the key expect this value once it's replayed.
+        self.see(&entry.key, &entry.value).await
+    }
+
+    /// Drops the connection after a failed replay, so later writes are
+    /// journaled behind the rest of the journal rather than sent ahead of it.
+    fn disconnect(&mut self, e: KvsError) -> KvsError {
+        self.client = None;
+        e
+    }
+
+    /// Returns the last value seen of `key`, or `None` if it's unknown.
+    async fn seen(&self, key: &str) -> Result<Option<Option<String>>> {
+        Ok(self.seen_entry(key).await?.map(|(_, value)| value))
+    }
+
+    /// Returns when `key` was last seen, and its value then.
+    async fn seen_entry(&self, key: &str) -> Result<Option<(u64, Option<String>)>> {
+        match self.journal.get(format!("seen/{}", key)).await? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Keeps `value` as the last seen of `key`, forgetting the keys seen the
+    /// longest ago beyond `MAX_SEEN_KEYS`.
+    async fn see(&mut self, key: &str, value: &Option<String>) -> Result<()> {
+        if let Some((when, _)) = self.seen_entry(key).await? {
+            self.seen_order.remove(&when);
+        }
+        let when = self.next_seen;
+        self.next_seen += 1;
+        let entry = bincode::serialize(&(when, value))?;
+        self.journal.set(format!("seen/{}", key), entry).await?;
+        self.seen_order.insert(when, key.to_owned());
+        while self.seen_order.len() > MAX_SEEN_KEYS {
+            if let Some((_, oldest)) = self.seen_order.pop_first() {
+                self.journal.remove(format!("seen/{}", oldest)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn seq_of(op: &[u8]) -> u64 {
+    String::from_utf8_lossy(&op[3..]).parse().unwrap()
+}
@@ -0,0 +1,377 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, Cursor, Write};
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use log::warn;
+use memmap::Mmap;
+
+use crate::kvs::LogPos;
+use crate::{Result, SkipMap};
+
+/// Entries of a shard between the keys of it kept in memory. It must be a
+/// power of two.
+const SHARD_BLOCK: usize = 64;
+
+/// Shards are merged into one when spilling would make more of them.
+const MAX_SHARDS: usize = 8;
+
+/// Rough memory taken by an entry besides its key.
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// Skip lists the entries in memory are split between by key hash, so
+/// writers and readers of different keys rarely touch the same nodes.
+const PARTITIONS: usize = 16;
+
+/// A key and its position, or `None` if it was removed.
+type Entry = (Vec<u8>, Option<LogPos>);
+
+type Entries<'a> = Box<dyn Iterator<Item = Entry> + Send + 'a>;
+
+/// Maps each key to the position of its current value.
+///
+/// Without a memory limit, it's a map in memory. With one, the entries in
+/// memory are spilled to a sorted shard file in the `index` directory once
+/// they take more than the limit, so recently written keys stay in memory
+/// and the rest are looked up in the mapped shards, which the OS keeps in its
+/// page cache as far as memory allows. Shards are merged once there are
+/// more than a few of them.
+///
+/// It's only written by whoever holds the store's writer, while readers
+/// look keys up concurrently. The entries in memory are partitioned by key
+/// hash, and merged back into order for ranges.
+pub(crate) struct Keydir {
+    /// `None` marks a key removed since it was spilled.
+    memory: Vec<SkipMap<Vec<u8>, Option<LogPos>>>,
+    memory_bytes: AtomicU64,
+    /// Newest first.
+    shards: ArcSwap<Vec<Arc<Shard>>>,
+    limit: Option<u64>,
+    dir: PathBuf,
+    next_shard: AtomicU64,
+}
+
+impl Keydir {
+    /// Creates an empty keydir spilling to `dir/index` beyond `limit` bytes.
+    ///
+    /// Shards left by the last time the store was open are removed, as the
+    /// keydir is loaded from scratch.
+    pub(crate) fn new(dir: &Path, limit: Option<u64>) -> Result<Keydir> {
+        let dir = dir.join("index");
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Ok(Keydir {
+            memory: (0..PARTITIONS).map(|_| SkipMap::new()).collect(),
+            memory_bytes: AtomicU64::new(0),
+            shards: ArcSwap::from_pointee(Vec::new()),
+            limit,
+            dir,
+            next_shard: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the partition holding `key`.
+    fn partition(&self, key: &[u8]) -> &SkipMap<Vec<u8>, Option<LogPos>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.memory[hasher.finish() as usize & (PARTITIONS - 1)]
+    }
+
+    /// Returns the entries in memory within `range`, merged in order.
+    fn memory_range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Merge<'_> {
+        let sources = self
+            .memory
+            .iter()
+            .map(|partition| -> Entries {
+                Box::new(
+                    partition
+                        .range(range.clone())
+                        .map(|entry| (entry.key().clone(), *entry.value())),
+                )
+            })
+            .collect();
+        Merge::new(sources)
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<LogPos> {
+        if let Some(entry) = self.partition(key).get(key) {
+            return *entry.value();
+        }
+        // Spilling stores the shard before clearing the memory, so a key
+        // missing from memory is in the shards loaded after.
+        self.shards
+            .load()
+            .iter()
+            .find_map(|shard| shard.get(key))
+            .flatten()
+    }
+
+    pub(crate) fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns the keys within `range` and their positions, in ascending order.
+    pub(crate) fn range<R>(
+        &self,
+        range: R,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, LogPos)> + Send + '_>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if self.limit.is_none() {
+            // Nothing is ever spilled, nor marked removed.
+            return Box::new(
+                self.memory_range(range)
+                    .filter_map(|(key, pos)| pos.map(|pos| (key, pos))),
+            );
+        }
+        // The memory is read before the shards are loaded, so entries spilled
+        // meanwhile are seen in one or the other.
+        let memory: Vec<_> = self.memory_range(range.clone()).collect();
+        let mut sources: Vec<Entries> = vec![Box::new(memory.into_iter())];
+        for shard in self.shards.load().iter() {
+            sources.push(Box::new(ShardIter::new(Arc::clone(shard), range.clone())));
+        }
+        Box::new(Merge::new(sources).filter_map(|(key, pos)| pos.map(|pos| (key, pos))))
+    }
+
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, LogPos)> + Send + '_> {
+        self.range(..)
+    }
+
+    pub(crate) fn insert(&self, key: Vec<u8>, pos: LogPos) -> Result<()> {
+        let partition = self.partition(&key);
+        if !partition.contains_key(&key) {
+            self.memory_bytes
+                .fetch_add(key.len() as u64 + ENTRY_OVERHEAD, Ordering::SeqCst);
+        }
+        partition.insert(key, Some(pos));
+        match self.limit {
+            Some(limit) if self.memory_bytes.load(Ordering::SeqCst) > limit => self.spill(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes `key`, returning its position if it existed.
+    pub(crate) fn remove(&self, key: &[u8]) -> Option<LogPos> {
+        let old = self.get(key)?;
+        let partition = self.partition(key);
+        if self.shards.load().is_empty() {
+            partition.remove(key);
+            self.memory_bytes
+                .fetch_sub(key.len() as u64 + ENTRY_OVERHEAD, Ordering::SeqCst);
+        } else {
+            // Shadows the key in the shards.
+            if !partition.contains_key(key) {
+                self.memory_bytes
+                    .fetch_add(key.len() as u64 + ENTRY_OVERHEAD, Ordering::SeqCst);
+            }
+            partition.insert(key.to_vec(), None);
+        }
+        Some(old)
+    }
+
+    /// Writes the entries in memory to a new shard, or merges them with
+    /// every shard into one if there are too many.
+    fn spill(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!(
+            "{}.idx",
+            self.next_shard.fetch_add(1, Ordering::SeqCst)
+        ));
+        let shards = self.shards.load_full();
+        let memory = self.memory_range((Bound::Unbounded, Bound::Unbounded));
+        let mut spilled = Vec::new();
+        if shards.len() < MAX_SHARDS {
+            spilled.extend(Shard::write(path, memory)?);
+            spilled.extend(shards.iter().cloned());
+        } else {
+            let mut sources: Vec<Entries> = vec![Box::new(memory)];
+            for shard in shards.iter() {
+                let range = (Bound::Unbounded, Bound::Unbounded);
+                sources.push(Box::new(ShardIter::new(Arc::clone(shard), range)));
+            }
+            // Nothing older is left for removed keys to shadow.
+            let merged = Merge::new(sources).filter(|(_, pos)| pos.is_some());
+            spilled.extend(Shard::write(path, merged)?);
+        }
+        self.shards.store(Arc::new(spilled));
+        self.clear_memory();
+        Ok(())
+    }
+
+    fn clear_memory(&self) {
+        for partition in &self.memory {
+            partition.clear();
+        }
+        self.memory_bytes.store(0, Ordering::SeqCst);
+    }
+}
+
+/// A sorted file of keydir entries, mapped into memory. The first key of
+/// every block of entries is kept in memory to find them by.
+struct Shard {
+    map: Mmap,
+    path: PathBuf,
+    /// The first key of each block and its offset.
+    index: Vec<(Vec<u8>, usize)>,
+}
+
+impl Shard {
+    /// Writes `entries`, which must be sorted, to a shard at `path`.
+    /// Returns `None` if there are none.
+    fn write(path: PathBuf, entries: impl Iterator<Item = Entry>) -> Result<Option<Arc<Shard>>> {
+        let mut file = BufWriter::new(File::create(&path)?);
+        let mut index = Vec::new();
+        let mut offset = 0;
+        for (i, entry) in entries.enumerate() {
+            let data = bincode::serialize(&entry)?;
+            if i & (SHARD_BLOCK - 1) == 0 {
+                index.push((entry.0, offset));
+            }
+            file.write_all(&data)?;
+            offset += data.len();
+        }
+        file.flush()?;
+        drop(file);
+        if index.is_empty() {
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        // Safety: shards are never written again once they're complete.
+        let map = unsafe { Mmap::map(&File::open(&path)?)? };
+        Ok(Some(Arc::new(Shard { map, path, index })))
+    }
+
+    /// Returns the entry of `key` if the shard has one.
+    fn get(&self, key: &[u8]) -> Option<Option<LogPos>> {
+        let block = self.block(key)?;
+        let mut offset = self.index[block].1;
+        for _ in 0..SHARD_BLOCK {
+            let ((found, pos), next) = self.entry_at(offset)?;
+            if found.as_slice() >= key {
+                return if found.as_slice() == key {
+                    Some(pos)
+                } else {
+                    None
+                };
+            }
+            offset = next;
+        }
+        None
+    }
+
+    /// Returns the block that would hold `key`, if any.
+    fn block(&self, key: &[u8]) -> Option<usize> {
+        self.index
+            .partition_point(|(first, _)| first.as_slice() <= key)
+            .checked_sub(1)
+    }
+
+    /// Decodes the entry at `offset`, returning it and the offset after it.
+    fn entry_at(&self, offset: usize) -> Option<(Entry, usize)> {
+        if offset >= self.map.len() {
+            return None;
+        }
+        let mut cursor = Cursor::new(&self.map[offset..]);
+        let entry = bincode::deserialize_from(&mut cursor).expect("shard entries are valid");
+        Some((entry, offset + cursor.position() as usize))
+    }
+}
+
+impl Drop for Shard {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove keydir shard {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Iterates the entries of a shard within a range.
+struct ShardIter {
+    shard: Arc<Shard>,
+    offset: usize,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+impl ShardIter {
+    fn new(shard: Arc<Shard>, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> ShardIter {
+        let block = match &range.0 {
+            Bound::Included(start) | Bound::Excluded(start) => shard.block(start).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        ShardIter {
+            offset: shard.index[block].1,
+            shard,
+            range,
+        }
+    }
+}
+
+impl Iterator for ShardIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            let (entry, next) = self.shard.entry_at(self.offset)?;
+            self.offset = next;
+            let before_start = match &self.range.0 {
+                Bound::Included(start) => entry.0 < *start,
+                Bound::Excluded(start) => entry.0 <= *start,
+                Bound::Unbounded => false,
+            };
+            if before_start {
+                continue;
+            }
+            if !self.range.contains(&entry.0) {
+                self.offset = self.shard.map.len();
+                return None;
+            }
+            return Some(entry);
+        }
+    }
+}
+
+/// Merges sorted entries, taking those of earlier sources over later ones
+/// for the same key.
+struct Merge<'a> {
+    sources: Vec<Peekable<Entries<'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Entries<'a>>) -> Merge<'a> {
+        Merge {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let key = self
+            .sources
+            .iter_mut()
+            .filter_map(|source| source.peek().map(|(key, _)| key))
+            .min()?
+            .clone();
+        let mut first = None;
+        for source in &mut self.sources {
+            if matches!(source.peek(), Some((next, _)) if *next == key) {
+                let entry = source.next();
+                first = first.or(entry);
+            }
+        }
+        first
+    }
+}
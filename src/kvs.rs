@@ -1,105 +1,1109 @@
-use std::collections::HashMap;
-
-use async_std::fs::{self, File, OpenOptions};
-use async_std::io::{self, SeekFrom};
-use async_std::path::PathBuf;
-use async_std::prelude::*;
-use async_std::sync::{Arc, Mutex};
-use async_std::task;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor};
+use std::mem;
+use std::net::SocketAddr;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{self as std_sync, Arc, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use arc_swap::ArcSwap;
+use futures::channel::oneshot;
+use futures::executor::block_on;
+use futures::future::{self, BoxFuture, FutureExt as _, Shared};
+use futures::lock::{Mutex, MutexGuard};
+use futures::sink::SinkExt as _;
+use futures::stream::{self, Stream, StreamExt};
+use log::warn;
+use memmap::Mmap;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-use crate::{KvsError, Result, SkipMap};
+use crate::archive;
+use crate::audit::AuditLog;
+use crate::backend::{self, IoBackend};
+use crate::backup::{self, BackupTarget};
+use crate::codec::{CodecStore, KeyCodec, ValueCodec};
+use crate::digest::MerkleRoot;
+use crate::file_cache::{CachedFile, FileCache};
+use crate::hint::{self, Hint};
+use crate::keydir::Keydir;
+use crate::listener::Listeners;
+use crate::maintenance::MaintenanceWindow;
+use crate::manifest;
+use crate::sample::KeySample;
+use crate::single_file::SingleFile;
+use crate::units::{parse_duration, parse_ratio, parse_size};
+use crate::watch::{ChangeFeed, Watchers};
+use crate::{Change, KvsError, Result, SkipMap, WatchEvent};
+
+/// Options for opening a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Size after which writes go to a new log file.
+    pub max_file_size: u64,
+    /// Fraction of `max_file_size` that must be dead before a log file is compacted.
+    pub compaction_ratio: f64,
+    /// Sync the active log file this often, saving how far it's synced as a
+    /// `Watermark`. If `None`, syncing is left to the OS.
+    pub sync_interval: Option<Duration>,
+    /// Sync each group of `set`s before acknowledging it.
+    pub sync_writes: bool,
+    /// Only compact and verify logs during these windows. Outside of them, a
+    /// log is only compacted once it's entirely dead. If empty, logs are
+    /// compacted whenever they pass the threshold.
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Previous values kept per key, for `get_version` and `history`. They're
+    /// stored in the record of the current value, so every write rewrites them.
+    pub versions: usize,
+    /// Notified of the store's lifecycle events, see `StoreListener`.
+    pub listeners: Listeners,
+    /// Memory the keydir may take before its entries are spilled to index
+    /// files, for stores with more keys than fit in memory. If `None`, it's
+    /// kept in memory entirely. Only read when the store is opened.
+    pub keydir_memory: Option<u64>,
+    /// Longest key `set` accepts. Besides these limits, a key and its value
+    /// must fit in `max_file_size` together.
+    pub max_key_size: Option<u64>,
+    /// Longest value `set` accepts.
+    pub max_value_size: Option<u64>,
+    /// Keep every log in one file, `kvs.data`, rather than a file each, for
+    /// systems short of file descriptors. Each log takes a region of
+    /// `max_file_size` bytes reserved up front, found by an index at the
+    /// start of the file, and a compacted log's region is reused. A store
+    /// must always be opened in the same mode. Only read when the store is
+    /// opened.
+    pub single_file: bool,
+    /// Log files kept open at once. Opening another closes the least
+    /// recently used one, which is opened again when it's next read. Only
+    /// read when the store is opened.
+    pub max_open_files: usize,
+    /// Read log files with direct I/O, bypassing the page cache, so scans
+    /// and compaction don't evict the pages of other processes. Writes are
+    /// still buffered. Only on Linux, and not with `single_file`; where the
+    /// file system doesn't support it, reads fall back to the page cache.
+    /// Only read when the store is opened.
+    pub direct_io: bool,
+    /// Create the directory, along with its parents, and an empty store in
+    /// it if it holds no store yet. Otherwise opening fails with
+    /// `KvsError::NoStore`.
+    pub create_if_missing: bool,
+    /// Fail with `KvsError::StoreExists` if the directory holds a store already.
+    pub error_if_exists: bool,
+    /// Append an entry for every mutation to an audit log in this directory,
+    /// see `AuditEntry`. Only read when the store is opened.
+    pub audit_log: Option<PathBuf>,
+    /// Size after which the audit log is rotated. Rotated files are kept.
+    /// Only read when the store is opened.
+    pub audit_max_size: u64,
+    /// Open the store without writing to it, failing writes and compaction
+    /// with `KvsError::ReadOnly`. The store must exist. Only read when the
+    /// store is opened.
+    pub read_only: bool,
+}
+
+impl Options {
+    /// Sets the option `name` from its textual value, as `kvs-client admin
+    /// config` does. Maintenance windows are separated by `;`, and `none`
+    /// turns off periodic syncing or a size limit.
+    pub fn set(&mut self, name: &str, value: &str) -> std::result::Result<(), String> {
+        match name {
+            "max_file_size" => self.max_file_size = parse_size(value)?,
+            "compaction_ratio" => self.compaction_ratio = parse_ratio(value)?,
+            "sync_interval" => {
+                self.sync_interval = match value {
+                    "none" => None,
+                    _ => Some(parse_duration(value)?),
+                }
+            }
+            "sync_writes" => {
+                self.sync_writes = value
+                    .parse()
+                    .map_err(|_| format!("expected `true` or `false`, got `{}`", value))?
+            }
+            "maintenance_windows" => {
+                self.maintenance_windows = value
+                    .split(';')
+                    .filter(|window| !window.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            "versions" => {
+                self.versions = value
+                    .parse()
+                    .map_err(|_| format!("expected a number of versions, got `{}`", value))?
+            }
+            "max_key_size" => {
+                self.max_key_size = match value {
+                    "none" => None,
+                    _ => Some(parse_size(value)?),
+                }
+            }
+            "max_value_size" => {
+                self.max_value_size = match value {
+                    "none" => None,
+                    _ => Some(parse_size(value)?),
+                }
+            }
+            _ => return Err(format!("unknown option `{}`", name)),
+        }
+        Ok(())
+    }
+
+    /// Fails unless `key` and `value` are within the size limits.
+    pub(crate) fn check_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (key_len, value_len) = (key.len() as u64, value.len() as u64);
+        match self.max_key_size {
+            Some(max) if key_len > max => return Err(KvsError::TooLarge("key", key_len, max)),
+            _ => {}
+        }
+        match self.max_value_size {
+            Some(max) if value_len > max => {
+                return Err(KvsError::TooLarge("value", value_len, max))
+            }
+            _ => {}
+        }
+        if key_len + value_len > self.max_file_size {
+            return Err(KvsError::TooLarge(
+                "key and value",
+                key_len + value_len,
+                self.max_file_size,
+            ));
+        }
+        Ok(())
+    }
+
+    fn compaction_threshold(&self) -> u64 {
+        (self.max_file_size as f64 * self.compaction_ratio) as u64
+    }
+
+    fn in_maintenance_window(&self) -> bool {
+        let now = SystemTime::now();
+        self.maintenance_windows
+            .iter()
+            .any(|window| window.contains(now))
+    }
+
+    /// Dead bytes at which a log is compacted right away.
+    fn current_compaction_threshold(&self) -> u64 {
+        if self.maintenance_windows.is_empty() || self.in_maintenance_window() {
+            self.compaction_threshold()
+        } else {
+            self.max_file_size
+        }
+    }
+}
 
-const MAX_FILE_SIZE: u64 = 1024;
-const COMPACTION_THRESHOLD: u64 = (MAX_FILE_SIZE as f64 * 0.6) as u64;
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_file_size: 1024,
+            compaction_ratio: 0.6,
+            sync_interval: None,
+            sync_writes: false,
+            maintenance_windows: Vec::new(),
+            versions: 0,
+            listeners: Listeners::default(),
+            keydir_memory: None,
+            max_key_size: None,
+            max_value_size: None,
+            single_file: false,
+            max_open_files: 256,
+            direct_io: false,
+            create_if_missing: true,
+            error_if_exists: false,
+            audit_log: None,
+            audit_max_size: 64 * 1024 * 1024,
+            read_only: false,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct KvStore {
     reader: KvsReader,
     writer: Arc<Mutex<KvsWriter>>,
+    options: Arc<ArcSwap<Options>>,
+    /// Feeds the thread writing `set`s, see `write_sets`.
+    sets: futures::channel::mpsc::Sender<PendingSet>,
+    watchers: Arc<Watchers>,
+    background: Arc<Background>,
+    /// Wakes the thread holding writes frozen, see `freeze_writes`.
+    thaw: Arc<std_sync::Mutex<Option<mpsc::Sender<()>>>>,
+    /// The watermark saved when the store was last open, see `recovery_watermark`.
+    recovered: Option<Watermark>,
+    audit: Option<Arc<AuditLog>>,
+    /// The client mutations are audited as made by, see `with_client`.
+    client: Option<SocketAddr>,
+    /// See `Options::read_only`.
+    read_only: bool,
+}
+
+/// Which background threads are running. Once started, they run until the
+/// store is dropped.
+#[derive(Default)]
+struct Background {
+    sync: AtomicBool,
+    maintenance: AtomicBool,
+}
+
+/// A `set` waiting to be written by the writer thread.
+struct PendingSet {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    flags: u8,
+    done: oneshot::Sender<Result<()>>,
 }
 
 #[derive(Clone)]
 struct KvsReader {
     dir: Arc<PathBuf>,
-    keydir: Arc<SkipMap<Vec<u8>, LogPos>>,
-    readers: Arc<SkipMap<u64, File>>,
-    rio: rio::Rio,
+    keydir: Arc<Keydir>,
+    sample: Arc<KeySample>,
+    readers: Arc<SkipMap<u64, Arc<Segment>>>,
+    io: Arc<dyn IoBackend>,
+    /// The keydir file, looked up while the keydir is loaded from it.
+    hint: Arc<std_sync::RwLock<Option<Hint>>>,
+    loading: Loading,
+}
+
+/// An open log, in a file of its own or a region of the store's single file.
+///
+/// A compacted log is only removed once no scan is reading from it.
+struct Segment {
+    place: Place,
+    pins: std_sync::Mutex<Pins>,
+}
+
+enum Place {
+    File(Arc<CachedFile>),
+    /// The slot of the log in the single file's index.
+    Region(Arc<SingleFile>, usize),
+}
+
+#[derive(Default)]
+struct Pins {
+    scans: usize,
+    retired: bool,
+}
+
+impl Segment {
+    /// Opens the log file at `path` through `files`, creating it if it
+    /// doesn't exist.
+    fn open(files: &Arc<FileCache>, path: PathBuf) -> io::Result<Segment> {
+        Ok(Segment::new(Place::File(files.open(path)?)))
+    }
+
+    fn new(place: Place) -> Segment {
+        Segment {
+            place,
+            pins: Default::default(),
+        }
+    }
+
+    /// Returns the file holding the log, which may have to be opened again,
+    /// and where the log starts in it.
+    fn handle(&self) -> io::Result<(Arc<File>, u64)> {
+        match &self.place {
+            Place::File(file) => Ok((file.get()?, 0)),
+            Place::Region(single, slot) => {
+                Ok((Arc::clone(&single.file), single.region(*slot).base))
+            }
+        }
+    }
+
+    /// Bytes written to the log.
+    fn len(&self) -> io::Result<u64> {
+        match &self.place {
+            Place::File(file) => Ok(fs::metadata(file.path())?.len()),
+            Place::Region(single, slot) => Ok(single.region(*slot).len),
+        }
+    }
+
+    fn created(&self) -> io::Result<SystemTime> {
+        match &self.place {
+            Place::File(file) => {
+                let metadata = fs::metadata(file.path())?;
+                metadata.created().or_else(|_| metadata.modified())
+            }
+            Place::Region(single, slot) => Ok(single.region(*slot).created()),
+        }
+    }
+
+    /// Whether a log of `len` bytes fits. Log files grow as needed, while
+    /// regions are as large as they were made.
+    fn fits(&self, len: u64) -> bool {
+        match &self.place {
+            Place::File(_) => true,
+            Place::Region(single, slot) => len <= single.region(*slot).capacity,
+        }
+    }
+
+    /// Returns the file to read the log from, see `ReadHandle`.
+    fn reader(&self) -> io::Result<ReadHandle> {
+        match &self.place {
+            Place::File(file) => {
+                let (file, direct) = file.reader()?;
+                Ok(ReadHandle {
+                    file,
+                    base: 0,
+                    direct,
+                })
+            }
+            Place::Region(..) => {
+                let (file, base) = self.handle()?;
+                Ok(ReadHandle {
+                    file,
+                    base,
+                    direct: false,
+                })
+            }
+        }
+    }
+
+    async fn read_at(&self, io: &dyn IoBackend, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        self.reader()?.read_at(io, buf, pos).await
+    }
+
+    /// Writes `buf` at `pos`, which must be the end of the log.
+    async fn append(&self, io: &dyn IoBackend, buf: &[u8], pos: u64) -> io::Result<()> {
+        let (file, base) = self.handle()?;
+        io.write_at(&file, buf, base + pos).await?;
+        match &self.place {
+            Place::File(_) => Ok(()),
+            // Written after the records, so the index never covers what
+            // isn't written yet.
+            Place::Region(single, slot) => single.set_len(*slot, pos + buf.len() as u64),
+        }
+    }
+
+    async fn fsync(&self, io: &dyn IoBackend) -> io::Result<()> {
+        io.fsync(&self.handle()?.0).await
+    }
+
+    async fn fdatasync(&self, io: &dyn IoBackend) -> io::Result<()> {
+        io.fdatasync(&self.handle()?.0).await
+    }
+
+    /// Cuts the log off after `len` bytes.
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        match &self.place {
+            Place::File(file) => file.get()?.set_len(len),
+            Place::Region(single, slot) => single.set_len(*slot, len),
+        }
+    }
+
+    /// Keeps the log from being removed until the returned pin is dropped.
+    fn pin(self: &Arc<Self>) -> PinnedSegment {
+        self.pins.lock().unwrap().scans += 1;
+        PinnedSegment(Arc::clone(self))
+    }
+
+    /// Removes the log now or, if it's pinned, once the last pin is dropped.
+    fn retire(&self) {
+        let mut pins = self.pins.lock().unwrap();
+        pins.retired = true;
+        if pins.scans == 0 {
+            self.remove();
+        }
+    }
+
+    /// Hard links the log to `path` if `link` is set and it's a file of its
+    /// own, or copies it there otherwise. Returns its length.
+    async fn export(&self, io: &dyn IoBackend, path: &Path, link: bool) -> io::Result<u64> {
+        let len = self.len()?;
+        if let Place::File(file) = &self.place {
+            if !(link && fs::hard_link(file.path(), path).is_ok()) {
+                fs::copy(file.path(), path)?;
+            }
+            return Ok(len);
+        }
+        let mut buffer = vec![0u8; len as usize];
+        self.read_at(io, &mut buffer, 0).await?;
+        fs::write(path, buffer)?;
+        Ok(len)
+    }
+
+    fn remove(&self) {
+        match &self.place {
+            Place::File(file) => {
+                if let Err(e) = fs::remove_file(file.path()) {
+                    warn!("Failed to remove compacted log {:?}: {}", file.path(), e);
+                }
+            }
+            Place::Region(single, slot) => {
+                if let Err(e) = single.free(*slot) {
+                    warn!("Failed to free the region of a compacted log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// An open file of a log to read from, along with where the log starts in
+/// it. With `Options::direct_io`, it's opened for direct I/O, so it's read
+/// through aligned buffers rather than by the I/O backend.
+struct ReadHandle {
+    file: Arc<File>,
+    base: u64,
+    direct: bool,
+}
+
+impl ReadHandle {
+    /// Starts reading right away, not once the returned future is polled.
+    fn read_at<'a>(
+        &'a self,
+        io: &'a dyn IoBackend,
+        buf: &'a mut [u8],
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        if self.direct {
+            future::ready(backend::read_direct(&self.file, buf, self.base + pos)).boxed()
+        } else {
+            io.read_at(&self.file, buf, self.base + pos)
+        }
+    }
+}
+
+struct PinnedSegment(Arc<Segment>);
+
+impl Drop for PinnedSegment {
+    fn drop(&mut self) {
+        let mut pins = self.0.pins.lock().unwrap();
+        pins.scans -= 1;
+        if pins.scans == 0 && pins.retired {
+            self.0.remove();
+        }
+    }
 }
 
+/// Completes once the keydir is fully loaded.
+type Loading = Shared<BoxFuture<'static, std::result::Result<(), String>>>;
+
 struct KvsWriter {
+    options: Arc<ArcSwap<Options>>,
     dir: Arc<PathBuf>,
-    keydir: Arc<SkipMap<Vec<u8>, LogPos>>,
-    readers: Arc<SkipMap<u64, File>>,
-    rio: rio::Rio,
+    keydir: Arc<Keydir>,
+    sample: Arc<KeySample>,
+    readers: Arc<SkipMap<u64, Arc<Segment>>>,
+    io: Arc<dyn IoBackend>,
     active_gen: u64,
-    writer: File,
+    files: Arc<FileCache>,
+    /// The file holding every log, if `Options::single_file` is set.
+    single: Option<Arc<SingleFile>>,
+    /// The active log.
+    writer: Arc<Segment>,
     writer_pos: u64,
     dead_bytes: HashMap<u64, u64>,
+    /// Sequence number of the last write.
+    seq: u64,
+    /// Streams of `KvStore::changes_since` tailing the writes.
+    changes: ChangeFeed,
+    /// The checkpoint of the last saved keydir file, if any.
+    saved: Option<Checkpoint>,
+    /// The last watermark saved.
+    durable: Option<Watermark>,
+    /// The store was opened by `KvStore::open_in_memory`, so its directory
+    /// is removed once it's dropped rather than saved.
+    ephemeral: bool,
+    /// Nothing may be written, see `Options::read_only`.
+    read_only: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LogPos {
+/// A summary of the store's contents and log files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub keys: u64,
+    /// Bytes of records holding current values.
+    pub live_bytes: u64,
+    /// Bytes of overwritten records and tombstones not compacted yet.
+    pub dead_bytes: u64,
+    pub log_files: u64,
+    /// Histogram of live record sizes: `record_sizes[i]` counts records of
+    /// less than `2^i` bytes that don't fit a smaller bucket.
+    pub record_sizes: Vec<u64>,
+    pub max_file_size: u64,
+    pub compaction_threshold: u64,
+    /// Reads that waited for a free slot in the I/O queue, and how long they
+    /// waited in total.
+    pub queued_reads: u64,
+    pub read_queue_wait: Duration,
+}
+
+/// `set`s queued for the writer thread at most, see `KvStore::set`.
+const SET_QUEUE_DEPTH: usize = 1024;
+
+/// How many keys `KvStore::estimate_count` counts before estimating instead.
+const EXACT_COUNT_LIMIT: usize = 4096;
+
+/// What a manual compaction did.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CompactionStats {
+    pub logs_compacted: u64,
+    /// How much smaller the log files are afterwards.
+    pub bytes_reclaimed: u64,
+}
+
+/// What a backup did, see `KvStore::backup_incremental` and
+/// `KvStore::backup`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackupStats {
+    /// The checkpoint the backup was taken from. `backup_incremental` keeps
+    /// it, to pass to the next one.
+    pub checkpoint: String,
+    pub logs_copied: u64,
+    pub bytes_copied: u64,
+    /// Logs compacted since the previous backup, removed from it.
+    pub logs_removed: u64,
+}
+
+/// How far the logs were synced, see `Options::sync_interval`.
+///
+/// Writes with sequence numbers up to `seq` are durable: the active log,
+/// `gen`, was synced up to `len` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Watermark {
+    pub gen: u64,
+    pub len: u64,
+    pub seq: u64,
+}
+
+/// A summary of one log file, see `KvStore::segment_stats`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentStats {
+    pub gen: u64,
+    /// Bytes written to the log.
+    pub size: u64,
+    /// Records holding current values, and their bytes.
+    pub live_records: u64,
+    pub live_bytes: u64,
+    /// Bytes of overwritten records and tombstones.
+    pub dead_bytes: u64,
+    /// Time since the log was created, or last written if the file system
+    /// doesn't record creation times.
+    pub age: Duration,
+}
+
+impl SegmentStats {
+    /// Fraction of the log that's dead, which compaction would reclaim.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.size == 0 {
+            0.0
+        } else {
+            self.dead_bytes as f64 / self.size as f64
+        }
+    }
+}
+
+/// What `KvStore::verify` found wrong with the store, if anything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub keys_checked: u64,
+    /// Keys whose keydir entry points at a missing log, or past the end of one.
+    pub dangling: Vec<Vec<u8>>,
+    /// Keys whose keydir entry points at bytes that don't decode as a record.
+    pub undecodable: Vec<Vec<u8>>,
+    /// Keys whose keydir entry points at a record of another key or a tombstone.
+    pub mismatched: Vec<Vec<u8>>,
+    /// Logs with bytes after the last record that decodes.
+    pub corrupt_logs: Vec<CorruptLog>,
+    /// Files in the store's directory that look like its own but aren't
+    /// used by it, such as logs created by another process or keydir files
+    /// left half written.
+    pub orphans: Vec<PathBuf>,
+}
+
+/// A log that can only be read up to `valid_len` of its `len` bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CorruptLog {
+    pub gen: u64,
+    pub valid_len: u64,
+    pub len: u64,
+}
+
+impl VerifyReport {
+    /// Returns whether nothing is wrong.
+    pub fn is_ok(&self) -> bool {
+        self.dangling.is_empty()
+            && self.undecodable.is_empty()
+            && self.mismatched.is_empty()
+            && self.corrupt_logs.is_empty()
+            && self.orphans.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} dangling, {} undecodable and {} mismatched of {} keys",
+            self.dangling.len(),
+            self.undecodable.len(),
+            self.mismatched.len(),
+            self.keys_checked
+        )?;
+        for log in &self.corrupt_logs {
+            write!(
+                f,
+                ", log {} only valid for {} of {} bytes",
+                log.gen, log.valid_len, log.len
+            )?;
+        }
+        for path in &self.orphans {
+            write!(f, ", orphaned {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Metadata stored alongside a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Metadata {
+    /// Bits set by `set_with_flags`, meaningful to the application only.
+    pub flags: u8,
+    /// When the value was written, or `None` if it was written before
+    /// timestamps were stored.
+    pub written_at: Option<SystemTime>,
+    /// Increases with every write to the store, so of two values the one
+    /// written later has the higher sequence number. Values written before
+    /// sequence numbers were stored have 0.
+    pub seq: u64,
+}
+
+/// Position of the `Record::Set` holding a key's current value.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct LogPos {
     gen: u64,
     pos: u64,
     len: u64,
 }
 
+/// An entry of a log file.
+#[derive(Debug, Serialize, Deserialize)]
+enum Record {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// Removes every key starting with `prefix` that was written before it.
+    RemovePrefix {
+        prefix: Vec<u8>,
+    },
+    /// A `Set` with non-zero flags.
+    SetWithFlags {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u8,
+    },
+    /// A `Set` that also holds the previous values of the key, newest first.
+    Versioned {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u8,
+        history: Vec<Vec<u8>>,
+    },
+    /// A `Versioned` along with when it was written, in milliseconds since
+    /// the Unix epoch, and its sequence number. Every `set` writes one.
+    Stamped {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u8,
+        history: Vec<Vec<u8>>,
+        written_at: u64,
+        seq: u64,
+    },
+    /// A `Remove` along with its sequence number. Every `remove` writes one.
+    StampedRemove {
+        key: Vec<u8>,
+        seq: u64,
+    },
+    /// A `RemovePrefix` along with its sequence number.
+    StampedRemovePrefix {
+        prefix: Vec<u8>,
+        seq: u64,
+    },
+}
+
+impl Record {
+    /// Returns the record setting `key`, stamped with the current time.
+    fn set(key: &[u8], value: &[u8], flags: u8, history: &[Vec<u8>], seq: u64) -> Record {
+        let written_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Record::Stamped {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            flags,
+            history: history.to_vec(),
+            written_at,
+            seq,
+        }
+    }
+
+    /// Returns the value and metadata of a record setting a key.
+    fn into_entry(self) -> Result<(Vec<u8>, Metadata)> {
+        let metadata = |flags| Metadata {
+            flags,
+            ..Metadata::default()
+        };
+        match self {
+            Record::Set { value, .. } => Ok((value, Metadata::default())),
+            Record::SetWithFlags { value, flags, .. } | Record::Versioned { value, flags, .. } => {
+                Ok((value, metadata(flags)))
+            }
+            Record::Stamped {
+                value,
+                flags,
+                written_at,
+                seq,
+                ..
+            } => Ok((
+                value,
+                Metadata {
+                    flags,
+                    written_at: Some(UNIX_EPOCH + Duration::from_millis(written_at)),
+                    seq,
+                },
+            )),
+            _ => Err(KvsError::Corrupted),
+        }
+    }
+
+    /// Returns the value of a record setting a key followed by its history.
+    fn into_versions(self) -> Result<Vec<Vec<u8>>> {
+        match self {
+            Record::Versioned { value, history, .. } | Record::Stamped { value, history, .. } => {
+                Ok(std::iter::once(value).chain(history).collect())
+            }
+            record => Ok(vec![record.into_entry()?.0]),
+        }
+    }
+
+    /// Returns the key of a record setting a key.
+    fn key(&self) -> Option<&Vec<u8>> {
+        match self {
+            Record::Set { key, .. }
+            | Record::SetWithFlags { key, .. }
+            | Record::Versioned { key, .. }
+            | Record::Stamped { key, .. } => Some(key),
+            Record::Remove { .. }
+            | Record::RemovePrefix { .. }
+            | Record::StampedRemove { .. }
+            | Record::StampedRemovePrefix { .. } => None,
+        }
+    }
+
+    /// Returns the sequence number of a record, or 0 if it has none.
+    fn seq(&self) -> u64 {
+        match self {
+            Record::Stamped { seq, .. }
+            | Record::StampedRemove { seq, .. }
+            | Record::StampedRemovePrefix { seq, .. } => *seq,
+            _ => 0,
+        }
+    }
+
+    /// Returns the change the record logs, if it has a sequence number.
+    fn into_change(self) -> Option<Change> {
+        match self {
+            Record::Stamped {
+                key, value, seq, ..
+            } => Some(Change::Set { seq, key, value }),
+            Record::StampedRemove { key, seq } => Some(Change::Remove { seq, key }),
+            Record::StampedRemovePrefix { prefix, seq } => {
+                Some(Change::RemovePrefix { seq, prefix })
+            }
+            _ => None,
+        }
+    }
+}
+
 impl KvStore {
     pub async fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(dir, Options::default()).await
+    }
+
+    /// Opens an empty store kept in memory as far as the system allows, for
+    /// tests and caches. See `open_in_memory_with_options`.
+    pub async fn open_in_memory() -> Result<Self> {
+        Self::open_in_memory_with_options(Options::default()).await
+    }
+
+    /// Opens an empty store in a new directory under `/dev/shm` where it's
+    /// a tmpfs, or the system's temporary directory otherwise. It isn't
+    /// synced or saved, and the directory is removed once the store and
+    /// its clones are dropped.
+    pub async fn open_in_memory_with_options(options: Options) -> Result<Self> {
+        let shm = Path::new("/dev/shm");
+        let parent = if shm.is_dir() {
+            shm.to_path_buf()
+        } else {
+            std::env::temp_dir()
+        };
+        let dir = parent.join(format!("kvs-{:016x}", rand::random::<u64>()));
+        let options = Options {
+            sync_interval: None,
+            sync_writes: false,
+            create_if_missing: true,
+            error_if_exists: true,
+            ..options
+        };
+        let store = match Self::open_with_options(&dir, options).await {
+            Ok(store) => store,
+            Err(e @ KvsError::StoreExists(_)) => return Err(e),
+            Err(e) => {
+                let _ = fs::remove_dir_all(&dir);
+                return Err(e);
+            }
+        };
+        store.writer.lock().await.ephemeral = true;
+        Ok(store)
+    }
+
+    pub async fn open_with_options(dir: impl Into<PathBuf>, options: Options) -> Result<Self> {
         let dir = Arc::new(dir.into());
+        let exists = store_exists(&dir)?;
+        if exists {
+            if options.error_if_exists {
+                return Err(KvsError::StoreExists(dir.to_path_buf()));
+            }
+        } else if options.create_if_missing && !options.read_only {
+            fs::create_dir_all(&*dir)?;
+        } else {
+            return Err(KvsError::NoStore(dir.to_path_buf()));
+        }
+        manifest::check(&dir, exists, options.read_only)?;
         let mut active_gen = 0;
         let readers = Arc::new(SkipMap::new());
-        let mut files = fs::read_dir(&*dir).await?;
-        while let Some(file) = files.next().await {
+        let files = FileCache::new(options.max_open_files, options.direct_io);
+        let mut log_files = false;
+        for file in fs::read_dir(&*dir)? {
             let path = file?.path();
-            if path.is_file().await && path.extension() == Some("log".as_ref()) {
-                let gen: u64 = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            // Other `.log` files, like an audit log, aren't the store's.
+            if let (true, Some(gen)) = (path.is_file(), log_gen(&path)) {
                 active_gen = active_gen.max(gen);
-                readers.insert(gen, File::open(path).await?);
+                log_files = true;
+                if !options.single_file {
+                    readers.insert(gen, Arc::new(Segment::open(&files, path)?));
+                }
+            }
+        }
+        let single_path = get_single_file_path(&dir);
+        let single = match options.single_file {
+            true if log_files => {
+                return Err(KvsError::Config(
+                    "the store keeps its logs in separate files".to_owned(),
+                ))
+            }
+            false if single_path.exists() => {
+                return Err(KvsError::Config(
+                    "the store keeps its logs in a single file".to_owned(),
+                ))
+            }
+            true => Some(Arc::new(SingleFile::open(&single_path)?)),
+            false => None,
+        };
+        if let Some(single) = &single {
+            for (slot, region) in single.regions() {
+                active_gen = active_gen.max(region.gen);
+                let segment = Segment::new(Place::Region(Arc::clone(single), slot));
+                readers.insert(region.gen, Arc::new(segment));
             }
         }
-        let mut writer = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(get_log_path(&dir, active_gen))
-            .await?;
-        let writer_pos = writer.seek(SeekFrom::End(0)).await?;
         if readers.is_empty() {
-            readers.insert(0, File::open(get_log_path(&dir, 0)).await?);
+            if options.read_only {
+                return Err(KvsError::NoStore(dir.to_path_buf()));
+            }
+            let segment = new_segment(&dir, &files, single.as_ref(), 0, options.max_file_size)?;
+            readers.insert(0, Arc::new(segment));
         }
+        let file = Arc::clone(readers.get(&active_gen).unwrap().value());
 
-        let rio = rio::new()?;
-        let (keydir, dead_bytes) = match File::open(get_keydir_path(&dir)).await {
-            Ok(file) => {
-                let buffer = vec![0u8; file.metadata().await?.len() as usize];
-                rio.read_at(&file, &buffer, 0).await?;
-                bincode::deserialize(&buffer)?
-            }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+        let keydir = Arc::new(Keydir::new(&dir, options.keydir_memory)?);
+        let sample = Arc::new(KeySample::default());
+        let read_only = options.read_only;
+        let options = Arc::new(ArcSwap::from_pointee(options));
+        let mut writer = KvsWriter {
+            options: Arc::clone(&options),
+            dir: Arc::clone(&dir),
+            keydir: Arc::clone(&keydir),
+            sample: Arc::clone(&sample),
+            io: backend::detect(),
+            active_gen,
+            readers: Arc::clone(&readers),
+            files,
+            single,
+            writer: file,
+            writer_pos: 0,
+            dead_bytes: HashMap::new(),
+            seq: 0,
+            changes: ChangeFeed::default(),
+            saved: None,
+            durable: None,
+            ephemeral: false,
+            read_only,
+        };
+        let hint = match File::open(get_keydir_path(&dir)) {
+            // Safety: the keydir file is replaced by renaming rather than
+            // written in place.
+            Ok(file) => Some(unsafe { Mmap::map(&file)? }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
             Err(e) => return Err(e.into()),
         };
-        let keydir = Arc::new(keydir);
+        writer.writer_pos = writer.writer.len()?;
+        let checkpoint = writer.checkpoint();
+        // A keydir file saved by `flush` is stale once the logs are written after it.
+        let saved = match hint.map(Hint::open::<(Checkpoint, HashMap<u64, u64>, u64)>) {
+            Some(Ok(((saved, dead_bytes, seq), hint))) if saved == checkpoint => {
+                Some((hint, dead_bytes, seq))
+            }
+            Some(res) => {
+                if let Err(e) = res {
+                    warn!("Invalid keydir file, replaying logs: {}", e);
+                }
+                if !read_only {
+                    fs::remove_file(get_keydir_path(&dir))?;
+                }
+                None
+            }
+            None => None,
+        };
+        if saved.is_none() {
+            writer.replay().await?;
+        }
+        let recovered = read_watermark(&dir)?;
+        if let Some(mark) = recovered {
+            let lost = mark.gen > writer.active_gen
+                || (mark.gen == writer.active_gen && writer.writer_pos < mark.len);
+            if lost {
+                warn!(
+                    "Synced writes up to sequence number {} were lost: log {} ends before {}",
+                    mark.seq, mark.gen, mark.len
+                );
+            }
+        }
+        writer.durable = recovered;
+        let io = Arc::clone(&writer.io);
+        let writer = Arc::new(Mutex::new(writer));
 
-        Ok(KvStore {
-            reader: KvsReader {
-                dir: Arc::clone(&dir),
-                keydir: Arc::clone(&keydir),
-                readers: Arc::clone(&readers),
-                rio: rio.clone(),
-            },
-            writer: Arc::new(Mutex::new(KvsWriter {
-                dir,
-                keydir,
-                rio,
-                active_gen,
-                readers,
-                writer,
-                writer_pos,
-                dead_bytes,
-            })),
-        })
+        let hint = Arc::new(std_sync::RwLock::new(None));
+        let loading = match saved {
+            Some((saved, dead_bytes, seq)) => {
+                // The keydir file is only valid until the next write, so it's
+                // removed now and written again by `flush` and on drop. If
+                // the store isn't closed cleanly, the logs are replayed instead.
+                // Read-only stores aren't written, so theirs stays valid.
+                if !read_only {
+                    fs::remove_file(get_keydir_path(&dir))?;
+                }
+                *hint.write().unwrap() = Some(saved);
+                let (done, loaded) = oneshot::channel();
+                let (hint, writer) = (Arc::clone(&hint), Arc::clone(&writer));
+                thread::spawn(move || {
+                    done.send(block_on(load_keydir(hint, dead_bytes, seq, writer)))
+                });
+                loaded
+                    .map(|res| res.unwrap_or_else(|_| Err("loading panicked".to_owned())))
+                    .boxed()
+                    .shared()
+            }
+            None => future::ready(Ok(())).boxed().shared(),
+        };
+
+        let reader = KvsReader {
+            dir,
+            keydir,
+            sample,
+            readers,
+            io,
+            hint,
+            loading,
+        };
+        let audit = match &options.load().audit_log {
+            Some(audit_dir) => Some(Arc::new(AuditLog::open(
+                audit_dir,
+                options.load().audit_max_size,
+            )?)),
+            None => None,
+        };
+        let watchers = Arc::new(Watchers::default());
+        let (sets, pending) = futures::channel::mpsc::channel(SET_QUEUE_DEPTH);
+        {
+            let (reader, writer, watchers) = (
+                reader.clone(),
+                Arc::downgrade(&writer),
+                Arc::clone(&watchers),
+            );
+            thread::spawn(move || block_on(write_sets(pending, reader, writer, watchers)));
+        }
+        let store = KvStore {
+            reader,
+            writer,
+            options,
+            sets,
+            watchers,
+            background: Default::default(),
+            thaw: Default::default(),
+            recovered,
+            audit,
+            client: None,
+            read_only,
+        };
+        store.start_background();
+        let options = store.options.load();
+        options.listeners.emit(|l| l.on_open(&store.reader.dir));
+        Ok(store)
+    }
+
+    /// Returns the options the store currently runs with.
+    pub fn options(&self) -> Options {
+        Options::clone(&self.options.load())
+    }
+
+    /// Replaces the options the store runs with. They take effect from the
+    /// next write or background check, without reopening the store.
+    pub fn reconfigure(&self, options: Options) {
+        self.options.store(Arc::new(options));
+        self.start_background();
+    }
+
+    /// Sets a single option by name from its textual value, see `Options::set`.
+    pub fn set_option(&self, name: &str, value: &str) -> Result<()> {
+        let mut res = Ok(());
+        self.options.rcu(|options| {
+            let mut options = Options::clone(options);
+            res = options.set(name, value);
+            options
+        });
+        self.start_background();
+        res.map_err(KvsError::Config)
+    }
+
+    /// Starts the background threads the current options need, unless
+    /// they're running already.
+    fn start_background(&self) {
+        let options = self.options.load();
+        if options.sync_interval.is_some() && !self.background.sync.swap(true, Ordering::SeqCst) {
+            let writer = Arc::downgrade(&self.writer);
+            let options = Arc::clone(&self.options);
+            thread::spawn(move || sync_periodically(writer, options));
+        }
+        if !options.maintenance_windows.is_empty()
+            && !self.background.maintenance.swap(true, Ordering::SeqCst)
+        {
+            let reader = self.reader.clone();
+            let writer = Arc::downgrade(&self.writer);
+            thread::spawn(move || maintain_periodically(reader, writer));
+        }
     }
 
     pub async fn get<K>(&self, key: K) -> Result<Option<Vec<u8>>>
@@ -109,124 +1113,2241 @@ impl KvStore {
         self.reader.get(key.as_ref()).await
     }
 
-    pub async fn set<K, V>(&self, key: K, value: V) -> Result<()>
+    /// Returns whether `key` is set, from the keydir alone.
+    pub async fn contains_key<K>(&self, key: K) -> Result<bool>
     where
         K: AsRef<[u8]>,
-        V: AsRef<[u8]>,
     {
-        let mut writer = self.writer.lock().await;
-        if let Some(gen) = writer.set(key.as_ref(), value.as_ref()).await? {
-            self.compact(gen, &mut writer).await?;
-        }
-        Ok(())
+        Ok(self.reader.pos(key.as_ref()).await?.is_some())
     }
 
-    pub async fn remove<K>(&self, key: K) -> Result<()>
+    /// Returns the length of the value of `key`, if it's set. Only the start
+    /// of its record is read, up to the length of the value.
+    pub async fn value_len<K>(&self, key: K) -> Result<Option<u64>>
     where
         K: AsRef<[u8]>,
     {
-        let mut writer = self.writer.lock().await;
-        if let Some(gen) = writer.remove(key.as_ref()).await? {
-            self.compact(gen, &mut writer).await?;
-        }
-        Ok(())
+        self.reader.value_len(key.as_ref()).await
     }
 
-    async fn compact(&self, gen: u64, writer: &mut KvsWriter) -> Result<()> {
-        for entry in self.reader.keydir.iter().filter(|x| x.value().gen == gen) {
-            let key = entry.key();
-            let value = self.reader.get(key).await?.unwrap();
-            writer.set(key, &value).await?;
-        }
-        writer.dead_bytes.remove(&gen);
-        writer.readers.remove(&gen);
-        fs::remove_file(get_log_path(&writer.dir, gen)).await?;
-        Ok(())
+    /// Gets the values of several keys at once, in the order of `keys`.
+    ///
+    /// All disk reads are submitted together and awaited as a batch.
+    pub async fn multi_get<I, K>(&self, keys: I) -> Result<Vec<Option<Vec<u8>>>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        self.reader.loaded().await?;
+        self.reader.multi_get(keys).await
     }
-}
 
-impl KvsReader {
-    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        match self.keydir.get(key) {
-            Some(entry) => {
-                let &LogPos { gen, pos, len } = entry.value();
-                let file = self.readers.get(&gen).unwrap();
-                let buffer = vec![0u8; len as usize];
-                self.rio.read_at(file.value(), &buffer, pos).await?;
-                Ok(Some(buffer))
-            }
-            None => Ok(None),
-        }
+    /// Returns the keys within `range` in ascending order.
+    ///
+    /// The keys are collected before this returns, so the stream is a snapshot
+    /// that isn't affected by writes made while it's consumed.
+    pub async fn keys<R>(&self, range: R) -> Result<impl Stream<Item = Vec<u8>>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        self.reader.loaded().await?;
+        let keys: Vec<_> = self
+            .reader
+            .keydir
+            .range(range)
+            .map(|(key, _)| key)
+            .collect();
+        Ok(stream::iter(keys))
     }
-}
 
-impl KvsWriter {
-    async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<Option<u64>> {
-        let res = self.remove(key).await.unwrap_or(None);
-        if self.writer_pos >= MAX_FILE_SIZE {
-            self.use_next_gen().await?;
-        }
-        self.rio
-            .write_at(&self.writer, &value, self.writer_pos)
-            .await?;
-        self.keydir.insert(
-            key.to_vec(),
-            LogPos {
-                gen: self.active_gen,
-                pos: self.writer_pos,
-                len: value.len() as u64,
-            },
-        );
-        self.writer_pos += value.len() as u64;
-        Ok(res)
+    /// Returns the keys and values within `range` in ascending order, as of
+    /// when this is called.
+    ///
+    /// Writes made while the stream is consumed aren't seen by it. The log
+    /// files it reads from are kept until it's dropped, even if they're
+    /// compacted meanwhile.
+    pub async fn scan<R>(
+        &self,
+        range: R,
+    ) -> Result<impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Unpin>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        self.scan_ordered(range, false).await
     }
 
-    async fn remove(&mut self, key: &[u8]) -> Result<Option<u64>> {
-        match self.keydir.remove(key) {
-            Some(old) => {
-                let old = old.value();
-                let dead = self.dead_bytes.entry(old.gen).or_insert(0);
-                *dead += old.len;
-                if *dead >= COMPACTION_THRESHOLD && old.gen != self.active_gen {
-                    Ok(Some(old.gen))
+    /// Returns the keys and values within `range` in descending order, as of
+    /// when this is called, see `scan`. Taking the first `n` of them only
+    /// reads the values of those.
+    pub async fn scan_rev<R>(
+        &self,
+        range: R,
+    ) -> Result<impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Unpin>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        self.scan_ordered(range, true).await
+    }
+
+    async fn scan_ordered<R>(
+        &self,
+        range: R,
+        rev: bool,
+    ) -> Result<impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Unpin>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        // Compaction needs the writer, so the logs can't change meanwhile.
+        let writer = self.lock_writer().await?;
+        let mut entries: Vec<_> = self.reader.keydir.range(range).collect();
+        if rev {
+            entries.reverse();
+        }
+        let mut segments = HashMap::new();
+        for (_, pos) in &entries {
+            segments
+                .entry(pos.gen)
+                .or_insert_with(|| self.reader.readers.get(&pos.gen).unwrap().value().pin());
+        }
+        drop(writer);
+
+        let io = Arc::clone(&self.reader.io);
+        let segments = Arc::new(segments);
+        Ok(stream::iter(entries)
+            .then(move |(key, pos)| {
+                let segments = Arc::clone(&segments);
+                let io = Arc::clone(&io);
+                async move {
+                    let mut buffer = vec![0u8; pos.len as usize];
+                    segments[&pos.gen]
+                        .0
+                        .read_at(&*io, &mut buffer, pos.pos)
+                        .await?;
+                    Ok((key, decode_value(&buffer)?))
+                }
+            })
+            .boxed())
+    }
+
+    /// Returns a Merkle root of every key and value, so two stores can be
+    /// compared by their digests.
+    ///
+    /// It's computed from a `scan`, so it reflects the store as of when it's
+    /// called, and only depends on the keys and values, not on how they're
+    /// laid out in the logs.
+    pub async fn digest(&self) -> Result<[u8; 32]> {
+        let mut pairs = self.scan(..).await?;
+        let mut root = MerkleRoot::default();
+        while let Some(pair) = pairs.next().await {
+            let (key, value) = pair?;
+            root.add(&key, &value);
+        }
+        Ok(root.finish())
+    }
+
+    /// Returns about how many keys start with `prefix`, without iterating
+    /// them all as `keys_with_prefix` does.
+    ///
+    /// Up to a few thousand keys are counted exactly. Beyond that, the count
+    /// is estimated from a sample of one in 64 keys, so it's more accurate
+    /// the more keys start with `prefix`.
+    pub async fn estimate_count(&self, prefix: impl AsRef<[u8]>) -> Result<u64> {
+        self.reader.loaded().await?;
+        let counted = self
+            .reader
+            .keydir
+            .range(prefix_range(prefix.as_ref()))
+            .take(EXACT_COUNT_LIMIT + 1)
+            .count();
+        if counted <= EXACT_COUNT_LIMIT {
+            return Ok(counted as u64);
+        }
+        let estimate = self.reader.sample.estimate(prefix.as_ref());
+        Ok(estimate.max(counted as u64))
+    }
+
+    /// Syncs the active log file and saves the keydir, so the store reopens
+    /// without replaying its logs even if it's never dropped.
+    ///
+    /// The saved keydir is only used while nothing is written after it.
+    pub async fn flush(&self) -> Result<()> {
+        self.lock_writer().await?.flush().await
+    }
+
+    /// Flushes the store and holds every write until `thaw` is called or
+    /// `timeout` passes, so a file system snapshot taken meanwhile captures
+    /// the store as of the flush.
+    ///
+    /// Reads go on while writes are frozen, but writes and anything else
+    /// needing the writer, such as compaction and `stats`, wait. Fails if
+    /// writes are frozen already.
+    pub async fn freeze_writes(&self, timeout: Duration) -> Result<()> {
+        self.reader.loaded().await?;
+        let (thaw, thawed) = mpsc::channel();
+        {
+            let mut current = self.thaw.lock().unwrap();
+            if current.is_some() {
+                return Err(KvsError::Frozen);
+            }
+            *current = Some(thaw);
+        }
+        let (done, frozen) = oneshot::channel();
+        let writer = Arc::clone(&self.writer);
+        let current = Arc::clone(&self.thaw);
+        // The writer lock is held on a thread of its own, so it outlives this call.
+        thread::spawn(move || {
+            let mut writer = block_on(writer.lock());
+            let res = block_on(writer.flush());
+            let flushed = res.is_ok();
+            let _ = done.send(res);
+            if flushed && thawed.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                warn!("Thawing writes after {:?} frozen", timeout);
+            }
+            current.lock().unwrap().take();
+        });
+        frozen
+            .await
+            .unwrap_or_else(|_| Err(io::Error::other("freezing writes panicked").into()))
+    }
+
+    /// Lets writes frozen by `freeze_writes` go on. Returns whether they
+    /// were frozen.
+    pub fn thaw(&self) -> bool {
+        match self.thaw.lock().unwrap().take() {
+            Some(thaw) => {
+                let _ = thaw.send(());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Saves the store as of now as checkpoint `name`, a store directory of
+    /// its own at `checkpoints/<name>` in the store's, which
+    /// `open_checkpoint` opens read-only. Restoring the store is copying the
+    /// checkpoint over it. Fails with `StoreExists` if the checkpoint exists.
+    ///
+    /// The active log is sealed and copied, and the others are hard linked,
+    /// so the checkpoint takes little time or space until compaction removes
+    /// them from the store. Where they can't be linked, such as with
+    /// `Options::single_file`, they're copied too.
+    pub async fn checkpoint(&self, name: &str) -> Result<()> {
+        let path = get_checkpoint_path(&self.reader.dir, name)?;
+        if path.exists() {
+            return Err(KvsError::StoreExists(path));
+        }
+        let mut writer = self.lock_writer().await?;
+        writer.writable()?;
+        writer.sync().await?;
+        writer.use_next_gen(0).await?;
+        // Renamed once complete, so a checkpoint is never left partly saved.
+        let temp = path.with_file_name(format!("{}.tmp", name));
+        if temp.exists() {
+            fs::remove_dir_all(&temp)?;
+        }
+        fs::create_dir_all(&temp)?;
+        let res = writer.save_checkpoint(&temp).await;
+        if res.is_err() {
+            let _ = fs::remove_dir_all(&temp);
+        }
+        res?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    /// Opens checkpoint `name` of the store in `dir` read-only, see
+    /// `checkpoint`. The store itself may be open meanwhile.
+    pub async fn open_checkpoint(dir: impl Into<PathBuf>, name: &str) -> Result<Self> {
+        let path = get_checkpoint_path(&dir.into(), name)?;
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        Self::open_with_options(path, options).await
+    }
+
+    /// Backs the store up to `dest`, which is kept a copy of a new checkpoint,
+    /// returned in the stats. Only logs created since `since_checkpoint`,
+    /// the checkpoint of the previous backup to `dest`, are copied, along
+    /// with the keydir file and manifest, and logs compacted since are
+    /// removed from `dest`. The previous checkpoint is removed once it's
+    /// superseded. Without `since_checkpoint`, every log is copied.
+    ///
+    /// `dest` is a store directory, so restoring is copying it back. A
+    /// backup cut off midway leaves it inconsistent, so the next one must
+    /// copy every log again.
+    pub async fn backup_incremental(
+        &self,
+        dest: impl AsRef<Path>,
+        since_checkpoint: Option<&str>,
+    ) -> Result<BackupStats> {
+        let dest = dest.as_ref();
+        let previous = match since_checkpoint {
+            Some(name) => {
+                let path = get_checkpoint_path(&self.reader.dir, name)?;
+                Some((
+                    log_gens(&path).map_err(|_| KvsError::NoStore(path.clone()))?,
+                    path,
+                ))
+            }
+            None => None,
+        };
+        let name = backup_checkpoint_name();
+        self.checkpoint(&name).await?;
+        let checkpoint = get_checkpoint_path(&self.reader.dir, &name)?;
+        let gens = log_gens(&checkpoint)?;
+
+        fs::create_dir_all(dest)?;
+        let mut stats = BackupStats {
+            checkpoint: name,
+            ..BackupStats::default()
+        };
+        for &gen in &gens {
+            let target = get_log_path(dest, gen);
+            // Sealed logs never change, so one backed up already is the same.
+            let copied = matches!(&previous, Some((previous, _)) if previous.contains(&gen));
+            if copied && target.exists() {
+                continue;
+            }
+            stats.bytes_copied += fs::copy(get_log_path(&checkpoint, gen), target)?;
+            stats.logs_copied += 1;
+        }
+        let temp = dest.join(format!("keydir.{:016x}.tmp", rand::random::<u64>()));
+        fs::copy(get_keydir_path(&checkpoint), &temp)?;
+        fs::rename(&temp, get_keydir_path(dest))?;
+        manifest::check(dest, false, false)?;
+        for gen in log_gens(dest)? {
+            if !gens.contains(&gen) {
+                fs::remove_file(get_log_path(dest, gen))?;
+                stats.logs_removed += 1;
+            }
+        }
+
+        if let Some((_, previous)) = previous {
+            fs::remove_dir_all(previous)?;
+        }
+        Ok(stats)
+    }
+
+    /// Backs the store up to `target`, from a checkpoint removed afterwards.
+    /// Logs the previous backup to `target` put already are skipped, and
+    /// objects it no longer needs are removed, so only what changed since is
+    /// transferred. The SHA-256 of every object is recorded in the backup's
+    /// manifest, `backup.json`, which `restore` checks them against.
+    ///
+    /// The manifest is put last, so a backup cut off midway leaves the
+    /// previous one restorable.
+    pub async fn backup(&self, target: &dyn BackupTarget) -> Result<BackupStats> {
+        let name = backup_checkpoint_name();
+        self.checkpoint(&name).await?;
+        let checkpoint = get_checkpoint_path(&self.reader.dir, &name)?;
+        let res = backup::backup(&checkpoint, &name, target).await;
+        fs::remove_dir_all(&checkpoint)?;
+        res
+    }
+
+    /// Restores the backup in `target` to a new store in `dir`, see
+    /// `backup`. Fails with `StoreExists` if `dir` holds a store already,
+    /// and with `KvsError::Backup` if an object is missing or doesn't match
+    /// its checksum, leaving `dir` as it was.
+    pub async fn restore(target: &dyn BackupTarget, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if store_exists(dir)? {
+            return Err(KvsError::StoreExists(dir.to_path_buf()));
+        }
+        // Renamed once complete, so a store is never left partly restored.
+        let temp = temp_sibling(dir);
+        let res = backup::restore(target, &temp).await;
+        if res.is_err() {
+            let _ = fs::remove_dir_all(&temp);
+        }
+        res?;
+        fs::rename(&temp, dir)?;
+        Ok(())
+    }
+
+    /// Exports the store as of now to a single file at `path`, holding the
+    /// logs, keydir file and manifest of a checkpoint and the SHA-256 of
+    /// each, which `import_archive` checks. Returns the size of the archive.
+    pub async fn export_archive(&self, path: impl AsRef<Path>) -> Result<u64> {
+        let path = path.as_ref();
+        let name = format!("archive-{:016x}", rand::random::<u64>());
+        self.checkpoint(&name).await?;
+        let checkpoint = get_checkpoint_path(&self.reader.dir, &name)?;
+        // Renamed once complete, so an archive is never left partly written.
+        let temp = temp_sibling(path);
+        let res = archive::write(&checkpoint, &temp);
+        fs::remove_dir_all(&checkpoint)?;
+        let len = match res {
+            Ok(len) => len,
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                return Err(e);
+            }
+        };
+        fs::rename(&temp, path)?;
+        Ok(len)
+    }
+
+    /// Imports the archive at `path` to a new store in `dir`, see
+    /// `export_archive`. Fails with `StoreExists` if `dir` holds a store
+    /// already, and with `InvalidArchive` if the archive is damaged, leaving
+    /// `dir` as it was.
+    pub async fn import_archive(path: impl AsRef<Path>, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if store_exists(dir)? {
+            return Err(KvsError::StoreExists(dir.to_path_buf()));
+        }
+        let temp = temp_sibling(dir);
+        let res = archive::read(path.as_ref(), &temp);
+        if res.is_err() {
+            let _ = fs::remove_dir_all(&temp);
+        }
+        res?;
+        fs::rename(&temp, dir)?;
+        Ok(())
+    }
+
+    /// Returns how far the logs are known to be synced, as of the last
+    /// periodic sync or `flush`.
+    pub async fn durable_watermark(&self) -> Option<Watermark> {
+        self.writer.lock().await.durable
+    }
+
+    /// Returns the watermark saved while the store was last open. After a
+    /// crash, writes with higher sequence numbers may be missing, while
+    /// those up to it are in the store.
+    pub fn recovery_watermark(&self) -> Option<Watermark> {
+        self.recovered
+    }
+
+    /// Returns a handle to the store whose mutations are audited as made by
+    /// `client`, see `Options::audit_log`. It shares the store with this one.
+    pub fn with_client(&self, client: SocketAddr) -> KvStore {
+        KvStore {
+            client: Some(client),
+            ..self.clone()
+        }
+    }
+
+    /// Records a mutation of `key` in the audit log, if there's one.
+    fn audit(&self, op: &str, key: &[u8]) {
+        if let Some(audit) = &self.audit {
+            audit.record(self.client, op, key);
+        }
+    }
+
+    /// Wraps the store in a `CodecStore`, which encodes keys with `keys` and
+    /// values with `values`.
+    pub fn with_codecs<K: KeyCodec, V: ValueCodec>(self, keys: K, values: V) -> CodecStore<K, V> {
+        CodecStore::new(self, keys, values)
+    }
+
+    /// Returns the directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.reader.dir
+    }
+
+    /// Flushes the store before dropping it, see `flush`.
+    ///
+    /// Dropping the store saves the keydir too, but on a thread of its own
+    /// and ignoring failures, so prefer this where the keydir must be saved.
+    /// Clones of the store stay usable.
+    pub async fn close(self) -> Result<()> {
+        self.flush().await
+    }
+
+    /// Returns the keys starting with `prefix` in ascending order, see `keys`.
+    pub async fn keys_with_prefix(
+        &self,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Stream<Item = Vec<u8>>> {
+        self.keys(prefix_range(prefix.as_ref())).await
+    }
+
+    /// Returns the value of `key` along with its metadata.
+    pub async fn get_with_metadata<K>(&self, key: K) -> Result<Option<(Vec<u8>, Metadata)>>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.reader.get_entry(key.as_ref()).await
+    }
+
+    /// Returns the `n`th most recent value of `key`, where 0 is its current
+    /// value. Only as many previous values as `Options::versions` are kept.
+    pub async fn get_version<K>(&self, key: K, n: usize) -> Result<Option<Vec<u8>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        Ok(self.history(key).await?.into_iter().nth(n))
+    }
+
+    /// Returns the kept values of `key`, newest first, starting with its
+    /// current value. Removing a key drops its history.
+    pub async fn history<K>(&self, key: K) -> Result<Vec<Vec<u8>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        match self.reader.get_record(key.as_ref()).await? {
+            Some(record) => record.into_versions(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Sets `key` to `value`, clearing any flags it had.
+    ///
+    /// Sets are queued for a writer thread of the store's own, which commits
+    /// every set queued meanwhile as a group and then wakes their callers.
+    /// Once 1024 sets are queued, further calls wait for room.
+    pub async fn set<K, V>(&self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.set_with_flags(key, value, 0).await
+    }
+
+    /// Sets `key` to `value` and its flags to `flags`, see `set`.
+    pub async fn set_with_flags<K, V>(&self, key: K, value: V, flags: u8) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        self.options
+            .load()
+            .check_size(key.as_ref(), value.as_ref())?;
+        self.reader.loaded().await?;
+        let (done, committed) = oneshot::channel();
+        let set = PendingSet {
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+            flags,
+            done,
+        };
+        let stopped = || KvsError::Commit("the writer thread stopped".to_owned());
+        self.sets.clone().send(set).await.map_err(|_| stopped())?;
+        committed.await.map_err(|_| stopped())??;
+        self.audit("set", key.as_ref());
+        Ok(())
+    }
+
+    /// Sets each key to its value, clearing any flags it had, with a single
+    /// write under a single writer lock. Keys set more than once end with
+    /// their last value.
+    pub async fn set_many<I, K, V>(&self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let options = self.options.load();
+        let sets = pairs
+            .into_iter()
+            .map(|(key, value)| {
+                options.check_size(key.as_ref(), value.as_ref())?;
+                Ok((key.as_ref().to_vec(), value.as_ref().to_vec(), 0))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if sets.is_empty() {
+            return Ok(());
+        }
+        let mut writer = self.lock_writer().await?;
+        self.reader
+            .commit(&sets, &mut writer, &self.watchers)
+            .await
+            .map_err(KvsError::Commit)?;
+        for (key, _, _) in &sets {
+            self.audit("set", key);
+        }
+        Ok(())
+    }
+
+    /// Sets `key` to `value` encoded with bincode, see `set`.
+    pub async fn set_typed<K, T>(&self, key: K, value: &T) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        T: Serialize + ?Sized,
+    {
+        self.set(key, bincode::serialize(value)?).await
+    }
+
+    /// Gets the value of `key` decoded with bincode, failing with `Serde` if
+    /// it isn't an encoded `T`.
+    pub async fn get_typed<K, T>(&self, key: K) -> Result<Option<T>>
+    where
+        K: AsRef<[u8]>,
+        T: DeserializeOwned,
+    {
+        match self.get(key).await? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn remove<K>(&self, key: K) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut writer = self.lock_writer().await?;
+        let due = writer.remove(key.as_ref()).await?;
+        self.audit("remove", key.as_ref());
+        self.watchers.publish(key.as_ref(), None);
+        if due {
+            self.reader.compact_written(&mut writer).await;
+        }
+        Ok(())
+    }
+
+    /// Sets `key` to `value`, or removes it if `value` is `None`, but only if
+    /// its current value is `expected`. Returns whether the write was made.
+    pub async fn compare_and_set(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        value: Option<&[u8]>,
+    ) -> Result<bool> {
+        let key = key.as_ref();
+        if let Some(value) = value {
+            self.options.load().check_size(key, value)?;
+        }
+        let mut writer = self.lock_writer().await?;
+        if self.reader.get(key).await?.as_deref() != expected {
+            return Ok(false);
+        }
+        let due = match (expected, value) {
+            (_, Some(value)) => writer.set(key, value, 0).await?,
+            (Some(_), None) => writer.remove(key).await?,
+            // The key is already absent.
+            (None, None) => return Ok(true),
+        };
+        self.audit("compare_and_set", key);
+        self.watchers.publish(key, value);
+        if due {
+            self.reader.compact_written(&mut writer).await;
+        }
+        Ok(true)
+    }
+
+    /// Moves the value of `from`, and its flags, to `to`, replacing any value
+    /// `to` had. Fails with `KeyNotFound` if `from` isn't set.
+    ///
+    /// The new key and the tombstone of the old one are logged with a single
+    /// write, the new key first, so a crash while writing can't lose the
+    /// value.
+    pub async fn rename(&self, from: impl AsRef<[u8]>, to: impl AsRef<[u8]>) -> Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let mut writer = self.lock_writer().await?;
+        let (value, metadata) = self
+            .reader
+            .get_entry(from)
+            .await?
+            .ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        self.options.load().check_size(to, &value)?;
+        let due = writer.rename(from, to, &value, metadata.flags).await?;
+        self.audit("rename", from);
+        self.watchers.publish(to, Some(&value));
+        self.watchers.publish(from, None);
+        if due {
+            self.reader.compact_written(&mut writer).await;
+        }
+        Ok(())
+    }
+
+    /// Sets `to` to the value of `from`, and its flags, unless `to` is set
+    /// and not `overwrite`. Returns whether it was set. Fails with
+    /// `KeyNotFound` if `from` isn't set.
+    pub async fn copy(
+        &self,
+        from: impl AsRef<[u8]>,
+        to: impl AsRef<[u8]>,
+        overwrite: bool,
+    ) -> Result<bool> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let mut writer = self.lock_writer().await?;
+        let (value, metadata) = self
+            .reader
+            .get_entry(from)
+            .await?
+            .ok_or(KvsError::KeyNotFound)?;
+        if !overwrite && self.reader.pos(to).await?.is_some() {
+            return Ok(false);
+        }
+        self.options.load().check_size(to, &value)?;
+        let due = writer.set(to, &value, metadata.flags).await?;
+        self.audit("copy", to);
+        self.watchers.publish(to, Some(&value));
+        if due {
+            self.reader.compact_written(&mut writer).await;
+        }
+        Ok(true)
+    }
+
+    /// Sets `key` to `value` only if it doesn't exist. Returns whether it was set.
+    pub async fn set_nx(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool> {
+        self.set_if(key.as_ref(), value.as_ref(), false).await
+    }
+
+    /// Sets `key` to `value` only if it exists. Returns whether it was set.
+    pub async fn set_xx(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool> {
+        self.set_if(key.as_ref(), value.as_ref(), true).await
+    }
+
+    /// Sets `key` to `value` if whether it exists is `exists`.
+    async fn set_if(&self, key: &[u8], value: &[u8], exists: bool) -> Result<bool> {
+        self.options.load().check_size(key, value)?;
+        let mut writer = self.lock_writer().await?;
+        if self.reader.keydir.contains_key(key) != exists {
+            return Ok(false);
+        }
+        let due = writer.set(key, value, 0).await?;
+        self.audit(if exists { "set_xx" } else { "set_nx" }, key);
+        self.watchers.publish(key, Some(value));
+        if due {
+            self.reader.compact_written(&mut writer).await;
+        }
+        Ok(true)
+    }
+
+    /// Sets the flags of `key` to `flags`, keeping its value, but only if the
+    /// key exists and its flags masked with `mask` are `expected`. Returns
+    /// whether the flags were set.
+    pub async fn compare_and_set_flags(
+        &self,
+        key: impl AsRef<[u8]>,
+        mask: u8,
+        expected: u8,
+        flags: u8,
+    ) -> Result<bool> {
+        let key = key.as_ref();
+        let mut writer = self.lock_writer().await?;
+        let value = match self.reader.get_entry(key).await? {
+            Some((value, old)) if old.flags & mask == expected => value,
+            _ => return Ok(false),
+        };
+        self.options.load().check_size(key, &value)?;
+        let due = writer.set(key, &value, flags).await?;
+        self.audit("compare_and_set_flags", key);
+        self.watchers.publish(key, Some(&value));
+        if due {
+            self.reader.compact_written(&mut writer).await;
+        }
+        Ok(true)
+    }
+
+    /// Applies `writes` in order, each setting a key to `Some(value)` or
+    /// removing it with `None`, with a single write under a single writer
+    /// lock. Fails with `KeyNotFound`, writing nothing, if a key removed
+    /// isn't set by then.
+    ///
+    /// Other writes can't come between them, though reads made meanwhile
+    /// may see some of them before the others.
+    pub async fn transact(&self, writes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let options = self.options.load();
+        for (key, value) in writes {
+            if let Some(value) = value {
+                options.check_size(key, value)?;
+            }
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let mut writer = self.lock_writer().await?;
+        // Whether each key written is set after the writes before.
+        let mut set: HashMap<&[u8], bool> = HashMap::new();
+        for (key, value) in writes {
+            let exists = match set.get(&key[..]) {
+                Some(&exists) => exists,
+                None => writer.keydir.contains_key(key),
+            };
+            if value.is_none() && !exists {
+                return Err(KvsError::KeyNotFound);
+            }
+            set.insert(key, value.is_some());
+        }
+        let due = writer.transact(writes).await?;
+        for (key, value) in writes {
+            self.audit(if value.is_some() { "set" } else { "remove" }, key);
+            self.watchers.publish(key, value.as_deref());
+        }
+        if due {
+            self.reader.compact_written(&mut writer).await;
+        }
+        Ok(())
+    }
+
+    /// Removes the given keys under a single writer lock, returning how many existed.
+    pub async fn remove_many<I, K>(&self, keys: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let mut writer = self.lock_writer().await?;
+        let mut removed = 0;
+        for key in keys {
+            match writer.remove(key.as_ref()).await {
+                Ok(due) => {
+                    self.audit("remove", key.as_ref());
+                    self.watchers.publish(key.as_ref(), None);
+                    if due {
+                        self.reader.compact_written(&mut writer).await;
+                    }
+                    removed += 1;
+                }
+                Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes every key starting with `prefix` under a single writer lock,
+    /// returning how many were removed.
+    ///
+    /// One range tombstone is logged rather than a tombstone per key.
+    pub async fn delete_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<usize> {
+        let prefix = prefix.as_ref();
+        let mut writer = self.lock_writer().await?;
+        writer.writable()?;
+        let keys: Vec<_> = self
+            .reader
+            .keydir
+            .range(prefix_range(prefix))
+            .map(|(key, _)| key)
+            .collect();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        // Logged first, so the keys stay in the keydir if logging fails.
+        writer.remove_prefix(prefix).await?;
+        let mut due = false;
+        for key in &keys {
+            due |= writer.discard(key);
+        }
+        self.audit("delete_prefix", prefix);
+        for key in &keys {
+            self.watchers.publish(key, None);
+        }
+        if due {
+            self.reader.compact_written(&mut writer).await;
+        }
+        Ok(keys.len())
+    }
+
+    /// Returns a stream of changes made to keys starting with `prefix`.
+    ///
+    /// Events are delivered in the order the writer applied them. The stream
+    /// is unbounded, so a watcher that falls behind buffers events in memory.
+    pub fn watch(&self, prefix: impl Into<Vec<u8>>) -> impl Stream<Item = WatchEvent> {
+        self.watchers.subscribe(prefix.into())
+    }
+
+    /// Returns the changes with sequence numbers above `seq` in the order
+    /// they were made, followed by further changes as they're made. The
+    /// stream ends once the store is dropped.
+    ///
+    /// Earlier changes are read from the logs, so those compacted away are
+    /// skipped: of a key set several times, only the latest set may be
+    /// returned, and a removal may be missing once compaction drops its
+    /// tombstone. A consumer that falls far behind should resync with a
+    /// `scan` instead. Like `watch`, the stream buffers changes in memory
+    /// while it isn't read.
+    pub async fn changes_since(&self, seq: u64) -> Result<impl Stream<Item = Change> + Unpin> {
+        let mut writer = self.lock_writer().await?;
+        let live = writer.changes.subscribe();
+        let last = writer.seq;
+        let segments: Vec<_> = self
+            .reader
+            .readers
+            .iter()
+            .map(|entry| entry.value().pin())
+            .collect();
+        drop(writer);
+
+        // Records copied by a compaction meanwhile may be read twice.
+        let mut changes = BTreeMap::new();
+        for segment in segments {
+            let (records, _) = read_records(&*self.reader.io, &segment.0).await?;
+            for (_, _, record) in records {
+                let change_seq = record.seq();
+                if change_seq > seq && change_seq <= last {
+                    if let Some(change) = record.into_change() {
+                        changes.insert(change_seq, change);
+                    }
+                }
+            }
+        }
+        Ok(stream::iter(changes.into_values()).chain(live))
+    }
+
+    /// Returns the sequence number of the last write, see `Metadata::seq`.
+    pub(crate) async fn seq(&self) -> Result<u64> {
+        Ok(self.lock_writer().await?.seq)
+    }
+
+    /// Compacts every log with dead bytes now, rather than waiting for them
+    /// to pass the threshold. The active log is compacted too, after writes
+    /// move on to a new one.
+    pub async fn compact(&self) -> Result<CompactionStats> {
+        let mut writer = self.lock_writer().await?;
+        let mut gens: Vec<_> = writer
+            .dead_bytes
+            .iter()
+            .filter(|&(_, &dead)| dead > 0)
+            .map(|(&gen, _)| gen)
+            .collect();
+        gens.sort();
+        self.compact_gens(&gens, &mut writer).await
+    }
+
+    /// Compacts log `gen` now, see `compact`.
+    pub async fn compact_gen(&self, gen: u64) -> Result<CompactionStats> {
+        let mut writer = self.lock_writer().await?;
+        if !self.reader.readers.contains_key(&gen) {
+            return Err(KvsError::NoSuchLog(gen));
+        }
+        self.compact_gens(&[gen], &mut writer).await
+    }
+
+    async fn compact_gens(&self, gens: &[u64], writer: &mut KvsWriter) -> Result<CompactionStats> {
+        writer.writable()?;
+        let before = self.reader.disk_usage()?;
+        if gens.contains(&writer.active_gen) {
+            writer.use_next_gen(0).await?;
+        }
+        for &gen in gens {
+            self.reader.compact(gen, writer).await?;
+        }
+        Ok(CompactionStats {
+            logs_compacted: gens.len() as u64,
+            bytes_reclaimed: before.saturating_sub(self.reader.disk_usage()?),
+        })
+    }
+
+    /// Checks that every keydir entry points at a record setting its key,
+    /// that every log decodes to its end, and that no stray files are in
+    /// the store's directory, reporting every problem found.
+    ///
+    /// Records carry no checksums, so a record is only known to be damaged
+    /// if it doesn't decode. Writes wait until the check is done.
+    pub async fn verify(&self) -> Result<VerifyReport> {
+        let writer = self.lock_writer().await?;
+        self.reader.verify(&writer).await
+    }
+
+    /// Returns a summary of every log file, oldest first, to see how much of
+    /// each is dead and tune the compaction threshold by.
+    pub async fn segment_stats(&self) -> Result<Vec<SegmentStats>> {
+        let writer = self.lock_writer().await?;
+        self.reader.segment_stats(writer).await
+    }
+
+    pub async fn stats(&self) -> Result<Stats> {
+        let writer = self.lock_writer().await?;
+        let mut stats = Stats {
+            keys: 0,
+            live_bytes: 0,
+            dead_bytes: writer.dead_bytes.values().sum(),
+            log_files: self.reader.readers.len() as u64,
+            record_sizes: Vec::new(),
+            max_file_size: writer.options.load().max_file_size,
+            compaction_threshold: writer.options.load().compaction_threshold(),
+            queued_reads: 0,
+            read_queue_wait: Duration::default(),
+        };
+        let (queued_reads, read_queue_wait) = self.reader.io.read_waits();
+        stats.queued_reads = queued_reads;
+        stats.read_queue_wait = read_queue_wait;
+        drop(writer);
+        for (_, pos) in self.reader.keydir.iter() {
+            let len = pos.len;
+            let bucket = (64 - len.leading_zeros()) as usize;
+            if stats.record_sizes.len() <= bucket {
+                stats.record_sizes.resize(bucket + 1, 0);
+            }
+            stats.record_sizes[bucket] += 1;
+            stats.keys += 1;
+            stats.live_bytes += len;
+        }
+        Ok(stats)
+    }
+
+    /// Locks the writer once the keydir is loaded.
+    async fn lock_writer(&self) -> Result<MutexGuard<'_, KvsWriter>> {
+        self.reader.loaded().await?;
+        Ok(self.writer.lock().await)
+    }
+}
+
+/// Writes the queued `set`s until the store is dropped, a group at a time.
+async fn write_sets(
+    mut pending: futures::channel::mpsc::Receiver<PendingSet>,
+    reader: KvsReader,
+    writer: Weak<Mutex<KvsWriter>>,
+    watchers: Arc<Watchers>,
+) {
+    while let Some(first) = pending.next().await {
+        let mut batch = vec![first];
+        while let Some(Some(set)) = pending.next().now_or_never() {
+            batch.push(set);
+        }
+        let (sets, waiters): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .map(|set| ((set.key, set.value, set.flags), set.done))
+            .unzip();
+        let res = match writer.upgrade() {
+            Some(writer) => {
+                let mut writer = writer.lock().await;
+                reader.commit(&sets, &mut writer, &watchers).await
+            }
+            None => return,
+        };
+        // The callers are woken once the writer is released, so one dropping
+        // the store right away drops the writer too.
+        for done in waiters {
+            // The caller may have given up waiting.
+            let _ = done.send(res.clone().map_err(KvsError::Commit));
+        }
+    }
+}
+
+impl KvsReader {
+    /// Writes a group of queued `set`s, then compacts if a log is due.
+    ///
+    /// Fails if the sets couldn't be written. Failing to compact is only
+    /// logged, as the sets are written regardless.
+    async fn commit(
+        &self,
+        sets: &[(Vec<u8>, Vec<u8>, u8)],
+        writer: &mut KvsWriter,
+        watchers: &Watchers,
+    ) -> std::result::Result<(), String> {
+        let due = writer.set_many(sets).await.map_err(|e| e.to_string())?;
+        for (key, value, _) in sets {
+            watchers.publish(key, Some(value));
+        }
+        if due {
+            self.compact_written(writer).await;
+        }
+        Ok(())
+    }
+
+    /// Compacts after a write made a log due. Failing to is only logged, as
+    /// the write is made regardless.
+    async fn compact_written(&self, writer: &mut KvsWriter) {
+        if let Err(e) = self.compact_due(writer).await {
+            warn!("Failed to compact logs: {}", e);
+        }
+    }
+
+    /// Waits until the keydir is fully loaded.
+    async fn loaded(&self) -> Result<()> {
+        self.loading.clone().await.map_err(KvsError::Load)
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_entry(key).await?.map(|(value, _)| value))
+    }
+
+    /// Returns the value of `key` along with its metadata.
+    async fn get_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Metadata)>> {
+        self.get_record(key)
+            .await?
+            .map(Record::into_entry)
+            .transpose()
+    }
+
+    /// Reads the record holding the current value of `key`.
+    async fn get_record(&self, key: &[u8]) -> Result<Option<Record>> {
+        match self.pos(key).await? {
+            Some(pos) => Ok(Some(read_record(&*self.io, &self.readers, pos).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the position of the record holding the current value of `key`.
+    async fn pos(&self, key: &[u8]) -> Result<Option<LogPos>> {
+        // Until the keydir is loaded, keys are looked up in the keydir file,
+        // unless the bucket holding them turns out to be invalid.
+        let hinted = match &*self.hint.read().unwrap() {
+            Some(hint) => hint.get(key).ok(),
+            None => None,
+        };
+        if let Some(pos) = hinted {
+            return Ok(pos);
+        }
+        self.loaded().await?;
+        Ok(self.keydir.get(key))
+    }
+
+    async fn value_len(&self, key: &[u8]) -> Result<Option<u64>> {
+        let pos = match self.pos(key).await? {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        // Every record setting a key starts with its tag, the key and the
+        // length of the value.
+        let header_len = (SET_HEADER_LEN + key.len() as u64).min(pos.len);
+        let file = self.readers.get(&pos.gen).ok_or(KvsError::Corrupted)?;
+        let mut buffer = vec![0u8; header_len as usize];
+        file.value()
+            .read_at(&*self.io, &mut buffer, pos.pos)
+            .await?;
+        let (tag, stored, len): (u32, Vec<u8>, u64) = bincode::deserialize(&buffer)?;
+        if !SET_TAGS.contains(&tag) || stored != key {
+            return Err(KvsError::Corrupted);
+        }
+        Ok(Some(len))
+    }
+
+    async fn multi_get<I, K>(&self, keys: I) -> Result<Vec<Option<Vec<u8>>>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        // Look up every position, and open its file, before submitting any read.
+        let mut reads = Vec::new();
+        for key in keys {
+            reads.push(match self.keydir.get(key.as_ref()) {
+                Some(LogPos { gen, pos, len }) => {
+                    // Compaction may have retired the log since the lookup.
+                    let file = self.readers.get(&gen).ok_or(KvsError::Corrupted)?;
+                    let file = file.value().reader()?;
+                    Some((file, pos, vec![0u8; len as usize]))
+                }
+                None => None,
+            });
+        }
+        let completions: Vec<_> = reads
+            .iter_mut()
+            .flatten()
+            .map(|(file, pos, buffer)| file.read_at(&*self.io, buffer, *pos))
+            .collect();
+        for completion in completions {
+            completion.await?;
+        }
+        reads
+            .into_iter()
+            .map(|read| read.map(|(_, _, buffer)| decode_value(&buffer)).transpose())
+            .collect()
+    }
+
+    /// Returns the total size of the log files.
+    fn disk_usage(&self) -> Result<u64> {
+        let mut usage = 0;
+        for entry in self.readers.iter() {
+            usage += entry.value().len()?;
+        }
+        Ok(usage)
+    }
+
+    /// Compacts every log past the compaction threshold, those with the most
+    /// dead bytes for their size first, merging small logs along with them.
+    ///
+    /// Logs written meanwhile are left for next time, so copied tombstones
+    /// aren't compacted over and over.
+    async fn compact_due(&self, writer: &mut KvsWriter) -> Result<()> {
+        // Nothing is written, so nothing falls due.
+        if writer.read_only {
+            return Ok(());
+        }
+        let below = writer.active_gen;
+        loop {
+            let threshold = writer.options.load().current_compaction_threshold();
+            let gens = writer.pick_compaction(threshold, below)?;
+            if gens.is_empty() {
+                return Ok(());
+            }
+            for gen in gens {
+                self.compact(gen, writer).await?;
+            }
+        }
+    }
+
+    /// Returns the size, live and dead bytes and age of every log, in the
+    /// order they were created.
+    async fn segment_stats(&self, writer: MutexGuard<'_, KvsWriter>) -> Result<Vec<SegmentStats>> {
+        let now = SystemTime::now();
+        let mut segments = Vec::new();
+        for entry in self.readers.iter() {
+            let (gen, segment) = (*entry.key(), entry.value());
+            let created = segment.created()?;
+            segments.push(SegmentStats {
+                gen,
+                size: if gen == writer.active_gen {
+                    writer.writer_pos
                 } else {
-                    Ok(None)
+                    segment.len()?
+                },
+                live_records: 0,
+                live_bytes: 0,
+                dead_bytes: writer.dead_bytes.get(&gen).copied().unwrap_or(0),
+                age: now.duration_since(created).unwrap_or_default(),
+            });
+        }
+        drop(writer);
+        for (_, pos) in self.keydir.iter() {
+            if let Ok(i) = segments.binary_search_by_key(&pos.gen, |segment| segment.gen) {
+                segments[i].live_records += 1;
+                segments[i].live_bytes += pos.len;
+            }
+        }
+        Ok(segments)
+    }
+
+    /// Checks the logs and keydir, see `KvStore::verify`.
+    async fn verify(&self, writer: &KvsWriter) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut lens = HashMap::new();
+        for entry in self.readers.iter() {
+            let (gen, file) = (*entry.key(), entry.value());
+            let (_, valid_len) = read_records(&*writer.io, file).await?;
+            let len = if gen == writer.active_gen {
+                writer.writer_pos
+            } else {
+                file.len()?
+            };
+            if valid_len != len {
+                report.corrupt_logs.push(CorruptLog {
+                    gen,
+                    valid_len,
+                    len,
+                });
+            }
+            lens.insert(gen, len);
+        }
+
+        for (key, pos) in self.keydir.iter() {
+            report.keys_checked += 1;
+            match lens.get(&pos.gen) {
+                Some(&len) if pos.pos + pos.len <= len => {}
+                _ => {
+                    report.dangling.push(key);
+                    continue;
+                }
+            }
+            match read_record(&*writer.io, &self.readers, pos).await {
+                Ok(record) if record.key() == Some(&key) => {}
+                Ok(_) => report.mismatched.push(key),
+                Err(KvsError::Io(e)) => return Err(e.into()),
+                Err(_) => report.undecodable.push(key),
+            }
+        }
+
+        for file in fs::read_dir(&*self.dir)? {
+            let path = file?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let orphan = match name.strip_suffix(".log") {
+                Some(gen) => matches!(gen.parse(), Ok(gen) if !lens.contains_key(&gen)),
+                None => name.starts_with("keydir.") && name.ends_with(".tmp"),
+            };
+            if orphan {
+                report.orphans.push(path);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Copies the live records of log `gen` to the active log, then retires it.
+    async fn compact(&self, gen: u64, writer: &mut KvsWriter) -> Result<()> {
+        let options = writer.options.load_full();
+        options.listeners.emit(|l| l.on_compaction_start(gen));
+        let file = self.readers.get(&gen).unwrap();
+        let (records, _) = read_records(&*writer.io, file.value()).await?;
+        drop(file);
+        // Tombstones are only needed while older logs may hold what they remove.
+        let keep_tombstones = writer.readers.range(..gen).next().is_some();
+
+        for (pos, _, record) in records {
+            match record {
+                Record::Set { ref key, .. }
+                | Record::SetWithFlags { ref key, .. }
+                | Record::Versioned { ref key, .. }
+                | Record::Stamped { ref key, .. } => {
+                    let live = matches!(self.keydir.get(key), Some(old) if old.gen == gen && old.pos == pos);
+                    if live {
+                        writer.relocate(key, &record).await?;
+                    }
+                }
+                Record::Remove { ref key } | Record::StampedRemove { ref key, .. } => {
+                    // A live key was set again after the removal.
+                    if keep_tombstones && !self.keydir.contains_key(key) {
+                        writer.append_tombstone(&record).await?;
+                    }
+                }
+                Record::RemovePrefix { ref prefix }
+                | Record::StampedRemovePrefix { ref prefix, .. }
+                    if keep_tombstones =>
+                {
+                    // Keys set again after the removal must be moved after the
+                    // copied tombstone, or replaying would remove them.
+                    writer.append_tombstone(&record).await?;
+                    let keys: Vec<_> = self
+                        .keydir
+                        .range(prefix_range(prefix))
+                        .map(|(key, _)| key)
+                        .collect();
+                    for key in keys {
+                        let record = self.get_record(&key).await?.unwrap();
+                        writer.relocate(&key, &record).await?;
+                    }
                 }
+                Record::RemovePrefix { .. } | Record::StampedRemovePrefix { .. } => {}
             }
-            None => Err(KvsError::KeyNotFound),
         }
+        writer.dead_bytes.remove(&gen);
+        if let Some(segment) = writer.readers.remove(&gen) {
+            segment.value().retire();
+        }
+        options.listeners.emit(|l| l.on_compaction_finish(gen));
+        Ok(())
+    }
+}
+
+impl KvsWriter {
+    async fn set(&mut self, key: &[u8], value: &[u8], flags: u8) -> Result<bool> {
+        self.writable()?;
+        let history = self.history(key).await?;
+        self.seq += 1;
+        let record = Record::set(key, value, flags, &history, self.seq);
+        let due = self.relocate(key, &record).await?;
+        let seq = self.seq;
+        self.changes.publish(|| Change::Set {
+            seq,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        Ok(due)
     }
 
-    async fn use_next_gen(&mut self) -> Result<()> {
-        self.active_gen += 1;
-        let path = get_log_path(&self.dir, self.active_gen);
-        self.writer = OpenOptions::new()
+    /// Appends `record` as is and points the keydir entry of `key` at it.
+    async fn relocate(&mut self, key: &[u8], record: &Record) -> Result<bool> {
+        let res = self.discard(key);
+        let record = bincode::serialize(record)?;
+        let pos = self.append(&record).await?;
+        self.sample.insert(key);
+        self.keydir.insert(
+            key.to_vec(),
+            LogPos {
+                gen: self.active_gen,
+                pos,
+                len: record.len() as u64,
+            },
+        )?;
+        Ok(res)
+    }
+
+    /// Appends a `Record::Set` for each key, value and flags with a single write.
+    ///
+    /// Returns whether a log is due for compaction.
+    async fn set_many(&mut self, sets: &[(Vec<u8>, Vec<u8>, u8)]) -> Result<bool> {
+        self.writable()?;
+        let mut buffer = Vec::new();
+        let mut lens = Vec::with_capacity(sets.len());
+        // History of keys set earlier in the batch, which isn't in the keydir yet.
+        let mut batch_history: HashMap<&[u8], Vec<Vec<u8>>> = HashMap::new();
+        let options = self.options.load();
+        for (key, value, flags) in sets {
+            let history = match batch_history.remove(&key[..]) {
+                Some(history) => history,
+                None => self.history(key).await?,
+            };
+            let start = buffer.len();
+            self.seq += 1;
+            let record = Record::set(key, value, *flags, &history, self.seq);
+            bincode::serialize_into(&mut buffer, &record)?;
+            lens.push((buffer.len() - start) as u64);
+            if options.versions > 0 {
+                let history = std::iter::once(value.clone()).chain(history);
+                batch_history.insert(key, history.take(options.versions).collect());
+            }
+        }
+        let mut pos = self.append(&buffer).await?;
+        if options.sync_writes {
+            self.writer.fdatasync(&*self.io).await?;
+        }
+
+        let mut due = false;
+        for ((key, _, _), len) in sets.iter().zip(lens) {
+            due |= self.discard(key);
+            let gen = self.active_gen;
+            self.sample.insert(key);
+            self.keydir.insert(key.clone(), LogPos { gen, pos, len })?;
+            pos += len;
+        }
+        let first = self.seq - sets.len() as u64 + 1;
+        for ((key, value, _), seq) in sets.iter().zip(first..) {
+            self.changes.publish(|| Change::Set {
+                seq,
+                key: key.clone(),
+                value: value.clone(),
+            });
+        }
+        Ok(due)
+    }
+
+    async fn remove(&mut self, key: &[u8]) -> Result<bool> {
+        self.writable()?;
+        if !self.keydir.contains_key(key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        let res = self.discard(key);
+        self.seq += 1;
+        let seq = self.seq;
+        self.append_tombstone(&Record::StampedRemove {
+            key: key.to_vec(),
+            seq,
+        })
+        .await?;
+        self.changes.publish(|| Change::Remove {
+            seq,
+            key: key.to_vec(),
+        });
+        Ok(res)
+    }
+
+    /// Appends a `Record::Set` for each key set and a tombstone for each key
+    /// removed with a single write. Keys removed must be set by then.
+    ///
+    /// Returns whether a log is due for compaction.
+    async fn transact(&mut self, writes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<bool> {
+        self.writable()?;
+        let mut buffer = Vec::new();
+        let mut lens = Vec::with_capacity(writes.len());
+        // History of keys written earlier in the transaction, which isn't in
+        // the keydir yet.
+        let mut batch_history: HashMap<&[u8], Vec<Vec<u8>>> = HashMap::new();
+        let options = self.options.load();
+        let first = self.seq + 1;
+        for ((key, value), seq) in writes.iter().zip(first..) {
+            let start = buffer.len();
+            match value {
+                Some(value) => {
+                    let history = match batch_history.remove(&key[..]) {
+                        Some(history) => history,
+                        None => self.history(key).await?,
+                    };
+                    let record = Record::set(key, value, 0, &history, seq);
+                    bincode::serialize_into(&mut buffer, &record)?;
+                    let history = std::iter::once(value.clone()).chain(history);
+                    batch_history.insert(key, history.take(options.versions).collect());
+                }
+                None => {
+                    let record = Record::StampedRemove {
+                        key: key.clone(),
+                        seq,
+                    };
+                    bincode::serialize_into(&mut buffer, &record)?;
+                    // Removing a key drops its history.
+                    batch_history.insert(key, Vec::new());
+                }
+            }
+            lens.push((buffer.len() - start) as u64);
+        }
+        let mut pos = self.append(&buffer).await?;
+        self.seq += writes.len() as u64;
+        if options.sync_writes {
+            self.writer.fdatasync(&*self.io).await?;
+        }
+
+        let mut due = false;
+        let gen = self.active_gen;
+        for ((key, value), len) in writes.iter().zip(lens) {
+            due |= self.discard(key);
+            match value {
+                Some(_) => {
+                    self.sample.insert(key);
+                    self.keydir.insert(key.clone(), LogPos { gen, pos, len })?;
+                }
+                None => *self.dead_bytes.entry(gen).or_insert(0) += len,
+            }
+            pos += len;
+        }
+        for ((key, value), seq) in writes.iter().zip(first..) {
+            self.changes.publish(|| match value {
+                Some(value) => Change::Set {
+                    seq,
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+                None => Change::Remove {
+                    seq,
+                    key: key.clone(),
+                },
+            });
+        }
+        Ok(due)
+    }
+
+    /// Appends a `Record::Set` of `to` followed by a tombstone of `from`
+    /// with a single write.
+    ///
+    /// Returns whether a log is due for compaction.
+    async fn rename(&mut self, from: &[u8], to: &[u8], value: &[u8], flags: u8) -> Result<bool> {
+        self.writable()?;
+        let history = self.history(to).await?;
+        let seq = self.seq + 1;
+        let mut buffer = bincode::serialize(&Record::set(to, value, flags, &history, seq))?;
+        let len = buffer.len() as u64;
+        bincode::serialize_into(
+            &mut buffer,
+            &Record::StampedRemove {
+                key: from.to_vec(),
+                seq: seq + 1,
+            },
+        )?;
+        let pos = self.append(&buffer).await?;
+        self.seq += 2;
+        if self.options.load().sync_writes {
+            self.writer.fdatasync(&*self.io).await?;
+        }
+
+        let mut due = self.discard(to);
+        self.sample.insert(to);
+        let gen = self.active_gen;
+        self.keydir.insert(to.to_vec(), LogPos { gen, pos, len })?;
+        due |= self.discard(from);
+        *self.dead_bytes.entry(gen).or_insert(0) += buffer.len() as u64 - len;
+        self.changes.publish(|| Change::Set {
+            seq,
+            key: to.to_vec(),
+            value: value.to_vec(),
+        });
+        self.changes.publish(|| Change::Remove {
+            seq: seq + 1,
+            key: from.to_vec(),
+        });
+        Ok(due)
+    }
+
+    /// Logs the removal of every key starting with `prefix`, before their
+    /// keydir entries are discarded.
+    async fn remove_prefix(&mut self, prefix: &[u8]) -> Result<()> {
+        self.seq += 1;
+        let seq = self.seq;
+        self.append_tombstone(&Record::StampedRemovePrefix {
+            prefix: prefix.to_vec(),
+            seq,
+        })
+        .await?;
+        self.changes.publish(|| Change::RemovePrefix {
+            seq,
+            prefix: prefix.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Rebuilds the keydir and dead bytes from the logs, and cuts off a
+    /// torn record at the end of the active log.
+    async fn replay(&mut self) -> Result<()> {
+        let (dead_bytes, writer_pos, seq) = replay(&self.io, &self.readers, &self.keydir).await?;
+        self.resample();
+        if !self.read_only {
+            self.writer.truncate(writer_pos)?;
+        }
+        self.dead_bytes = dead_bytes;
+        self.seq = seq;
+        self.writer_pos = writer_pos;
+        Ok(())
+    }
+
+    /// Returns the values to keep in the next record of `key`: its current
+    /// value followed by its history, up to `Options::versions` of them.
+    async fn history(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let versions = self.options.load().versions;
+        if versions == 0 {
+            return Ok(Vec::new());
+        }
+        let pos = match self.keydir.get(key) {
+            Some(pos) => pos,
+            None => return Ok(Vec::new()),
+        };
+        let mut history = read_record(&*self.io, &self.readers, pos)
+            .await?
+            .into_versions()?;
+        history.truncate(versions);
+        Ok(history)
+    }
+
+    /// Picks the logs before `below` to compact next, if any is past
+    /// `threshold`: the one with the most dead bytes for its size, along with
+    /// the small logs whose live records fit in a log file with its own, so
+    /// they're merged into one.
+    fn pick_compaction(&self, threshold: u64, below: u64) -> Result<Vec<u64>> {
+        let max_file_size = self.options.load().max_file_size;
+        let mut logs = Vec::new();
+        for entry in self.readers.range(..below) {
+            let gen = *entry.key();
+            let size = entry.value().len()?;
+            let dead = self.dead_bytes.get(&gen).copied().unwrap_or(0);
+            logs.push((gen, size.saturating_sub(dead), size, dead));
+        }
+        let ratio = |&(_, _, size, dead): &(u64, u64, u64, u64)| dead as f64 / size.max(1) as f64;
+        let (gen, mut live, _, _) = match logs
+            .iter()
+            .filter(|&&(_, _, _, dead)| dead >= threshold)
+            .max_by(|a, b| ratio(a).total_cmp(&ratio(b)))
+        {
+            Some(&log) => log,
+            None => return Ok(Vec::new()),
+        };
+        let mut gens = vec![gen];
+        let mut small: Vec<_> = logs
+            .into_iter()
+            .filter(|&(small, _, size, _)| {
+                small != gen && size < max_file_size / SMALL_LOG_FRACTION
+            })
+            .collect();
+        small.sort_by_key(|&(_, live, _, _)| live);
+        for (gen, small_live, _, _) in small {
+            if live + small_live > max_file_size {
+                break;
+            }
+            live += small_live;
+            gens.push(gen);
+        }
+        Ok(gens)
+    }
+
+    /// Drops the keydir entry of `key`, counting its record as dead.
+    ///
+    /// Returns whether its log is now due for compaction.
+    fn discard(&mut self, key: &[u8]) -> bool {
+        let old = match self.keydir.remove(key) {
+            Some(old) => old,
+            None => return false,
+        };
+        self.sample.remove(key);
+        let dead = self.dead_bytes.entry(old.gen).or_insert(0);
+        *dead += old.len;
+        *dead >= self.options.load().current_compaction_threshold() && old.gen != self.active_gen
+    }
+
+    /// Appends a record removing data, which is dead as soon as it's written.
+    async fn append_tombstone(&mut self, record: &Record) -> Result<()> {
+        let record = bincode::serialize(record)?;
+        self.append(&record).await?;
+        *self.dead_bytes.entry(self.active_gen).or_insert(0) += record.len() as u64;
+        Ok(())
+    }
+
+    /// Fails unless the store may be written.
+    fn writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Writes an encoded record at the end of the active log, returning its position.
+    async fn append(&mut self, record: &[u8]) -> Result<u64> {
+        self.writable()?;
+        let len = record.len() as u64;
+        if self.writer_pos >= self.options.load().max_file_size
+            || !self.writer.fits(self.writer_pos + len)
+        {
+            self.use_next_gen(len).await?;
+        }
+        let pos = self.writer_pos;
+        self.writer.append(&*self.io, record, pos).await?;
+        self.writer_pos += record.len() as u64;
+        Ok(pos)
+    }
+
+    /// Samples the keys of a freshly loaded keydir.
+    fn resample(&self) {
+        self.sample.rebuild(self.keydir.iter().map(|(key, _)| key));
+    }
+
+    /// Syncs the active log file and saves the keydir.
+    async fn flush(&mut self) -> Result<()> {
+        if self.ephemeral || self.read_only {
+            return Ok(());
+        }
+        self.sync().await?;
+        self.save_keydir()
+    }
+
+    /// Syncs the active log file, then saves how far it's synced in the
+    /// watermark file, so the writes that may be lost in a crash are known.
+    async fn sync(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let mark = Watermark {
+            gen: self.active_gen,
+            len: self.writer_pos,
+            seq: self.seq,
+        };
+        self.writer.fsync(&*self.io).await?;
+        if self.durable == Some(mark) {
+            return Ok(());
+        }
+        // Small enough to be written at once, so it's overwritten in place.
+        let file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .write(true)
-            .open(&path)
-            .await?;
+            .open(get_watermark_path(&self.dir))?;
+        backend::write_all_at(&file, &bincode::serialize(&mark)?, 0)?;
+        file.sync_data()?;
+        self.durable = Some(mark);
+        Ok(())
+    }
+
+    /// Writes the keydir file, for the next `KvStore::open` to load instead
+    /// of replaying the logs.
+    fn save_keydir(&mut self) -> Result<()> {
+        let checkpoint = self.checkpoint();
+        write_keydir(
+            &self.dir,
+            &checkpoint,
+            &self.keydir,
+            &self.dead_bytes,
+            self.seq,
+        )?;
+        self.saved = Some(checkpoint);
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            gens: self.readers.iter().map(|entry| *entry.key()).collect(),
+            len: self.writer_pos,
+        }
+    }
+
+    /// Makes `dir` a copy of the store as of the last sealed log: links or
+    /// copies the logs before the active one into it, along with a keydir
+    /// file and manifest.
+    ///
+    /// The last of them is always copied, as opening the copy writable
+    /// appends to it, while the others are only ever removed.
+    async fn save_checkpoint(&self, dir: &Path) -> Result<()> {
+        let mut gens = Vec::new();
+        let mut len = 0;
+        let last = self.active_gen - 1;
+        for entry in self.readers.range(..self.active_gen) {
+            let gen = *entry.key();
+            let path = get_log_path(dir, gen);
+            len = entry.value().export(&*self.io, &path, gen < last).await?;
+            gens.push(gen);
+        }
+        let checkpoint = Checkpoint { gens, len };
+        write_keydir(dir, &checkpoint, &self.keydir, &self.dead_bytes, self.seq)?;
+        manifest::check(dir, false, false)
+    }
+
+    /// Starts a new active log, with room for at least `len` bytes.
+    async fn use_next_gen(&mut self, len: u64) -> Result<()> {
+        let sealed = self.active_gen;
+        let options = self.options.load();
+        let capacity = options.max_file_size.max(len);
+        let segment = new_segment(
+            &self.dir,
+            &self.files,
+            self.single.as_ref(),
+            sealed + 1,
+            capacity,
+        )?;
+        self.active_gen += 1;
+        self.writer = Arc::new(segment);
         self.writer_pos = 0;
         self.readers
-            .insert(self.active_gen, File::open(&path).await?);
+            .insert(self.active_gen, Arc::clone(&self.writer));
+        options
+            .listeners
+            .emit(|l| l.on_rotation(sealed, self.active_gen));
         Ok(())
     }
 }
 
 impl Drop for KvsWriter {
+    /// Saves the keydir file unless it's saved already. It's written on a
+    /// thread of its own so dropping never blocks whoever drops the store.
+    ///
+    /// The directory of an in-memory store is removed instead.
     fn drop(&mut self) {
-        let _ = task::block_on(async {
-            let file = File::create(get_keydir_path(&self.dir)).await?;
-            let data = bincode::serialize(&(&*self.keydir, &self.dead_bytes))?;
-            self.rio.write_at(&file, &data, 0).await?;
-            Result::<()>::Ok(())
+        let options = self.options.load();
+        options.listeners.emit(|l| l.on_close(&self.dir));
+        if self.ephemeral {
+            if let Err(e) = fs::remove_dir_all(&*self.dir) {
+                warn!("Failed to remove in-memory store {:?}: {}", self.dir, e);
+            }
+            return;
+        }
+        let checkpoint = self.checkpoint();
+        if self.read_only || self.saved.as_ref() == Some(&checkpoint) {
+            return;
+        }
+        let dir = Arc::clone(&self.dir);
+        let keydir = Arc::clone(&self.keydir);
+        let dead_bytes = mem::take(&mut self.dead_bytes);
+        let seq = self.seq;
+        thread::spawn(move || {
+            if let Err(e) = write_keydir(&dir, &checkpoint, &keydir, &dead_bytes, seq) {
+                warn!("Failed to save keydir: {}", e);
+            }
         });
     }
 }
 
-fn get_log_path(dir: &PathBuf, gen: u64) -> PathBuf {
+/// Writes a keydir file, replacing the previous one at once so it's never
+/// read partly written.
+fn write_keydir(
+    dir: &Path,
+    checkpoint: &Checkpoint,
+    keydir: &Keydir,
+    dead_bytes: &HashMap<u64, u64>,
+    seq: u64,
+) -> Result<()> {
+    let data = hint::encode(&(checkpoint, dead_bytes, seq), keydir.iter())?;
+    let temp = dir.join(format!("keydir.{:016x}.tmp", rand::random::<u64>()));
+    let res = fs::write(&temp, data).and_then(|()| fs::rename(&temp, get_keydir_path(dir)));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    Ok(res?)
+}
+
+/// The log files, and the length of the active one, a keydir file was
+/// saved with. It's only valid while they're unchanged.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Checkpoint {
+    gens: Vec<u64>,
+    len: u64,
+}
+
+fn get_log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
-fn get_keydir_path(dir: &PathBuf) -> PathBuf {
+/// Returns where checkpoint `name` of the store in `dir` is, failing if the
+/// name isn't a plain file name.
+pub(crate) fn get_checkpoint_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let plain = !name.is_empty()
+        && !name.ends_with(".tmp")
+        && Path::new(name).file_name() == Some(name.as_ref());
+    if !plain {
+        return Err(KvsError::Config(format!(
+            "invalid checkpoint name `{}`",
+            name
+        )));
+    }
+    Ok(dir.join("checkpoints").join(name))
+}
+
+/// Returns a path for a temporary file or directory next to `path`, to be
+/// renamed to it once complete.
+fn temp_sibling(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("kvs");
+    path.with_file_name(format!("{}.{:016x}.tmp", name, rand::random::<u64>()))
+}
+
+/// Returns a name for the checkpoint of a backup taken now.
+fn backup_checkpoint_name() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("backup-{}", millis)
+}
+
+/// Returns the generations of the log files in `dir`.
+fn log_gens(dir: &Path) -> io::Result<BTreeSet<u64>> {
+    let mut gens = BTreeSet::new();
+    for file in fs::read_dir(dir)? {
+        if let Some(gen) = log_gen(&file?.path()) {
+            gens.insert(gen);
+        }
+    }
+    Ok(gens)
+}
+
+/// Returns the generation of the log at `path`, or `None` if it isn't one.
+fn log_gen(path: &Path) -> Option<u64> {
+    if path.extension() != Some("log".as_ref()) {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+fn get_single_file_path(dir: &Path) -> PathBuf {
+    dir.join("kvs.data")
+}
+
+/// Whether `dir` holds any log, in files of their own or a single file.
+pub(crate) fn store_exists(dir: &Path) -> io::Result<bool> {
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    for file in files {
+        let path = file?.path();
+        if log_gen(&path).is_some() || path == get_single_file_path(dir) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Creates log `gen`, as a file of its own or, with room for `capacity`
+/// bytes, in the single file.
+fn new_segment(
+    dir: &Path,
+    files: &Arc<FileCache>,
+    single: Option<&Arc<SingleFile>>,
+    gen: u64,
+    capacity: u64,
+) -> Result<Segment> {
+    Ok(match single {
+        Some(single) => {
+            let slot = single.allocate(gen, capacity)?;
+            Segment::new(Place::Region(Arc::clone(single), slot))
+        }
+        None => Segment::open(files, get_log_path(dir, gen))?,
+    })
+}
+
+/// Returns the range of keys starting with `prefix`.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+fn decode_value(buffer: &[u8]) -> Result<Vec<u8>> {
+    Ok(decode_entry(buffer)?.0)
+}
+
+/// Decodes the value and metadata of an encoded `Record::Set`.
+fn decode_entry(buffer: &[u8]) -> Result<(Vec<u8>, Metadata)> {
+    bincode::deserialize::<Record>(buffer)?.into_entry()
+}
+
+/// Bytes of the tag of a record, and of the lengths of its key and value.
+const SET_HEADER_LEN: u64 = 4 + 8 + 8;
+
+/// Tags of the records setting a key: `Set`, `SetWithFlags`, `Versioned`
+/// and `Stamped`.
+const SET_TAGS: [u32; 4] = [0, 3, 4, 5];
+
+/// Reads the record at `pos`.
+async fn read_record(
+    io: &dyn IoBackend,
+    readers: &SkipMap<u64, Arc<Segment>>,
+    pos: LogPos,
+) -> Result<Record> {
+    // Entries of the keydir file are only checked when used.
+    let file = readers.get(&pos.gen).ok_or(KvsError::Corrupted)?;
+    let mut buffer = vec![0u8; pos.len as usize];
+    file.value().read_at(io, &mut buffer, pos.pos).await?;
+    Ok(bincode::deserialize(&buffer)?)
+}
+
+/// Reads every record of a log file along with its position and length.
+///
+/// Reading stops at the first record that can't be decoded, which is left by
+/// a crash in the middle of a write. The length of the valid part is returned.
+async fn read_records(
+    io: &dyn IoBackend,
+    segment: &Segment,
+) -> Result<(Vec<(u64, u64, Record)>, u64)> {
+    let mut buffer = vec![0u8; segment.len()? as usize];
+    segment.read_at(io, &mut buffer, 0).await?;
+    let mut cursor = Cursor::new(&buffer[..]);
+    let mut records = Vec::new();
+    loop {
+        let pos = cursor.position();
+        match bincode::config()
+            .limit(buffer.len() as u64 - pos)
+            .deserialize_from(&mut cursor)
+        {
+            Ok(record) => records.push((pos, cursor.position() - pos, record)),
+            Err(_) => return Ok((records, pos)),
+        }
+    }
+}
+
+/// Rebuilds the keydir and dead bytes by replaying every log.
+///
+/// Logs are read and reduced on a thread per core, and merged into the
+/// keydir in order as they're done. Also returns the length of the valid
+/// part of the last log, and the highest sequence number written.
+async fn replay(
+    io: &Arc<dyn IoBackend>,
+    readers: &SkipMap<u64, Arc<Segment>>,
+    keydir: &Keydir,
+) -> Result<(HashMap<u64, u64>, u64, u64)> {
+    let logs: Vec<_> = readers
+        .iter()
+        .map(|entry| (*entry.key(), Arc::clone(entry.value())))
+        .collect();
+    let logs = Arc::new(logs);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (done, mut replayed) = futures::channel::mpsc::unbounded();
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    for _ in 0..threads.min(logs.len()) {
+        let (logs, next, done, io) = (
+            Arc::clone(&logs),
+            Arc::clone(&next),
+            done.clone(),
+            Arc::clone(io),
+        );
+        thread::spawn(move || loop {
+            let i = next.fetch_add(1, Ordering::SeqCst);
+            let (gen, file) = match logs.get(i) {
+                Some(log) => log,
+                None => return,
+            };
+            if done
+                .unbounded_send((i, block_on(replay_log(&*io, *gen, file))))
+                .is_err()
+            {
+                return;
+            }
+        });
+    }
+    drop(done);
+
+    let mut dead_bytes = HashMap::new();
+    let mut valid_len = 0;
+    let mut seq = 0;
+    let discard = |dead_bytes: &mut HashMap<u64, u64>, key: &[u8]| {
+        if let Some(old) = keydir.remove(key) {
+            *dead_bytes.entry(old.gen).or_insert(0) += old.len;
+        }
+    };
+    // Logs replayed ahead of the next one to merge.
+    let mut ahead = HashMap::new();
+    for i in 0..logs.len() {
+        let log = loop {
+            if let Some(log) = ahead.remove(&i) {
+                break log;
+            }
+            match replayed.next().await {
+                Some((j, log)) => ahead.insert(j, log),
+                None => panic!("replaying a log panicked"),
+            };
+        };
+        let log: LogReplay = log?;
+        for prefix in &log.removed_prefixes {
+            let keys: Vec<_> = keydir
+                .range(prefix_range(prefix))
+                .map(|(key, _)| key)
+                .collect();
+            for key in keys {
+                discard(&mut dead_bytes, &key);
+            }
+        }
+        for (key, pos) in log.keys {
+            discard(&mut dead_bytes, &key);
+            if let Some(pos) = pos {
+                keydir.insert(key, pos)?;
+            }
+        }
+        if log.dead_bytes > 0 {
+            *dead_bytes.entry(log.gen).or_insert(0) += log.dead_bytes;
+        }
+        valid_len = log.valid_len;
+        seq = seq.max(log.seq);
+    }
+    Ok((dead_bytes, valid_len, seq))
+}
+
+/// What replaying a log on its own leaves, to be merged into the keydir
+/// after the logs before it.
+struct LogReplay {
+    gen: u64,
+    /// The last record of each key in the log, or `None` if it was removed.
+    keys: BTreeMap<Vec<u8>, Option<LogPos>>,
+    /// Prefixes removed in the log, which remove keys of the logs before it.
+    removed_prefixes: Vec<Vec<u8>>,
+    /// Bytes of tombstones, and of records overwritten within the log.
+    dead_bytes: u64,
+    valid_len: u64,
+    seq: u64,
+}
+
+impl LogReplay {
+    /// Makes `pos` the last record of `key`, counting the one it replaces as dead.
+    fn set(&mut self, key: Vec<u8>, pos: Option<LogPos>) {
+        if let Some(Some(old)) = self.keys.insert(key, pos) {
+            self.dead_bytes += old.len;
+        }
+    }
+}
+
+async fn replay_log(io: &dyn IoBackend, gen: u64, segment: &Segment) -> Result<LogReplay> {
+    let (records, valid_len) = read_records(io, segment).await?;
+    let mut log = LogReplay {
+        gen,
+        keys: BTreeMap::new(),
+        removed_prefixes: Vec::new(),
+        dead_bytes: 0,
+        valid_len,
+        seq: 0,
+    };
+    for (pos, len, record) in records {
+        log.seq = log.seq.max(record.seq());
+        match record {
+            Record::Set { key, .. }
+            | Record::SetWithFlags { key, .. }
+            | Record::Versioned { key, .. }
+            | Record::Stamped { key, .. } => log.set(key, Some(LogPos { gen, pos, len })),
+            Record::Remove { key } | Record::StampedRemove { key, .. } => {
+                log.set(key, None);
+                log.dead_bytes += len;
+            }
+            Record::RemovePrefix { prefix } | Record::StampedRemovePrefix { prefix, .. } => {
+                let keys: Vec<_> = log
+                    .keys
+                    .range(prefix_range(&prefix))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in keys {
+                    log.set(key, None);
+                }
+                log.removed_prefixes.push(prefix);
+                log.dead_bytes += len;
+            }
+        }
+    }
+    Ok(log)
+}
+
+/// Fills the keydir from a keydir file, on a thread of its own.
+///
+/// Every bucket of the file is verified before the writer is locked to
+/// insert them, so compaction and syncing only wait for the inserting. If
+/// any is invalid, the file stops being looked up and the logs are replayed
+/// instead.
+async fn load_keydir(
+    hint: Arc<std_sync::RwLock<Option<Hint>>>,
+    dead_bytes: HashMap<u64, u64>,
+    seq: u64,
+    writer: Arc<Mutex<KvsWriter>>,
+) -> std::result::Result<(), String> {
+    let entries = match &*hint.read().unwrap() {
+        Some(hint) => hint.entries(),
+        None => unreachable!("the keydir file is only dropped once loaded"),
+    };
+    let mut writer = writer.lock().await;
+    let res = match entries {
+        Ok(buckets) => (|| {
+            for (key, pos) in buckets.iter().flat_map(|bucket| bucket.iter()) {
+                writer.keydir.insert(key.clone(), *pos)?;
+            }
+            writer.dead_bytes = dead_bytes;
+            writer.seq = seq;
+            writer.resample();
+            Ok(())
+        })(),
+        Err(e) => {
+            warn!("Invalid keydir file, replaying logs: {}", e);
+            writer.replay().await
+        }
+    };
+    // Lookups in the file are done before the writer is unlocked for writes.
+    *hint.write().unwrap() = None;
+    res.map_err(|e| e.to_string())
+}
+
+/// Logs smaller than `max_file_size` divided by this are merged with the
+/// logs compacted along with them.
+const SMALL_LOG_FRACTION: u64 = 4;
+
+/// How often to check whether a maintenance window has started.
+const MAINTENANCE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Runs maintenance during the configured windows until the store is dropped.
+///
+/// Logs are verified once at the start of each window, and compacted once
+/// they pass the threshold throughout it, including those whose compaction
+/// was put off outside the windows.
+fn maintain_periodically(reader: KvsReader, writer: Weak<Mutex<KvsWriter>>) {
+    let mut verified = false;
+    loop {
+        thread::sleep(MAINTENANCE_CHECK_INTERVAL);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = block_on(writer.lock());
+        if !writer.options.load().in_maintenance_window() {
+            verified = false;
+            continue;
+        }
+        if !verified {
+            match block_on(reader.verify(&writer)) {
+                Ok(report) if !report.is_ok() => warn!("Verifying the store found {}", report),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to verify logs: {}", e),
+            }
+            verified = true;
+        }
+        if let Err(e) = block_on(reader.compact_due(&mut writer)) {
+            warn!("Failed to compact logs: {}", e);
+        }
+    }
+}
+
+/// How often to check whether periodic syncing was turned back on.
+const SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Syncs the active log file, and saves the watermark, every
+/// `Options::sync_interval` until the store is dropped.
+fn sync_periodically(writer: Weak<Mutex<KvsWriter>>, options: Arc<ArcSwap<Options>>) {
+    loop {
+        let interval = options.load().sync_interval;
+        thread::sleep(interval.unwrap_or(SYNC_CHECK_INTERVAL));
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        if interval.is_none() {
+            continue;
+        }
+        let mut writer = block_on(writer.lock());
+        if let Err(e) = block_on(writer.sync()) {
+            warn!("Failed to sync log file: {}", e);
+        }
+    }
+}
+
+fn get_keydir_path(dir: &Path) -> PathBuf {
     dir.join("keydir")
 }
+
+fn get_watermark_path(dir: &Path) -> PathBuf {
+    dir.join("watermark")
+}
+
+fn read_watermark(dir: &Path) -> Result<Option<Watermark>> {
+    match fs::read(get_watermark_path(dir)) {
+        Ok(data) => Ok(bincode::deserialize(&data).ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
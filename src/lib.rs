@@ -1,41 +1,672 @@
+mod acl;
+mod archive;
+mod audit;
+mod backend;
+mod backup;
+pub mod blocking;
+mod chaos;
 mod client;
+pub mod codec;
+mod cursor;
+mod digest;
+mod engine;
+mod file_cache;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod hint;
+mod http;
+mod info;
+mod journal;
+mod keydir;
 mod kvs;
+mod listener;
+mod maintenance;
+mod manifest;
+mod memcached;
+mod memory;
+mod migrate;
+mod multiplex;
+mod namespace;
+mod profile;
+mod raft;
+mod redact;
+mod replication;
+mod resp;
+mod rt;
+mod s3;
+mod sample;
 mod server;
+mod session;
+mod shard;
+mod signing;
+mod single_file;
 mod skipmap;
+#[cfg(feature = "sled")]
+mod sled_engine;
+mod transform;
+pub mod units;
+mod watch;
 
-pub use self::kvs::KvStore;
-pub use client::KvsClient;
-pub use server::start_server;
+pub use self::kvs::{
+    BackupStats, CompactionStats, CorruptLog, KvStore, Metadata, Options, SegmentStats, Stats,
+    VerifyReport, Watermark,
+};
+pub use acl::{Acl, Grant, Permission};
+pub use audit::{read_audit_log, AuditEntry, AuditQuery};
+pub use backup::{BackupTarget, DirTarget};
+pub use chaos::Chaos;
+pub use client::{BatchOp, ClientConfig, KvsClient, Subscription};
+pub use codec::CodecStore;
+pub use cursor::SnapshotPage;
+pub use engine::{EngineKind, KvsEngine, RoutingEngine};
+#[cfg(feature = "graphql")]
+pub use graphql::serve_graphql;
+pub use info::{ClientsInfo, CompactionInfo, Info, KeyspaceInfo, PersistenceInfo, ServerInfo};
+pub use journal::{Conflict, OfflineClient};
+pub use listener::{Listeners, StoreListener};
+pub use maintenance::MaintenanceWindow;
+pub use memory::MemoryEngine;
+pub use migrate::migrate_from_sled;
+pub use multiplex::MultiplexedClient;
+pub use raft::Cluster;
+pub use redact::Redaction;
+pub use rt::block_on;
+pub use s3::S3Target;
+pub use server::{KvsServer, Protocol, ServerConfig};
+pub use session::replay_session;
+pub use shard::{Sharding, Topology};
+pub use signing::SigningKey;
+use signing::{SignedFrame, Signer};
 use skipmap::SkipMap;
+#[cfg(feature = "sled")]
+pub use sled_engine::SledEngine;
+pub use transform::Transform;
+pub use watch::{Change, WatchEvent};
 
-use async_std::net::TcpStream;
-use async_std::prelude::*;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use rt::TcpStream;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Remove {
+        key: String,
+    },
+    CountPrefix {
+        prefix: String,
+    },
+    RemovePrefix {
+        prefix: String,
+        limit: u64,
+    },
+    Stats,
+    /// Sets `key` to `value`, or removes it if `value` is `None`, if its
+    /// current value is `expected`. A missing `expected` matches anything.
+    ///
+    /// A request retried with the same `id` is only applied once.
+    CompareAndSet {
+        id: u64,
+        key: String,
+        expected: Option<Option<String>>,
+        value: Option<String>,
+    },
+    /// Samples the server's CPU usage for a while and returns a flamegraph.
+    Profile {
+        seconds: u64,
+    },
+    Digest,
+    /// Gets the value of `key` with `transform` applied to it.
+    GetTransformed {
+        key: String,
+        transform: Transform,
+    },
+    /// Sets a store option while the server runs, see `Options::set`.
+    Configure {
+        name: String,
+        value: String,
+    },
+    /// Compacts log `gen`, or every log with dead bytes.
+    Compact {
+        gen: Option<u64>,
+    },
+    SegmentStats,
+    /// Flushes and holds writes until thawed or `timeout` passes, see
+    /// `KvStore::freeze_writes`.
+    FreezeWrites {
+        timeout: Duration,
+    },
+    Thaw,
+    /// Returns keys starting with `prefix` after `start_after`, and their
+    /// values, up to the server's limits.
+    Scan {
+        prefix: String,
+        start_after: Option<String>,
+        max_keys: u64,
+        max_bytes: u64,
+    },
+    /// Handles the requests one after another, returning the encoded reply
+    /// of each. Requests that act on the connection can't be batched.
+    Batch(Vec<Request>),
+    /// A message from another node of the server's Raft cluster.
+    Raft(raft::Message),
+    /// Returns the address of the cluster's leader, or `None` if it isn't
+    /// known or the server isn't in a cluster.
+    Leader,
+    /// An operation forwarded by another node of the server's sharding, on
+    /// keys this node owns.
+    Forward(shard::Forward),
+    /// Returns the addresses of the nodes keys are sharded across, or `None`
+    /// if the server isn't sharded.
+    Topology,
+    /// Returns a summary of the server, see `Info`.
+    Info,
+    /// Pushes a `Some(chunk)` per chunk of an archive of the store, see
+    /// `KvStore::export_archive`, after the reply, then `None`. Sent by
+    /// replicas to their primary as they first connect.
+    Bootstrap,
+    /// Returns `payload` at once, whatever's in flight, to check the
+    /// connection.
+    Ping {
+        payload: Vec<u8>,
+    },
+    /// A piece of a value too long for one frame, see `VALUE_CHUNK_BYTES`.
+    /// Chunks of `key` are appended until one without `more`, which sets it
+    /// to the whole value; the others are answered with `()`.
+    SetChunk {
+        key: String,
+        data: Vec<u8>,
+        more: bool,
+    },
+    /// Gets the value of `key`, answered with its first `Chunk` if it's set.
+    /// The others follow in frames of their own, until one without `more`.
+    GetChunked {
+        key: String,
+    },
+    /// Switches the connection to multiplexed frames after the reply: each
+    /// later request is sent with an ID as `(id, request)`, and answered as
+    /// it's handled with `(id, reply)`, in any order. Only requests that can
+    /// be batched can be multiplexed.
+    Multiplex,
+    /// Authenticates the connection's later requests with `token`, see
+    /// `ServerConfig::acl`.
+    Auth {
+        token: String,
+    },
+    /// Returns whether `key` is set, without reading its value.
+    Exists {
+        key: String,
+    },
+    /// Returns the length of the value of `key`, if it's set, without
+    /// reading the value.
+    Strlen {
+        key: String,
+    },
+    /// Returns the values of `keys`, in their order, read together.
+    MultiGet {
+        keys: Vec<String>,
+    },
+    /// Sets each key to its value with a single write, answered once all
+    /// of them are set.
+    MultiSet {
+        pairs: Vec<(String, String)>,
+    },
+    /// Removes `keys`, returning how many of them were set. Keys that
+    /// aren't set are skipped rather than failing the request.
+    RemoveMany {
+        keys: Vec<String>,
+    },
+    /// Moves the value of `from` to `to`, replacing any value `to` had, in
+    /// one write if the engine takes it. Fails with `KeyNotFound` if `from`
+    /// isn't set.
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Sets `to` to the value of `from` on the server, unless `to` is set
+    /// and not `overwrite`. Returns whether it was set. Fails with
+    /// `KeyNotFound` if `from` isn't set.
+    Copy {
+        from: String,
+        to: String,
+        overwrite: bool,
+    },
+    /// Starts a transaction: the connection's later `Set` and `Remove`
+    /// requests are queued, answered with `()`, until `Exec` or `Discard`.
+    /// Other requests fail meanwhile.
+    Begin,
+    /// Applies the writes queued since `Begin` all at once or not at all,
+    /// see `KvsEngine::transact`, and ends the transaction.
+    Exec,
+    /// Ends the transaction, dropping the writes queued since `Begin`.
+    Discard,
+    /// Sends the connection's later requests to namespace `namespace`, or
+    /// the default one if `None`.
+    Select {
+        namespace: Option<String>,
+    },
+    /// Pushes a `Some(WatchEvent)` for every change to keys starting with
+    /// `prefix` after the reply, until `Unsubscribe`. Requests sent while
+    /// subscribed are answered once it ends.
+    Subscribe {
+        prefix: String,
+    },
+    /// Ends the subscription or replication, answered with `None` after the
+    /// last event or change pushed.
+    Unsubscribe,
+    /// Returns up to `limit` keys starting with `prefix`, and their values
+    /// if `values`, from a snapshot of the store taken by the first page.
+    /// `cursor` continues the scan of an earlier page on the connection,
+    /// whose `prefix` is kept.
+    ScanSnapshot {
+        cursor: Option<u64>,
+        prefix: String,
+        limit: u64,
+        values: bool,
+    },
+    /// Pushes a `Some(Change)` for every change with a sequence number
+    /// above `since` after the reply, see `KvStore::changes_since`, until
+    /// `Unsubscribe`. Sent by replicas to their primary.
+    Replicate {
+        since: u64,
+    },
+}
+
+/// Values longer than this are sent in chunks of this many bytes, so no
+/// frame holds the whole value. Either side still holds the value itself
+/// whole, see `ServerConfig::max_chunked_value_size`.
+const VALUE_CHUNK_BYTES: usize = 1 << 20;
+
+/// A piece of a value, see `Request::GetChunked`.
+#[derive(Serialize, Deserialize, Debug)]
+struct Chunk {
+    data: Vec<u8>,
+    more: bool,
+}
+
+impl Request {
+    /// Name of the operation, as used in server options.
+    fn op(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Get { .. } => "get",
+            Request::Remove { .. } => "remove",
+            Request::CountPrefix { .. } => "count_prefix",
+            Request::RemovePrefix { .. } => "remove_prefix",
+            Request::Stats => "stats",
+            Request::Info => "info",
+            Request::CompareAndSet { .. } => "compare_and_set",
+            Request::Profile { .. } => "profile",
+            Request::Digest => "digest",
+            Request::GetTransformed { .. } => "get_transformed",
+            Request::Configure { .. } => "configure",
+            Request::Compact { .. } => "compact",
+            Request::SegmentStats => "segment_stats",
+            Request::FreezeWrites { .. } => "freeze_writes",
+            Request::Thaw => "thaw",
+            Request::Scan { .. } => "scan",
+            Request::Select { .. } => "select",
+            Request::Subscribe { .. } => "subscribe",
+            Request::ScanSnapshot { .. } => "scan_snapshot",
+            Request::Unsubscribe => "unsubscribe",
+            Request::Batch(_) => "batch",
+            Request::Replicate { .. } => "replicate",
+            Request::Bootstrap => "bootstrap",
+            Request::Ping { .. } => "ping",
+            Request::SetChunk { .. } => "set_chunk",
+            Request::GetChunked { .. } => "get_chunked",
+            Request::Multiplex => "multiplex",
+            Request::Auth { .. } => "auth",
+            Request::Exists { .. } => "exists",
+            Request::Strlen { .. } => "strlen",
+            Request::MultiGet { .. } => "multi_get",
+            Request::MultiSet { .. } => "multi_set",
+            Request::RemoveMany { .. } => "remove_many",
+            Request::Rename { .. } => "rename",
+            Request::Copy { .. } => "copy",
+            Request::Begin => "begin",
+            Request::Exec => "exec",
+            Request::Discard => "discard",
+            Request::Raft(_) => "raft",
+            Request::Leader => "leader",
+            Request::Forward(_) => "forward",
+            Request::Topology => "topology",
+        }
+    }
+
+    /// Whether the request can be sent in a `Batch`.
+    fn batchable(&self) -> bool {
+        !matches!(
+            self,
+            Request::Select { .. }
+                | Request::Subscribe { .. }
+                | Request::Unsubscribe
+                | Request::Replicate { .. }
+                | Request::Bootstrap
+                | Request::Raft(_)
+                | Request::Leader
+                | Request::Forward(_)
+                | Request::Topology
+                | Request::Info
+                | Request::Ping { .. }
+                | Request::SetChunk { .. }
+                | Request::GetChunked { .. }
+                | Request::Multiplex
+                | Request::Auth { .. }
+                | Request::Begin
+                | Request::Exec
+                | Request::Discard
+                | Request::Batch(_)
+        )
+    }
+}
+
+/// Operations every server handles, including those from before servers
+/// sent their capabilities.
+const BASELINE_OPS: &[&str] = &["set", "get", "remove"];
+
+/// How long a client waits for the server's hello, as the first servers
+/// send none.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Keys and values returned by `KvsClient::scan`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanPage {
+    pub entries: Vec<(String, String)>,
+    /// The key to continue after, if more keys may follow.
+    pub cursor: Option<String>,
+}
+
+/// What a server supports, sent to clients as they connect.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Version of the server, or empty if it's too old to tell.
+    pub version: String,
+    /// Operations the server handles, like `get` or `digest`.
+    pub ops: Vec<String>,
+    /// Codecs the server can compress values with.
+    pub compression: Vec<String>,
+}
+
+impl Capabilities {
+    /// What this version of the server supports.
+    fn current() -> Self {
+        let mut ops = BASELINE_OPS.to_vec();
+        ops.extend(&[
+            "count_prefix",
+            "remove_prefix",
+            "stats",
+            "compare_and_set",
+            "digest",
+            "get_transformed",
+            "configure",
+            "compact",
+            "segment_stats",
+            "freeze_writes",
+            "thaw",
+            "scan",
+            "select",
+            "subscribe",
+            "scan_snapshot",
+            "batch",
+            "replicate",
+            "bootstrap",
+            "raft",
+            "leader",
+            "forward",
+            "topology",
+            "info",
+            "ping",
+            "set_chunk",
+            "get_chunked",
+            "multiplex",
+            "auth",
+            "exists",
+            "strlen",
+            "multi_get",
+            "multi_set",
+            "remove_many",
+            "rename",
+            "copy",
+            "begin",
+            "exec",
+            "discard",
+        ]);
+        #[cfg(feature = "profiling")]
+        ops.push("profile");
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            ops: ops.into_iter().map(String::from).collect(),
+            compression: Vec::new(),
+        }
+    }
+
+    /// What a server that doesn't send its capabilities supports.
+    fn baseline() -> Self {
+        Capabilities {
+            version: String::new(),
+            ops: BASELINE_OPS.iter().map(|&op| op.to_owned()).collect(),
+            compression: Vec::new(),
+        }
+    }
+
+    pub fn supports(&self, op: &str) -> bool {
+        self.ops.iter().any(|supported| supported == op)
+    }
+}
+
+async fn send<T: Serialize>(stream: &mut (impl AsyncWrite + Unpin), data: &T) -> Result<()> {
+    send_bytes(stream, &bincode::serialize(data).unwrap()).await
 }
 
-async fn send<T: Serialize>(stream: &mut TcpStream, data: &T) -> Result<()> {
-    let data = bincode::serialize(data).unwrap();
+async fn send_bytes(stream: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> Result<()> {
     stream.write_all(&data.len().to_be_bytes()).await?;
-    stream.write_all(&data).await?;
+    stream.write_all(data).await?;
     Ok(())
 }
 
-async fn receive(stream: &mut TcpStream) -> Result<Vec<u8>> {
+/// Receives a frame, failing with `TooLarge` if its length prefix is above
+/// `max_len`. The frame is read as it arrives, rather than allocated upfront
+/// from its length.
+async fn receive(stream: &mut (impl AsyncRead + Unpin), max_len: u64) -> Result<Vec<u8>> {
     let mut len = [0u8; 8];
     stream.read_exact(&mut len).await?;
-    let len = usize::from_be_bytes(len);
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf).await?;
+    let len = u64::from_be_bytes(len);
+    if len > max_len {
+        return Err(KvsError::TooLarge("frame", len, max_len));
+    }
+    let mut buf = Vec::new();
+    (&mut *stream).take(len).read_to_end(&mut buf).await?;
+    if (buf.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
     Ok(buf)
 }
 
+/// A framed connection, optionally signing every frame with a shared key.
+struct Connection {
+    receiver: Receiver,
+    sender: Sender,
+}
+
+/// The receiving half of a `Connection`.
+struct Receiver {
+    stream: ReadHalf<TcpStream>,
+    signer: Option<Signer>,
+    /// Longest frame received, see `ServerConfig::max_frame_size`.
+    max_frame_size: u64,
+}
+
+/// The sending half of a `Connection`.
+struct Sender {
+    stream: WriteHalf<TcpStream>,
+    signer: Option<Signer>,
+}
+
+impl Connection {
+    /// Server side of the handshake: send a fresh session nonce to the
+    /// client, followed by the server's capabilities. Requests longer than
+    /// `max_frame_size` bytes are refused.
+    ///
+    /// With a key, the client replies with a nonce of its own, see
+    /// `Signer`. A client without the key is told `AuthRequired`, unsigned.
+    async fn accept(
+        mut stream: TcpStream,
+        key: Option<&SigningKey>,
+        max_frame_size: u64,
+    ) -> Result<Self> {
+        let session: u64 = rand::random();
+        let hello = bincode::serialize(&(session, Capabilities::current()))?;
+        send_bytes(&mut stream, &hello).await?;
+        let signer = match key {
+            Some(key) => {
+                let reply = receive(&mut stream, max_frame_size).await?;
+                let signer = bincode::deserialize(&reply)
+                    .map_err(KvsError::from)
+                    .and_then(|reply| Signer::server(key.clone(), &hello, reply));
+                if signer.is_err() {
+                    let refusal = Err::<(), _>(ServerError::AuthRequired);
+                    send(&mut stream, &refusal).await?;
+                }
+                Some(signer?)
+            }
+            None => None,
+        };
+        Ok(Connection::new(stream, signer, max_frame_size))
+    }
+
+    /// Client side of the handshake: receive the session nonce and the
+    /// capabilities from the server, replying with the client's nonce if
+    /// frames are signed.
+    ///
+    /// Older servers only send the nonce, which newer ones send first so
+    /// older clients can ignore the rest, and the first ones send nothing
+    /// until `HELLO_TIMEOUT` passes. Both only handle the baseline
+    /// operations, and can't sign frames as this client does.
+    async fn connect(
+        mut stream: TcpStream,
+        key: Option<&SigningKey>,
+    ) -> Result<(Self, Capabilities)> {
+        let hello = match rt::timeout(HELLO_TIMEOUT, receive(&mut stream, u64::MAX)).await {
+            Some(hello) => Some(hello?),
+            None => None,
+        };
+        let capabilities = match &hello {
+            Some(hello) if hello.len() > std::mem::size_of::<u64>() => {
+                bincode::deserialize::<(u64, Capabilities)>(hello)?.1
+            }
+            Some(hello) => {
+                bincode::deserialize::<u64>(hello)?;
+                Capabilities::baseline()
+            }
+            None => Capabilities::baseline(),
+        };
+        let signer = match (key, hello) {
+            (Some(_), _) if capabilities.version.is_empty() => {
+                return Err(KvsError::ServerTooOld("signing"))
+            }
+            (Some(key), Some(hello)) => {
+                let (signer, reply) = Signer::client(key.clone(), &hello);
+                send(&mut stream, &reply).await?;
+                Some(signer)
+            }
+            _ => None,
+        };
+        Ok((Connection::new(stream, signer, u64::MAX), capabilities))
+    }
+
+    /// Each half only uses its own direction of the signer's frame counters,
+    /// so they share a copy of it.
+    fn new(stream: TcpStream, signer: Option<Signer>, max_frame_size: u64) -> Self {
+        let (reader, writer) = stream.split();
+        Connection {
+            receiver: Receiver {
+                stream: reader,
+                signer: signer.clone(),
+                max_frame_size,
+            },
+            sender: Sender {
+                stream: writer,
+                signer,
+            },
+        }
+    }
+
+    /// Splits the connection into one half for receiving and one for sending.
+    fn split(self) -> (Receiver, Sender) {
+        (self.receiver, self.sender)
+    }
+
+    async fn send<T: Serialize>(&mut self, data: &T) -> Result<()> {
+        self.sender.send(data).await
+    }
+
+    async fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
+        self.receiver.receive().await
+    }
+}
+
+impl Sender {
+    async fn send<T: Serialize>(&mut self, data: &T) -> Result<()> {
+        self.send_encoded(bincode::serialize(data)?).await
+    }
+
+    /// Closes this direction of the connection, once what's sent is flushed.
+    async fn close(&mut self) -> Result<()> {
+        self.stream.close().await?;
+        Ok(())
+    }
+
+    /// Sends a payload that's already encoded with bincode, unsigned, for a
+    /// client without the signing key to read.
+    async fn send_unsigned(&mut self, payload: Vec<u8>) -> Result<()> {
+        send_bytes(&mut self.stream, &payload).await
+    }
+
+    /// Sends a payload that's already encoded with bincode.
+    async fn send_encoded(&mut self, payload: Vec<u8>) -> Result<()> {
+        match &mut self.signer {
+            Some(signer) => send(&mut self.stream, &signer.seal(payload)).await,
+            None => send_bytes(&mut self.stream, &payload).await,
+        }
+    }
+}
+
+impl Receiver {
+    /// Reads and drops whatever's sent until the peer closes the connection.
+    async fn drain(&mut self) -> Result<()> {
+        let mut buf = [0; 4096];
+        while self.stream.read(&mut buf).await? > 0 {}
+        Ok(())
+    }
+
+    async fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
+        Ok(bincode::deserialize(&self.receive_encoded().await?)?)
+    }
+
+    /// Receives a payload without decoding it.
+    async fn receive_encoded(&mut self) -> Result<Vec<u8>> {
+        let buf = receive(&mut self.stream, self.max_frame_size).await?;
+        match &mut self.signer {
+            Some(signer) => signer.open(bincode::deserialize::<SignedFrame>(&buf)?),
+            None => Ok(buf),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum KvsError {
     #[error("io error: {0}")]
@@ -47,8 +678,175 @@ pub enum KvsError {
     #[error("key not found")]
     KeyNotFound,
 
+    #[error("corrupted record")]
+    Corrupted,
+
+    #[error("failed to load keydir: {0}")]
+    Load(String),
+
     #[error("server error: {0}")]
-    Server(String),
+    Server(ServerError),
+
+    #[error("injected fault")]
+    InjectedFault,
+
+    #[error("too many requests in flight")]
+    Backpressure,
+
+    #[error("server busy: too many connections")]
+    ServerBusy,
+
+    #[error("unknown cursor {0}: its scan ended, or was dropped for newer ones")]
+    UnknownCursor(u64),
+
+    #[error("{0} requests can't be batched")]
+    Unbatchable(&'static str),
+
+    #[error("{0} requests can't be multiplexed")]
+    NotMultiplexable(&'static str),
+
+    #[error("{0} requests can't be queued in a transaction")]
+    NotTransactional(&'static str),
+
+    #[error("transaction error: {0}")]
+    Transaction(&'static str),
+
+    #[error("invalid or missing auth token")]
+    Unauthenticated,
+
+    #[error("permission denied: the auth token doesn't allow this {0} request")]
+    Forbidden(&'static str),
+
+    #[error("invalid namespace `{0}`: expected up to 64 ASCII letters, digits, `-` or `_`")]
+    InvalidNamespace(String),
+
+    #[error("server unreachable")]
+    Offline,
+
+    #[error("profiling failed: {0}")]
+    Profiling(String),
+
+    #[error("group commit failed: {0}")]
+    Commit(String),
+
+    #[error("signature error: {0}")]
+    Signature(&'static str),
+
+    #[error("transform failed: {0}")]
+    Transform(String),
+
+    #[error("server too old: it doesn't support {0}")]
+    ServerTooOld(&'static str),
+
+    #[error("invalid option: {0}")]
+    Config(String),
+
+    #[error("no log file with generation {0}")]
+    NoSuchLog(u64),
+
+    #[error("writes are frozen already")]
+    Frozen,
+
+    #[error("{0} of {1} bytes is larger than the limit of {2}")]
+    TooLarge(&'static str, u64, u64),
+
+    #[error("no store in {0:?}")]
+    NoStore(PathBuf),
+
+    #[error("a store exists in {0:?} already")]
+    StoreExists(PathBuf),
+
+    #[error("the store's format version {0} is newer than version {1}, the latest supported")]
+    UnsupportedFormat(u32, u32),
+
+    #[error("migration failed: {0}")]
+    Migrate(String),
+
+    #[error("the store is read-only")]
+    ReadOnly,
+
+    #[error("the server is a replica of {0}, which takes the writes")]
+    Replica(SocketAddr),
+
+    #[error("not the cluster's leader{}", leader_hint(.0))]
+    NotLeader(Option<SocketAddr>),
+
+    #[error("no majority of the cluster's nodes is reachable")]
+    NoQuorum,
+
+    #[error("backup failed: {0}")]
+    Backup(String),
+
+    #[error("invalid archive {0:?}: {1}")]
+    InvalidArchive(PathBuf, String),
+
+    #[error("engine error: {0}")]
+    Engine(String),
+
+    #[error("the data directory was written by the {0} engine, not {1}")]
+    WrongEngine(EngineKind, EngineKind),
+
+    #[error("the server's engine doesn't support {0}")]
+    EngineUnsupported(&'static str),
 }
 
 pub type Result<T> = std::result::Result<T, KvsError>;
+
+/// An error a server replies with, returned by `KvsClient` as
+/// `KvsError::Server`.
+#[derive(Error, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ServerError {
+    #[error("key not found")]
+    KeyNotFound,
+
+    /// The server requires signed requests, or an auth token, see
+    /// `ClientConfig::signing_key` and `ClientConfig::token`.
+    #[error("authentication required")]
+    AuthRequired,
+
+    /// Too many connections are open, or requests in flight on this one.
+    #[error("rate limited")]
+    RateLimited,
+
+    #[error("corrupted record")]
+    Corruption,
+
+    /// A request, or a key or value in it, is longer than the server takes.
+    #[error("{what} of {len} bytes is larger than the limit of {max}")]
+    TooLarge { what: String, len: u64, max: u64 },
+
+    #[error("{msg}")]
+    Internal { msg: String },
+
+    /// The connection's auth token doesn't allow the request, see
+    /// `ServerConfig::acl`.
+    #[error("permission denied for {op} request")]
+    Forbidden { op: String },
+}
+
+impl From<&KvsError> for ServerError {
+    fn from(e: &KvsError) -> Self {
+        match e {
+            KvsError::KeyNotFound => ServerError::KeyNotFound,
+            KvsError::Signature(_) | KvsError::Unauthenticated => ServerError::AuthRequired,
+            KvsError::Forbidden(op) => ServerError::Forbidden {
+                op: (*op).to_owned(),
+            },
+            KvsError::Backpressure | KvsError::ServerBusy => ServerError::RateLimited,
+            KvsError::Corrupted => ServerError::Corruption,
+            &KvsError::TooLarge(what, len, max) => ServerError::TooLarge {
+                what: what.to_owned(),
+                len,
+                max,
+            },
+            e => ServerError::Internal { msg: e.to_string() },
+        }
+    }
+}
+
+fn leader_hint(leader: &Option<SocketAddr>) -> String {
+    match leader {
+        Some(leader) => format!(": it's {}", leader),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Receives lifecycle events of a `KvStore`, for metrics, backups or cache
+/// warming. Register it in `Options::listeners`.
+///
+/// Events are delivered on whichever task or thread causes them, some while
+/// the store's writer is locked, so listeners should return quickly and must
+/// not write to the store. Every event is ignored by default.
+pub trait StoreListener: Send + Sync {
+    /// The store in `dir` was opened. Its keydir may still be loading.
+    fn on_open(&self, _dir: &Path) {}
+
+    /// Writes moved on from log `sealed` to the new log `active`.
+    fn on_rotation(&self, _sealed: u64, _active: u64) {}
+
+    /// The live records of log `gen` are about to be copied to the active log.
+    fn on_compaction_start(&self, _gen: u64) {}
+
+    /// Log `gen` was compacted, and is deleted once no scan reads it.
+    fn on_compaction_finish(&self, _gen: u64) {}
+
+    /// The last handle to the store in `dir` was dropped. Its keydir is
+    /// still being saved unless it was closed with `KvStore::close`.
+    fn on_close(&self, _dir: &Path) {}
+}
+
+/// The listeners registered on a store.
+#[derive(Clone, Default)]
+pub struct Listeners(Vec<Arc<dyn StoreListener>>);
+
+impl Listeners {
+    pub fn push(&mut self, listener: Arc<dyn StoreListener>) {
+        self.0.push(listener);
+    }
+
+    /// Delivers an event to every listener.
+    pub(crate) fn emit(&self, event: impl Fn(&dyn StoreListener)) {
+        for listener in &self.0 {
+            event(&**listener);
+        }
+    }
+}
+
+impl fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Listeners({})", self.0.len())
+    }
+}
@@ -0,0 +1,102 @@
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// A weekly recurring period in UTC during which maintenance runs, written
+/// like `mon-fri 01:00-05:00`, `sat,sun 00:00-00:00` or `* 22:00-02:00`.
+///
+/// A period ending before it starts runs past midnight, and one ending when
+/// it starts lasts the whole day. Days name the day the period starts on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    /// Days from Monday.
+    days: [bool; 7],
+    /// Minutes after midnight.
+    start: u32,
+    end: u32,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // 1970-01-01 was a Thursday.
+        let day = ((secs / 86400 + 3) % 7) as usize;
+        let minute = (secs % 86400 / 60) as u32;
+        let yesterday = (day + 6) % 7;
+        if self.start == self.end {
+            self.days[day]
+        } else if self.start < self.end {
+            self.days[day] && self.start <= minute && minute < self.end
+        } else {
+            (self.days[day] && minute >= self.start) || (self.days[yesterday] && minute < self.end)
+        }
+    }
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut parts = s.split_whitespace();
+        let (days, times) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(days), Some(times), None) => (days, times),
+            _ => return Err(format!("expected `<days> <HH:MM>-<HH:MM>`, got `{}`", s)),
+        };
+        let mut times = times.splitn(2, '-');
+        let (start, end) = match (times.next(), times.next()) {
+            (Some(start), Some(end)) => (parse_time(start)?, parse_time(end)?),
+            _ => return Err(format!("expected `<HH:MM>-<HH:MM>` in `{}`", s)),
+        };
+        Ok(MaintenanceWindow {
+            days: parse_days(days)?,
+            start,
+            end,
+        })
+    }
+}
+
+/// Parses `*` or a comma separated list of days and day ranges like `mon-fri`.
+fn parse_days(s: &str) -> Result<[bool; 7], String> {
+    if s == "*" {
+        return Ok([true; 7]);
+    }
+    let mut days = [false; 7];
+    for range in s.split(',') {
+        let mut ends = range.splitn(2, '-');
+        let first = parse_day(ends.next().unwrap())?;
+        let last = match ends.next() {
+            Some(last) => parse_day(last)?,
+            None => first,
+        };
+        let mut day = first;
+        loop {
+            days[day] = true;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+fn parse_day(s: &str) -> Result<usize, String> {
+    let s = s.to_ascii_lowercase();
+    DAYS.iter()
+        .position(|&day| day == s)
+        .ok_or_else(|| format!("unknown day `{}`", s))
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let mut parts = s.splitn(2, ':');
+    let hour: Option<u32> = parts.next().and_then(|hour| hour.parse().ok());
+    let minute: Option<u32> = parts.next().and_then(|minute| minute.parse().ok());
+    match (hour, minute) {
+        (Some(hour), Some(minute)) if hour < 24 && minute < 60 => Ok(hour * 60 + minute),
+        _ => Err(format!("invalid time `{}`, expected `HH:MM`", s)),
+    }
+}
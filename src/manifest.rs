@@ -0,0 +1,80 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{KvsError, Result};
+
+/// Version of the on-disk format this build reads and writes. Changing the
+/// format means bumping it and adding a migration from the previous one.
+pub(crate) const FORMAT_VERSION: u32 = 2;
+
+/// Upgrades a store's directory from one format version to the next.
+type Migration = fn(&Path) -> Result<()>;
+
+/// `MIGRATIONS[v]` upgrades version `v` to `v + 1`.
+const MIGRATIONS: [Migration; FORMAT_VERSION as usize] = [from_unversioned, from_v1];
+
+/// Checks the format version in the `MANIFEST` file of a store, upgrading
+/// the store if it's older. A new store gets a manifest of the current
+/// version, and one made before manifests existed counts as version 0.
+///
+/// A read-only store can't be upgraded, so it must be of the current version.
+pub(crate) fn check(dir: &Path, exists: bool, read_only: bool) -> Result<()> {
+    let version = match read(dir)? {
+        Some(version) => version,
+        None if exists => 0,
+        None => return write(dir, FORMAT_VERSION),
+    };
+    if version > FORMAT_VERSION {
+        return Err(KvsError::UnsupportedFormat(version, FORMAT_VERSION));
+    }
+    if read_only && version < FORMAT_VERSION {
+        return Err(KvsError::Config(format!(
+            "the store's format version {} must be upgraded by opening it writable",
+            version
+        )));
+    }
+    for version in version..FORMAT_VERSION {
+        MIGRATIONS[version as usize](dir)?;
+        write(dir, version + 1)?;
+    }
+    Ok(())
+}
+
+/// Stores from before manifests have the same logs and keydir file as
+/// version 1, so only the manifest is added.
+fn from_unversioned(_: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Version 2 adds tombstones with sequence numbers, which version 1 logs
+/// just don't have.
+fn from_v1(_: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn read(dir: &Path) -> Result<Option<u32>> {
+    let manifest = match fs::read_to_string(get_manifest_path(dir)) {
+        Ok(manifest) => manifest,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    manifest
+        .trim()
+        .strip_prefix("format-version = ")
+        .and_then(|version| version.parse().ok())
+        .map(Some)
+        .ok_or(KvsError::Corrupted)
+}
+
+/// Replaces the manifest at once, so it's never read partly written.
+fn write(dir: &Path, version: u32) -> Result<()> {
+    let temp = dir.join("MANIFEST.tmp");
+    fs::write(&temp, format!("format-version = {}\n", version))?;
+    fs::rename(&temp, get_manifest_path(dir))?;
+    Ok(())
+}
+
+fn get_manifest_path(dir: &Path) -> PathBuf {
+    dir.join("MANIFEST")
+}
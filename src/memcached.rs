@@ -0,0 +1,196 @@
+//! The memcached text protocol, for memcached clients to read and write
+//! keys, see `ServerConfig::memcached_addr`.
+//!
+//! `get`, `set`, `delete`, `version` and `quit` are supported. Keys don't
+//! expire and no flags are kept, so `set` fails unless both are 0.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::{self, Either, FutureExt as _};
+use futures::io::{self, AsyncReadExt, AsyncWriteExt, BufReader};
+use log::debug;
+
+use super::resp::read_line;
+use super::rt::{self, TcpStream};
+use super::server::Shutdown;
+use super::{KvsEngine, KvsError, Result, ServerConfig};
+
+/// Longest key accepted, as in memcached.
+const MAX_KEY_LEN: usize = 250;
+
+/// Longest value accepted, memcached's default item size.
+const MAX_VALUE_LEN: u64 = 1 << 20;
+
+/// Sent to connections refused for `ServerConfig::max_connections`, as
+/// memcached does.
+pub(crate) const BUSY: &[u8] = b"SERVER_ERROR too many open connections\r\n";
+
+/// Answers the commands of one connection, until the client closes it or
+/// the server shuts down.
+pub(crate) async fn serve(
+    stream: TcpStream,
+    engine: Arc<dyn KvsEngine>,
+    config: Arc<ServerConfig>,
+    shutdown: Shutdown,
+    peer: SocketAddr,
+) -> Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let receiving = match config.idle_timeout {
+            Some(idle_timeout) => rt::timeout(idle_timeout, read_line(&mut reader)).boxed(),
+            None => read_line(&mut reader).map(Some).boxed(),
+        };
+        let line = match future::select(receiving, shutdown.clone()).await {
+            Either::Left((Some(line), _)) => match line {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e @ KvsError::TooLarge(..)) => {
+                    writer.write_all(&client_error(&e.to_string())).await?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            },
+            Either::Left((None, _)) => {
+                debug!("{}: closing idle connection", peer);
+                break;
+            }
+            Either::Right(_) => {
+                debug!("{}: closing for shutdown", peer);
+                break;
+            }
+        };
+        let line = String::from_utf8_lossy(&line).into_owned();
+        let args: Vec<&str> = line.split(' ').filter(|arg| !arg.is_empty()).collect();
+        let name = match args.first() {
+            Some(name) => *name,
+            None => continue,
+        };
+        debug!("{}: memcached {}", peer, name);
+        let reply = match name {
+            "get" => get(&args[1..], &*engine, &config).await,
+            "set" => match set(&args[1..], &mut reader, &*engine, &config).await? {
+                Some(reply) => reply,
+                None => break,
+            },
+            "delete" => delete(&args[1..], &*engine, &config).await,
+            "version" => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+            "quit" => break,
+            _ => b"ERROR\r\n".to_vec(),
+        };
+        writer.write_all(&reply).await?;
+    }
+    Ok(())
+}
+
+/// Answers `get <key>*`.
+async fn get(keys: &[&str], engine: &dyn KvsEngine, config: &ServerConfig) -> Vec<u8> {
+    if keys.is_empty() || !keys.iter().all(|key| valid_key(key)) {
+        return client_error("bad command line format");
+    }
+    if let Err(e) = config.chaos.inject("get").await {
+        return server_error(e);
+    }
+    let mut reply = Vec::new();
+    for key in keys {
+        match engine.get(key.as_bytes()).await {
+            Ok(Some(value)) => {
+                reply.extend_from_slice(format!("VALUE {} 0 {}\r\n", key, value.len()).as_bytes());
+                reply.extend_from_slice(&value);
+                reply.extend_from_slice(b"\r\n");
+            }
+            Ok(None) => {}
+            Err(e) => return server_error(e),
+        }
+    }
+    reply.extend_from_slice(b"END\r\n");
+    reply
+}
+
+/// Answers `set <key> <flags> <exptime> <bytes> [noreply]`, reading the
+/// value after it. Returns `None` if the connection should be closed, as
+/// the value couldn't be read.
+async fn set(
+    args: &[&str],
+    reader: &mut BufReader<impl io::AsyncRead + Unpin>,
+    engine: &dyn KvsEngine,
+    config: &ServerConfig,
+) -> Result<Option<Vec<u8>>> {
+    let (key, flags, exptime, len, noreply) = match args {
+        [key, flags, exptime, len, rest @ ..] if rest.is_empty() || rest == ["noreply"] => (
+            *key,
+            flags.parse::<u32>().ok(),
+            exptime.parse::<i64>().ok(),
+            len.parse::<u64>().ok(),
+            !rest.is_empty(),
+        ),
+        _ => return Ok(Some(client_error("bad command line format"))),
+    };
+    let len = match (flags, exptime, len) {
+        (Some(_), Some(_), Some(len)) if valid_key(key) => len,
+        _ => return Ok(Some(client_error("bad command line format"))),
+    };
+    if len > MAX_VALUE_LEN {
+        // The value is skipped, so the next command can be read.
+        io::copy(&mut (&mut *reader).take(len + 2), &mut io::sink()).await?;
+        return Ok(Some(server_error("object too large for cache")));
+    }
+    let mut value = Vec::new();
+    (&mut *reader).take(len + 2).read_to_end(&mut value).await?;
+    if value.len() as u64 != len + 2 {
+        return Ok(None);
+    }
+    if !value.ends_with(b"\r\n") {
+        return Ok(Some(client_error("bad data chunk")));
+    }
+    value.truncate(len as usize);
+
+    let reply = match (flags, exptime) {
+        (Some(0), Some(0)) => match config.chaos.inject("set").await {
+            Ok(()) => match engine.set(key.as_bytes(), &value).await {
+                Ok(()) => b"STORED\r\n".to_vec(),
+                Err(e) => server_error(e),
+            },
+            Err(e) => server_error(e),
+        },
+        (Some(0), _) => client_error("keys can't expire"),
+        _ => client_error("flags aren't supported"),
+    };
+    Ok(Some(if noreply { Vec::new() } else { reply }))
+}
+
+/// Answers `delete <key> [noreply]`.
+async fn delete(args: &[&str], engine: &dyn KvsEngine, config: &ServerConfig) -> Vec<u8> {
+    let (key, noreply) = match args {
+        [key] if valid_key(key) => (*key, false),
+        [key, "noreply"] if valid_key(key) => (*key, true),
+        _ => return client_error("bad command line format"),
+    };
+    if let Err(e) = config.chaos.inject("remove").await {
+        return server_error(e);
+    }
+    let reply = match engine.remove(key.as_bytes()).await {
+        Ok(()) => b"DELETED\r\n".to_vec(),
+        Err(KvsError::KeyNotFound) => b"NOT_FOUND\r\n".to_vec(),
+        Err(e) => server_error(e),
+    };
+    if noreply {
+        Vec::new()
+    } else {
+        reply
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    key.len() <= MAX_KEY_LEN && !key.chars().any(char::is_control)
+}
+
+fn client_error(message: &str) -> Vec<u8> {
+    format!("CLIENT_ERROR {}\r\n", message).into_bytes()
+}
+
+fn server_error(e: impl ToString) -> Vec<u8> {
+    let message = e.to_string().replace(['\r', '\n'], " ");
+    format!("SERVER_ERROR {}\r\n", message).into_bytes()
+}
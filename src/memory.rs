@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+use futures::future::{self, BoxFuture, FutureExt as _};
+
+use super::kvs::prefix_range;
+use super::{KvsEngine, KvsError, Result};
+
+/// An engine keeping keys in memory only, for caches and other data that
+/// needn't survive a restart.
+///
+/// Cloning it is cheap, and clones share the keys.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryEngine {
+    map: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl KvsEngine for MemoryEngine {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        let value = self.map.read().unwrap().get(key).cloned();
+        future::ready(Ok(value)).boxed()
+    }
+
+    fn set<'a>(&'a self, key: &'a [u8], value: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.map
+            .write()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+        future::ready(Ok(())).boxed()
+    }
+
+    fn remove<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        let res = match self.map.write().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound),
+        };
+        future::ready(res).boxed()
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> BoxFuture<'a, Result<Vec<Vec<u8>>>> {
+        let map = self.map.read().unwrap();
+        let keys = map.range(prefix_range(prefix)).map(|(key, _)| key.clone());
+        future::ready(Ok(keys.collect())).boxed()
+    }
+
+    fn compare_and_set<'a>(
+        &'a self,
+        key: &'a [u8],
+        expected: Option<&'a [u8]>,
+        value: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let mut map = self.map.write().unwrap();
+        if map.get(key).map(|value| &value[..]) != expected {
+            return future::ready(Ok(false)).boxed();
+        }
+        match value {
+            Some(value) => map.insert(key.to_vec(), value.to_vec()),
+            None => map.remove(key),
+        };
+        future::ready(Ok(true)).boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a [u8], to: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        let mut map = self.map.write().unwrap();
+        let res = match map.remove(from) {
+            Some(value) => {
+                map.insert(to.to_vec(), value);
+                Ok(())
+            }
+            None => Err(KvsError::KeyNotFound),
+        };
+        future::ready(res).boxed()
+    }
+
+    fn transact<'a>(
+        &'a self,
+        writes: &'a [(Vec<u8>, Option<Vec<u8>>)],
+    ) -> BoxFuture<'a, Result<()>> {
+        let mut map = self.map.write().unwrap();
+        // Whether each key written is set after the writes before.
+        let mut set: HashMap<&[u8], bool> = HashMap::new();
+        for (key, value) in writes {
+            let exists = set
+                .get(&key[..])
+                .copied()
+                .unwrap_or_else(|| map.contains_key(key));
+            if value.is_none() && !exists {
+                return future::ready(Err(KvsError::KeyNotFound)).boxed();
+            }
+            set.insert(key, value.is_some());
+        }
+        for (key, value) in writes {
+            match value {
+                Some(value) => map.insert(key.clone(), value.clone()),
+                None => map.remove(key),
+            };
+        }
+        future::ready(Ok(())).boxed()
+    }
+
+    fn copy<'a>(
+        &'a self,
+        from: &'a [u8],
+        to: &'a [u8],
+        overwrite: bool,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let mut map = self.map.write().unwrap();
+        let res = match map.get(from).cloned() {
+            Some(_) if !overwrite && map.contains_key(to) => Ok(false),
+            Some(value) => {
+                map.insert(to.to_vec(), value);
+                Ok(true)
+            }
+            None => Err(KvsError::KeyNotFound),
+        };
+        future::ready(res).boxed()
+    }
+}
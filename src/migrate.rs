@@ -0,0 +1,35 @@
+//! Copying the data of other engines into a store.
+
+use std::path::Path;
+
+use crate::{KvStore, KvsError, Result};
+
+/// Copies every key and value of the sled database in `dir` into `store`,
+/// returning how many were copied.
+///
+/// Only the default tree is read, which is the one sled-backed `kvs`
+/// servers kept their keys in. Keys the store has already are overwritten.
+#[cfg(feature = "sled")]
+pub async fn migrate_from_sled(dir: impl AsRef<Path>, store: &KvStore) -> Result<u64> {
+    let dir = dir.as_ref();
+    // Opening creates a database where there's none.
+    if !dir.join("conf").exists() {
+        return Err(KvsError::Migrate(format!("no sled database in {:?}", dir)));
+    }
+    let sled_error = |e: sled::Error| KvsError::Migrate(e.to_string());
+    let db = sled::open(dir).map_err(sled_error)?;
+    let mut copied = 0;
+    for pair in db.iter() {
+        let (key, value) = pair.map_err(sled_error)?;
+        store.set(&*key, &*value).await?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+#[cfg(not(feature = "sled"))]
+pub async fn migrate_from_sled(_dir: impl AsRef<Path>, _store: &KvStore) -> Result<u64> {
+    Err(KvsError::Migrate(
+        "kvs was built without the `sled` feature".to_owned(),
+    ))
+}
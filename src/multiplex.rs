@@ -0,0 +1,139 @@
+//! A client sending many requests at once on one connection, see
+//! `KvsClient::multiplex`.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+use futures::future::{self, Either, FutureExt as _};
+use futures::lock::Mutex as AsyncMutex;
+use serde::de::DeserializeOwned;
+
+use super::client::Response;
+use super::rt;
+use super::{Capabilities, Connection, KvsError, Receiver, Request, Result, Sender};
+
+/// Replies awaited on a multiplexed connection, by request ID.
+#[derive(Default)]
+struct Pending {
+    waiting: HashMap<u64, oneshot::Sender<Vec<u8>>>,
+    /// Whether the connection failed or was closed, failing later requests.
+    closed: bool,
+}
+
+struct Shared {
+    sender: AsyncMutex<Sender>,
+    pending: Arc<Mutex<Pending>>,
+    next_id: AtomicU64,
+    capabilities: Capabilities,
+    /// Stops receiving replies when dropped with the last client.
+    _stop: oneshot::Sender<()>,
+}
+
+/// A connection to a `kvs-server` sending requests from any number of tasks
+/// at once, each answered as soon as the server handles it. Clones share the
+/// connection.
+///
+/// Only requests that fit in one frame are sent, so long values are better
+/// set with a `KvsClient`.
+#[derive(Clone)]
+pub struct MultiplexedClient {
+    shared: Arc<Shared>,
+}
+
+impl MultiplexedClient {
+    /// Switches `conn` to multiplexed frames, see `Request::Multiplex`.
+    pub(crate) async fn new(mut conn: Connection, capabilities: Capabilities) -> Result<Self> {
+        conn.send(&Request::Multiplex).await?;
+        let resp: Response<()> = conn.receive().await?;
+        resp.map_err(KvsError::Server)?;
+        let (receiver, sender) = conn.split();
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (stop, stopped) = oneshot::channel();
+        rt::spawn(dispatch(receiver, Arc::clone(&pending), stopped));
+        Ok(MultiplexedClient {
+            shared: Arc::new(Shared {
+                sender: AsyncMutex::new(sender),
+                pending,
+                next_id: AtomicU64::new(0),
+                capabilities,
+                _stop: stop,
+            }),
+        })
+    }
+
+    /// Returns what the server supports, as it reported on connecting.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.shared.capabilities
+    }
+
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.call(Request::Set { key, value }).await
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.call(Request::Get { key }).await
+    }
+
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.call(Request::Remove { key }).await
+    }
+
+    /// Sends `request` with a fresh ID and waits for the reply with it.
+    async fn call<T: DeserializeOwned>(&self, request: Request) -> Result<T> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.shared.pending.lock().unwrap();
+            if pending.closed {
+                return Err(io::Error::from(io::ErrorKind::ConnectionAborted).into());
+            }
+            pending.waiting.insert(id, tx);
+        }
+        let sent = self.shared.sender.lock().await.send(&(id, request)).await;
+        if let Err(e) = sent {
+            self.shared.pending.lock().unwrap().waiting.remove(&id);
+            return Err(e);
+        }
+        // Dropped unanswered if the connection fails.
+        let reply = rx
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))?;
+        let resp: Response<T> = bincode::deserialize(&reply)?;
+        resp.map_err(KvsError::Server)
+    }
+}
+
+/// Hands each reply received to the request with its ID, until the
+/// connection fails or `stopped` is.
+async fn dispatch(
+    mut receiver: Receiver,
+    pending: Arc<Mutex<Pending>>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    loop {
+        let receiving = receiver.receive_encoded().boxed();
+        let frame = match future::select(receiving, &mut stopped).await {
+            Either::Left((Ok(frame), _)) => frame,
+            Either::Left((Err(_), _)) | Either::Right(_) => break,
+        };
+        if frame.len() < mem::size_of::<u64>() {
+            break;
+        }
+        let (id, reply) = frame.split_at(mem::size_of::<u64>());
+        let id: u64 = match bincode::deserialize(id) {
+            Ok(id) => id,
+            Err(_) => break,
+        };
+        let waiting = pending.lock().unwrap().waiting.remove(&id);
+        if let Some(waiting) = waiting {
+            let _ = waiting.send(reply.to_vec());
+        }
+    }
+    let mut pending = pending.lock().unwrap();
+    pending.closed = true;
+    pending.waiting.clear();
+}
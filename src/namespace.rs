@@ -0,0 +1,92 @@
+//! Named keyspaces a server hosts besides its default one, see
+//! `KvsClient::select`. Each is a store in a directory of its own, under
+//! `namespaces` in the server's directory.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use futures::lock::Mutex;
+
+use super::{KvStore, KvsError, Options, Result};
+
+/// Longest namespace name accepted.
+const MAX_NAME_LEN: usize = 64;
+
+/// The namespaces of a server, opened as clients select them.
+pub(crate) struct Namespaces {
+    dir: PathBuf,
+    /// The options namespaces are opened with, and the stores opened.
+    open: Mutex<(Options, HashMap<String, KvStore>)>,
+}
+
+impl Namespaces {
+    /// Namespaces in the server directory `dir`, opened with `options`.
+    pub(crate) fn new(dir: &Path, options: Options) -> Self {
+        Namespaces {
+            dir: dir.join("namespaces"),
+            open: Mutex::new((options, HashMap::new())),
+        }
+    }
+
+    /// Returns the store of namespace `name`, creating it if it's new.
+    ///
+    /// Its mutations are audited in a directory named after it in the audit
+    /// log's directory.
+    pub(crate) async fn get(&self, name: &str) -> Result<KvStore> {
+        check_name(name)?;
+        let mut open = self.open.lock().await;
+        let (options, open) = &mut *open;
+        if let Some(kvs) = open.get(name) {
+            return Ok(kvs.clone());
+        }
+        let options = namespace_options(options, name);
+        let kvs = KvStore::open_with_options(self.dir.join(name), options).await?;
+        open.insert(name.to_owned(), kvs.clone());
+        Ok(kvs)
+    }
+
+    /// Replaces the options of every namespace, open or not, see
+    /// `KvStore::reconfigure`.
+    pub(crate) async fn reconfigure(&self, options: Options) {
+        let mut open = self.open.lock().await;
+        let (current, open) = &mut *open;
+        for (name, kvs) in open.iter() {
+            kvs.reconfigure(namespace_options(&options, name));
+        }
+        *current = options;
+    }
+
+    /// Closes the store of every namespace opened.
+    pub(crate) async fn close(&self) -> Result<()> {
+        let open = self.open.lock().await.1.drain().collect::<Vec<_>>();
+        for (_, kvs) in open {
+            kvs.close().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the options namespace `name` is opened with, from the server's
+/// `options`.
+fn namespace_options(options: &Options, name: &str) -> Options {
+    let mut options = options.clone();
+    options.create_if_missing = true;
+    options.error_if_exists = false;
+    options.audit_log = options.audit_log.map(|dir| dir.join(name));
+    options
+}
+
+/// Fails unless `name` is made of ASCII letters, digits, `-` and `_`, so it
+/// can name a directory.
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if valid {
+        Ok(())
+    } else {
+        Err(KvsError::InvalidNamespace(name.to_owned()))
+    }
+}
@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use crate::{KvsError, Result};
+
+/// Samples the server's CPU usage for `duration`, returning a flamegraph SVG.
+#[cfg(feature = "profiling")]
+pub(crate) async fn flamegraph(duration: Duration) -> Result<Vec<u8>> {
+    let profiling_error = |e: pprof::Error| KvsError::Profiling(e.to_string());
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(99)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(profiling_error)?;
+    crate::rt::sleep(duration).await;
+    let report = guard.report().build().map_err(profiling_error)?;
+    let mut svg = Vec::new();
+    report.flamegraph(&mut svg).map_err(profiling_error)?;
+    Ok(svg)
+}
+
+#[cfg(not(feature = "profiling"))]
+pub(crate) async fn flamegraph(_duration: Duration) -> Result<Vec<u8>> {
+    Err(KvsError::Profiling(
+        "the server was built without the `profiling` feature".to_owned(),
+    ))
+}
@@ -0,0 +1,1318 @@
+//! A Raft consensus layer, so the servers of a cluster apply the same writes
+//! in the same order, see `ServerConfig::cluster`.
+//!
+//! Writes are appended to the leader's log, replicated to the other nodes,
+//! and applied to each node's store once a majority of the nodes has them.
+//! Reads are served by the leader once a majority confirms it's still the
+//! leader, so they see every write acknowledged before them. Other nodes
+//! fail reads and writes with `KvsError::NotLeader`.
+//!
+//! The term, vote and log are kept in the `raft` directory of the server's
+//! directory. Once `Cluster::max_log_entries` entries are applied, the
+//! store is saved as a checkpoint, see `KvStore::checkpoint`, and they're
+//! dropped from the log. Nodes restarting rebuild the store from it and
+//! apply the entries after it again, and nodes missing dropped entries are
+//! sent the leader's keys instead. The nodes of a cluster are fixed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, BoxFuture, Either, FutureExt as _};
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::kvs::get_checkpoint_path;
+use super::replication::unless_shutdown;
+use super::rt::{self, JoinHandle};
+use super::server::Shutdown;
+use super::{ClientConfig, KvStore, KvsClient, KvsEngine, KvsError, Result, SigningKey};
+
+/// How often followers check whether to start an election.
+const TICK: Duration = Duration::from_millis(50);
+
+/// How often the leader sends entries, or heartbeats without them.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Followers not hearing from a leader for this long, plus up to as long
+/// again at random, start an election.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a node waits for the reply to a message, except snapshots.
+const RPC_TIMEOUT: Duration = Duration::from_millis(400);
+
+/// How long the leader waits for the reply to a chunk of a snapshot.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a write or read waits for a majority, failing with `NoQuorum`.
+const QUORUM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a read checks whether the entries before it are applied.
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Most entries sent in one message.
+const MAX_APPEND_ENTRIES: usize = 1000;
+
+/// Applied entries kept in the log before they're dropped, unless the
+/// cluster says otherwise.
+const MAX_LOG_ENTRIES: u64 = 10_000;
+
+/// Bytes of keys and values sent in one chunk of a snapshot.
+const SNAPSHOT_CHUNK_BYTES: usize = 1 << 20;
+
+/// The nodes of a Raft cluster, see `ServerConfig::cluster`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    /// The address each node serves clients on, in the same order on every
+    /// node. Nodes talk to each other on these addresses too.
+    pub nodes: Vec<SocketAddr>,
+    /// The position of this node in `nodes`.
+    pub id: usize,
+    /// Applied entries kept in the log before they're dropped, 10,000 if
+    /// `None`. Nodes missing dropped entries are sent every key instead.
+    pub max_log_entries: Option<u64>,
+}
+
+/// A write replicated through the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Command {
+    /// Appended by a new leader, to commit the entries of earlier terms.
+    Noop,
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    RemoveMany {
+        keys: Vec<Vec<u8>>,
+    },
+    CompareAndSet {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Option<Vec<u8>>,
+    },
+    Rename {
+        from: Vec<u8>,
+        to: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    term: u64,
+    command: Command,
+}
+
+/// A message between the nodes of a cluster, sent as `Request::Raft`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Message {
+    Vote {
+        term: u64,
+        candidate: usize,
+        last_index: u64,
+        last_term: u64,
+    },
+    Append {
+        term: u64,
+        leader: usize,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    /// Asks followers to confirm the sender is still the leader, for reads.
+    Heartbeat { term: u64, leader: usize },
+    /// A chunk of the leader's keys, as of entry `last_index`. The first
+    /// chunk empties the store, and the last one ends the log there.
+    Snapshot {
+        term: u64,
+        leader: usize,
+        last_index: u64,
+        last_term: u64,
+        first: bool,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        done: bool,
+    },
+}
+
+/// The reply to a `Message`, with the term of the node replying.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Reply {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// Unless `success`, the leader goes back to the entry after `hint`.
+    Append {
+        term: u64,
+        success: bool,
+        hint: u64,
+    },
+    Heartbeat {
+        term: u64,
+    },
+    /// Unless `ok`, the leader sends the snapshot again from the start.
+    Snapshot {
+        term: u64,
+        ok: bool,
+    },
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Message::Vote {
+                term, candidate, ..
+            } => write!(f, "raft vote for node {} in term {}", candidate, term),
+            Message::Append { term, entries, .. } => write!(
+                f,
+                "raft append of {} entries in term {}",
+                entries.len(),
+                term
+            ),
+            Message::Heartbeat { term, .. } => write!(f, "raft heartbeat in term {}", term),
+            Message::Snapshot {
+                term, last_index, ..
+            } => write!(f, "raft snapshot at {} in term {}", last_index, term),
+        }
+    }
+}
+
+/// The term and vote, which must survive restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<usize>,
+}
+
+impl HardState {
+    fn load(dir: &Path) -> Result<HardState> {
+        match fs::read(dir.join("state")) {
+            Ok(buf) => Ok(bincode::deserialize(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HardState::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join("state.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bincode::serialize(self)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, dir.join("state"))?;
+        Ok(())
+    }
+}
+
+/// A record of the log file.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    /// The index and term of the last entry dropped, first in the file.
+    Start {
+        index: u64,
+        term: u64,
+    },
+    Entry(Entry),
+}
+
+/// The entries after the last one dropped, in memory and in a file.
+struct Log {
+    dir: PathBuf,
+    file: File,
+    start_index: u64,
+    start_term: u64,
+    entries: Vec<Entry>,
+}
+
+impl Log {
+    /// Opens the log in `dir`, dropping a record torn by a crash.
+    fn open(dir: &Path) -> Result<Log> {
+        let path = dir.join("log");
+        let (mut start_index, mut start_term, mut entries) = (0, 0, Vec::new());
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            while let Ok(record) = bincode::deserialize_from(&mut reader) {
+                match record {
+                    Record::Start { index, term } => {
+                        start_index = index;
+                        start_term = term;
+                    }
+                    Record::Entry(entry) => entries.push(entry),
+                }
+            }
+        }
+        let mut log = Log {
+            dir: dir.to_owned(),
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            start_index,
+            start_term,
+            entries,
+        };
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.start_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.start_term, |entry| entry.term)
+    }
+
+    /// Returns the term of the entry at `index`, unless it was dropped
+    /// before the last one dropped or isn't there yet.
+    fn term(&self, index: u64) -> Option<u64> {
+        if index == self.start_index {
+            Some(self.start_term)
+        } else {
+            self.entry(index).map(|entry| entry.term)
+        }
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        let offset = index.checked_sub(self.start_index + 1)?;
+        self.entries.get(offset as usize)
+    }
+
+    /// Returns up to `max` entries from `index` on.
+    fn entries_from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let offset = (index - self.start_index - 1) as usize;
+        self.entries
+            .iter()
+            .skip(offset)
+            .take(max)
+            .cloned()
+            .collect()
+    }
+
+    /// Appends `entries`, syncing them to disk.
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut writer = BufWriter::new(&self.file);
+        for entry in &entries {
+            bincode::serialize_into(&mut writer, &Record::Entry(entry.clone()))?;
+        }
+        writer.flush()?;
+        drop(writer);
+        self.file.sync_data()?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Drops the entries after `index`.
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate((index - self.start_index) as usize);
+        self.rewrite()
+    }
+
+    /// Drops the entries up to `index`, whose term is `term`, or every
+    /// entry if the one at `index` has another term.
+    fn compact(&mut self, index: u64, term: u64) -> Result<()> {
+        if self.term(index) == Some(term) && index >= self.start_index {
+            self.entries.drain(..(index - self.start_index) as usize);
+        } else {
+            self.entries.clear();
+        }
+        self.start_index = index;
+        self.start_term = term;
+        self.rewrite()
+    }
+
+    /// Writes the log to a new file, replacing the current one.
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp = self.dir.join("log.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let start = Record::Start {
+            index: self.start_index,
+            term: self.start_term,
+        };
+        bincode::serialize_into(&mut writer, &start)?;
+        for entry in &self.entries {
+            bincode::serialize_into(&mut writer, &Record::Entry(entry.clone()))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp, self.dir.join("log"))?;
+        self.file = OpenOptions::new().append(true).open(self.dir.join("log"))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct State {
+    hard: HardState,
+    role: Role,
+    leader: Option<usize>,
+    log: Log,
+    commit: u64,
+    applied: u64,
+    /// When to start an election, unless a leader is heard from first.
+    deadline: Instant,
+    /// While leader, the next entry to send to each node, and the last
+    /// known to be in its log.
+    next: Vec<u64>,
+    matched: Vec<u64>,
+    /// While leader, wakes the task replicating to each node.
+    wakers: Vec<Option<mpsc::UnboundedSender<()>>>,
+    /// Writes waiting for the entry at an index, of a term, to be applied.
+    waiters: HashMap<u64, (u64, oneshot::Sender<Result<u64>>)>,
+    /// The term and last index of the snapshot being installed, during
+    /// which no entry is applied.
+    installing: Option<(u64, u64)>,
+}
+
+/// A node of a Raft cluster, serving the keys of a store.
+pub(crate) struct Raft {
+    id: usize,
+    nodes: Vec<SocketAddr>,
+    max_log_entries: u64,
+    dir: PathBuf,
+    kvs: KvStore,
+    state: Mutex<State>,
+    /// Held while an entry is applied to the store, or a snapshot read from
+    /// or written to it.
+    applying: AsyncMutex<()>,
+    /// Wakes the task applying entries once more are committed.
+    commits: mpsc::UnboundedSender<()>,
+    /// A connection to each other node, made when it's first needed.
+    peers: Vec<AsyncMutex<Option<KvsClient>>>,
+    signing_key: Option<SigningKey>,
+    shutdown: Shutdown,
+}
+
+impl Raft {
+    /// Starts the node `cluster.id` of `cluster`, keeping its state in the
+    /// `raft` directory of `dir` and applying entries to `kvs`, and connecting
+    /// to the other nodes with `signing_key`.
+    ///
+    /// The store is rebuilt from the checkpoint the log starts after, and
+    /// the entries after it are applied again once they're known to be
+    /// committed. Returns the node, and a task ending once it stops for
+    /// `shutdown`.
+    pub(crate) async fn start(
+        cluster: &Cluster,
+        dir: &Path,
+        kvs: KvStore,
+        signing_key: Option<SigningKey>,
+        shutdown: Shutdown,
+    ) -> Result<(Arc<Raft>, JoinHandle<()>)> {
+        if cluster.id >= cluster.nodes.len() {
+            return Err(KvsError::Config(format!(
+                "node {} isn't among the {} nodes of the cluster",
+                cluster.id,
+                cluster.nodes.len()
+            )));
+        }
+        let dir = dir.join("raft");
+        fs::create_dir_all(&dir)?;
+        let hard = HardState::load(&dir)?;
+        let log = Log::open(&dir)?;
+        let applied = log.start_index;
+        let count = cluster.nodes.len();
+        let state = State {
+            hard,
+            role: Role::Follower,
+            leader: None,
+            log,
+            commit: applied,
+            applied,
+            deadline: election_deadline(),
+            next: vec![0; count],
+            matched: vec![0; count],
+            wakers: (0..count).map(|_| None).collect(),
+            waiters: HashMap::new(),
+            installing: None,
+        };
+        let (commits, committed) = mpsc::unbounded();
+        let raft = Arc::new(Raft {
+            id: cluster.id,
+            nodes: cluster.nodes.clone(),
+            max_log_entries: cluster.max_log_entries.unwrap_or(MAX_LOG_ENTRIES),
+            dir,
+            kvs,
+            state: Mutex::new(state),
+            applying: AsyncMutex::new(()),
+            commits,
+            peers: (0..count).map(|_| AsyncMutex::new(None)).collect(),
+            signing_key,
+            shutdown,
+        });
+        raft.restore().await?;
+        let running = future::join(Arc::clone(&raft).tick(), Arc::clone(&raft).apply(committed));
+        let task = rt::spawn(running.map(|_| ()));
+        Ok((raft, task))
+    }
+
+    /// Returns the address of the leader, if it's known.
+    pub(crate) fn leader(&self) -> Option<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        state.leader.map(|leader| self.nodes[leader])
+    }
+
+    fn majority(&self) -> usize {
+        self.nodes.len() / 2 + 1
+    }
+
+    fn not_leader(&self, state: &State) -> KvsError {
+        let leader = state.leader.filter(|&leader| leader != self.id);
+        KvsError::NotLeader(leader.map(|leader| self.nodes[leader]))
+    }
+
+    /// Starts elections while no leader is heard from, until shutdown.
+    async fn tick(self: Arc<Self>) {
+        while unless_shutdown(rt::sleep(TICK), &self.shutdown)
+            .await
+            .is_some()
+        {
+            let timed_out = {
+                let state = self.state.lock().unwrap();
+                state.role != Role::Leader && Instant::now() >= state.deadline
+            };
+            if timed_out {
+                if let Err(e) = self.elect() {
+                    warn!("Error starting an election: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Becomes a candidate in a new term, asking the other nodes for votes.
+    fn elect(self: &Arc<Self>) -> Result<()> {
+        let (term, last_index, last_term) = {
+            let mut state = self.state.lock().unwrap();
+            state.hard.term += 1;
+            state.hard.voted_for = Some(self.id);
+            state.hard.save(&self.dir)?;
+            state.role = Role::Candidate;
+            state.leader = None;
+            state.deadline = election_deadline();
+            (
+                state.hard.term,
+                state.log.last_index(),
+                state.log.last_term(),
+            )
+        };
+        debug!("Starting an election for term {}", term);
+        if self.majority() == 1 {
+            self.lead(term)?;
+            return Ok(());
+        }
+        let votes = Arc::new(Mutex::new(1));
+        for peer in self.others() {
+            let raft = Arc::clone(self);
+            let votes = Arc::clone(&votes);
+            let message = Message::Vote {
+                term,
+                candidate: self.id,
+                last_index,
+                last_term,
+            };
+            rt::spawn(async move {
+                let granted = match raft.call(peer, message, RPC_TIMEOUT).await {
+                    Ok(Reply::Vote {
+                        term: reply_term,
+                        granted,
+                    }) => {
+                        if reply_term > term {
+                            let mut state = raft.state.lock().unwrap();
+                            raft.step_down(&mut state, reply_term);
+                        }
+                        granted
+                    }
+                    _ => false,
+                };
+                if granted {
+                    let mut votes = votes.lock().unwrap();
+                    *votes += 1;
+                    if *votes == raft.majority() {
+                        if let Err(e) = raft.lead(term) {
+                            warn!("Error becoming the leader: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Becomes the leader of `term`, unless the election is over.
+    fn lead(self: &Arc<Self>, term: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Candidate || state.hard.term != term {
+            return Ok(());
+        }
+        info!("Elected the leader for term {}", term);
+        state.role = Role::Leader;
+        state.leader = Some(self.id);
+        let next = state.log.last_index() + 1;
+        state.next = vec![next; self.nodes.len()];
+        state.matched = vec![0; self.nodes.len()];
+        state.log.append(vec![Entry {
+            term,
+            command: Command::Noop,
+        }])?;
+        for peer in self.others() {
+            let (waker, woken) = mpsc::unbounded();
+            state.wakers[peer] = Some(waker);
+            rt::spawn(Arc::clone(self).replicate(peer, term, woken));
+        }
+        self.advance_commit(&mut state);
+        Ok(())
+    }
+
+    /// Follows the leader of `term`, or a later one, failing the writes
+    /// waiting on this node if it was the leader.
+    fn step_down(&self, state: &mut State, term: u64) {
+        if term > state.hard.term {
+            state.hard.term = term;
+            state.hard.voted_for = None;
+            state.leader = None;
+            if let Err(e) = state.hard.save(&self.dir) {
+                warn!("Error saving the Raft state: {}", e);
+            }
+        }
+        if state.role == Role::Leader {
+            info!("No longer the leader, in term {}", term);
+            // It didn't wait for a leader while leading.
+            state.deadline = election_deadline();
+        }
+        state.role = Role::Follower;
+        state.wakers.iter_mut().for_each(|waker| *waker = None);
+        for (_, (_, waiter)) in state.waiters.drain() {
+            let _ = waiter.send(Err(KvsError::NotLeader(None)));
+        }
+    }
+
+    /// Handles a message from the leader of `term`, or a candidate, returning
+    /// whether it's from the current term.
+    fn heard_from(&self, state: &mut State, term: u64, leader: usize) -> bool {
+        if term < state.hard.term {
+            return false;
+        }
+        if term > state.hard.term || state.role != Role::Follower {
+            self.step_down(state, term);
+        }
+        state.leader = Some(leader);
+        state.deadline = election_deadline();
+        true
+    }
+
+    fn others(&self) -> impl Iterator<Item = usize> {
+        let id = self.id;
+        (0..self.nodes.len()).filter(move |&node| node != id)
+    }
+
+    /// Commits the entries of the current term a majority has, and those
+    /// before them.
+    fn advance_commit(&self, state: &mut State) {
+        let mut matched = state.matched.clone();
+        matched[self.id] = state.log.last_index();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.majority() - 1];
+        if index > state.commit && state.log.term(index) == Some(state.hard.term) {
+            state.commit = index;
+            let _ = self.commits.unbounded_send(());
+        }
+    }
+
+    /// Sends node `peer` the entries it lacks while this node leads `term`,
+    /// or heartbeats once it has them all.
+    async fn replicate(
+        self: Arc<Self>,
+        peer: usize,
+        term: u64,
+        mut woken: mpsc::UnboundedReceiver<()>,
+    ) {
+        loop {
+            let message = {
+                let state = self.state.lock().unwrap();
+                if state.role != Role::Leader || state.hard.term != term {
+                    return;
+                }
+                let next = state.next[peer].min(state.log.last_index() + 1);
+                // Entries before the last one compacted are sent as a snapshot.
+                match state.log.term(next - 1) {
+                    Some(prev_term) if next > state.log.start_index => Some(Message::Append {
+                        term,
+                        leader: self.id,
+                        prev_index: next - 1,
+                        prev_term,
+                        entries: state.log.entries_from(next, MAX_APPEND_ENTRIES),
+                        commit: state.commit,
+                    }),
+                    _ => None,
+                }
+            };
+            let res = match message {
+                Some(message) => self.send_entries(peer, term, message).await,
+                None => self.send_snapshot(peer, term).await,
+            };
+            if let Err(e) = &res {
+                debug!("Error replicating to {}: {}", self.nodes[peer], e);
+            }
+            let behind = {
+                let state = self.state.lock().unwrap();
+                state.next[peer] <= state.log.last_index()
+            };
+            if res.is_err() || !behind {
+                let waiting = future::select(woken.next(), Box::pin(rt::sleep(HEARTBEAT_INTERVAL)));
+                if let Either::Left((None, _)) = waiting.await {
+                    return;
+                }
+            }
+            if self.shutdown.peek().is_some() {
+                return;
+            }
+        }
+    }
+
+    async fn send_entries(&self, peer: usize, term: u64, message: Message) -> Result<()> {
+        let sent = match &message {
+            Message::Append {
+                prev_index,
+                entries,
+                ..
+            } => prev_index + entries.len() as u64,
+            _ => unreachable!("only entries are sent here"),
+        };
+        let reply = self.call(peer, message, RPC_TIMEOUT).await?;
+        let mut state = self.state.lock().unwrap();
+        if let Reply::Append {
+            term: reply_term,
+            success,
+            hint,
+        } = reply
+        {
+            if reply_term > term {
+                self.step_down(&mut state, reply_term);
+            } else if state.role == Role::Leader && state.hard.term == term {
+                if success {
+                    state.matched[peer] = state.matched[peer].max(sent);
+                    state.next[peer] = state.matched[peer] + 1;
+                    self.advance_commit(&mut state);
+                } else {
+                    state.next[peer] = (hint + 1).min(state.log.last_index() + 1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends node `peer` every key, as of the last entry applied, in
+    /// chunks.
+    async fn send_snapshot(&self, peer: usize, term: u64) -> Result<()> {
+        let applying = self.applying.lock().await;
+        let (last_index, last_term) = {
+            let state = self.state.lock().unwrap();
+            (state.applied, state.log.term(state.applied).unwrap())
+        };
+        let mut scan = self.kvs.scan(..).await?;
+        drop(applying);
+        debug!(
+            "Sending a snapshot at entry {} to {}",
+            last_index, self.nodes[peer]
+        );
+        let mut first = true;
+        let mut pairs = Vec::new();
+        let mut bytes = 0;
+        loop {
+            let pair = scan.next().await.transpose()?;
+            let done = pair.is_none();
+            if let Some((key, value)) = pair {
+                bytes += key.len() + value.len();
+                pairs.push((key, value));
+            }
+            if !done && bytes < SNAPSHOT_CHUNK_BYTES {
+                continue;
+            }
+            let message = Message::Snapshot {
+                term,
+                leader: self.id,
+                last_index,
+                last_term,
+                first,
+                pairs: mem::take(&mut pairs),
+                done,
+            };
+            match self.call(peer, message, SNAPSHOT_TIMEOUT).await? {
+                Reply::Snapshot {
+                    term: reply_term, ..
+                } if reply_term > term => {
+                    let mut state = self.state.lock().unwrap();
+                    self.step_down(&mut state, reply_term);
+                    return Ok(());
+                }
+                Reply::Snapshot { ok: true, .. } => {}
+                _ => return Ok(()),
+            }
+            if done {
+                break;
+            }
+            first = false;
+            bytes = 0;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.role == Role::Leader && state.hard.term == term {
+            state.matched[peer] = state.matched[peer].max(last_index);
+            state.next[peer] = state.matched[peer] + 1;
+        }
+        info!(
+            "Sent a snapshot at entry {} to {}",
+            last_index, self.nodes[peer]
+        );
+        Ok(())
+    }
+
+    /// Sends `message` to node `peer`, connecting to it if needed.
+    async fn call(&self, peer: usize, message: Message, timeout: Duration) -> Result<Reply> {
+        let mut client = self.peers[peer].lock().await;
+        let calling = async {
+            if client.is_none() {
+                let config = ClientConfig {
+                    signing_key: self.signing_key.clone(),
+                    ..ClientConfig::default()
+                };
+                *client = Some(KvsClient::connect(self.nodes[peer], config).await?);
+            }
+            client.as_mut().unwrap().raft(message).await
+        };
+        let res = rt::timeout(timeout, calling)
+            .await
+            .unwrap_or(Err(KvsError::Offline));
+        if res.is_err() {
+            *client = None;
+        }
+        res
+    }
+
+    /// Handles a message from another node.
+    pub(crate) async fn receive(&self, message: Message) -> Result<Reply> {
+        match message {
+            Message::Vote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => self.vote(term, candidate, last_index, last_term),
+            Message::Append {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                let abandoning = {
+                    let state = self.state.lock().unwrap();
+                    state.installing.is_some() && term >= state.hard.term
+                };
+                if abandoning {
+                    // The leader has the entries after the log's start, so
+                    // the snapshot is abandoned for them.
+                    self.restore().await?;
+                }
+                self.append(term, leader, prev_index, prev_term, entries, commit)
+            }
+            Message::Heartbeat { term, leader } => {
+                let mut state = self.state.lock().unwrap();
+                self.heard_from(&mut state, term, leader);
+                Ok(Reply::Heartbeat {
+                    term: state.hard.term,
+                })
+            }
+            Message::Snapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                first,
+                pairs,
+                done,
+            } => {
+                let snapshot = (term, last_index, last_term);
+                self.install(leader, snapshot, first, pairs, done).await
+            }
+        }
+    }
+
+    fn vote(&self, term: u64, candidate: usize, last_index: u64, last_term: u64) -> Result<Reply> {
+        let mut state = self.state.lock().unwrap();
+        if term > state.hard.term {
+            self.step_down(&mut state, term);
+        }
+        let up_to_date = (last_term, last_index) >= (state.log.last_term(), state.log.last_index());
+        let granted = term == state.hard.term
+            && up_to_date
+            && state.hard.voted_for.is_none_or(|voted| voted == candidate);
+        if granted {
+            state.hard.voted_for = Some(candidate);
+            state.hard.save(&self.dir)?;
+            state.deadline = election_deadline();
+        }
+        Ok(Reply::Vote {
+            term: state.hard.term,
+            granted,
+        })
+    }
+
+    fn append(
+        &self,
+        term: u64,
+        leader: usize,
+        mut prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<Reply> {
+        let mut state = self.state.lock().unwrap();
+        let refuse = |state: &State, hint| {
+            Ok(Reply::Append {
+                term: state.hard.term,
+                success: false,
+                hint,
+            })
+        };
+        if !self.heard_from(&mut state, term, leader) {
+            return refuse(&state, state.log.last_index());
+        }
+        if state.installing.is_some() {
+            return refuse(&state, state.log.last_index());
+        }
+        if prev_index > state.log.last_index() {
+            return refuse(&state, state.log.last_index());
+        }
+        let mut entries = entries.into_iter();
+        if prev_index < state.log.start_index {
+            // The entries up to the start are applied already.
+            let skipped = state.log.start_index - prev_index;
+            entries.by_ref().take(skipped as usize).for_each(drop);
+            prev_index = state.log.start_index;
+        } else if state.log.term(prev_index) != Some(prev_term) {
+            return refuse(&state, prev_index - 1);
+        }
+        let mut index = prev_index;
+        let mut new = Vec::new();
+        for entry in entries.by_ref() {
+            index += 1;
+            match state.log.term(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => state.log.truncate(index - 1)?,
+                None => {}
+            }
+            new.push(entry);
+            break;
+        }
+        new.extend(entries);
+        let last_new = index + new.len().saturating_sub(1) as u64;
+        state.log.append(new)?;
+        if commit > state.commit {
+            state.commit = commit.min(last_new.max(prev_index));
+            let _ = self.commits.unbounded_send(());
+        }
+        Ok(Reply::Append {
+            term: state.hard.term,
+            success: true,
+            hint: state.log.last_index(),
+        })
+    }
+
+    /// Installs a chunk of the leader's snapshot `(term, last_index,
+    /// last_term)`.
+    async fn install(
+        &self,
+        leader: usize,
+        (term, last_index, last_term): (u64, u64, u64),
+        first: bool,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        done: bool,
+    ) -> Result<Reply> {
+        let _applying = self.applying.lock().await;
+        {
+            let mut state = self.state.lock().unwrap();
+            let reply = |state: &State, ok| {
+                Ok(Reply::Snapshot {
+                    term: state.hard.term,
+                    ok,
+                })
+            };
+            if !self.heard_from(&mut state, term, leader) {
+                return reply(&state, false);
+            }
+            if first {
+                info!("Installing a snapshot at entry {}", last_index);
+                state.installing = Some((term, last_index));
+            } else if state.installing != Some((term, last_index)) {
+                return reply(&state, false);
+            }
+        }
+        if first {
+            self.kvs.delete_prefix("").await?;
+        }
+        for (key, value) in pairs {
+            self.kvs.set(key, value).await?;
+        }
+        if done {
+            self.checkpoint(last_index).await?;
+            let mut state = self.state.lock().unwrap();
+            state.log.compact(last_index, last_term)?;
+            state.applied = last_index;
+            state.commit = state.commit.max(last_index);
+            state.installing = None;
+            self.prune_checkpoints(last_index)?;
+            let _ = self.commits.unbounded_send(());
+        }
+        let state = self.state.lock().unwrap();
+        Ok(Reply::Snapshot {
+            term: state.hard.term,
+            ok: true,
+        })
+    }
+
+    /// Applies committed entries to the store as they're committed, until
+    /// shutdown.
+    async fn apply(self: Arc<Self>, mut committed: mpsc::UnboundedReceiver<()>) {
+        loop {
+            loop {
+                let _applying = self.applying.lock().await;
+                let (index, entry) = {
+                    let state = self.state.lock().unwrap();
+                    if state.installing.is_some() || state.applied >= state.commit {
+                        break;
+                    }
+                    let index = state.applied + 1;
+                    match state.log.entry(index) {
+                        Some(entry) => (index, entry.clone()),
+                        None => break,
+                    }
+                };
+                let res = self.execute(entry.command).await;
+                if let Err(e) = &res {
+                    if !matches!(e, KvsError::KeyNotFound) {
+                        debug!("Error applying entry {}: {}", index, e);
+                    }
+                }
+                let mut state = self.state.lock().unwrap();
+                state.applied = index;
+                if let Some((term, waiter)) = state.waiters.remove(&index) {
+                    let res = if term == entry.term {
+                        res
+                    } else {
+                        Err(KvsError::NotLeader(None))
+                    };
+                    let _ = waiter.send(res);
+                }
+            }
+            if let Err(e) = self.compact().await {
+                warn!("Error compacting the Raft log: {}", e);
+            }
+            if unless_shutdown(committed.next(), &self.shutdown)
+                .await
+                .flatten()
+                .is_none()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Applies a command to the store, returning what the write returns.
+    async fn execute(&self, command: Command) -> Result<u64> {
+        match command {
+            Command::Noop => Ok(0),
+            Command::Set { key, value } => self.kvs.set(key, value).await.map(|()| 0),
+            Command::Remove { key } => self.kvs.remove(key).await.map(|()| 0),
+            Command::RemoveMany { keys } => self.kvs.remove_many(keys).await.map(|n| n as u64),
+            Command::CompareAndSet {
+                key,
+                expected,
+                value,
+            } => self
+                .kvs
+                .compare_and_set(key, expected.as_deref(), value.as_deref())
+                .await
+                .map(u64::from),
+            Command::Rename { from, to } => self.kvs.rename(from, to).await.map(|()| 0),
+        }
+    }
+
+    /// Drops the applied entries from the log once there are too many,
+    /// after saving the store holding them as a checkpoint.
+    async fn compact(&self) -> Result<()> {
+        let _applying = self.applying.lock().await;
+        let (index, term) = {
+            let state = self.state.lock().unwrap();
+            if state.applied - state.log.start_index < self.max_log_entries {
+                return Ok(());
+            }
+            (state.applied, state.log.term(state.applied).unwrap())
+        };
+        self.checkpoint(index).await?;
+        self.state.lock().unwrap().log.compact(index, term)?;
+        self.prune_checkpoints(index)?;
+        debug!("Dropped the Raft log up to entry {}", index);
+        Ok(())
+    }
+
+    /// Saves the store as the checkpoint of entry `index`, replacing one
+    /// left by a crash.
+    async fn checkpoint(&self, index: u64) -> Result<()> {
+        let path = get_checkpoint_path(self.kvs.dir(), &checkpoint_name(index))?;
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        self.kvs.checkpoint(&checkpoint_name(index)).await
+    }
+
+    /// Removes the checkpoints of entries other than `index`.
+    fn prune_checkpoints(&self, index: u64) -> Result<()> {
+        let dir = self.kvs.dir().join("checkpoints");
+        if !dir.exists() {
+            return Ok(());
+        }
+        for checkpoint in fs::read_dir(dir)? {
+            let checkpoint = checkpoint?;
+            let name = checkpoint.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("raft-") && name != checkpoint_name(index) {
+                fs::remove_dir_all(checkpoint.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the store from the checkpoint of the entry the log starts
+    /// after, undoing the entries applied since and any snapshot partly
+    /// installed.
+    async fn restore(&self) -> Result<()> {
+        let _applying = self.applying.lock().await;
+        let start = self.state.lock().unwrap().log.start_index;
+        self.kvs.delete_prefix("").await?;
+        if start > 0 {
+            let checkpoint =
+                KvStore::open_checkpoint(self.kvs.dir(), &checkpoint_name(start)).await?;
+            let mut pairs = checkpoint.scan(..).await?;
+            while let Some((key, value)) = pairs.next().await.transpose()? {
+                self.kvs.set(key, value).await?;
+            }
+        }
+        self.prune_checkpoints(start)?;
+        let mut state = self.state.lock().unwrap();
+        state.applied = start;
+        state.commit = state.commit.max(start);
+        state.installing = None;
+        Ok(())
+    }
+
+    /// Replicates `command`, returning what applying it returned once it's
+    /// applied.
+    async fn propose(&self, command: Command) -> Result<u64> {
+        let (waiter, applied) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Err(self.not_leader(&state));
+            }
+            let term = state.hard.term;
+            state.log.append(vec![Entry { term, command }])?;
+            let index = state.log.last_index();
+            state.waiters.insert(index, (term, waiter));
+            self.advance_commit(&mut state);
+            for waker in state.wakers.iter().flatten() {
+                let _ = waker.unbounded_send(());
+            }
+        }
+        match rt::timeout(QUORUM_TIMEOUT, applied).await {
+            Some(Ok(res)) => res,
+            Some(Err(_)) => Err(KvsError::NotLeader(None)),
+            None => Err(KvsError::NoQuorum),
+        }
+    }
+
+    /// Waits until reads see every write committed before, failing unless
+    /// this node is still the leader.
+    async fn read_barrier(&self) -> Result<()> {
+        let started = Instant::now();
+        // Entries of earlier terms are only known to be committed once one
+        // of this term is.
+        let (term, index) = loop {
+            {
+                let state = self.state.lock().unwrap();
+                if state.role != Role::Leader {
+                    return Err(self.not_leader(&state));
+                }
+                if state.log.term(state.commit) == Some(state.hard.term) {
+                    break (state.hard.term, state.commit);
+                }
+            }
+            if started.elapsed() >= QUORUM_TIMEOUT {
+                return Err(KvsError::NoQuorum);
+            }
+            rt::sleep(READ_POLL_INTERVAL).await;
+        };
+        self.confirm(term).await?;
+        loop {
+            if self.state.lock().unwrap().applied >= index {
+                return Ok(());
+            }
+            if started.elapsed() >= QUORUM_TIMEOUT {
+                return Err(KvsError::NoQuorum);
+            }
+            rt::sleep(READ_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Fails unless a majority still follows this node as the leader of
+    /// `term`.
+    async fn confirm(&self, term: u64) -> Result<()> {
+        let mut confirmed = 1;
+        if confirmed >= self.majority() {
+            return Ok(());
+        }
+        let mut replies: FuturesUnordered<_> = self
+            .others()
+            .map(|peer| {
+                let message = Message::Heartbeat {
+                    term,
+                    leader: self.id,
+                };
+                self.call(peer, message, RPC_TIMEOUT)
+            })
+            .collect();
+        while let Some(reply) = replies.next().await {
+            match reply {
+                Ok(Reply::Heartbeat { term: reply_term }) if reply_term == term => {
+                    confirmed += 1;
+                    if confirmed >= self.majority() {
+                        return Ok(());
+                    }
+                }
+                Ok(Reply::Heartbeat { term: reply_term }) if reply_term > term => {
+                    let mut state = self.state.lock().unwrap();
+                    self.step_down(&mut state, reply_term);
+                    return Err(self.not_leader(&state));
+                }
+                _ => {}
+            }
+        }
+        Err(KvsError::NoQuorum)
+    }
+}
+
+/// Serves keys through the cluster: writes are replicated before they're
+/// applied, and reads are served by the leader.
+impl KvsEngine for Raft {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        async move {
+            self.read_barrier().await?;
+            self.kvs.get(key).await
+        }
+        .boxed()
+    }
+
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            self.read_barrier().await?;
+            self.kvs.contains_key(key).await
+        }
+        .boxed()
+    }
+
+    fn value_len<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<u64>>> {
+        async move {
+            self.read_barrier().await?;
+            self.kvs.value_len(key).await
+        }
+        .boxed()
+    }
+
+    fn set<'a>(&'a self, key: &'a [u8], value: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        let command = Command::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        self.propose(command).map(|res| res.map(|_| ())).boxed()
+    }
+
+    fn remove<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        let command = Command::Remove { key: key.to_vec() };
+        self.propose(command).map(|res| res.map(|_| ())).boxed()
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> BoxFuture<'a, Result<Vec<Vec<u8>>>> {
+        async move {
+            self.read_barrier().await?;
+            KvsEngine::keys_with_prefix(&self.kvs, prefix).await
+        }
+        .boxed()
+    }
+
+    fn compare_and_set<'a>(
+        &'a self,
+        key: &'a [u8],
+        expected: Option<&'a [u8]>,
+        value: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let command = Command::CompareAndSet {
+            key: key.to_vec(),
+            expected: expected.map(<[u8]>::to_vec),
+            value: value.map(<[u8]>::to_vec),
+        };
+        self.propose(command).map(|res| res.map(|n| n == 1)).boxed()
+    }
+
+    /// Replicated as one entry, so every node renames atomically.
+    fn rename<'a>(&'a self, from: &'a [u8], to: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        let command = Command::Rename {
+            from: from.to_vec(),
+            to: to.to_vec(),
+        };
+        self.propose(command).map(|res| res.map(|_| ())).boxed()
+    }
+
+    fn remove_many<'a>(&'a self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Result<usize>> {
+        let command = Command::RemoveMany {
+            keys: keys.to_vec(),
+        };
+        self.propose(command)
+            .map(|res| res.map(|n| n as usize))
+            .boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        KvStore::flush(&self.kvs).boxed()
+    }
+}
+
+fn checkpoint_name(index: u64) -> String {
+    format!("raft-{}", index)
+}
+
+/// Returns when to start an election, at random so nodes rarely start
+/// them together.
+fn election_deadline() -> Instant {
+    let jitter = rand::thread_rng().gen_range(0, ELECTION_TIMEOUT.as_millis() as u64);
+    Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::Request;
+
+/// Key prefixes whose values must never be written to logs.
+///
+/// Everything that logs requests goes through `Redaction::request`, so adding
+/// a prefix here is enough to keep its values out of every log line.
+#[derive(Debug, Default, Clone)]
+pub struct Redaction {
+    prefixes: Arc<Vec<Vec<u8>>>,
+}
+
+impl Redaction {
+    pub fn new<I, P>(prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Vec<u8>>,
+    {
+        Redaction {
+            prefixes: Arc::new(prefixes.into_iter().map(Into::into).collect()),
+        }
+    }
+
+    /// Returns true if values of `key` must not be logged.
+    pub fn is_sensitive(&self, key: &[u8]) -> bool {
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    /// Formats `request` for logging, hiding values of sensitive keys.
+    pub(crate) fn request<'a>(&'a self, request: &'a Request) -> impl fmt::Display + 'a {
+        RedactedRequest {
+            redaction: self,
+            request,
+        }
+    }
+}
+
+struct RedactedRequest<'a> {
+    redaction: &'a Redaction,
+    request: &'a Request,
+}
+
+impl fmt::Display for RedactedRequest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.request {
+            Request::Set { key, value } if !self.redaction.is_sensitive(key.as_bytes()) => {
+                write!(f, "set {:?} {:?}", key, value)
+            }
+            Request::Set { key, value } => {
+                write!(f, "set {:?} <redacted {} bytes>", key, value.len())
+            }
+            Request::Get { key } => write!(f, "get {:?}", key),
+            Request::Remove { key } => write!(f, "remove {:?}", key),
+            Request::CountPrefix { prefix } => write!(f, "count prefix {:?}", prefix),
+            Request::RemovePrefix { prefix, limit } => {
+                write!(f, "remove prefix {:?} limit {}", prefix, limit)
+            }
+            Request::Stats => write!(f, "stats"),
+            Request::Info => write!(f, "info"),
+            Request::CompareAndSet { id, key, value, .. } => match value {
+                Some(value) if !self.redaction.is_sensitive(key.as_bytes()) => {
+                    write!(f, "compare and set {:?} {:?} ({})", key, value, id)
+                }
+                Some(value) => write!(
+                    f,
+                    "compare and set {:?} <redacted {} bytes> ({})",
+                    key,
+                    value.len(),
+                    id
+                ),
+                None => write!(f, "compare and remove {:?} ({})", key, id),
+            },
+            Request::Profile { seconds } => write!(f, "profile {}s", seconds),
+            Request::Digest => write!(f, "digest"),
+            Request::GetTransformed { key, transform } => {
+                write!(f, "get {:?} ({:?})", key, transform)
+            }
+            Request::Configure { name, value } => write!(f, "configure {} = {:?}", name, value),
+            Request::Compact { gen: Some(gen) } => write!(f, "compact log {}", gen),
+            Request::Compact { gen: None } => write!(f, "compact"),
+            Request::SegmentStats => write!(f, "segment stats"),
+            Request::FreezeWrites { timeout } => write!(f, "freeze writes for {:?}", timeout),
+            Request::Thaw => write!(f, "thaw"),
+            Request::Select {
+                namespace: Some(namespace),
+            } => write!(f, "select {:?}", namespace),
+            Request::Select { namespace: None } => write!(f, "select default namespace"),
+            Request::ScanSnapshot {
+                cursor: Some(cursor),
+                ..
+            } => write!(f, "continue scan {}", cursor),
+            Request::ScanSnapshot { prefix, .. } => write!(f, "scan snapshot {:?}", prefix),
+            Request::Subscribe { prefix } => write!(f, "subscribe {:?}", prefix),
+            Request::Unsubscribe => write!(f, "unsubscribe"),
+            Request::Replicate { since } => write!(f, "replicate since {}", since),
+            Request::Bootstrap => write!(f, "bootstrap"),
+            Request::Ping { payload } => write!(f, "ping ({} bytes)", payload.len()),
+            Request::SetChunk { key, data, more } => write!(
+                f,
+                "set {:?} chunk of {} bytes{}",
+                key,
+                data.len(),
+                if *more { "" } else { ", the last" }
+            ),
+            Request::GetChunked { key } => write!(f, "get {:?} in chunks", key),
+            Request::Multiplex => write!(f, "multiplex"),
+            Request::Auth { .. } => write!(f, "auth <redacted>"),
+            Request::Exists { key } => write!(f, "exists {:?}", key),
+            Request::Strlen { key } => write!(f, "strlen {:?}", key),
+            Request::MultiGet { keys } => write!(f, "multi get {:?}", keys),
+            Request::RemoveMany { keys } => write!(f, "remove {:?}", keys),
+            Request::Begin => write!(f, "begin transaction"),
+            Request::Exec => write!(f, "exec transaction"),
+            Request::Discard => write!(f, "discard transaction"),
+            Request::Rename { from, to } => write!(f, "rename {:?} to {:?}", from, to),
+            Request::Copy {
+                from,
+                to,
+                overwrite,
+            } => write!(
+                f,
+                "copy {:?} to {:?}{}",
+                from,
+                to,
+                if *overwrite { ", overwriting" } else { "" }
+            ),
+            Request::MultiSet { pairs } => {
+                write!(f, "multi set")?;
+                for (key, value) in pairs {
+                    if self.redaction.is_sensitive(key.as_bytes()) {
+                        write!(f, " {:?} <redacted {} bytes>", key, value.len())?;
+                    } else {
+                        write!(f, " {:?} {:?}", key, value)?;
+                    }
+                }
+                Ok(())
+            }
+            Request::Batch(requests) => write!(f, "batch of {} requests", requests.len()),
+            Request::Raft(message) => write!(f, "{}", message),
+            Request::Leader => write!(f, "leader"),
+            Request::Forward(op) => write!(f, "{}", op),
+            Request::Topology => write!(f, "topology"),
+            Request::Scan {
+                prefix,
+                start_after: Some(after),
+                ..
+            } => write!(f, "scan {:?} after {:?}", prefix, after),
+            Request::Scan { prefix, .. } => write!(f, "scan {:?}", prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hides_values_of_sensitive_keys() {
+        let redaction = Redaction::new(vec!["secret:"]);
+        let format = |request: Request| redaction.request(&request).to_string();
+
+        let set = |key: &str| Request::Set {
+            key: key.to_owned(),
+            value: "hunter2".to_owned(),
+        };
+        assert_eq!(
+            format(set("secret:1")),
+            r#"set "secret:1" <redacted 7 bytes>"#
+        );
+        assert_eq!(format(set("public")), r#"set "public" "hunter2""#);
+
+        let compare_and_set = |key: &str| Request::CompareAndSet {
+            id: 1,
+            key: key.to_owned(),
+            expected: None,
+            value: Some("hunter2".to_owned()),
+        };
+        assert_eq!(
+            format(compare_and_set("secret:1")),
+            r#"compare and set "secret:1" <redacted 7 bytes> (1)"#
+        );
+        assert_eq!(
+            format(compare_and_set("public")),
+            r#"compare and set "public" "hunter2" (1)"#
+        );
+
+        let multi_set = Request::MultiSet {
+            pairs: vec![
+                ("secret:1".to_owned(), "hunter2".to_owned()),
+                ("public".to_owned(), "visible".to_owned()),
+            ],
+        };
+        assert_eq!(
+            format(multi_set),
+            r#"multi set "secret:1" <redacted 7 bytes> "public" "visible""#
+        );
+    }
+}
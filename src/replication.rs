@@ -0,0 +1,173 @@
+//! Asynchronous replication, see `ServerConfig::replica_of`. A replica
+//! follows the changes made to its primary's store and applies them to its
+//! own, reconnecting whenever the primary is lost.
+
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use futures::stream::StreamExt;
+use log::{info, warn};
+
+use super::rt;
+use super::server::Shutdown;
+use super::{Change, ClientConfig, KvStore, KvsClient, KvsError, Options, Result, SigningKey};
+
+/// How long to wait before reconnecting to a primary that was lost.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes of the archive a primary sends its replica in one chunk.
+pub(crate) const BOOTSTRAP_CHUNK_BYTES: u64 = 1 << 20;
+
+/// Applies the changes of the store of `primary` to `kvs` until the server
+/// shuts down, connecting with `signing_key`.
+///
+/// The first time the replica connects, the keys of `kvs` are replaced with
+/// those of a checkpoint of the primary's store. After that, it continues
+/// from the last change applied.
+pub(crate) async fn follow(
+    primary: SocketAddr,
+    kvs: KvStore,
+    signing_key: Option<SigningKey>,
+    shutdown: Shutdown,
+) {
+    let kvs = kvs.with_client(primary);
+    // The sequence number of the last change applied, once syncing started.
+    let mut synced = None;
+    loop {
+        match follow_once(primary, &kvs, signing_key.clone(), &mut synced, &shutdown).await {
+            Ok(()) if shutdown.peek().is_some() => break,
+            Ok(()) => warn!("Primary {} ended replication", primary),
+            Err(e) => warn!("Error replicating from {}: {}", primary, e),
+        }
+        if unless_shutdown(rt::sleep(RETRY_INTERVAL), &shutdown)
+            .await
+            .is_none()
+        {
+            break;
+        }
+    }
+}
+
+/// Applies changes from one connection to `primary`, until it's lost or the
+/// server shuts down.
+async fn follow_once(
+    primary: SocketAddr,
+    kvs: &KvStore,
+    signing_key: Option<SigningKey>,
+    synced: &mut Option<u64>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let config = ClientConfig {
+        signing_key,
+        ..ClientConfig::default()
+    };
+    let connecting = async {
+        let mut client = KvsClient::connect(primary, config).await?;
+        let since = match *synced {
+            Some(seq) => seq,
+            None => {
+                info!("Syncing from {}", primary);
+                let seq = bootstrap(&mut client, kvs).await?;
+                *synced = Some(seq);
+                seq
+            }
+        };
+        info!("Replicating from {} after change {}", primary, since);
+        client.replicate(since).await
+    };
+    let mut replication = match unless_shutdown(connecting, shutdown).await {
+        Some(replication) => replication?,
+        None => return Ok(()),
+    };
+    loop {
+        let change = match unless_shutdown(replication.next(), shutdown).await {
+            Some(change) => change?,
+            None => return Ok(()),
+        };
+        let change = match change {
+            Some(change) => change,
+            None => return Ok(()),
+        };
+        let seq = change.seq();
+        match change {
+            Change::Set { key, value, .. } => kvs.set(key, value).await?,
+            Change::Remove { key, .. } => match kvs.remove(key).await {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            },
+            Change::RemovePrefix { prefix, .. } => {
+                kvs.delete_prefix(prefix).await?;
+            }
+        }
+        *synced = Some(seq);
+    }
+}
+
+/// Replaces the keys of `kvs` with those of an archive of the primary's
+/// store, streamed by `client`. Returns the sequence number of the last
+/// change in it, which replication continues after.
+async fn bootstrap(client: &mut KvsClient, kvs: &KvStore) -> Result<u64> {
+    let archive = TempPath::new("kvs-bootstrap");
+    client.bootstrap(&archive).await?;
+    let dir = TempPath::new("kvs-bootstrap");
+    KvStore::import_archive(&*archive, &*dir).await?;
+    let options = Options {
+        read_only: true,
+        ..Options::default()
+    };
+    let checkpoint = KvStore::open_with_options(dir.to_path_buf(), options).await?;
+    let seq = checkpoint.seq().await?;
+    kvs.delete_prefix("").await?;
+    let mut pairs = checkpoint.scan(..).await?;
+    while let Some((key, value)) = pairs.next().await.transpose()? {
+        kvs.set(key, value).await?;
+    }
+    Ok(seq)
+}
+
+/// A file or directory in the system's temporary directory, removed once
+/// it's dropped.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Returns a new path, named `prefix` and a random suffix.
+    pub(crate) fn new(prefix: &str) -> Self {
+        let name = format!("{}-{:016x}", prefix, rand::random::<u64>());
+        TempPath(env::temp_dir().join(name))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() {
+            fs::remove_dir_all(&self.0)
+        } else {
+            fs::remove_file(&self.0)
+        };
+    }
+}
+
+/// Runs `future`, unless the server shuts down first.
+pub(crate) async fn unless_shutdown<T>(
+    future: impl Future<Output = T>,
+    shutdown: &Shutdown,
+) -> Option<T> {
+    match future::select(Box::pin(future), shutdown.clone()).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
@@ -0,0 +1,288 @@
+//! Enough of the Redis protocol, RESP, for redis-cli and Redis client
+//! libraries to read and write keys, see `Protocol::Resp`.
+//!
+//! Commands are arrays of bulk strings, or inline commands split on spaces
+//! as typed into telnet. `GET`, `SET`, `MGET`, `MSET`, `DEL`, `EXISTS`,
+//! `STRLEN`, `RENAME`, `COPY`, `PING` and `QUIT` are supported, and `SET`
+//! takes no options. Commands are answered one at a time, in the order they
+//! arrive.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::{self, Either, FutureExt as _};
+use futures::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use log::debug;
+
+use super::rt::{self, TcpStream};
+use super::server::Shutdown;
+use super::{KvsEngine, KvsError, Result, ServerConfig};
+
+/// Longest bulk string accepted, as in Redis.
+const MAX_BULK_LEN: u64 = 512 << 20;
+
+/// Longest line accepted, without its line ending, as Redis limits inline
+/// commands.
+const MAX_LINE_LEN: u64 = 64 << 10;
+
+/// Sent to connections refused for `ServerConfig::max_connections`, as
+/// Redis does.
+pub(crate) const BUSY: &[u8] = b"-ERR max number of clients reached\r\n";
+
+/// Most arguments accepted in one command.
+const MAX_ARGS: usize = 1 << 20;
+
+/// A reply to a command.
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Reply::Simple(s) => format!("+{}\r\n", s).into_bytes(),
+            Reply::Error(e) => format!("-{}\r\n", e.replace(['\r', '\n'], " ")).into_bytes(),
+            Reply::Integer(n) => format!(":{}\r\n", n).into_bytes(),
+            Reply::Bulk(None) => b"$-1\r\n".to_vec(),
+            Reply::Bulk(Some(value)) => {
+                let mut buf = format!("${}\r\n", value.len()).into_bytes();
+                buf.extend_from_slice(value);
+                buf.extend_from_slice(b"\r\n");
+                buf
+            }
+            Reply::Array(replies) => {
+                let mut buf = format!("*{}\r\n", replies.len()).into_bytes();
+                for reply in replies {
+                    buf.extend(reply.encode());
+                }
+                buf
+            }
+        }
+    }
+}
+
+/// Answers the commands of one connection, until the client closes it or
+/// the server shuts down.
+pub(crate) async fn serve(
+    stream: TcpStream,
+    engine: Arc<dyn KvsEngine>,
+    config: Arc<ServerConfig>,
+    shutdown: Shutdown,
+    peer: SocketAddr,
+) -> Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let receiving = match config.idle_timeout {
+            Some(idle_timeout) => rt::timeout(idle_timeout, read_command(&mut reader)).boxed(),
+            None => read_command(&mut reader).map(Some).boxed(),
+        };
+        let command = match future::select(receiving, shutdown.clone()).await {
+            Either::Left((Some(Err(e @ KvsError::TooLarge(..))), _)) => Err(e.to_string()),
+            Either::Left((Some(res), _)) => res?,
+            Either::Left((None, _)) => {
+                debug!("{}: closing idle connection", peer);
+                break;
+            }
+            Either::Right(_) => {
+                debug!("{}: closing for shutdown", peer);
+                break;
+            }
+        };
+        let args = match command {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) => {
+                let reply = Reply::Error(format!("ERR Protocol error: {}", e));
+                writer.write_all(&reply.encode()).await?;
+                break;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        debug!("{}: RESP {}", peer, name);
+        let reply = match handle(&name, &args[1..], &*engine, &config).await {
+            Ok(reply) => reply,
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        };
+        writer.write_all(&reply.encode()).await?;
+        if name == "quit" {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the arguments of the next command, or returns `None` if the
+/// client closed the connection. Malformed commands are returned as the
+/// inner error, to be answered before closing.
+async fn read_command(
+    reader: &mut BufReader<impl AsyncRead + Unpin>,
+) -> Result<std::result::Result<Option<Vec<Vec<u8>>>, String>> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(Ok(None)),
+    };
+    if !line.starts_with(b"*") {
+        let args = line
+            .split(|&b| b == b' ')
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Ok(Some(args)));
+    }
+    let count = match parse_len(&line[1..]) {
+        Some(count) if count <= MAX_ARGS as u64 => count,
+        _ => return Ok(Err("invalid multibulk length".to_owned())),
+    };
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let line = match read_line(reader).await? {
+            Some(line) => line,
+            None => return Ok(Ok(None)),
+        };
+        let len = match line.strip_prefix(b"$").and_then(parse_len) {
+            Some(len) if len <= MAX_BULK_LEN => len,
+            _ => return Ok(Err("invalid bulk length".to_owned())),
+        };
+        let mut arg = Vec::new();
+        (&mut *reader).take(len + 2).read_to_end(&mut arg).await?;
+        if arg.len() as u64 != len + 2 {
+            return Ok(Ok(None));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Ok(Err("expected CRLF after a bulk string".to_owned()));
+        }
+        arg.truncate(len as usize);
+        args.push(arg);
+    }
+    Ok(Ok(Some(args)))
+}
+
+/// Reads a line without its line ending, or returns `None` at the end of
+/// the stream. Lines longer than `MAX_LINE_LEN` fail with
+/// `KvsError::TooLarge`, rather than being buffered whole.
+pub(crate) async fn read_line(
+    reader: &mut BufReader<impl AsyncRead + Unpin>,
+) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let max = MAX_LINE_LEN + 2;
+    if (&mut *reader)
+        .take(max)
+        .read_until(b'\n', &mut line)
+        .await?
+        == 0
+    {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() as u64 == max {
+            return Err(KvsError::TooLarge("line", max, MAX_LINE_LEN));
+        }
+        return Ok(None);
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Option<u64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Runs the command `name`, lowercased, with `args`.
+async fn handle(
+    name: &str,
+    args: &[Vec<u8>],
+    engine: &dyn KvsEngine,
+    config: &ServerConfig,
+) -> Result<Reply> {
+    let arity_ok = match name {
+        "ping" => args.len() <= 1,
+        "quit" => true,
+        "get" | "strlen" => args.len() == 1,
+        "set" | "rename" => args.len() == 2,
+        "copy" => match args {
+            [_, _] => true,
+            [_, _, option] => option.eq_ignore_ascii_case(b"replace"),
+            _ => false,
+        },
+        "del" | "exists" | "mget" => !args.is_empty(),
+        "mset" => !args.is_empty() && args.len().is_multiple_of(2),
+        _ => return Ok(Reply::Error(format!("ERR unknown command '{}'", name))),
+    };
+    if !arity_ok {
+        return Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )));
+    }
+    let op = match name {
+        "get" | "exists" | "strlen" | "mget" => Some("get"),
+        "set" | "mset" => Some("set"),
+        "rename" => Some("rename"),
+        "copy" => Some("copy"),
+        "del" => Some("remove"),
+        _ => None,
+    };
+    if let Some(op) = op {
+        config.chaos.inject(op).await?;
+    }
+    match name {
+        "ping" => Ok(match args.first() {
+            Some(message) => Reply::Bulk(Some(message.clone())),
+            None => Reply::Simple("PONG"),
+        }),
+        "quit" => Ok(Reply::Simple("OK")),
+        "get" => Ok(Reply::Bulk(engine.get(&args[0]).await?)),
+        "strlen" => {
+            let len = engine.value_len(&args[0]).await?.unwrap_or(0);
+            Ok(Reply::Integer(len as i64))
+        }
+        "set" => {
+            engine.set(&args[0], &args[1]).await?;
+            Ok(Reply::Simple("OK"))
+        }
+        "rename" => match engine.rename(&args[0], &args[1]).await {
+            Ok(()) => Ok(Reply::Simple("OK")),
+            Err(KvsError::KeyNotFound) => Ok(Reply::Error("ERR no such key".to_owned())),
+            Err(e) => Err(e),
+        },
+        "copy" => match engine.copy(&args[0], &args[1], args.len() == 3).await {
+            Ok(copied) => Ok(Reply::Integer(copied as i64)),
+            Err(KvsError::KeyNotFound) => Ok(Reply::Integer(0)),
+            Err(e) => Err(e),
+        },
+        "mget" => {
+            let values = engine.multi_get(args).await?;
+            Ok(Reply::Array(values.into_iter().map(Reply::Bulk).collect()))
+        }
+        "mset" => {
+            let pairs: Vec<_> = args
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            engine.set_many(&pairs).await?;
+            Ok(Reply::Simple("OK"))
+        }
+        "del" => Ok(Reply::Integer(engine.remove_many(args).await? as i64)),
+        "exists" => {
+            let mut found = 0;
+            for key in args {
+                if engine.contains_key(key).await? {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        _ => unreachable!("unknown commands are answered above"),
+    }
+}
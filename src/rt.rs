@@ -0,0 +1,102 @@
+//! The runtime used for networking and timers: async-std by default, or
+//! Tokio with the `tokio-runtime` feature. `KvStore` itself works with either.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, FutureExt as _};
+
+#[cfg(not(any(feature = "async-std", feature = "tokio-runtime")))]
+compile_error!("either the `async-std` or the `tokio-runtime` feature must be enabled");
+
+#[cfg(feature = "tokio-runtime")]
+pub use tokio::net::{TcpListener, ToSocketAddrs};
+
+/// A TCP stream implementing the `futures` io traits.
+#[cfg(feature = "tokio-runtime")]
+pub type TcpStream = tokio_util::compat::Compat<tokio::net::TcpStream>;
+
+#[cfg(not(feature = "tokio-runtime"))]
+pub use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+    #[cfg(feature = "tokio-runtime")]
+    {
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        Ok(tokio::net::TcpStream::connect(addr).await?.compat())
+    }
+    #[cfg(not(feature = "tokio-runtime"))]
+    TcpStream::connect(addr).await
+}
+
+pub async fn accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+    #[cfg(feature = "tokio-runtime")]
+    {
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        let (stream, peer) = listener.accept().await?;
+        Ok((stream.compat(), peer))
+    }
+    #[cfg(not(feature = "tokio-runtime"))]
+    listener.accept().await
+}
+
+/// A spawned task. Dropping it detaches the task rather than cancelling it.
+pub struct JoinHandle<T>(BoxFuture<'static, T>);
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        self.0.poll_unpin(cx)
+    }
+}
+
+/// Runs `future` in the background.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(feature = "tokio-runtime")]
+    {
+        let handle = tokio::spawn(future);
+        JoinHandle(async move { handle.await.expect("task panicked or was cancelled") }.boxed())
+    }
+    #[cfg(not(feature = "tokio-runtime"))]
+    JoinHandle(async_std::task::spawn(future).boxed())
+}
+
+pub async fn sleep(duration: Duration) {
+    #[cfg(feature = "tokio-runtime")]
+    tokio::time::sleep(duration).await;
+    #[cfg(not(feature = "tokio-runtime"))]
+    async_std::task::sleep(duration).await;
+}
+
+/// Returns `None` if `future` doesn't complete within `duration`.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    #[cfg(feature = "tokio-runtime")]
+    return tokio::time::timeout(duration, future).await.ok();
+    #[cfg(not(feature = "tokio-runtime"))]
+    return async_std::future::timeout(duration, future).await.ok();
+}
+
+/// Runs `future` to completion on the runtime, blocking the current thread.
+///
+/// With Tokio, a runtime is started on first use and shared afterwards. This
+/// must not be called from within an async context.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    #[cfg(feature = "tokio-runtime")]
+    {
+        static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+        RUNTIME
+            .get_or_init(|| tokio::runtime::Runtime::new().expect("failed to start Tokio"))
+            .block_on(future)
+    }
+    #[cfg(not(feature = "tokio-runtime"))]
+    async_std::task::block_on(future)
+}
@@ -0,0 +1,562 @@
+//! A backup target speaking the S3 API, see `S3Target`.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::{BoxFuture, FutureExt};
+use futures::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::backup::BackupTarget;
+use crate::{rt, KvsError, Result};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The smallest part S3 takes, but for the last.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// A bucket of an S3-compatible object store to back up to.
+///
+/// Objects are named `<prefix><name>` and addressed by path, as in
+/// `http://<endpoint>/<bucket>/<key>`. Requests are signed with AWS
+/// Signature Version 4, which covers the SHA-256 of their payloads, so the
+/// store rejects any corrupted on the way. Objects larger than a part are
+/// uploaded with a multipart upload, one part at a time.
+///
+/// Only `http://` endpoints are supported, as the crate has no TLS, so
+/// reach S3 itself through a local proxy.
+#[derive(Clone)]
+pub struct S3Target {
+    /// The endpoint's authority, like `localhost:9000`, sent as `Host`.
+    host: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    part_size: u64,
+}
+
+impl fmt::Debug for S3Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("S3Target")
+            .field("host", &self.host)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("part_size", &self.part_size)
+            .finish()
+    }
+}
+
+/// A response, whose body is only kept if it wasn't written elsewhere.
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+impl S3Target {
+    /// Backs up to `bucket` at `endpoint`, like `http://localhost:9000`,
+    /// signing requests for `region` with the given credentials.
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        access_key: &str,
+        secret_key: &str,
+    ) -> Result<Self> {
+        let host = endpoint
+            .strip_prefix("http://")
+            .ok_or_else(|| {
+                KvsError::Config(format!(
+                    "only http:// endpoints are supported, not `{}`",
+                    endpoint
+                ))
+            })?
+            .trim_end_matches('/');
+        if host.is_empty() || host.contains('/') {
+            return Err(KvsError::Config(format!("invalid endpoint `{}`", endpoint)));
+        }
+        Ok(S3Target {
+            host: host.to_owned(),
+            bucket: bucket.to_owned(),
+            prefix: String::new(),
+            region: region.to_owned(),
+            access_key: access_key.to_owned(),
+            secret_key: secret_key.to_owned(),
+            part_size: 16 * 1024 * 1024,
+        })
+    }
+
+    /// Names objects `<prefix><name>`, to keep several backups in a bucket.
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        S3Target {
+            prefix: prefix.into(),
+            ..self
+        }
+    }
+
+    /// Uploads objects in parts of `size` bytes, 16MiB by default. Parts
+    /// are buffered in memory, and S3 takes none smaller than 5MiB.
+    pub fn with_part_size(self, size: u64) -> Self {
+        S3Target {
+            part_size: size.max(MIN_PART_SIZE),
+            ..self
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    async fn put_part(&self, key: &str, query: &[(&str, &str)], body: &[u8]) -> Result<Response> {
+        let res = self.send("PUT", key, query, body, None).await?;
+        self.check(&res, "PUT", key)?;
+        Ok(res)
+    }
+
+    async fn put_multipart(&self, key: &str, len: u64, data: &mut (dyn Read + Send)) -> Result<()> {
+        let res = self
+            .send("POST", key, &[("uploads", "")], &[], None)
+            .await?;
+        self.check(&res, "POST", key)?;
+        let upload_id = xml_values(&res.body, "UploadId")
+            .pop()
+            .ok_or_else(|| KvsError::Backup(format!("no upload ID for `{}`", key)))?;
+        let res = self.upload_parts(key, &upload_id, len, data).await;
+        if res.is_err() {
+            // The parts uploaded are kept, and billed, until aborted.
+            let _ = self
+                .send("DELETE", key, &[("uploadId", &upload_id)], &[], None)
+                .await;
+        }
+        res
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        len: u64,
+        data: &mut (dyn Read + Send),
+    ) -> Result<()> {
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        let mut left = len;
+        let mut number = 1;
+        while left > 0 {
+            let part = read_part(data, left.min(self.part_size))?;
+            left -= part.len() as u64;
+            let number_str = number.to_string();
+            let query = [("partNumber", number_str.as_str()), ("uploadId", upload_id)];
+            let res = self.put_part(key, &query, &part).await?;
+            let etag = res
+                .header("ETag")
+                .ok_or_else(|| KvsError::Backup(format!("no ETag for a part of `{}`", key)))?;
+            complete += &format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                number,
+                xml_escape(etag)
+            );
+            number += 1;
+        }
+        complete += "</CompleteMultipartUpload>";
+        let res = self
+            .send(
+                "POST",
+                key,
+                &[("uploadId", upload_id)],
+                complete.as_bytes(),
+                None,
+            )
+            .await?;
+        // Completing may fail after the status is sent, in the body.
+        if res.is_success() && xml_values(&res.body, "Code").is_empty() {
+            Ok(())
+        } else {
+            Err(self.error(&res, "POST", key))
+        }
+    }
+
+    fn check(&self, res: &Response, method: &str, key: &str) -> Result<()> {
+        if res.is_success() {
+            Ok(())
+        } else {
+            Err(self.error(res, method, key))
+        }
+    }
+
+    fn error(&self, res: &Response, method: &str, key: &str) -> KvsError {
+        let code = xml_values(&res.body, "Code").pop().unwrap_or_default();
+        KvsError::Backup(format!(
+            "{} `{}` in bucket `{}` failed with status {} {}",
+            method, key, self.bucket, res.status, code
+        ))
+    }
+
+    /// Sends a signed request for `key`, or the bucket if it's empty. The
+    /// body of a successful response is written to `out` if it's given.
+    async fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+        out: Option<&mut (dyn Write + Send)>,
+    ) -> Result<Response> {
+        let path = if key.is_empty() {
+            format!("/{}", uri_encode(&self.bucket, false))
+        } else {
+            format!(
+                "/{}/{}",
+                uri_encode(&self.bucket, false),
+                uri_encode(key, false)
+            )
+        };
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        let (date, time) = utc(SystemTime::now());
+        let payload_hash = hex(&Sha256::digest(body));
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, self.host, payload_hash, time, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            time,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in &[self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature = hex(&hmac(&key, &string_to_sign));
+
+        let target = if query.is_empty() {
+            path
+        } else {
+            format!("{}?{}", path, query)
+        };
+        let head = format!(
+            "{} {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             x-amz-content-sha256: {}\r\n\
+             x-amz-date: {}\r\n\
+             Authorization: AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            method,
+            target,
+            self.host,
+            payload_hash,
+            time,
+            self.access_key,
+            scope,
+            SIGNED_HEADERS,
+            signature,
+            body.len()
+        );
+        let addr = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{}:80", self.host)
+        };
+        let mut stream = rt::connect(addr.as_str()).await?;
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+        read_response(BufReader::new(stream), out).await
+    }
+}
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+impl BackupTarget for S3Target {
+    fn put<'a>(
+        &'a self,
+        name: &'a str,
+        len: u64,
+        data: &'a mut (dyn Read + Send),
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let key = self.key(name);
+            if len > self.part_size {
+                return self.put_multipart(&key, len, data).await;
+            }
+            let body = read_part(data, len)?;
+            self.put_part(&key, &[], &body).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get<'a>(
+        &'a self,
+        name: &'a str,
+        out: &'a mut (dyn Write + Send),
+    ) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            let key = self.key(name);
+            let res = self.send("GET", &key, &[], &[], Some(out)).await?;
+            if res.status == 404 {
+                return Ok(false);
+            }
+            self.check(&res, "GET", &key)?;
+            Ok(true)
+        }
+        .boxed()
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        async move {
+            let mut names = Vec::new();
+            let mut token: Option<String> = None;
+            loop {
+                let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+                if let Some(token) = &token {
+                    query.push(("continuation-token", token.as_str()));
+                }
+                let res = self.send("GET", "", &query, &[], None).await?;
+                self.check(&res, "GET", "")?;
+                for key in xml_values(&res.body, "Key") {
+                    if let Some(name) = key.strip_prefix(self.prefix.as_str()) {
+                        names.push(name.to_owned());
+                    }
+                }
+                let truncated = xml_values(&res.body, "IsTruncated").pop();
+                token = xml_values(&res.body, "NextContinuationToken").pop();
+                if truncated.as_deref() != Some("true") || token.is_none() {
+                    return Ok(names);
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let key = self.key(name);
+            let res = self.send("DELETE", &key, &[], &[], None).await?;
+            if res.status == 404 {
+                return Ok(());
+            }
+            self.check(&res, "DELETE", &key)
+        }
+        .boxed()
+    }
+}
+
+/// Reads exactly `len` bytes of `data`.
+fn read_part(data: &mut (dyn Read + Send), len: u64) -> Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(len as usize);
+    data.take(len).read_to_end(&mut part)?;
+    if (part.len() as u64) < len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(part)
+}
+
+async fn read_response<R: futures::io::AsyncBufRead + Unpin>(
+    mut stream: R,
+    out: Option<&mut (dyn Write + Send)>,
+) -> Result<Response> {
+    let invalid = || KvsError::Backup("invalid HTTP response".to_owned());
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(invalid());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_at(line.find(':').ok_or_else(invalid)?);
+        headers.push((name.to_owned(), value[1..].trim().to_owned()));
+    }
+    let mut res = Response {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    if res.status == 204 || res.status == 304 {
+        return Ok(res);
+    }
+    let mut body = Vec::new();
+    let out: &mut (dyn Write + Send) = match out {
+        Some(out) if res.is_success() => out,
+        _ => &mut body,
+    };
+    let chunked = res
+        .header("Transfer-Encoding")
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+    let mut buf = vec![0; 64 * 1024];
+    if chunked {
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let mut size = u64::from_str_radix(size, 16).map_err(|_| invalid())?;
+            if size == 0 {
+                // The trailers, up to an empty line.
+                loop {
+                    line.clear();
+                    if stream.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            while size > 0 {
+                let n = size.min(buf.len() as u64) as usize;
+                stream.read_exact(&mut buf[..n]).await?;
+                out.write_all(&buf[..n])?;
+                size -= n as u64;
+            }
+            line.clear();
+            stream.read_line(&mut line).await?;
+        }
+    } else if let Some(len) = res.header("Content-Length") {
+        let mut left: u64 = len.parse().map_err(|_| invalid())?;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            stream.read_exact(&mut buf[..n]).await?;
+            out.write_all(&buf[..n])?;
+            left -= n as u64;
+        }
+    } else {
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            out.write_all(&buf[..n])?;
+        }
+    }
+    res.body = body;
+    Ok(res)
+}
+
+/// Percent-encodes all but unreserved characters, and `/` unless
+/// `slash` is set.
+fn uri_encode(s: &str, slash: bool) -> String {
+    let mut encoded = String::new();
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if !slash => encoded.push('/'),
+            _ => encoded += &format!("%{:02X}", b),
+        }
+    }
+    encoded
+}
+
+/// Returns the text of every `<tag>` element, unescaped.
+fn xml_values(xml: &[u8], tag: &str) -> Vec<String> {
+    let xml = String::from_utf8_lossy(xml);
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = &*xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let end = match rest.find(&close) {
+            Some(end) => end,
+            None => break,
+        };
+        values.push(xml_unescape(&rest[..end]));
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#34;", "\"")
+        .replace("&amp;", "&")
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC takes keys of any size");
+    mac.input(data.as_bytes());
+    mac.result().code().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns `time` in UTC as the date and time of a signature, like
+/// `20200102` and `20200102T030405Z`.
+fn utc(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let secs = secs % 86400;
+    let time = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    (date, time)
+}
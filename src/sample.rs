@@ -0,0 +1,47 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+use crate::kvs::prefix_range;
+use crate::SkipMap;
+
+/// One in this many keys is sampled. It must be a power of two.
+const SAMPLE_RATE: u64 = 64;
+
+/// A sample of the store's keys, chosen by hash so it stays the same however
+/// the keys were written, for estimating how many keys a prefix has without
+/// iterating them all.
+#[derive(Default)]
+pub(crate) struct KeySample(SkipMap<Vec<u8>, ()>);
+
+impl KeySample {
+    fn sampled(key: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        hasher.finish() & (SAMPLE_RATE - 1) == 0
+    }
+
+    pub(crate) fn insert(&self, key: &[u8]) {
+        if Self::sampled(key) {
+            self.0.insert(key.to_vec(), ());
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &[u8]) {
+        if Self::sampled(key) {
+            self.0.remove(key);
+        }
+    }
+
+    /// Samples `keys` from scratch.
+    pub(crate) fn rebuild(&self, keys: impl Iterator<Item = Vec<u8>>) {
+        self.0.clear();
+        for key in keys {
+            self.insert(&key);
+        }
+    }
+
+    /// Estimates how many keys start with `prefix`.
+    pub(crate) fn estimate(&self, prefix: &[u8]) -> u64 {
+        self.0.range(prefix_range(prefix)).count() as u64 * SAMPLE_RATE
+    }
+}
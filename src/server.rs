@@ -1,42 +1,1848 @@
+use std::collections::{HashMap, VecDeque};
 use std::env::current_dir;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use async_std::io::ErrorKind;
-use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use async_std::prelude::*;
-use async_std::task;
-use log::warn;
+use arc_swap::ArcSwap;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, BoxFuture, Either, Future, FutureExt as _, Shared};
+use futures::io::AsyncWriteExt;
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use log::{debug, info, warn};
+use serde::Serialize;
 
-use super::{receive, send, KvStore, KvsError, Request, Result};
+use super::cursor::Cursors;
+use super::engine::ReplicaEngine;
+use super::namespace::Namespaces;
+use super::raft::Raft;
+use super::replication::{TempPath, BOOTSTRAP_CHUNK_BYTES};
+use super::rt::{self, JoinHandle, ToSocketAddrs};
+use super::session::{ConnectionRecorder, Recorder};
+use super::shard::{Forward, ShardedEngine, Shards};
+use super::units::{parse_duration, parse_size};
+use super::{
+    engine, profile, Acl, Chaos, Chunk, ClientsInfo, Cluster, CompactionInfo, Connection,
+    EngineKind, Grant, Info, KeyspaceInfo, KvStore, KvsEngine, KvsError, MemoryEngine, Options,
+    PersistenceInfo, Redaction, Request, Result, ScanPage, ServerError, ServerInfo, Sharding,
+    SigningKey, WatchEvent, VALUE_CHUNK_BYTES,
+};
+use super::{http, memcached, replication, resp};
 
-pub async fn start_server(addr: impl ToSocketAddrs) -> Result<()> {
-    let kvs = KvStore::open(current_dir()?).await?;
-    let listener = TcpListener::bind(addr).await?;
+/// Options for running a `kvs-server`.
+#[derive(Debug, Default, Clone)]
+pub struct ServerConfig {
+    /// Require every request to be signed with this key, and sign responses.
+    pub signing_key: Option<SigningKey>,
 
-    let mut incoming = listener.incoming();
-    while let Some(stream) = incoming.next().await {
-        let mut stream = stream?;
-        let kvs = kvs.clone();
-        task::spawn(async move {
-            if let Err(e) = serve(&mut stream, kvs).await {
-                warn!("Error serving {}: {}", stream.peer_addr().unwrap(), e);
+    /// Require clients to authenticate with one of its tokens, see
+    /// `ClientConfig::token`, and only allow them what the token does.
+    /// Requests it doesn't allow fail with `ServerError::Forbidden`, and
+    /// those before a client authenticates, but pings, with
+    /// `ServerError::AuthRequired`.
+    ///
+    /// Only clients of the kvs protocol can authenticate, so the server
+    /// can't serve the others, nor run with other nodes.
+    pub acl: Option<Acl>,
+
+    /// Keys whose values are hidden from the logs.
+    pub redaction: Redaction,
+
+    /// Faults to inject into request handling.
+    pub chaos: Chaos,
+
+    /// Directory to keep the data in, created if it doesn't exist. If
+    /// `None`, the current directory.
+    ///
+    /// Namespaces clients select are kept in its `namespaces` directory.
+    pub dir: Option<PathBuf>,
+
+    /// The engine serving keys, from data in `dir`. It's recorded there, so
+    /// starting again with another fails with `WrongEngine`, except for
+    /// `Memory`, which keeps nothing there.
+    pub engine: EngineKind,
+
+    /// Options for opening the store, with `EngineKind::Kvs`.
+    pub store: Options,
+
+    /// Serve keys starting with these prefixes from other engines than
+    /// `engine`. The longest matching prefix wins. Only `Memory` is another
+    /// engine, as `engine` is the one in the directory.
+    pub routes: Vec<(String, EngineKind)>,
+
+    /// The protocol clients speak.
+    pub protocol: Protocol,
+
+    /// Close connections that send no request for this long.
+    pub idle_timeout: Option<Duration>,
+
+    /// Refuse connections beyond this many open on every listener, telling
+    /// clients the server is busy. Kvs clients get `ServerError::RateLimited`
+    /// in reply to their first request.
+    pub max_connections: Option<usize>,
+
+    /// Reply with a backpressure error to requests beyond this many in
+    /// flight on one connection.
+    pub max_in_flight: Option<usize>,
+
+    /// Serve the connections of the main listener on this many tasks, each
+    /// taking one connection at a time from a queue of those accepted. If
+    /// `None`, every connection gets a task of its own.
+    pub workers: Option<usize>,
+
+    /// Refuse connections beyond this many waiting for a worker, as with
+    /// `max_connections`. If `None`, as many as there are workers.
+    pub worker_queue: Option<usize>,
+
+    /// Most keys returned by one scan request. If `None`, 1000.
+    pub max_scan_keys: Option<usize>,
+
+    /// Most bytes of keys and values returned by one scan request, though
+    /// a page always holds at least one key. If `None`, 1 MiB.
+    pub max_scan_bytes: Option<u64>,
+
+    /// Refuse requests longer than this many bytes, replying with
+    /// `ServerError::TooLarge` and closing the connection, as the rest of
+    /// the request can't be skipped safely. If `None`, 64 MiB.
+    pub max_frame_size: Option<u64>,
+
+    /// Refuse values set in chunks longer than this many bytes, as each is
+    /// held in memory whole until its last chunk, besides the store's own
+    /// limits. If `None`, 64 MiB.
+    pub max_chunked_value_size: Option<u64>,
+
+    /// Append every request received to this file, for `replay_session`.
+    pub record_session: Option<PathBuf>,
+
+    /// Replace values in recorded requests, keeping their lengths. Values
+    /// of keys `redaction` hides are replaced either way.
+    pub scrub_recorded_values: bool,
+
+    /// Also speak the memcached text protocol on this address, serving the
+    /// same keys.
+    pub memcached_addr: Option<SocketAddr>,
+
+    /// Also serve a JSON API over HTTP on this address, with keys at
+    /// `/keys/{key}` and the store's stats at `/stats`.
+    pub http_addr: Option<SocketAddr>,
+
+    /// Also serve a GraphQL endpoint on this address.
+    #[cfg(feature = "graphql")]
+    pub graphql_addr: Option<SocketAddr>,
+
+    /// Replicate the store of the primary server at this address, which
+    /// must use the kvs engine too, applying its changes as they're made.
+    /// Clients can only read, as writes fail with `KvsError::Replica`, and
+    /// can't select namespaces, which aren't replicated.
+    ///
+    /// Once the replica first reaches its primary, its store is replaced
+    /// with a checkpoint of the primary's, streamed as an archive, and
+    /// changes are applied from there. They're applied asynchronously, so
+    /// reads may not see the latest writes to the primary.
+    pub replica_of: Option<SocketAddr>,
+
+    /// Run as a node of this Raft cluster, which must use the kvs engine,
+    /// replicating every write to a majority of the nodes before it's
+    /// applied. Only the leader serves reads and writes; the other nodes fail
+    /// them with `KvsError::NotLeader`, naming the leader if it's known, see
+    /// `KvsClient::connect_leader`. Namespaces can't be selected, as they
+    /// aren't replicated.
+    ///
+    /// The store is rebuilt from the cluster's log as the server starts, so
+    /// keys it held before joining are removed.
+    pub cluster: Option<Cluster>,
+
+    /// Partition keys across these nodes by consistent hashing, serving
+    /// every key from any node: requests for keys another node owns are
+    /// forwarded to it, and listing keys, as scans and prefix requests do,
+    /// asks every node. Clients can send requests to the owner themselves
+    /// with `KvsClient::topology`. Namespaces can't be selected, renames
+    /// between keys of different nodes aren't atomic, and requests on the
+    /// whole store, like stats, cover this node's keys only.
+    pub sharding: Option<Sharding>,
+}
+
+impl ServerConfig {
+    /// Sets the setting `name` from its textual value, as lines of a `kvs
+    /// server --config` file do. Other names are store options, set with
+    /// `Options::set`. `none` turns off a limit.
+    pub fn set(&mut self, name: &str, value: &str) -> std::result::Result<(), String> {
+        let count = |value: &str| match value {
+            "none" => Ok(None),
+            _ => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("expected a number, got `{}`", value)),
+        };
+        match name {
+            "idle_timeout" => {
+                self.idle_timeout = match value {
+                    "none" => None,
+                    _ => Some(parse_duration(value)?),
+                }
+            }
+            "max_connections" => self.max_connections = count(value)?,
+            "max_in_flight" => self.max_in_flight = count(value)?,
+            "max_scan_keys" => self.max_scan_keys = count(value)?,
+            "max_scan_bytes" => self.max_scan_bytes = Some(parse_size(value)?),
+            "max_frame_size" => self.max_frame_size = Some(parse_size(value)?),
+            "max_chunked_value_size" => self.max_chunked_value_size = Some(parse_size(value)?),
+            _ => self.store.set(name, value)?,
+        }
+        Ok(())
+    }
+}
+
+/// The protocols a server can speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// The protocol of `KvsClient`.
+    #[default]
+    Kvs,
+    /// Enough of the Redis protocol for redis-cli and Redis client libraries
+    /// to get, set and remove keys. Requests can't be signed or recorded.
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(format!(
+                "unknown protocol `{}`, expected `kvs` or `resp`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Kvs => "kvs",
+            Protocol::Resp => "resp",
+        })
+    }
+}
+
+/// Limits of scan requests, unless configured otherwise.
+const MAX_SCAN_KEYS: usize = 1000;
+const MAX_SCAN_BYTES: u64 = 1 << 20;
+
+/// Longest request taken, unless configured otherwise.
+const MAX_FRAME_SIZE: u64 = 64 << 20;
+
+/// Longest value set in chunks, unless configured otherwise.
+const MAX_CHUNKED_VALUE_SIZE: u64 = 64 << 20;
+
+/// How many idempotency keys of `compare_and_set` requests are remembered.
+const IDEMPOTENCY_KEYS: usize = 10_000;
+
+/// Outcomes of recent requests by idempotency key, so retries aren't applied twice.
+#[derive(Default)]
+pub(crate) struct Outcomes {
+    outcomes: HashMap<u64, bool>,
+    order: VecDeque<u64>,
+}
+
+impl Outcomes {
+    fn insert(&mut self, id: u64, outcome: bool) {
+        if self.order.len() == IDEMPOTENCY_KEYS {
+            let oldest = self.order.pop_front().unwrap();
+            self.outcomes.remove(&oldest);
+        }
+        self.order.push_back(id);
+        self.outcomes.insert(id, outcome);
+    }
+}
+
+/// Completes when the server should shut down, see `KvsServer::shutdown`.
+pub(crate) type Shutdown = Shared<BoxFuture<'static, ()>>;
+
+/// A server bound to its addresses with its engine open, which serves once
+/// it's `run`.
+pub struct KvsServer {
+    local_addr: SocketAddr,
+    current: Arc<ArcSwap<ServerConfig>>,
+    engines: Engines,
+    started: Instant,
+    shutdown: Shutdown,
+    /// Completes `shutdown` once sent to or dropped.
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+    /// What `run` serves with, until it's called.
+    bound: Mutex<Option<Bound>>,
+}
+
+/// The listeners of a server, and the tasks it started that `run` waits
+/// for as it shuts down.
+struct Bound {
+    listener: rt::TcpListener,
+    /// Listeners other than the main one, how their connections are served,
+    /// and what's sent to those refused.
+    others: Vec<(rt::TcpListener, Handler, &'static [u8])>,
+    following: Option<JoinHandle<()>>,
+    consensus: Option<JoinHandle<()>>,
+}
+
+impl KvsServer {
+    /// Opens the engine in `config.dir` and binds `addr`, along with the
+    /// other addresses in `config`. A replica starts following its primary
+    /// and a cluster node its cluster, but clients are only served once the
+    /// server is `run`.
+    pub async fn bind(addr: impl ToSocketAddrs, config: ServerConfig) -> Result<Self> {
+        let started = Instant::now();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let shutdown: Shutdown = stopped.map(|_| ()).boxed().shared();
+        let dir = match &config.dir {
+            Some(dir) => dir.clone(),
+            None => current_dir()?,
+        };
+        fs::create_dir_all(&dir)?;
+        if config.protocol == Protocol::Resp {
+            if config.signing_key.is_some() {
+                return Err(KvsError::Config(
+                    "requests can't be signed with the RESP protocol".to_owned(),
+                ));
+            }
+            if config.record_session.is_some() {
+                return Err(KvsError::Config(
+                    "sessions can't be recorded with the RESP protocol".to_owned(),
+                ));
+            }
+        }
+        engine::check_routes(config.engine, &config.routes)?;
+        if config.acl.is_some() {
+            #[cfg(feature = "graphql")]
+            let graphql = config.graphql_addr.is_some();
+            #[cfg(not(feature = "graphql"))]
+            let graphql = false;
+            if config.protocol != Protocol::Kvs
+                || config.memcached_addr.is_some()
+                || config.http_addr.is_some()
+                || graphql
+            {
+                return Err(KvsError::Config(
+                    "only kvs clients can authenticate with an ACL".to_owned(),
+                ));
+            }
+            if config.replica_of.is_some() || config.cluster.is_some() || config.sharding.is_some()
+            {
+                return Err(KvsError::Config(
+                    "a server with an ACL can't run with other nodes".to_owned(),
+                ));
+            }
+        }
+        if config.workers == Some(0) || config.worker_queue == Some(0) {
+            return Err(KvsError::Config(
+                "a server needs a worker and room to queue a connection".to_owned(),
+            ));
+        }
+        if config.replica_of.is_some() && config.engine != EngineKind::Kvs {
+            return Err(KvsError::Config(
+                "only the kvs engine can replicate".to_owned(),
+            ));
+        }
+        if config.cluster.is_some() {
+            if config.engine != EngineKind::Kvs {
+                return Err(KvsError::Config(
+                    "only the kvs engine can run in a cluster".to_owned(),
+                ));
+            }
+            if config.replica_of.is_some() {
+                return Err(KvsError::Config(
+                    "a replica can't run in a cluster".to_owned(),
+                ));
+            }
+        }
+        if config.sharding.is_some() && (config.cluster.is_some() || config.replica_of.is_some()) {
+            return Err(KvsError::Config(
+                "a sharded server can't run in a cluster or as a replica".to_owned(),
+            ));
+        }
+        let memory = MemoryEngine::default();
+        let (kvs, other) = match config.engine {
+            EngineKind::Kvs => {
+                engine::check_recorded(&dir, config.engine)?;
+                let kvs = KvStore::open_with_options(&dir, config.store.clone()).await?;
+                (Some(kvs), None)
+            }
+            EngineKind::Memory => (None, Some(Arc::new(memory.clone()) as Arc<dyn KvsEngine>)),
+            EngineKind::Sled => {
+                engine::check_recorded(&dir, config.engine)?;
+                (None, Some(engine::open_sled(&dir)?))
+            }
+        };
+        let listener = rt::TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let handlers: [(_, Handler, &[u8]); 2] = [
+            (
+                config.memcached_addr,
+                |stream, _, engine, config, shutdown, peer| {
+                    memcached::serve(stream, engine, config, shutdown, peer).boxed()
+                },
+                memcached::BUSY,
+            ),
+            (
+                config.http_addr,
+                |stream, kvs, engine, config, shutdown, peer| {
+                    http::serve(stream, kvs, engine, config, shutdown, peer).boxed()
+                },
+                http::BUSY,
+            ),
+        ];
+        let mut others = Vec::new();
+        for (addr, handler, busy) in handlers {
+            if let Some(addr) = addr {
+                others.push((rt::TcpListener::bind(addr).await?, handler, busy));
+            }
+        }
+        let replicated = config.replica_of.is_some() || config.cluster.is_some();
+        let namespaces = match &kvs {
+            Some(_) if !replicated && config.sharding.is_none() => {
+                Some(Arc::new(Namespaces::new(&dir, config.store.clone())))
+            }
+            _ => None,
+        };
+        let following = match (&kvs, config.replica_of) {
+            (Some(kvs), Some(primary)) => Some(rt::spawn(replication::follow(
+                primary,
+                kvs.clone(),
+                config.signing_key.clone(),
+                shutdown.clone(),
+            ))),
+            _ => None,
+        };
+        let (raft, consensus) = match (&kvs, &config.cluster) {
+            (Some(kvs), Some(cluster)) => {
+                let signing_key = config.signing_key.clone();
+                let (raft, consensus) =
+                    Raft::start(cluster, &dir, kvs.clone(), signing_key, shutdown.clone()).await?;
+                (Some(raft), Some(consensus))
+            }
+            _ => (None, None),
+        };
+        let shards = match &config.sharding {
+            Some(sharding) => Some(Arc::new(Shards::new(sharding, config.signing_key.clone())?)),
+            None => None,
+        };
+        let engines = Engines {
+            kvs,
+            other,
+            memory,
+            namespaces,
+            replica_of: config.replica_of,
+            raft,
+            shards,
+        };
+        Ok(KvsServer {
+            local_addr,
+            current: Arc::new(ArcSwap::from_pointee(config)),
+            engines,
+            started,
+            shutdown,
+            stop: Mutex::new(Some(stop)),
+            bound: Mutex::new(Some(Bound {
+                listener,
+                others,
+                following,
+                consensus,
+            })),
+        })
+    }
+
+    /// Returns the address the server listens on, which tells the port
+    /// picked when bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Serves clients until `shutdown` is called, then shuts down
+    /// gracefully: stops accepting connections, closes each once the
+    /// requests it's handling are answered, and closes the engine, saving
+    /// the store's keydir. Fails at once if the server ran already.
+    pub async fn run(&self) -> Result<()> {
+        let bound = self.bound.lock().unwrap().take();
+        let Bound {
+            listener,
+            others,
+            following,
+            consensus,
+        } = bound.ok_or_else(|| KvsError::Config("the server ran already".to_owned()))?;
+        let engines = &self.engines;
+        let shutdown = &self.shutdown;
+        let config = self.current.load_full();
+        let outcomes = Arc::new(AsyncMutex::new(Outcomes::default()));
+        let recorder = match &config.record_session {
+            Some(path) => Some(Arc::new(Recorder::open(
+                path,
+                config.scrub_recorded_values,
+            )?)),
+            None => None,
+        };
+        #[cfg(feature = "graphql")]
+        {
+            if let Some(addr) = config.graphql_addr {
+                let kvs = engines.kvs.clone().ok_or_else(|| {
+                    KvsError::Config("the GraphQL endpoint needs the kvs engine".to_owned())
+                })?;
+                rt::spawn(async move {
+                    if let Err(e) = super::serve_graphql(addr, kvs).await {
+                        warn!("GraphQL endpoint stopped: {}", e);
+                    }
+                });
+            }
+        }
+
+        // Each connection holds a sender, so the receiver ends once all are closed.
+        let (alive, mut closed) = mpsc::channel::<()>(0);
+        let connections = Connections {
+            alive,
+            open: Arc::new(AtomicUsize::new(0)),
+            config: Arc::clone(&self.current),
+        };
+        let pool = config
+            .workers
+            .map(|workers| WorkerPool::start(workers, config.worker_queue.unwrap_or(workers)));
+        for (other, handler, busy) in others {
+            rt::spawn(serve_listener(
+                other,
+                handler,
+                busy,
+                engines.clone(),
+                Arc::clone(&self.current),
+                shutdown.clone(),
+                connections.clone(),
+            ));
+        }
+        loop {
+            let (stream, peer) =
+                match future::select(rt::accept(&listener).boxed(), shutdown.clone()).await {
+                    Either::Left((accepted, _)) => accepted?,
+                    Either::Right(_) => break,
+                };
+            let config = self.current.load_full();
+            let alive = match connections.admit() {
+                Some(_) if pool.as_ref().is_some_and(WorkerPool::full) => {
+                    debug!("{}: refusing connection, no worker is free", peer);
+                    None
+                }
+                Some(alive) => Some(alive),
+                None => {
+                    debug!("{}: refusing connection, too many are open", peer);
+                    None
+                }
+            };
+            let alive = match alive {
+                Some(alive) => alive,
+                None => {
+                    let alive = connections.alive.clone();
+                    let config = Arc::clone(&config);
+                    rt::spawn(async move {
+                        let res = match config.protocol {
+                            Protocol::Kvs => refuse(stream, &config).await,
+                            Protocol::Resp => refuse_with(stream, resp::BUSY).await,
+                        };
+                        if let Err(e) = res {
+                            debug!("{}: error refusing connection: {}", peer, e);
+                        }
+                        drop(alive);
+                    });
+                    continue;
+                }
+            };
+            let (kvs, engine) = engines.for_client(peer, &config.routes);
+            if config.protocol == Protocol::Resp {
+                let config = Arc::clone(&config);
+                let shutdown = shutdown.clone();
+                let serving = async move {
+                    if let Err(e) = resp::serve(stream, engine, config, shutdown, peer).await {
+                        warn!("Error serving {}: {}", peer, e);
+                    }
+                    drop(alive);
+                };
+                match &pool {
+                    Some(pool) => pool.queue(serving.boxed()),
+                    None => drop(rt::spawn(serving)),
+                }
+                continue;
+            }
+            let state = ServerState {
+                config: Arc::clone(&config),
+                outcomes: Arc::clone(&outcomes),
+                shutdown: shutdown.clone(),
+                namespaces: engines.namespaces.clone(),
+                raft: engines.raft.clone(),
+                shard: engines.shards.clone().map(|shards| {
+                    let (_, local) = engines.local_for_client(peer, &config.routes);
+                    (shards, local)
+                }),
+                started: self.started,
+                connected: Arc::clone(&connections.open),
+            };
+            let recorder = recorder.as_ref().map(Recorder::connection);
+            let serving = async move {
+                let res = async {
+                    let config = &state.config;
+                    let max_frame_size = config.max_frame_size.unwrap_or(MAX_FRAME_SIZE);
+                    let key = config.signing_key.as_ref();
+                    let accepting = Connection::accept(stream, key, max_frame_size);
+                    // A signed connection waits for the client's hello.
+                    let conn = match config.idle_timeout {
+                        Some(idle_timeout) => match rt::timeout(idle_timeout, accepting).await {
+                            Some(conn) => conn?,
+                            None => return Ok(()),
+                        },
+                        None => accepting.await?,
+                    };
+                    serve(conn, kvs, engine, state, recorder, peer).await
+                };
+                if let Err(e) = res.await {
+                    warn!("Error serving {}: {}", peer, e);
+                }
+                drop(alive);
+            };
+            match &pool {
+                Some(pool) => pool.queue(serving.boxed()),
+                None => drop(rt::spawn(serving)),
+            }
+        }
+
+        info!("Shutting down, waiting for connections to close");
+        drop(listener);
+        drop(pool);
+        drop(connections);
+        closed.next().await;
+        if let Some(following) = following {
+            following.await;
+        }
+        if let Some(consensus) = consensus {
+            consensus.await;
+        }
+        if let Some(namespaces) = &engines.namespaces {
+            namespaces.close().await?;
+        }
+        match (&engines.kvs, &engines.other) {
+            (Some(kvs), _) => kvs.clone().close().await?,
+            (None, Some(other)) => other.flush().await?,
+            (None, None) => {}
+        }
+        info!("Shut down");
+        Ok(())
+    }
+
+    /// Makes `run` shut the server down, see there.
+    pub fn shutdown(&self) {
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            let _ = stop.send(());
+        }
+    }
+
+    /// Reconfigures the server with `config`, without restarting it.
+    ///
+    /// Only the settings that are safe to change at runtime are taken from
+    /// it: `signing_key`, `acl` if the server started with one, `redaction`,
+    /// `chaos`, the limits, and the store options, which apply as
+    /// `KvStore::reconfigure` says. Open connections
+    /// keep the config they were accepted with, except for the store options
+    /// and `max_connections`.
+    pub async fn reload(&self, config: ServerConfig) {
+        if let Some(kvs) = &self.engines.kvs {
+            kvs.reconfigure(config.store.clone());
+        }
+        if let Some(namespaces) = &self.engines.namespaces {
+            namespaces.reconfigure(config.store.clone()).await;
+        }
+        let current = ServerConfig::clone(&self.current.load());
+        self.current.store(Arc::new(ServerConfig {
+            signing_key: config.signing_key,
+            acl: config.acl.filter(|_| current.acl.is_some()),
+            redaction: config.redaction,
+            chaos: config.chaos,
+            store: config.store,
+            idle_timeout: config.idle_timeout,
+            max_connections: config.max_connections,
+            max_in_flight: config.max_in_flight,
+            max_scan_keys: config.max_scan_keys,
+            max_scan_bytes: config.max_scan_bytes,
+            max_frame_size: config.max_frame_size,
+            max_chunked_value_size: config.max_chunked_value_size,
+            ..current
+        }));
+        info!("Reloaded the configuration");
+    }
+}
+
+/// The engines a server opened.
+#[derive(Clone)]
+struct Engines {
+    kvs: Option<KvStore>,
+    other: Option<Arc<dyn KvsEngine>>,
+    /// Shared by the engines of every connection.
+    memory: MemoryEngine,
+    namespaces: Option<Arc<Namespaces>>,
+    /// The primary, if the server is a replica serving reads only.
+    replica_of: Option<SocketAddr>,
+    /// The server's node, if it's in a cluster, serving the store's keys.
+    raft: Option<Arc<Raft>>,
+    /// The server's node, if keys are sharded, forwarding those it doesn't
+    /// own.
+    shards: Option<Arc<Shards>>,
+}
+
+impl Engines {
+    /// Returns the store, if it's open, and the engine serving the keys of
+    /// client `peer`. Its mutations are audited as made by the client.
+    fn for_client(
+        &self,
+        peer: SocketAddr,
+        routes: &[(String, EngineKind)],
+    ) -> (Option<KvStore>, Arc<dyn KvsEngine>) {
+        let (kvs, local) = self.local_for_client(peer, routes);
+        let engine: Arc<dyn KvsEngine> = match (&self.shards, self.replica_of) {
+            (Some(shards), _) => Arc::new(ShardedEngine::new(Arc::clone(shards), local)),
+            (None, Some(primary)) => Arc::new(ReplicaEngine::new(local, primary)),
+            (None, None) => local,
+        };
+        (kvs, engine)
+    }
+
+    /// Returns what `for_client` does, but with an engine serving only the
+    /// keys this node holds, as other nodes forward them.
+    fn local_for_client(
+        &self,
+        peer: SocketAddr,
+        routes: &[(String, EngineKind)],
+    ) -> (Option<KvStore>, Arc<dyn KvsEngine>) {
+        let kvs = self.kvs.as_ref().map(|kvs| kvs.with_client(peer));
+        let default = match (&self.raft, &kvs, &self.other) {
+            (Some(raft), _, _) => Arc::clone(raft) as Arc<dyn KvsEngine>,
+            (None, Some(kvs), _) => Arc::new(kvs.clone()),
+            (None, None, Some(other)) => Arc::clone(other),
+            (None, None, None) => unreachable!("the server opens one engine"),
+        };
+        (kvs, engine::routed(default, &self.memory, routes))
+    }
+}
+
+/// Serves a connection to a listener other than the main one.
+type Handler = fn(
+    rt::TcpStream,
+    Option<KvStore>,
+    Arc<dyn KvsEngine>,
+    Arc<ServerConfig>,
+    Shutdown,
+    SocketAddr,
+) -> BoxFuture<'static, Result<()>>;
+
+/// Accepts connections to a listener other than the main one, such as the
+/// memcached one, until the server shuts down. Connections beyond
+/// `max_connections` are sent `busy` and closed.
+async fn serve_listener(
+    listener: rt::TcpListener,
+    handler: Handler,
+    busy: &'static [u8],
+    engines: Engines,
+    config: Arc<ArcSwap<ServerConfig>>,
+    shutdown: Shutdown,
+    connections: Connections,
+) {
+    loop {
+        let (stream, peer) =
+            match future::select(rt::accept(&listener).boxed(), shutdown.clone()).await {
+                Either::Left((Ok(accepted), _)) => accepted,
+                Either::Left((Err(e), _)) => {
+                    warn!("Listener stopped: {}", e);
+                    break;
+                }
+                Either::Right(_) => break,
+            };
+        let alive = match connections.admit() {
+            Some(alive) => alive,
+            None => {
+                debug!("{}: refusing connection, too many are open", peer);
+                let alive = connections.alive.clone();
+                rt::spawn(async move {
+                    if let Err(e) = refuse_with(stream, busy).await {
+                        debug!("{}: error refusing connection: {}", peer, e);
+                    }
+                    drop(alive);
+                });
+                continue;
             }
+        };
+        let config = config.load_full();
+        let (kvs, engine) = engines.for_client(peer, &config.routes);
+        let serving = handler(stream, kvs, engine, config, shutdown.clone(), peer);
+        rt::spawn(async move {
+            if let Err(e) = serving.await {
+                warn!("Error serving {}: {}", peer, e);
+            }
+            drop(alive);
         });
     }
+}
+
+/// Tasks serving the connections of the main listener, see
+/// `ServerConfig::workers`.
+struct WorkerPool {
+    queue: mpsc::UnboundedSender<BoxFuture<'static, ()>>,
+    /// Connections waiting for a worker.
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+}
+
+impl WorkerPool {
+    /// Starts `workers` tasks, which end once the pool is dropped and the
+    /// connections queued are served.
+    fn start(workers: usize, max_queued: usize) -> Self {
+        let (queue, jobs) = mpsc::unbounded::<BoxFuture<'static, ()>>();
+        let jobs = Arc::new(AsyncMutex::new(jobs));
+        let queued = Arc::new(AtomicUsize::new(0));
+        for _ in 0..workers {
+            let jobs = Arc::clone(&jobs);
+            let queued = Arc::clone(&queued);
+            rt::spawn(async move {
+                loop {
+                    // The lock is released before serving the connection.
+                    let serving = jobs.lock().await.next().await;
+                    match serving {
+                        Some(serving) => {
+                            queued.fetch_sub(1, Ordering::SeqCst);
+                            serving.await;
+                        }
+                        None => break,
+                    }
+                }
+            });
+        }
+        WorkerPool {
+            queue,
+            queued,
+            max_queued,
+        }
+    }
+
+    /// Whether `max_queued` connections are waiting already.
+    fn full(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) >= self.max_queued
+    }
+
+    /// Queues `serving` for the next free worker.
+    fn queue(&self, serving: BoxFuture<'static, ()>) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let _ = self.queue.unbounded_send(serving);
+    }
+}
+
+/// How long a connection refused for `max_connections` is waited on for
+/// its first request, to answer it.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The open connections of a server, on every listener.
+#[derive(Clone)]
+struct Connections {
+    /// Held by every connection, so shutdown can wait for them to close.
+    alive: mpsc::Sender<()>,
+    open: Arc<AtomicUsize>,
+    /// The server's current config, whose `max_connections` applies.
+    config: Arc<ArcSwap<ServerConfig>>,
+}
+
+/// Counts a connection as open until it's dropped.
+struct Admitted {
+    _alive: mpsc::Sender<()>,
+    open: Arc<AtomicUsize>,
+}
+
+impl Connections {
+    /// Counts a new connection as open, unless `max_connections` are already.
+    fn admit(&self) -> Option<Admitted> {
+        let open = self.open.fetch_add(1, Ordering::SeqCst);
+        let admitted = Admitted {
+            _alive: self.alive.clone(),
+            open: Arc::clone(&self.open),
+        };
+        match self.config.load().max_connections {
+            Some(max) if open >= max => None,
+            _ => Some(admitted),
+        }
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answers the first request of a connection refused for `max_connections`
+/// with `RateLimited`, then closes it. The request is read first, so the
+/// client can't miss the reply as the connection is reset.
+async fn refuse(stream: rt::TcpStream, config: &ServerConfig) -> Result<()> {
+    let max_frame_size = config.max_frame_size.unwrap_or(MAX_FRAME_SIZE);
+    let key = config.signing_key.as_ref();
+    let conn = match rt::timeout(
+        REFUSAL_TIMEOUT,
+        Connection::accept(stream, key, max_frame_size),
+    )
+    .await
+    {
+        Some(conn) => conn?,
+        None => return Ok(()),
+    };
+    let (mut receiver, mut sender) = conn.split();
+    if let Some(Ok(_)) = rt::timeout(REFUSAL_TIMEOUT, receiver.receive::<Request>()).await {
+        sender
+            .send_encoded(encode::<()>(Err(KvsError::ServerBusy))?)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Sends `busy` to a connection refused for `max_connections`, then closes
+/// it.
+async fn refuse_with(mut stream: rt::TcpStream, busy: &[u8]) -> Result<()> {
+    stream.write_all(busy).await?;
     Ok(())
 }
 
-async fn serve(stream: &mut TcpStream, kvs: KvStore) -> Result<()> {
+/// What the connections of a server share.
+struct ServerState {
+    config: Arc<ServerConfig>,
+    outcomes: Arc<AsyncMutex<Outcomes>>,
+    shutdown: Shutdown,
+    /// The namespaces clients can select, with the kvs engine.
+    namespaces: Option<Arc<Namespaces>>,
+    /// The server's node, if it's in a cluster.
+    raft: Option<Arc<Raft>>,
+    /// The server's node, if keys are sharded, and the engine serving the
+    /// keys it owns.
+    shard: Option<(Arc<Shards>, Arc<dyn KvsEngine>)>,
+    /// When the server started, and how many connections are open, for
+    /// `Request::Info`.
+    started: Instant,
+    connected: Arc<AtomicUsize>,
+}
+
+/// Handles the requests of one connection, until the client closes it or
+/// the server shuts down.
+///
+/// Pipelined requests are handled concurrently, and their replies are sent
+/// in the order the requests arrived. A subscription is sent as a reply
+/// that goes on until it's stopped, so later replies wait for it.
+async fn serve(
+    conn: Connection,
+    kvs: Option<KvStore>,
+    engine: Arc<dyn KvsEngine>,
+    state: ServerState,
+    recorder: Option<ConnectionRecorder>,
+    peer: SocketAddr,
+) -> Result<()> {
+    let ServerState {
+        config,
+        outcomes,
+        shutdown,
+        namespaces,
+        raft,
+        shard,
+        started,
+        connected,
+    } = state;
+    let default = (kvs.clone(), Arc::clone(&engine));
+    let cursors = Arc::new(Cursors::default());
+    let (mut kvs, mut engine) = (kvs, engine);
+    let (mut receiver, mut sender) = conn.split();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let (replies, queue) = mpsc::unbounded::<BoxStream<'static, Result<Vec<u8>>>>();
+    // Replies to multiplexed requests, sent as they're ready.
+    let (tagged, tagged_queue) = mpsc::unbounded::<Result<Vec<u8>>>();
+    let sending = rt::spawn({
+        let in_flight = Arc::clone(&in_flight);
+        async move {
+            // `None` follows the last frame of each reply.
+            let ordered =
+                queue.flat_map(|frames| frames.map(Some).chain(stream::once(future::ready(None))));
+            let tagged = tagged_queue.flat_map(|frame| stream::iter([Some(frame), None]));
+            let mut frames = stream::select(ordered, tagged);
+            while let Some(frame) = frames.next().await {
+                match frame {
+                    Some(frame) => sender.send_encoded(frame?).await?,
+                    None => {
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+            }
+            Result::<_>::Ok(sender)
+        }
+    });
+    // Stops the current subscription or replication when sent to or dropped.
+    let mut subscribed: Option<oneshot::Sender<()>> = None;
+    // The reply to a request refused along with the connection, and why.
+    let mut refused = None;
+    // The value being set in chunks.
+    let mut chunked: Option<ChunkedSet> = None;
+    // Whether requests are sent with IDs, see `Request::Multiplex`.
+    let mut multiplexed = false;
+    // What the connection's auth token allows, once it authenticated.
+    let mut grant: Option<Grant> = None;
+    // The writes queued since `Request::Begin`, until `Exec` or `Discard`.
+    let mut transaction: Option<Vec<Request>> = None;
+
     loop {
-        let response = match receive(stream).await {
-            Ok(buf) => match bincode::deserialize(&buf)? {
-                Request::Get { key } => kvs.get(key).await,
-                Request::Set { key, value } => kvs.set(key, value).await.map(|()| None),
-                Request::Remove { key } => kvs.remove(key).await.map(|()| None),
-            },
-            Err(KvsError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+        // Subscribed and replicating clients only send to unsubscribe.
+        let idle_timeout = match subscribed {
+            Some(_) => None,
+            None => config.idle_timeout,
+        };
+        let receiving = async {
+            if multiplexed {
+                let (id, request) = receiver.receive::<(u64, Request)>().await?;
+                Ok((Some(id), request))
+            } else {
+                Ok((None, receiver.receive().await?))
+            }
+        };
+        let receiving = match idle_timeout {
+            Some(idle_timeout) => rt::timeout(idle_timeout, receiving).boxed(),
+            None => receiving.map(Some).boxed(),
+        };
+        let request = match future::select(receiving, shutdown.clone()).await {
+            Either::Left((Some(res), _)) => res,
+            Either::Left((None, _)) => {
+                debug!("{}: closing idle connection", peer);
+                break;
+            }
+            Either::Right(_) => {
+                debug!("{}: closing for shutdown", peer);
+                break;
+            }
+        };
+        let (id, request) = match request {
+            Ok(request) => request,
+            Err(KvsError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            // Told once the replies to earlier requests are sent.
+            Err(e @ (KvsError::Signature(_) | KvsError::Serde(_)))
+                if config.signing_key.is_some() =>
+            {
+                refused = Some((ServerError::AuthRequired, e));
+                break;
+            }
+            Err(e @ KvsError::TooLarge(..)) => {
+                refused = Some((ServerError::from(&e), e));
+                break;
+            }
             Err(e) => return Err(e),
+        };
+        debug!("{}: {}", peer, config.redaction.request(&request));
+        let mut denied = match (&config.acl, &grant, &request) {
+            (None, _, _) | (_, _, Request::Auth { .. } | Request::Ping { .. }) => None,
+            (Some(_), Some(grant), request) => grant.check(request).err(),
+            (Some(_), None, _) => Some(KvsError::Unauthenticated),
+        };
+        // Messages between nodes aren't the client's requests, chunks are
+        // recorded as the set they add up to, tokens aren't recorded, and
+        // transactions are recorded as the batch of their writes once applied.
+        let internal = transaction.is_some()
+            || matches!(
+                request,
+                Request::Raft(_)
+                    | Request::Forward(_)
+                    | Request::SetChunk { .. }
+                    | Request::Auth { .. }
+                    | Request::Begin
+                    | Request::Exec
+                    | Request::Discard
+            );
+        if let (Some(recorder), false, None) = (&recorder, internal, &denied) {
+            recorder.record(&request, &config.redaction);
+        }
+        if let Some(id) = id {
+            let handling = if let Some(e) = denied {
+                future::ready(encode::<()>(Err(e))).boxed()
+            } else if !request.batchable() {
+                let res = encode::<()>(Err(KvsError::NotMultiplexable(request.op())));
+                future::ready(res).boxed()
+            } else if config
+                .max_in_flight
+                .is_some_and(|max| in_flight.load(Ordering::SeqCst) >= max)
+            {
+                debug!("{}: too many requests in flight", peer);
+                future::ready(encode::<()>(Err(KvsError::Backpressure))).boxed()
+            } else {
+                handle(
+                    request,
+                    kvs.clone(),
+                    Arc::clone(&engine),
+                    Arc::clone(&config),
+                    Arc::clone(&outcomes),
+                    Arc::clone(&cursors),
+                )
+                .boxed()
+            };
+            in_flight.fetch_add(1, Ordering::SeqCst);
+            let tagged = tagged.clone();
+            rt::spawn(async move {
+                let frame = async {
+                    let mut frame = bincode::serialize(&id)?;
+                    frame.extend(handling.await?);
+                    Ok(frame)
+                };
+                // Sending failed if the channel is closed, the error is
+                // returned below.
+                let _ = tagged.unbounded_send(frame.await);
+            });
+            continue;
+        }
+        let reply = match (request, config.max_in_flight) {
+            _ if denied.is_some() => {
+                debug!("{}: request refused by the ACL", peer);
+                let res = encode::<()>(Err(denied.take().unwrap()));
+                future::ready(res).into_stream().boxed()
+            }
+            (Request::Auth { token }, _) => {
+                let res = match &config.acl {
+                    Some(acl) => {
+                        let res = acl.grant(&token).cloned();
+                        grant = res.as_ref().ok().cloned();
+                        res.map(|_| ())
+                    }
+                    // Every request is allowed without an ACL.
+                    None => Ok(()),
+                };
+                future::ready(encode(res)).into_stream().boxed()
+            }
+            (Request::Begin, _) => {
+                let res = match transaction {
+                    Some(_) => Err(KvsError::Transaction("one is open already")),
+                    None => {
+                        transaction = Some(Vec::new());
+                        Ok(())
+                    }
+                };
+                future::ready(encode(res)).into_stream().boxed()
+            }
+            (Request::Discard, _) => {
+                let res = match transaction.take() {
+                    Some(_) => Ok(()),
+                    None => Err(KvsError::Transaction("none is open")),
+                };
+                future::ready(encode(res)).into_stream().boxed()
+            }
+            (Request::Exec, _) => match transaction.take() {
+                Some(queued) => {
+                    if let (Some(recorder), false) = (&recorder, queued.is_empty()) {
+                        recorder.record(&Request::Batch(queued.clone()), &config.redaction);
+                    }
+                    let writes: Vec<_> = queued
+                        .into_iter()
+                        .map(|request| match request {
+                            Request::Set { key, value } => {
+                                (key.into_bytes(), Some(value.into_bytes()))
+                            }
+                            Request::Remove { key } => (key.into_bytes(), None),
+                            _ => unreachable!("only sets and removes are queued"),
+                        })
+                        .collect();
+                    let engine = Arc::clone(&engine);
+                    let config = Arc::clone(&config);
+                    let applying = async move {
+                        config.chaos.inject("exec").await?;
+                        engine.transact(&writes).await
+                    };
+                    rt::spawn(async move { encode(applying.await) })
+                        .into_stream()
+                        .boxed()
+                }
+                None => {
+                    let res = encode::<()>(Err(KvsError::Transaction("none is open")));
+                    future::ready(res).into_stream().boxed()
+                }
+            },
+            // Pings are answered at once, in a transaction too.
+            (request, _) if transaction.is_some() && !matches!(request, Request::Ping { .. }) => {
+                let res = match request {
+                    request @ (Request::Set { .. } | Request::Remove { .. }) => {
+                        transaction.as_mut().unwrap().push(request);
+                        Ok(())
+                    }
+                    request => Err(KvsError::NotTransactional(request.op())),
+                };
+                future::ready(encode(res)).into_stream().boxed()
+            }
+            // Handled before later requests are read, which it applies to.
+            (Request::Select { namespace }, _) => {
+                let res = match namespace {
+                    Some(namespace) => match &namespaces {
+                        Some(namespaces) => namespaces.get(&namespace).await.map(|store| {
+                            let store = store.with_client(peer);
+                            (Some(store.clone()), Arc::new(store) as Arc<dyn KvsEngine>)
+                        }),
+                        None => Err(KvsError::EngineUnsupported("select")),
+                    },
+                    None => Ok(default.clone()),
+                };
+                let res = res.map(|selected| (kvs, engine) = selected);
+                future::ready(encode(res)).into_stream().boxed()
+            }
+            (Request::Multiplex, _) => {
+                multiplexed = true;
+                future::ready(encode(Ok(()))).into_stream().boxed()
+            }
+            (Request::Subscribe { prefix }, _) => match &kvs {
+                Some(kvs) => {
+                    let (stop, stopped) = oneshot::channel();
+                    // A subscription replacing another ends it first.
+                    subscribed = Some(stop);
+                    pushed(kvs.watch(prefix), stopped)
+                }
+                None => {
+                    let res = encode::<()>(Err(KvsError::EngineUnsupported("subscribe")));
+                    future::ready(res).into_stream().boxed()
+                }
+            },
+            (Request::Replicate { since }, _) => {
+                let res = match &kvs {
+                    Some(kvs) => kvs.changes_since(since).await,
+                    None => Err(KvsError::EngineUnsupported("replicate")),
+                };
+                match res {
+                    Ok(changes) => {
+                        let (stop, stopped) = oneshot::channel();
+                        subscribed = Some(stop);
+                        pushed(changes, stopped)
+                    }
+                    Err(e) => future::ready(encode::<()>(Err(e))).into_stream().boxed(),
+                }
+            }
+            (Request::Bootstrap, _) => match &kvs {
+                Some(kvs) => archived(kvs.clone()),
+                None => {
+                    let res = encode::<()>(Err(KvsError::EngineUnsupported("bootstrap")));
+                    future::ready(res).into_stream().boxed()
+                }
+            },
+            (Request::Unsubscribe, _) => match subscribed.take() {
+                // The subscription or replication sends the reply as it ends.
+                Some(_) => continue,
+                None => {
+                    let end = bincode::serialize(&None::<WatchEvent>).map_err(KvsError::from);
+                    future::ready(end).into_stream().boxed()
+                }
+            },
+            // Handled whatever's in flight, so nodes keep up with each other.
+            (Request::Raft(message), _) => match &raft {
+                Some(raft) => {
+                    let raft = Arc::clone(raft);
+                    let receiving = async move { encode(raft.receive(message).await) };
+                    rt::spawn(receiving).into_stream().boxed()
+                }
+                None => {
+                    let res = encode::<()>(Err(KvsError::EngineUnsupported("raft")));
+                    future::ready(res).into_stream().boxed()
+                }
+            },
+            (Request::Leader, _) => {
+                let leader = raft.as_ref().and_then(|raft| raft.leader());
+                future::ready(encode(Ok(leader))).into_stream().boxed()
+            }
+            // Nodes forward one operation at a time, so they're never held up.
+            (Request::Forward(op), _) => match &shard {
+                Some((_, local)) => {
+                    let local = Arc::clone(local);
+                    rt::spawn(async move { forwarded(op, &*local).await })
+                        .into_stream()
+                        .boxed()
+                }
+                None => {
+                    let res = encode::<()>(Err(KvsError::EngineUnsupported("forward")));
+                    future::ready(res).into_stream().boxed()
+                }
+            },
+            (Request::Topology, _) => {
+                let nodes = shard.as_ref().map(|(shards, _)| shards.nodes().to_vec());
+                future::ready(encode(Ok(nodes))).into_stream().boxed()
+            }
+            (Request::Ping { payload }, _) => {
+                future::ready(encode(Ok(payload))).into_stream().boxed()
+            }
+            // Handled whatever's in flight, as a refused chunk would leave
+            // the value incomplete.
+            (Request::SetChunk { key, data, more }, _) => {
+                match add_chunk(&mut chunked, key, data, more, &config) {
+                    Ok(Some(request)) => {
+                        if let Some(recorder) = &recorder {
+                            recorder.record(&request, &config.redaction);
+                        }
+                        let handling = handle(
+                            request,
+                            kvs.clone(),
+                            Arc::clone(&engine),
+                            Arc::clone(&config),
+                            Arc::clone(&outcomes),
+                            Arc::clone(&cursors),
+                        );
+                        rt::spawn(handling).into_stream().boxed()
+                    }
+                    res => future::ready(encode(res.map(|_| ()))).into_stream().boxed(),
+                }
+            }
+            (_, Some(max)) if in_flight.load(Ordering::SeqCst) >= max => {
+                debug!("{}: too many requests in flight", peer);
+                future::ready(encode::<()>(Err(KvsError::Backpressure)))
+                    .into_stream()
+                    .boxed()
+            }
+            (Request::Info, _) => {
+                let info = info(
+                    kvs.clone(),
+                    Arc::clone(&engine),
+                    Arc::clone(&config),
+                    started,
+                    Arc::clone(&connected),
+                );
+                rt::spawn(async move { encode(info.await) })
+                    .into_stream()
+                    .boxed()
+            }
+            (Request::GetChunked { key }, _) => {
+                let engine = Arc::clone(&engine);
+                let config = Arc::clone(&config);
+                chunks(async move {
+                    config.chaos.inject("get").await?;
+                    engine.get(key.as_bytes()).await
+                })
+            }
+            (request, _) => {
+                let handling = handle(
+                    request,
+                    kvs.clone(),
+                    Arc::clone(&engine),
+                    Arc::clone(&config),
+                    Arc::clone(&outcomes),
+                    Arc::clone(&cursors),
+                );
+                rt::spawn(handling).into_stream().boxed()
+            }
+        };
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        if replies.unbounded_send(reply).is_err() {
+            // Sending failed, the error is returned below.
+            break;
         }
-        .map_err(|e| e.to_string());
-        send(stream, &response).await?;
     }
+    drop(subscribed);
+    drop(replies);
+    drop(tagged);
+    let mut sender = sending.await?;
+    if let Some((reply, e)) = refused {
+        let refusal = bincode::serialize(&Err::<(), _>(reply.clone()))?;
+        match reply {
+            // Unsigned, as the client can't verify a signed reply.
+            ServerError::AuthRequired => sender.send_unsigned(refusal).await?,
+            _ => {
+                sender.send_encoded(refusal).await?;
+                // The rest of the request is read until the client closes the
+                // connection, as closing it with data unread would reset it,
+                // dropping the reply.
+                sender.close().await?;
+                rt::timeout(REFUSAL_TIMEOUT, receiver.drain()).await;
+            }
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Returns the frames sent for a subscription or replication: the reply to
+/// the request, then `Some(event)` per event until `stopped`, then `None`.
+fn pushed<T: Serialize + Send + 'static>(
+    events: impl Stream<Item = T> + Send + 'static,
+    stopped: oneshot::Receiver<()>,
+) -> BoxStream<'static, Result<Vec<u8>>> {
+    let events = events.map(Some);
+    let stop = stopped.into_stream().map(|_| None);
+    let frames = stream::select(events, stop)
+        .take_while(|event| future::ready(event.is_some()))
+        .chain(stream::once(future::ready(None)))
+        .map(|event: Option<T>| Ok(bincode::serialize(&event)?));
+    stream::once(future::ready(encode::<()>(Ok(()))))
+        .chain(frames)
+        .boxed()
+}
+
+/// Returns the frames sent for a bootstrap: the reply to the request, then
+/// `Some(chunk)` per chunk of an archive of `kvs` taken now, then `None`.
+fn archived(kvs: KvStore) -> BoxStream<'static, Result<Vec<u8>>> {
+    let exporting = async move {
+        let archive = TempPath::new("kvs-bootstrap");
+        kvs.export_archive(&*archive).await?;
+        let file = File::open(&*archive)?;
+        Ok((archive, file))
+    };
+    exporting
+        .into_stream()
+        .flat_map(|res: Result<_>| match res {
+            Ok(exported) => {
+                let chunks = stream::unfold(Some(exported), |exported| async move {
+                    let (archive, mut file) = exported?;
+                    let mut chunk = Vec::new();
+                    let read = (&mut file)
+                        .take(BOOTSTRAP_CHUNK_BYTES)
+                        .read_to_end(&mut chunk);
+                    match read {
+                        Ok(0) => Some((Ok(None), None)),
+                        Ok(_) => Some((Ok(Some(chunk)), Some((archive, file)))),
+                        Err(e) => Some((Err(e.into()), None)),
+                    }
+                });
+                stream::once(future::ready(encode::<()>(Ok(()))))
+                    .chain(
+                        chunks
+                            .map(|chunk: Result<Option<Vec<u8>>>| Ok(bincode::serialize(&chunk?)?)),
+                    )
+                    .boxed()
+            }
+            Err(e) => future::ready(encode::<()>(Err(e))).into_stream().boxed(),
+        })
+        .boxed()
+}
+
+/// A value a client is setting in chunks, see `Request::SetChunk`.
+struct ChunkedSet {
+    key: String,
+    /// The chunks so far, or `None` once the value was refused.
+    value: Option<Vec<u8>>,
+}
+
+/// Adds a chunk of the value of `key` to `chunked`, which starts over with
+/// a chunk of another key. Returns the set of the whole value once its last
+/// chunk is added. Fails as the value outgrows `max_chunked_value_size` or
+/// the limits of the store, and its later chunks are ignored.
+fn add_chunk(
+    chunked: &mut Option<ChunkedSet>,
+    key: String,
+    data: Vec<u8>,
+    more: bool,
+    config: &ServerConfig,
+) -> Result<Option<Request>> {
+    if chunked.as_ref().is_none_or(|set| set.key != key) {
+        *chunked = Some(ChunkedSet {
+            key,
+            value: Some(Vec::new()),
+        });
+    }
+    let set = chunked.as_mut().unwrap();
+    let mut res = Ok(());
+    if let Some(value) = &mut set.value {
+        value.extend_from_slice(&data);
+        let max = config
+            .max_chunked_value_size
+            .unwrap_or(MAX_CHUNKED_VALUE_SIZE);
+        res = match value.len() as u64 {
+            len if len > max => Err(KvsError::TooLarge("value", len, max)),
+            _ => config.store.check_size(set.key.as_bytes(), value),
+        };
+        if res.is_err() {
+            set.value = None;
+        }
+    }
+    if more {
+        return res.map(|()| None);
+    }
+    let ChunkedSet { key, value } = chunked.take().unwrap();
+    res?;
+    match value.map(String::from_utf8) {
+        Some(Ok(value)) => Ok(Some(Request::Set { key, value })),
+        Some(Err(e)) => Err(io::Error::new(ErrorKind::InvalidData, e).into()),
+        None => Ok(None),
+    }
+}
+
+/// Returns the frames sent for a value in chunks: the reply to the request,
+/// with the first `Chunk` of the value `getting` returns if it's set, then a
+/// frame per other chunk.
+fn chunks(
+    getting: impl Future<Output = Result<Option<Vec<u8>>>> + Send + 'static,
+) -> BoxStream<'static, Result<Vec<u8>>> {
+    rt::spawn(getting)
+        .into_stream()
+        .flat_map(|res| {
+            let value = match res {
+                Ok(Some(value)) => value,
+                res => {
+                    let reply = encode(res.map(|_| None::<Chunk>));
+                    return future::ready(reply).into_stream().boxed();
+                }
+            };
+            let count = value.len().div_ceil(VALUE_CHUNK_BYTES).max(1);
+            let frames = (0..count).map(move |i| {
+                let end = value.len().min((i + 1) * VALUE_CHUNK_BYTES);
+                let chunk = Chunk {
+                    data: value[i * VALUE_CHUNK_BYTES..end].to_vec(),
+                    more: i + 1 < count,
+                };
+                match i {
+                    0 => encode(Ok(Some(chunk))),
+                    _ => Ok(bincode::serialize(&chunk)?),
+                }
+            });
+            stream::iter(frames).boxed()
+        })
+        .boxed()
+}
+
+/// Returns a summary of the server, with the keyspace and store of the
+/// connection's namespace.
+async fn info(
+    kvs: Option<KvStore>,
+    engine: Arc<dyn KvsEngine>,
+    config: Arc<ServerConfig>,
+    started: Instant,
+    connected: Arc<AtomicUsize>,
+) -> Result<Info> {
+    let role = if let Some(primary) = config.replica_of {
+        format!("replica of {}", primary)
+    } else if let Some(cluster) = &config.cluster {
+        format!(
+            "node {} of a cluster of {}",
+            cluster.id,
+            cluster.nodes.len()
+        )
+    } else if let Some(sharding) = &config.sharding {
+        format!("node {} of {} shards", sharding.id, sharding.nodes.len())
+    } else {
+        "standalone".to_owned()
+    };
+    let server = ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        engine: config.engine.to_string(),
+        role,
+        uptime: started.elapsed(),
+    };
+    let clients = ClientsInfo {
+        connected: connected.load(Ordering::SeqCst) as u64,
+        max_connections: config.max_connections.map(|max| max as u64),
+    };
+    let kvs = match kvs {
+        Some(kvs) => kvs,
+        None => {
+            let keys = engine.keys_with_prefix(b"").await?;
+            return Ok(Info {
+                server,
+                clients,
+                persistence: None,
+                keyspace: KeyspaceInfo {
+                    keys: keys.len() as u64,
+                    live_bytes: None,
+                },
+                compaction: None,
+            });
+        }
+    };
+    let stats = kvs.stats().await?;
+    Ok(Info {
+        server,
+        clients,
+        persistence: Some(PersistenceInfo {
+            seq: kvs.seq().await?,
+            durable: kvs.durable_watermark().await,
+            recovered: kvs.recovery_watermark(),
+            sync_interval: kvs.options().sync_interval,
+        }),
+        keyspace: KeyspaceInfo {
+            keys: stats.keys,
+            live_bytes: Some(stats.live_bytes),
+        },
+        compaction: Some(CompactionInfo {
+            dead_bytes: stats.dead_bytes,
+            log_files: stats.log_files,
+            max_file_size: stats.max_file_size,
+            compaction_threshold: stats.compaction_threshold,
+        }),
+    })
+}
+
+/// Handles a request, returning the encoded reply.
+///
+/// Keys are read and written through `engine`, and `kvs` is only used for
+/// its stats, options and compaction. Without it, as with other engines,
+/// those requests fail with `EngineUnsupported`.
+pub(crate) async fn handle(
+    request: Request,
+    kvs: Option<KvStore>,
+    engine: Arc<dyn KvsEngine>,
+    config: Arc<ServerConfig>,
+    outcomes: Arc<AsyncMutex<Outcomes>>,
+    cursors: Arc<Cursors>,
+) -> Result<Vec<u8>> {
+    if let Err(e) = config.chaos.inject(request.op()).await {
+        return encode::<()>(Err(e));
+    }
+    match request {
+        Request::Get { key } => encode(engine.get(key.as_bytes()).await),
+        Request::Exists { key } => encode(engine.contains_key(key.as_bytes()).await),
+        Request::Strlen { key } => encode(engine.value_len(key.as_bytes()).await),
+        Request::Set { key, value } => encode(engine.set(key.as_bytes(), value.as_bytes()).await),
+        Request::Remove { key } => encode(engine.remove(key.as_bytes()).await),
+        Request::MultiGet { keys } => {
+            let keys: Vec<_> = keys.into_iter().map(String::into_bytes).collect();
+            encode(engine.multi_get(&keys).await)
+        }
+        Request::MultiSet { pairs } => {
+            let pairs: Vec<_> = pairs
+                .into_iter()
+                .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+                .collect();
+            encode(engine.set_many(&pairs).await)
+        }
+        Request::RemoveMany { keys } => {
+            let keys: Vec<_> = keys.into_iter().map(String::into_bytes).collect();
+            encode(engine.remove_many(&keys).await.map(|n| n as u64))
+        }
+        Request::Rename { from, to } => encode(engine.rename(from.as_bytes(), to.as_bytes()).await),
+        Request::Copy {
+            from,
+            to,
+            overwrite,
+        } => encode(engine.copy(from.as_bytes(), to.as_bytes(), overwrite).await),
+        Request::CountPrefix { prefix } => {
+            let keys = engine.keys_with_prefix(prefix.as_bytes()).await;
+            encode(keys.map(|keys| keys.len() as u64))
+        }
+        Request::RemovePrefix { prefix, limit } => {
+            let res = match engine.keys_with_prefix(prefix.as_bytes()).await {
+                Ok(mut keys) => {
+                    keys.truncate(limit as usize);
+                    engine.remove_many(&keys).await.map(|n| n as u64)
+                }
+                Err(e) => Err(e),
+            };
+            encode(res)
+        }
+        Request::GetTransformed { key, transform } => {
+            let res = match engine.get(key.as_bytes()).await {
+                Ok(Some(value)) => transform.apply(&String::from_utf8_lossy(&value)),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            };
+            encode(res)
+        }
+        Request::CompareAndSet {
+            id,
+            key,
+            expected,
+            value,
+        } => {
+            // Held until the outcome is recorded, so a concurrent retry waits for it.
+            let mut outcomes = outcomes.lock().await;
+            if let Some(&outcome) = outcomes.outcomes.get(&id) {
+                return encode(Ok(outcome));
+            }
+            let value = value.as_ref().map(|value| value.as_bytes());
+            let res = match expected {
+                Some(expected) => {
+                    let expected = expected.as_ref().map(|expected| expected.as_bytes());
+                    engine
+                        .compare_and_set(key.as_bytes(), expected, value)
+                        .await
+                }
+                None => match value {
+                    Some(value) => engine.set(key.as_bytes(), value).await.map(|_| true),
+                    None => match engine.remove(key.as_bytes()).await {
+                        Err(KvsError::KeyNotFound) => Ok(true),
+                        res => res.map(|_| true),
+                    },
+                },
+            };
+            if let Ok(outcome) = res {
+                outcomes.insert(id, outcome);
+            }
+            encode(res)
+        }
+        Request::Scan {
+            prefix,
+            start_after,
+            max_keys,
+            max_bytes,
+        } => {
+            let max_keys = config
+                .max_scan_keys
+                .unwrap_or(MAX_SCAN_KEYS)
+                .min(max_keys as usize);
+            let max_bytes = config
+                .max_scan_bytes
+                .unwrap_or(MAX_SCAN_BYTES)
+                .min(max_bytes);
+            encode(scan(&*engine, &prefix, start_after, max_keys, max_bytes).await)
+        }
+        Request::Batch(requests) => {
+            if let Some(request) = requests.iter().find(|request| !request.batchable()) {
+                return encode::<()>(Err(KvsError::Unbatchable(request.op())));
+            }
+            let mut replies = Vec::with_capacity(requests.len());
+            for request in requests {
+                let handling = handle(
+                    request,
+                    kvs.clone(),
+                    Arc::clone(&engine),
+                    Arc::clone(&config),
+                    Arc::clone(&outcomes),
+                    Arc::clone(&cursors),
+                );
+                replies.push(Box::pin(handling).await?);
+            }
+            encode(Ok(replies))
+        }
+        Request::ScanSnapshot {
+            cursor,
+            prefix,
+            limit,
+            values,
+        } => {
+            let max_keys = config
+                .max_scan_keys
+                .unwrap_or(MAX_SCAN_KEYS)
+                .min(limit as usize);
+            let max_bytes = config.max_scan_bytes.unwrap_or(MAX_SCAN_BYTES);
+            let res = match kvs {
+                Some(kvs) => {
+                    cursors
+                        .page(&kvs, cursor, &prefix, values, max_keys, max_bytes)
+                        .await
+                }
+                None => Err(KvsError::EngineUnsupported("scan_snapshot")),
+            };
+            encode(res)
+        }
+        Request::Profile { seconds } => {
+            encode(profile::flamegraph(Duration::from_secs(seconds)).await)
+        }
+        request => match kvs {
+            Some(kvs) => handle_store(request, kvs).await,
+            None => encode::<()>(Err(KvsError::EngineUnsupported(request.op()))),
+        },
+    }
+}
+
+/// Applies an operation another node forwarded to `local`, the engine
+/// serving the keys this node owns, returning the encoded reply.
+async fn forwarded(op: Forward, local: &dyn KvsEngine) -> Result<Vec<u8>> {
+    match op {
+        Forward::Get { key } => encode(local.get(&key).await),
+        Forward::Set { key, value } => encode(local.set(&key, &value).await),
+        Forward::Remove { key } => encode(match local.remove(&key).await {
+            Ok(()) => Ok(true),
+            Err(KvsError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }),
+        Forward::CompareAndSet {
+            key,
+            expected,
+            value,
+        } => encode(
+            local
+                .compare_and_set(&key, expected.as_deref(), value.as_deref())
+                .await,
+        ),
+        Forward::RemoveMany { keys } => encode(local.remove_many(&keys).await.map(|n| n as u64)),
+        Forward::KeysWithPrefix { prefix } => encode(local.keys_with_prefix(&prefix).await),
+        Forward::ContainsKey { key } => encode(local.contains_key(&key).await),
+        Forward::ValueLen { key } => encode(local.value_len(&key).await),
+        Forward::Rename { from, to } => encode(match local.rename(&from, &to).await {
+            Ok(()) => Ok(true),
+            Err(KvsError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }),
+    }
+}
+
+/// Handles a request only the store supports.
+async fn handle_store(request: Request, kvs: KvStore) -> Result<Vec<u8>> {
+    match request {
+        Request::Stats => encode(kvs.stats().await),
+        Request::Digest => encode(kvs.digest().await),
+        Request::Configure { name, value } => encode(kvs.set_option(&name, &value)),
+        Request::Compact { gen: Some(gen) } => encode(kvs.compact_gen(gen).await),
+        Request::Compact { gen: None } => encode(kvs.compact().await),
+        Request::SegmentStats => encode(kvs.segment_stats().await),
+        Request::FreezeWrites { timeout } => encode(kvs.freeze_writes(timeout).await),
+        Request::Thaw => encode(Ok(kvs.thaw())),
+        Request::Select { .. }
+        | Request::Subscribe { .. }
+        | Request::Unsubscribe
+        | Request::Replicate { .. }
+        | Request::Bootstrap
+        | Request::Raft(_)
+        | Request::Leader
+        | Request::Forward(_)
+        | Request::Topology
+        | Request::Info
+        | Request::Ping { .. }
+        | Request::SetChunk { .. }
+        | Request::GetChunked { .. }
+        | Request::Multiplex
+        | Request::Auth { .. }
+        | Request::Begin
+        | Request::Exec
+        | Request::Discard => {
+            unreachable!("`serve` handles requests changing the connection")
+        }
+        _ => unreachable!("`handle` handles the other requests"),
+    }
+}
+
+/// Returns the keys starting with `prefix` after `start_after` and their
+/// values, stopping before `max_keys` keys or `max_bytes` bytes are exceeded.
+async fn scan(
+    engine: &dyn KvsEngine,
+    prefix: &str,
+    start_after: Option<String>,
+    max_keys: usize,
+    max_bytes: u64,
+) -> Result<ScanPage> {
+    let keys = engine.keys_with_prefix(prefix.as_bytes()).await?;
+    let start = match &start_after {
+        Some(after) => keys.partition_point(|key| key.as_slice() <= after.as_bytes()),
+        None => 0,
+    };
+    let mut page = ScanPage::default();
+    let mut bytes = 0;
+    for key in &keys[start..] {
+        // Keys removed since they were listed are skipped.
+        let value = match engine.get(key).await? {
+            Some(value) => value,
+            None => continue,
+        };
+        let len = (key.len() + value.len()) as u64;
+        if !page.entries.is_empty() && (page.entries.len() >= max_keys || bytes + len > max_bytes) {
+            page.cursor = page.entries.last().map(|(key, _)| key.clone());
+            break;
+        }
+        bytes += len;
+        page.entries.push((
+            String::from_utf8_lossy(key).into_owned(),
+            String::from_utf8_lossy(&value).into_owned(),
+        ));
+    }
+    Ok(page)
+}
+
+/// Converts a result to what's sent over the wire.
+fn reply<T>(res: Result<T>) -> std::result::Result<T, ServerError> {
+    res.map_err(|e| ServerError::from(&e))
+}
+
+/// Encodes the reply to a request with bincode.
+fn encode<T: Serialize>(res: Result<T>) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&reply(res))?)
 }
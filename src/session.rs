@@ -0,0 +1,184 @@
+//! Recording the requests a server receives, and replaying them against a
+//! store, so reported bugs can be reproduced deterministically.
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::lock::Mutex as AsyncMutex;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::cursor::Cursors;
+use super::server::{handle, Outcomes};
+use super::{engine, KvStore, KvsError, MemoryEngine, Redaction, Request, Result, ServerConfig};
+
+/// A request as recorded, along with the connection it arrived on.
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    conn: u64,
+    request: Request,
+}
+
+/// Appends the requests of every connection to a session file.
+pub(crate) struct Recorder {
+    file: Mutex<File>,
+    scrub: bool,
+    next_conn: AtomicU64,
+}
+
+impl Recorder {
+    /// Records to the end of `path`. If `scrub` is set, every value is
+    /// replaced before it's written, not only those of redacted keys, see
+    /// `scrub`.
+    pub(crate) fn open(path: &Path, scrub: bool) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+            scrub,
+            next_conn: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns a recorder for the requests of a new connection.
+    pub(crate) fn connection(self: &Arc<Self>) -> ConnectionRecorder {
+        ConnectionRecorder {
+            conn: self.next_conn.fetch_add(1, Ordering::SeqCst),
+            recorder: Arc::clone(self),
+        }
+    }
+}
+
+pub(crate) struct ConnectionRecorder {
+    conn: u64,
+    recorder: Arc<Recorder>,
+}
+
+impl ConnectionRecorder {
+    /// Appends `request` to the session file, without the values of keys
+    /// `redaction` hides. Failing to record is logged rather than failing
+    /// the request.
+    pub(crate) fn record(&self, request: &Request, redaction: &Redaction) {
+        let hidden = |key: &String| self.recorder.scrub || redaction.is_sensitive(key.as_bytes());
+        let request = scrub(request, &hidden);
+        let frame = Frame {
+            conn: self.conn,
+            request,
+        };
+        let res = bincode::serialize(&frame)
+            .map_err(KvsError::from)
+            .and_then(|data| Ok(self.recorder.file.lock().unwrap().write_all(&data)?));
+        if let Err(e) = res {
+            warn!("Failed to record request: {}", e);
+        }
+    }
+}
+
+/// Replaces the values of the keys in `request` that are `hidden` with a
+/// filler of the same length, so sizes are kept but nothing of the values.
+fn scrub(request: &Request, hidden: &dyn Fn(&String) -> bool) -> Request {
+    let scrubbed = |key: &String, value: &String| match hidden(key) {
+        true => "*".repeat(value.len()),
+        false => value.clone(),
+    };
+    match request {
+        Request::Set { key, value } => Request::Set {
+            key: key.clone(),
+            value: scrubbed(key, value),
+        },
+        Request::CompareAndSet {
+            id,
+            key,
+            expected,
+            value,
+        } => Request::CompareAndSet {
+            id: *id,
+            key: key.clone(),
+            expected: expected
+                .as_ref()
+                .map(|expected| expected.as_ref().map(|v| scrubbed(key, v))),
+            value: value.as_ref().map(|v| scrubbed(key, v)),
+        },
+        Request::MultiSet { pairs } => Request::MultiSet {
+            pairs: pairs
+                .iter()
+                .map(|(key, value)| (key.clone(), scrubbed(key, value)))
+                .collect(),
+        },
+        Request::Batch(requests) => {
+            Request::Batch(requests.iter().map(|r| scrub(r, hidden)).collect())
+        }
+        request => request.clone(),
+    }
+}
+
+/// Sends the requests recorded in the session file at `path` to `kvs` one
+/// at a time, in the order the server received them, as if they came from
+/// clients. Returns how many were replayed.
+///
+/// Profiling, subscription and replication requests aren't replayed, nor requests to
+/// namespaces other than the default one.
+pub async fn replay_session(path: impl AsRef<Path>, kvs: &KvStore) -> Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let engine = engine::routed(Arc::new(kvs.clone()), &MemoryEngine::default(), &[]);
+    let config = Arc::new(ServerConfig::default());
+    let outcomes = Arc::new(AsyncMutex::new(Outcomes::default()));
+    // Recorded cursors aren't known here, so continued scans fail.
+    let cursors = Arc::new(Cursors::default());
+    let mut replayed = 0;
+    // Connections that selected another namespace than the default one.
+    let mut elsewhere = HashSet::new();
+    loop {
+        let frame: Frame = match bincode::deserialize_from(&mut reader) {
+            Ok(frame) => frame,
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                _ => return Err(e.into()),
+            },
+        };
+        match frame.request {
+            Request::Profile { .. }
+            | Request::Subscribe { .. }
+            | Request::Unsubscribe
+            | Request::Replicate { .. }
+            | Request::Bootstrap
+            | Request::Raft(_)
+            | Request::Leader
+            | Request::Forward(_)
+            | Request::Topology
+            | Request::Info
+            | Request::Ping { .. }
+            | Request::SetChunk { .. }
+            | Request::GetChunked { .. }
+            | Request::Multiplex
+            | Request::Auth { .. }
+            | Request::Begin
+            | Request::Exec
+            | Request::Discard => continue,
+            Request::Select { namespace } => {
+                if namespace.is_some() {
+                    elsewhere.insert(frame.conn);
+                } else {
+                    elsewhere.remove(&frame.conn);
+                }
+                continue;
+            }
+            _ if elsewhere.contains(&frame.conn) => continue,
+            _ => {}
+        }
+        handle(
+            frame.request,
+            Some(kvs.clone()),
+            Arc::clone(&engine),
+            Arc::clone(&config),
+            Arc::clone(&outcomes),
+            Arc::clone(&cursors),
+        )
+        .await?;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
@@ -0,0 +1,403 @@
+//! Keys partitioned across servers by consistent hashing, see
+//! `ServerConfig::sharding`.
+//!
+//! Every node hashes keys onto the same ring, so each key has one owner.
+//! Requests for keys a node doesn't own are forwarded to their owner, and
+//! listing keys asks every node for its own.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{BoxFuture, FutureExt as _};
+use futures::lock::Mutex as AsyncMutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::rt;
+use super::{ClientConfig, KvsClient, KvsEngine, KvsError, Result, SigningKey};
+
+/// Points each node has on the ring, so keys spread evenly.
+const POINTS_PER_NODE: u32 = 64;
+
+/// Longest an operation forwarded to another node takes, connecting
+/// included, before the node is taken to be down.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The nodes keys are partitioned across, see `ServerConfig::sharding`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sharding {
+    /// The address each node serves clients on, this one included. Nodes
+    /// forward requests to each other on these addresses too.
+    pub nodes: Vec<SocketAddr>,
+    /// The position of this node in `nodes`.
+    pub id: usize,
+}
+
+/// Which node owns each key, as returned by `KvsClient::topology`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    nodes: Vec<SocketAddr>,
+    /// Points on the ring and the node at each, in ascending order.
+    ring: Vec<(u64, usize)>,
+}
+
+impl Topology {
+    /// Returns the topology of `nodes`. Nodes given the same addresses, in
+    /// any order, agree on the owner of every key.
+    pub fn new(nodes: Vec<SocketAddr>) -> Self {
+        let mut ring = Vec::with_capacity(nodes.len() * POINTS_PER_NODE as usize);
+        for (node, addr) in nodes.iter().enumerate() {
+            for point in 0..POINTS_PER_NODE {
+                ring.push((hash(format!("{}#{}", addr, point).as_bytes()), node));
+            }
+        }
+        ring.sort_unstable_by_key(|&(point, node)| (point, nodes[node]));
+        Topology { nodes, ring }
+    }
+
+    pub fn nodes(&self) -> &[SocketAddr] {
+        &self.nodes
+    }
+
+    /// Returns the node owning `key`: the first on the ring at or after the
+    /// key's hash.
+    pub fn owner(&self, key: &[u8]) -> SocketAddr {
+        self.nodes[self.owner_index(key)]
+    }
+
+    fn owner_index(&self, key: &[u8]) -> usize {
+        let hash = hash(key);
+        let i = self.ring.partition_point(|&(point, _)| point < hash);
+        self.ring[i % self.ring.len()].1
+    }
+}
+
+fn hash(bytes: &[u8]) -> u64 {
+    let digest = Sha256::digest(bytes);
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix)
+}
+
+/// An operation forwarded to the node owning its keys, sent as
+/// `Request::Forward`. The node applies it to its own keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Forward {
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Answered with whether the key existed.
+    Remove {
+        key: Vec<u8>,
+    },
+    CompareAndSet {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Option<Vec<u8>>,
+    },
+    RemoveMany {
+        keys: Vec<Vec<u8>>,
+    },
+    KeysWithPrefix {
+        prefix: Vec<u8>,
+    },
+    ContainsKey {
+        key: Vec<u8>,
+    },
+    ValueLen {
+        key: Vec<u8>,
+    },
+    /// Answered with whether `from` existed.
+    Rename {
+        from: Vec<u8>,
+        to: Vec<u8>,
+    },
+}
+
+/// Formats the operation for logging, without its values.
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let key = |key: &[u8]| String::from_utf8_lossy(key).into_owned();
+        match self {
+            Forward::Get { key: k } => write!(f, "forwarded get {:?}", key(k)),
+            Forward::Set { key: k, value } => {
+                write!(f, "forwarded set {:?} ({} bytes)", key(k), value.len())
+            }
+            Forward::Remove { key: k } => write!(f, "forwarded remove {:?}", key(k)),
+            Forward::CompareAndSet { key: k, .. } => {
+                write!(f, "forwarded compare and set {:?}", key(k))
+            }
+            Forward::RemoveMany { keys } => write!(f, "forwarded remove of {} keys", keys.len()),
+            Forward::KeysWithPrefix { prefix } => {
+                write!(f, "forwarded keys with prefix {:?}", key(prefix))
+            }
+            Forward::ContainsKey { key: k } => write!(f, "forwarded exists {:?}", key(k)),
+            Forward::ValueLen { key: k } => write!(f, "forwarded strlen {:?}", key(k)),
+            Forward::Rename { from, to } => {
+                write!(f, "forwarded rename {:?} to {:?}", key(from), key(to))
+            }
+        }
+    }
+}
+
+/// A node of a sharded server, with a connection to each other node, made
+/// when it's first needed.
+pub(crate) struct Shards {
+    id: usize,
+    topology: Topology,
+    peers: Vec<AsyncMutex<Option<KvsClient>>>,
+    signing_key: Option<SigningKey>,
+}
+
+impl Shards {
+    /// The node `sharding.id` of `sharding`, connecting to the other nodes
+    /// with `signing_key`.
+    pub(crate) fn new(sharding: &Sharding, signing_key: Option<SigningKey>) -> Result<Self> {
+        if sharding.id >= sharding.nodes.len() {
+            return Err(KvsError::Config(format!(
+                "node {} isn't among the {} nodes of the sharding",
+                sharding.id,
+                sharding.nodes.len()
+            )));
+        }
+        Ok(Shards {
+            id: sharding.id,
+            topology: Topology::new(sharding.nodes.clone()),
+            peers: sharding
+                .nodes
+                .iter()
+                .map(|_| AsyncMutex::new(None))
+                .collect(),
+            signing_key,
+        })
+    }
+
+    pub(crate) fn nodes(&self) -> &[SocketAddr] {
+        self.topology.nodes()
+    }
+
+    /// Sends `op` to node `peer`, connecting to it if needed. A connection
+    /// the node closed since it was last used is made again, and one taking
+    /// longer than `FORWARD_TIMEOUT` fails with `KvsError::Offline`.
+    async fn forward<T: DeserializeOwned>(&self, peer: usize, op: Forward) -> Result<T> {
+        let mut client = self.peers[peer].lock().await;
+        let forwarding = async {
+            loop {
+                let reused = client.is_some();
+                if !reused {
+                    let config = ClientConfig {
+                        signing_key: self.signing_key.clone(),
+                        ..ClientConfig::default()
+                    };
+                    *client = Some(KvsClient::connect(self.topology.nodes[peer], config).await?);
+                }
+                let res = client.as_mut().unwrap().forward(op.clone()).await;
+                match res {
+                    // The node failed the operation, but the connection is fine.
+                    Ok(_) | Err(KvsError::Server(_)) => return res,
+                    Err(KvsError::Io(_)) if reused => *client = None,
+                    Err(_) => {
+                        *client = None;
+                        return res;
+                    }
+                }
+            }
+        };
+        match rt::timeout(FORWARD_TIMEOUT, forwarding).await {
+            Some(res) => res,
+            None => {
+                // The reply may still come, so the connection is dropped.
+                *client = None;
+                Err(KvsError::Offline)
+            }
+        }
+    }
+}
+
+/// Serves the keys of every node of a sharded server, applying operations
+/// on the keys this node owns to `local`, and forwarding the others.
+pub(crate) struct ShardedEngine {
+    shards: Arc<Shards>,
+    local: Arc<dyn KvsEngine>,
+}
+
+impl ShardedEngine {
+    pub(crate) fn new(shards: Arc<Shards>, local: Arc<dyn KvsEngine>) -> Self {
+        ShardedEngine { shards, local }
+    }
+
+    /// Returns the node owning `key`, unless it's this one.
+    fn remote_owner(&self, key: &[u8]) -> Option<usize> {
+        let owner = self.shards.topology.owner_index(key);
+        if owner == self.shards.id {
+            None
+        } else {
+            Some(owner)
+        }
+    }
+}
+
+impl KvsEngine for ShardedEngine {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        match self.remote_owner(key) {
+            Some(owner) => {
+                let op = Forward::Get { key: key.to_vec() };
+                self.shards.forward(owner, op).boxed()
+            }
+            None => self.local.get(key),
+        }
+    }
+
+    fn set<'a>(&'a self, key: &'a [u8], value: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        match self.remote_owner(key) {
+            Some(owner) => {
+                let op = Forward::Set {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                };
+                self.shards.forward(owner, op).boxed()
+            }
+            None => self.local.set(key, value),
+        }
+    }
+
+    fn remove<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        match self.remote_owner(key) {
+            Some(owner) => {
+                let op = Forward::Remove { key: key.to_vec() };
+                async move {
+                    match self.shards.forward(owner, op).await? {
+                        true => Ok(()),
+                        false => Err(KvsError::KeyNotFound),
+                    }
+                }
+                .boxed()
+            }
+            None => self.local.remove(key),
+        }
+    }
+
+    /// Lists the keys of every node, merged.
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> BoxFuture<'a, Result<Vec<Vec<u8>>>> {
+        async move {
+            let mut keys = self.local.keys_with_prefix(prefix).await?;
+            for peer in 0..self.shards.nodes().len() {
+                if peer != self.shards.id {
+                    let op = Forward::KeysWithPrefix {
+                        prefix: prefix.to_vec(),
+                    };
+                    keys.extend(self.shards.forward::<Vec<Vec<u8>>>(peer, op).await?);
+                }
+            }
+            keys.sort_unstable();
+            keys.dedup();
+            Ok(keys)
+        }
+        .boxed()
+    }
+
+    fn compare_and_set<'a>(
+        &'a self,
+        key: &'a [u8],
+        expected: Option<&'a [u8]>,
+        value: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        match self.remote_owner(key) {
+            Some(owner) => {
+                let op = Forward::CompareAndSet {
+                    key: key.to_vec(),
+                    expected: expected.map(<[u8]>::to_vec),
+                    value: value.map(<[u8]>::to_vec),
+                };
+                self.shards.forward(owner, op).boxed()
+            }
+            None => self.local.compare_and_set(key, expected, value),
+        }
+    }
+
+    /// Removes the keys of each node in one request to it.
+    fn remove_many<'a>(&'a self, keys: &'a [Vec<u8>]) -> BoxFuture<'a, Result<usize>> {
+        async move {
+            let mut owned = vec![Vec::new(); self.shards.nodes().len()];
+            for key in keys {
+                owned[self.shards.topology.owner_index(key)].push(key.clone());
+            }
+            let mut removed = 0;
+            for (node, keys) in owned.into_iter().enumerate() {
+                if keys.is_empty() {
+                    continue;
+                }
+                removed += if node == self.shards.id {
+                    self.local.remove_many(&keys).await?
+                } else {
+                    let op = Forward::RemoveMany { keys };
+                    self.shards.forward::<u64>(node, op).await? as usize
+                };
+            }
+            Ok(removed)
+        }
+        .boxed()
+    }
+
+    /// Renames keys owned by the same node there, atomically if its engine
+    /// does. Otherwise `to` is set before `from` is removed, so a failure
+    /// between the two leaves both set.
+    fn rename<'a>(&'a self, from: &'a [u8], to: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        let owner = self.shards.topology.owner_index(from);
+        if owner != self.shards.topology.owner_index(to) {
+            return async move {
+                let value = self.get(from).await?.ok_or(KvsError::KeyNotFound)?;
+                self.set(to, &value).await?;
+                self.remove(from).await
+            }
+            .boxed();
+        }
+        match self.remote_owner(from) {
+            Some(owner) => {
+                let op = Forward::Rename {
+                    from: from.to_vec(),
+                    to: to.to_vec(),
+                };
+                async move {
+                    match self.shards.forward(owner, op).await? {
+                        true => Ok(()),
+                        false => Err(KvsError::KeyNotFound),
+                    }
+                }
+                .boxed()
+            }
+            None => self.local.rename(from, to),
+        }
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        self.local.flush()
+    }
+
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool>> {
+        match self.remote_owner(key) {
+            Some(owner) => {
+                let op = Forward::ContainsKey { key: key.to_vec() };
+                self.shards.forward(owner, op).boxed()
+            }
+            None => self.local.contains_key(key),
+        }
+    }
+
+    fn value_len<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<u64>>> {
+        match self.remote_owner(key) {
+            Some(owner) => {
+                let op = Forward::ValueLen { key: key.to_vec() };
+                self.shards.forward(owner, op).boxed()
+            }
+            None => self.local.value_len(key),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{KvsError, Result};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A secret shared by the server and its clients, used to sign every frame.
+#[derive(Clone)]
+pub struct SigningKey(Arc<Vec<u8>>);
+
+impl SigningKey {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        SigningKey(Arc::new(key.into()))
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct SignedFrame {
+    nonce: u64,
+    payload: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+/// Sent by a client in reply to the server's hello: a nonce of its own, and
+/// a tag proving it has the key and got the hello unchanged.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ClientHello {
+    nonce: u64,
+    tag: Vec<u8>,
+}
+
+/// Signs outgoing and verifies incoming frames of one connection.
+///
+/// The MAC covers the session, the sender's role and a frame counter. The
+/// session is a hash of the server's hello, with its nonce and
+/// capabilities, and of the client's nonce, so frames can't be replayed on
+/// another connection by either side, and a changed hello fails every
+/// frame. Frames can't be reflected back to their sender, or reordered or
+/// dropped either.
+#[derive(Clone)]
+pub(crate) struct Signer {
+    key: SigningKey,
+    session: [u8; 32],
+    role: Role,
+    sent: u64,
+    received: u64,
+}
+
+impl Signer {
+    /// Client side of the handshake: picks the client's nonce for the
+    /// server's `hello`, returning what to reply with.
+    pub(crate) fn client(key: SigningKey, hello: &[u8]) -> (Self, ClientHello) {
+        let nonce = rand::random();
+        let signer = Signer::new(key, hello, nonce, Role::Client);
+        let tag = signer.hello_mac().result().code().to_vec();
+        (signer, ClientHello { nonce, tag })
+    }
+
+    /// Server side of the handshake: checks the client's reply to `hello`.
+    pub(crate) fn server(key: SigningKey, hello: &[u8], reply: ClientHello) -> Result<Self> {
+        let signer = Signer::new(key, hello, reply.nonce, Role::Server);
+        signer
+            .hello_mac()
+            .verify(&reply.tag)
+            .map_err(|_| KvsError::Signature("bad hello"))?;
+        Ok(signer)
+    }
+
+    fn new(key: SigningKey, hello: &[u8], client_nonce: u64, role: Role) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.input((hello.len() as u64).to_be_bytes());
+        hasher.input(hello);
+        hasher.input(client_nonce.to_be_bytes());
+        Signer {
+            key,
+            session: hasher.result().into(),
+            role,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    pub(crate) fn seal(&mut self, payload: Vec<u8>) -> SignedFrame {
+        self.sent += 1;
+        let tag = self
+            .mac(self.role, self.sent, &payload)
+            .result()
+            .code()
+            .to_vec();
+        SignedFrame {
+            nonce: self.sent,
+            payload,
+            tag,
+        }
+    }
+
+    pub(crate) fn open(&mut self, frame: SignedFrame) -> Result<Vec<u8>> {
+        let peer = match self.role {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client,
+        };
+        self.mac(peer, frame.nonce, &frame.payload)
+            .verify(&frame.tag)
+            .map_err(|_| KvsError::Signature("bad tag"))?;
+        if frame.nonce != self.received + 1 {
+            return Err(KvsError::Signature("replayed or out-of-order frame"));
+        }
+        self.received = frame.nonce;
+        Ok(frame.payload)
+    }
+
+    fn mac(&self, sender: Role, nonce: u64, payload: &[u8]) -> HmacSha256 {
+        let mut mac = self.session_mac();
+        mac.input(&[sender as u8]);
+        mac.input(&nonce.to_be_bytes());
+        mac.input(payload);
+        mac
+    }
+
+    /// Unlike frames, the client's hello has no role or counter, so its MAC
+    /// can't be mistaken for a frame's.
+    fn hello_mac(&self) -> HmacSha256 {
+        let mut mac = self.session_mac();
+        mac.input(b"hello");
+        mac
+    }
+
+    fn session_mac(&self) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.key.0).expect("HMAC takes keys of any size");
+        mac.input(&self.session);
+        mac
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{read_exact_at, write_all_at};
+use crate::{KvsError, Result};
+
+/// Marks the start of a store's single file.
+const MAGIC: &[u8; 8] = b"kvsfile1";
+
+/// Logs the index has room for.
+const SLOTS: usize = 1024;
+
+/// Bytes of an encoded `Region`.
+const SLOT_SIZE: u64 = 40;
+
+/// Where the first region may start, past the magic and the index.
+const HEADER_SIZE: u64 = 64 * 1024;
+
+/// Where a log is in the file, and how much of it is written.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct Region {
+    pub(crate) gen: u64,
+    pub(crate) base: u64,
+    /// Bytes reserved for the log. A free slot of the index has none.
+    pub(crate) capacity: u64,
+    pub(crate) len: u64,
+    /// Seconds since the Unix epoch.
+    created: u64,
+}
+
+impl Region {
+    pub(crate) fn created(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.created)
+    }
+}
+
+/// Every log of a store in one file, see `Options::single_file`.
+///
+/// The file starts with an index of the logs, each a region reserved for it
+/// further on. The index holds how much of each region is written, so
+/// whatever is left past it by an earlier log is never read. Regions of
+/// compacted logs are reused by later ones that fit in them.
+pub(crate) struct SingleFile {
+    pub(crate) file: Arc<File>,
+    /// The index, with `None` for free slots.
+    slots: Mutex<Vec<Option<Region>>>,
+}
+
+impl SingleFile {
+    /// Opens the file at `path`, creating it with an empty index if it
+    /// doesn't exist.
+    pub(crate) fn open(path: &Path) -> Result<SingleFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            write_all_at(&file, MAGIC, 0)?;
+            file.set_len(HEADER_SIZE)?;
+            file.sync_all()?;
+        }
+        let mut header = vec![0u8; MAGIC.len() + SLOTS * SLOT_SIZE as usize];
+        read_exact_at(&file, &mut header, 0).map_err(|_| KvsError::Corrupted)?;
+        if !header.starts_with(MAGIC) {
+            return Err(KvsError::Corrupted);
+        }
+        let slots = header[MAGIC.len()..]
+            .chunks(SLOT_SIZE as usize)
+            .map(|slot| {
+                let region: Region = bincode::deserialize(slot)?;
+                Ok(Some(region).filter(|region| region.capacity > 0))
+            })
+            .collect::<Result<_>>()?;
+        Ok(SingleFile {
+            file: Arc::new(file),
+            slots: Mutex::new(slots),
+        })
+    }
+
+    /// Returns the logs in the file and their slots.
+    pub(crate) fn regions(&self) -> Vec<(usize, Region)> {
+        let slots = self.slots.lock().unwrap();
+        slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, region)| region.map(|region| (slot, region)))
+            .collect()
+    }
+
+    pub(crate) fn region(&self, slot: usize) -> Region {
+        self.slots.lock().unwrap()[slot].expect("the slot of a log is used")
+    }
+
+    /// Reserves `capacity` bytes for log `gen` in the first gap that fits
+    /// them, or at the end of the file, returning its slot.
+    pub(crate) fn allocate(&self, gen: u64, capacity: u64) -> Result<usize> {
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots.iter().position(Option::is_none).ok_or_else(|| {
+            KvsError::Config(format!("the single file has room for {} logs", SLOTS))
+        })?;
+        let mut used: Vec<_> = slots.iter().flatten().collect();
+        used.sort_by_key(|region| region.base);
+        let mut base = HEADER_SIZE;
+        for region in used {
+            if region.base - base >= capacity {
+                break;
+            }
+            base = region.base + region.capacity;
+        }
+        if self.file.metadata()?.len() < base + capacity {
+            self.file.set_len(base + capacity)?;
+        }
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let region = Region {
+            gen,
+            base,
+            capacity,
+            len: 0,
+            created,
+        };
+        self.write_slot(slot, &region)?;
+        slots[slot] = Some(region);
+        Ok(slot)
+    }
+
+    /// Records that the first `len` bytes of the log in `slot` are written.
+    pub(crate) fn set_len(&self, slot: usize, len: u64) -> io::Result<()> {
+        let mut slots = self.slots.lock().unwrap();
+        let region = slots[slot].as_mut().expect("the slot of a log is used");
+        region.len = len;
+        let region = *region;
+        self.write_slot(slot, &region)
+    }
+
+    /// Frees the region of the log in `slot` for later logs.
+    pub(crate) fn free(&self, slot: usize) -> io::Result<()> {
+        let mut slots = self.slots.lock().unwrap();
+        self.write_slot(slot, &Region::default())?;
+        slots[slot] = None;
+        Ok(())
+    }
+
+    fn write_slot(&self, slot: usize, region: &Region) -> io::Result<()> {
+        let data = bincode::serialize(region).expect("regions are encodable");
+        write_all_at(
+            &self.file,
+            &data,
+            MAGIC.len() as u64 + slot as u64 * SLOT_SIZE,
+        )
+    }
+}
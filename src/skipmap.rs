@@ -1,8 +1,8 @@
 use serde::de::{Deserialize, MapAccess, Visitor};
-use serde::export::PhantomData;
 use serde::ser::{Serialize, SerializeMap, Serializer};
 use serde::Deserializer;
 use std::fmt;
+use std::marker::PhantomData;
 use std::ops::Deref;
 
 #[derive(Debug)]
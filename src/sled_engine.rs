@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use futures::future::{self, BoxFuture, FutureExt as _};
+
+use super::{KvsEngine, KvsError, Result};
+
+/// An engine keeping keys in a sled database, for servers started with
+/// `EngineKind::Sled`.
+///
+/// Cloning it is cheap, and clones share the database.
+#[derive(Debug, Clone)]
+pub struct SledEngine {
+    db: sled::Db,
+}
+
+impl SledEngine {
+    /// Opens the sled database in `dir`, creating it if there's none.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let db = sled::open(dir).map_err(sled_error)?;
+        Ok(SledEngine { db })
+    }
+}
+
+fn sled_error(e: sled::Error) -> KvsError {
+    KvsError::Engine(e.to_string())
+}
+
+impl KvsEngine for SledEngine {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        let res = self.db.get(key).map_err(sled_error);
+        future::ready(res.map(|value| value.map(|value| value.to_vec()))).boxed()
+    }
+
+    fn set<'a>(&'a self, key: &'a [u8], value: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        let res = self.db.insert(key, value).map_err(sled_error);
+        future::ready(res.map(|_| ())).boxed()
+    }
+
+    fn remove<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        let res = match self.db.remove(key) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(KvsError::KeyNotFound),
+            Err(e) => Err(sled_error(e)),
+        };
+        future::ready(res).boxed()
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> BoxFuture<'a, Result<Vec<Vec<u8>>>> {
+        let keys = self
+            .db
+            .scan_prefix(prefix)
+            .keys()
+            .map(|key| key.map(|key| key.to_vec()).map_err(sled_error))
+            .collect();
+        future::ready(keys).boxed()
+    }
+
+    fn compare_and_set<'a>(
+        &'a self,
+        key: &'a [u8],
+        expected: Option<&'a [u8]>,
+        value: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let res = self
+            .db
+            .compare_and_swap(key, expected, value)
+            .map(|swapped| swapped.is_ok())
+            .map_err(sled_error);
+        future::ready(res).boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        let res = self.db.flush().map(|_| ()).map_err(sled_error);
+        future::ready(res).boxed()
+    }
+}
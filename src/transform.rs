@@ -0,0 +1,61 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{KvsError, Result};
+
+/// A transformation the server applies to a value before replying, so that
+/// clients needing only part of a large value don't fetch all of it.
+///
+/// Written like `length`, `sha256` or `json:/users/0/name`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Transform {
+    /// The length of the value in bytes.
+    Length,
+    /// The SHA-256 hash of the value, hex encoded.
+    Sha256,
+    /// The part of a JSON value at a JSON pointer, as JSON.
+    JsonPointer(String),
+}
+
+impl Transform {
+    /// Transforms `value`, returning `None` if a JSON pointer matches nothing.
+    pub fn apply(&self, value: &str) -> Result<Option<String>> {
+        match self {
+            Transform::Length => Ok(Some(value.len().to_string())),
+            Transform::Sha256 => {
+                let hash = Sha256::digest(value.as_bytes());
+                Ok(Some(hash.iter().map(|b| format!("{:02x}", b)).collect()))
+            }
+            Transform::JsonPointer(pointer) => {
+                let json: serde_json::Value = serde_json::from_str(value)
+                    .map_err(|e| KvsError::Transform(format!("value isn't JSON: {}", e)))?;
+                Ok(json.pointer(pointer).map(|part| part.to_string()))
+            }
+        }
+    }
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "length" => Ok(Transform::Length),
+            "sha256" => Ok(Transform::Sha256),
+            _ if s.starts_with("json:") => {
+                let pointer = &s["json:".len()..];
+                if pointer.is_empty() || pointer.starts_with('/') {
+                    Ok(Transform::JsonPointer(pointer.to_owned()))
+                } else {
+                    Err(format!("JSON pointer `{}` must start with `/`", pointer))
+                }
+            }
+            _ => Err(format!(
+                "unknown transform `{}`, expected `length`, `sha256` or `json:<pointer>`",
+                s
+            )),
+        }
+    }
+}
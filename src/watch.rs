@@ -0,0 +1,103 @@
+use std::sync::Mutex;
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use serde::{Deserialize, Serialize};
+
+/// A change made to the store, as seen by `KvStore::watch`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+/// A change logged by the store, as returned by `KvStore::changes_since`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+    Set {
+        seq: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        seq: u64,
+        key: Vec<u8>,
+    },
+    /// Every key starting with `prefix` was removed.
+    RemovePrefix {
+        seq: u64,
+        prefix: Vec<u8>,
+    },
+}
+
+impl Change {
+    /// The sequence number of the change, which increases with every change.
+    pub fn seq(&self) -> u64 {
+        match self {
+            Change::Set { seq, .. }
+            | Change::Remove { seq, .. }
+            | Change::RemovePrefix { seq, .. } => *seq,
+        }
+    }
+}
+
+struct Watcher {
+    prefix: Vec<u8>,
+    sender: UnboundedSender<WatchEvent>,
+}
+
+/// Watchers registered on a store, notified by the writer after each change.
+#[derive(Default)]
+pub(crate) struct Watchers(Mutex<Vec<Watcher>>);
+
+impl Watchers {
+    pub(crate) fn subscribe(&self, prefix: Vec<u8>) -> UnboundedReceiver<WatchEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.0.lock().unwrap().push(Watcher { prefix, sender });
+        receiver
+    }
+
+    /// Notifies watchers of `key` that it was set to `value`, or removed if `value` is `None`.
+    ///
+    /// Watchers whose stream was dropped are unregistered.
+    pub(crate) fn publish(&self, key: &[u8], value: Option<&[u8]>) {
+        let mut watchers = self.0.lock().unwrap();
+        watchers.retain(|watcher| {
+            if !key.starts_with(&watcher.prefix) {
+                return !watcher.sender.is_closed();
+            }
+            let event = match value {
+                Some(value) => WatchEvent::Set {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                },
+                None => WatchEvent::Remove { key: key.to_vec() },
+            };
+            watcher.sender.unbounded_send(event).is_ok()
+        });
+    }
+}
+
+/// Streams tailing the changes of a store, fed by the writer as it logs them.
+#[derive(Default)]
+pub(crate) struct ChangeFeed(Vec<UnboundedSender<Change>>);
+
+impl ChangeFeed {
+    pub(crate) fn subscribe(&mut self) -> UnboundedReceiver<Change> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.0.push(sender);
+        receiver
+    }
+
+    /// Sends the change made by `change` to every stream, unregistering
+    /// dropped ones. It's only made if a stream is left.
+    pub(crate) fn publish(&mut self, change: impl FnOnce() -> Change) {
+        self.0.retain(|sender| !sender.is_closed());
+        if self.0.is_empty() {
+            return;
+        }
+        let change = change();
+        for sender in &self.0 {
+            let _ = sender.unbounded_send(change.clone());
+        }
+    }
+}
@@ -1,9 +1,22 @@
 use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use async_std::net::TcpStream;
+use async_std::prelude::*;
 use async_std::task;
 use tempfile::TempDir;
 
-use kvs::{KvStore, Result};
+use kvs::{
+    replay_session, BatchOp, Chaos, ClientConfig, Cluster, Conflict, DirTarget, KvStore, KvsClient,
+    KvsEngine, KvsError, KvsServer, MaintenanceWindow, MemoryEngine, Metadata, OfflineClient,
+    Options, Protocol, Redaction, Result, RoutingEngine, ScanPage, ServerConfig, ServerError,
+    Sharding, SigningKey, StoreListener, Topology, Transform, WatchEvent,
+};
 
 // Should get previously stored value
 #[test]
@@ -67,6 +80,22 @@ fn get_non_existent_value() -> Result<()> {
     })
 }
 
+#[test]
+fn multi_get() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set("key1", "value1").await?;
+        store.set("key2", "value2").await?;
+
+        assert_eq!(
+            store.multi_get(&["key2", "key3", "key1"]).await?,
+            vec![Some(b"value2".to_vec()), None, Some(b"value1".to_vec())]
+        );
+        Ok(())
+    })
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     task::block_on(async {
@@ -89,6 +118,355 @@ fn remove_key() -> Result<()> {
     })
 }
 
+// Should list keys in order, and not see writes made after listing started
+#[test]
+fn list_keys() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        for key in &["key3", "key1", "key2", "other"] {
+            store.set(key, "value").await?;
+        }
+
+        let mut keys = store.keys(b"key".to_vec()..b"kez".to_vec()).await?;
+        store.set("key0", "value").await?;
+        store.remove("key2").await?;
+        let mut listed = Vec::new();
+        while let Some(key) = keys.next().await {
+            listed.push(key);
+        }
+        assert_eq!(
+            listed,
+            vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()]
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn watch_prefix() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        let mut events = store.watch("user:");
+
+        store.set("user:1", "alice").await?;
+        store.set("item:1", "apple").await?;
+        store.remove("user:1").await?;
+
+        assert_eq!(
+            events.next().await,
+            Some(WatchEvent::Set {
+                key: b"user:1".to_vec(),
+                value: b"alice".to_vec()
+            })
+        );
+        assert_eq!(
+            events.next().await,
+            Some(WatchEvent::Remove {
+                key: b"user:1".to_vec()
+            })
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn remove_keys_with_prefix() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        for key in &["a", "b:1", "b:2", "b:3", "c"] {
+            store.set(key, "value").await?;
+        }
+
+        let mut keys = Vec::new();
+        let mut stream = store.keys_with_prefix("b:").await?;
+        while let Some(key) = stream.next().await {
+            keys.push(key);
+        }
+        assert_eq!(keys.len(), 3);
+        assert_eq!(
+            store
+                .remove_many(keys.iter().chain(&[b"x".to_vec()]))
+                .await?,
+            3
+        );
+
+        assert_eq!(store.get("b:2").await?, None);
+        assert_eq!(store.get("a").await?, Some(b"value".to_vec()));
+        assert_eq!(store.get("c").await?, Some(b"value".to_vec()));
+        Ok(())
+    })
+}
+
+#[test]
+fn delete_prefix() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        for key in &["a", "b:1", "b:2", "b:3", "c"] {
+            store.set(key, "value").await?;
+        }
+
+        assert_eq!(store.delete_prefix("b:").await?, 3);
+        assert_eq!(store.delete_prefix("b:").await?, 0);
+        assert_eq!(store.get("b:1").await?, None);
+        assert_eq!(store.get("c").await?, Some(b"value".to_vec()));
+        Ok(())
+    })
+}
+
+// Should rebuild the index from the logs if the store wasn't closed cleanly
+#[test]
+fn replay_after_crash() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        for iter in 0..20 {
+            for key_id in 0..20 {
+                store
+                    .set(format!("key{}", key_id), format!("{}", iter))
+                    .await?;
+            }
+        }
+        store.remove("key0").await?;
+        store.delete_prefix("key1").await?;
+        store.set("key10", "again").await?;
+
+        // Skip `Drop`, so no keydir is persisted
+        std::mem::forget(store);
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key0").await?, None);
+        assert_eq!(store.get("key1").await?, None);
+        assert_eq!(store.get("key15").await?, None);
+        assert_eq!(store.get("key10").await?, Some(b"again".to_vec()));
+        assert_eq!(store.get("key2").await?, Some(b"19".to_vec()));
+        Ok(())
+    })
+}
+
+// A flushed keydir should be loaded without `Drop`, unless written after
+#[test]
+fn flush() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let keydir = temp_dir.path().join("keydir");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set("key1", "value1").await?;
+        store.flush().await?;
+        assert!(keydir.exists());
+        std::mem::forget(store);
+
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert!(!keydir.exists());
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        store.flush().await?;
+        store.set("key1", "value2").await?;
+        store.set("key2", "value3").await?;
+        std::mem::forget(store);
+
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key1").await?, Some(b"value2".to_vec()));
+        assert_eq!(store.get("key2").await?, Some(b"value3".to_vec()));
+        Ok(())
+    })
+}
+
+// Small prefixes are counted exactly and large ones estimated closely,
+// also after reopening
+#[test]
+fn estimate_count() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 1 << 20,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).await?;
+        for i in 0..20_000 {
+            store.set(format!("big:{}", i), "value").await?;
+        }
+        for i in 0..100 {
+            store.set(format!("small:{}", i), "value").await?;
+        }
+        assert_eq!(store.estimate_count("small:").await?, 100);
+        assert_eq!(store.estimate_count("none:").await?, 0);
+        let estimate = store.estimate_count("big:").await?;
+        assert!(18_000 < estimate && estimate < 22_000, "{}", estimate);
+        assert_eq!(store.delete_prefix("big:1").await?, 11_111);
+        let after_delete = store.estimate_count("big:").await?;
+        assert!(
+            7_000 < after_delete && after_delete < 11_000,
+            "{}",
+            after_delete
+        );
+
+        store.close().await?;
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).await?;
+        assert_eq!(store.estimate_count("big:").await?, after_delete);
+        std::mem::forget(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        assert_eq!(store.estimate_count("big:").await?, after_delete);
+        assert_eq!(store.estimate_count("small:").await?, 100);
+        Ok(())
+    })
+}
+
+// Should look keys up in spilled keydir shards the same as in memory, also
+// after reopening
+#[test]
+fn spilled_keydir() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 1 << 16,
+            keydir_memory: Some(4096),
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).await?;
+        for i in 0..3000 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        for i in (0..3000).step_by(3) {
+            store.remove(format!("key{}", i)).await?;
+        }
+        for i in (0..3000).step_by(5) {
+            store.set(format!("key{}", i), "new").await?;
+        }
+        assert_eq!(store.delete_prefix("key1").await?, 817);
+        assert!(fs::read_dir(temp_dir.path().join("index"))?
+            .next()
+            .is_some());
+        check_spilled_keydir(&store).await?;
+        assert!(store.verify().await?.is_ok());
+
+        store.close().await?;
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).await?;
+        check_spilled_keydir(&store).await?;
+        std::mem::forget(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        check_spilled_keydir(&store).await?;
+        Ok(())
+    })
+}
+
+async fn check_spilled_keydir(store: &KvStore) -> Result<()> {
+    let mut expected = Vec::new();
+    for i in 0..3000 {
+        let key = format!("key{}", i);
+        let value = if key.starts_with("key1") {
+            None
+        } else if i % 5 == 0 {
+            Some("new".to_owned())
+        } else if i % 3 == 0 {
+            None
+        } else {
+            Some(format!("value{}", i))
+        };
+        assert_eq!(
+            store.get(&key).await?,
+            value.clone().map(String::into_bytes)
+        );
+        if value.is_some() {
+            expected.push(key.into_bytes());
+        }
+    }
+    expected.sort();
+    let mut keys = store.keys(..).await?;
+    let mut listed = Vec::new();
+    while let Some(key) = keys.next().await {
+        listed.push(key);
+    }
+    assert_eq!(listed, expected);
+    Ok(())
+}
+
+// Should rebuild the same keydir from many logs, replayed in parallel
+#[test]
+fn replay_many_logs() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        for round in 0..3 {
+            for i in 0..500 {
+                store
+                    .set(format!("key{}", i), format!("{}:{}", round, i))
+                    .await?;
+            }
+            for i in (0..500).step_by(7) {
+                store.remove(format!("key{}", i)).await?;
+            }
+            store.delete_prefix(format!("key{}", round + 1)).await?;
+            store.set(format!("key{}", round + 1), "again").await?;
+        }
+        let stats = store.stats().await?;
+        assert!(stats.log_files > 10, "{}", stats.log_files);
+        let digest = store.digest().await?;
+
+        std::mem::forget(store);
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.digest().await?, digest);
+        let replayed = store.stats().await?;
+        assert_eq!(replayed.keys, stats.keys);
+        assert_eq!(replayed.live_bytes, stats.live_bytes);
+        assert_eq!(replayed.dead_bytes, stats.dead_bytes);
+        Ok(())
+    })
+}
+
+// Should fall back to replaying the logs if the keydir file is invalid
+#[test]
+fn invalid_keydir_file() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set("key1", "value1").await?;
+        store.set("key2", "value2").await?;
+        store.close().await?;
+
+        fs::write(temp_dir.path().join("keydir"), b"garbage")?;
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        assert_eq!(store.get("key2").await?, Some(b"value2".to_vec()));
+        Ok(())
+    })
+}
+
+// Keys in a part of the keydir file that's corrupted should be looked up
+// in the replayed logs instead
+#[test]
+fn corrupted_keydir_file() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        for key_id in 0..10 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", key_id))
+                .await?;
+        }
+        store.set("other", "value").await?;
+        store.close().await?;
+
+        let path = temp_dir.path().join("keydir");
+        let mut data = fs::read(&path)?;
+        let at = data
+            .windows(4)
+            .position(|window| window == b"key5")
+            .expect("the keydir file holds key5");
+        data[at + 2] = b'z';
+        fs::write(&path, data)?;
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("other").await?, Some(b"value".to_vec()));
+        assert_eq!(store.get("key5").await?, Some(b"value5".to_vec()));
+        assert_eq!(store.get("kez5").await?, None);
+        assert_eq!(store.estimate_count("").await?, 11);
+        Ok(())
+    })
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -222,3 +600,3714 @@ fn concurrent_get() -> Result<()> {
         Ok(())
     })
 }
+
+// Concurrent writes should all be durable when committed in groups
+#[test]
+fn group_commit() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            sync_writes: true,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+
+        let handles: Vec<_> = (0..100)
+            .map(|i| {
+                let store = store.clone();
+                task::spawn(
+                    async move { store.set(format!("key{}", i), format!("value{}", i)).await },
+                )
+            })
+            .collect();
+        for handle in handles {
+            handle.await?;
+        }
+
+        std::mem::forget(store);
+        let store = KvStore::open(temp_dir.path()).await?;
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", i)).await?,
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+        Ok(())
+    })
+}
+
+// Should only write when the current value matches the expected one
+#[test]
+fn compare_and_set() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+
+        assert!(store.compare_and_set("key1", None, Some(b"value1")).await?);
+        assert!(!store.compare_and_set("key1", None, Some(b"value2")).await?);
+        assert!(!store.compare_and_set("key1", Some(b"value2"), None).await?);
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+
+        assert!(
+            store
+                .compare_and_set("key1", Some(b"value1"), Some(b"value2"))
+                .await?
+        );
+        assert_eq!(store.get("key1").await?, Some(b"value2".to_vec()));
+        assert!(store.compare_and_set("key1", Some(b"value2"), None).await?);
+        assert_eq!(store.get("key1").await?, None);
+        Ok(())
+    })
+}
+
+#[test]
+fn conditional_set() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+
+        assert!(!store.set_xx("key1", "value1").await?);
+        assert_eq!(store.get("key1").await?, None);
+        assert!(store.set_nx("key1", "value1").await?);
+        assert!(!store.set_nx("key1", "value2").await?);
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        assert!(store.set_xx("key1", "value3").await?);
+        assert_eq!(store.get("key1").await?, Some(b"value3".to_vec()));
+
+        store.remove("key1").await?;
+        assert!(!store.set_xx("key1", "value4").await?);
+        assert!(store.set_nx("key1", "value5").await?);
+        assert_eq!(store.get("key1").await?, Some(b"value5".to_vec()));
+        Ok(())
+    })
+}
+
+// Should work without an async runtime through the blocking API
+#[test]
+fn blocking_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = kvs::blocking::KvStore::open(temp_dir.path())?;
+    let mut events = store.watch("key");
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.remove("key1")?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some(b"value2".to_vec()));
+    assert_eq!(store.keys_with_prefix("key")?, vec![b"key2".to_vec()]);
+    assert_eq!(
+        events.next(),
+        Some(WatchEvent::Set {
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec(),
+        })
+    );
+
+    drop(store);
+    let store = kvs::blocking::KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Should serve keys from the engine of the longest matching route
+#[test]
+fn routing_engine() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        let cache = MemoryEngine::default();
+        let engine = RoutingEngine::new(Arc::new(store.clone()))
+            .route("cache:", Arc::new(cache.clone()))
+            .route("cache:durable:", Arc::new(store.clone()));
+
+        engine.set(b"key1", b"value1").await?;
+        engine.set(b"cache:key2", b"value2").await?;
+        engine.set(b"cache:durable:key3", b"value3").await?;
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        assert_eq!(store.get("cache:key2").await?, None);
+        assert_eq!(cache.get(b"cache:key2").await?, Some(b"value2".to_vec()));
+        assert_eq!(
+            store.get("cache:durable:key3").await?,
+            Some(b"value3".to_vec())
+        );
+
+        assert_eq!(
+            engine.keys_with_prefix(b"").await?,
+            vec![
+                b"cache:durable:key3".to_vec(),
+                b"cache:key2".to_vec(),
+                b"key1".to_vec(),
+            ]
+        );
+        assert_eq!(
+            engine.keys_with_prefix(b"cache:").await?,
+            vec![b"cache:durable:key3".to_vec(), b"cache:key2".to_vec()]
+        );
+        engine.remove(b"cache:key2").await?;
+        assert_eq!(engine.get(b"cache:key2").await?, None);
+        Ok(())
+    })
+}
+
+// Should keep flags with values and only change them when the condition holds
+fn flags_of(entry: Option<(Vec<u8>, Metadata)>) -> Option<(Vec<u8>, u8)> {
+    entry.map(|(value, metadata)| (value, metadata.flags))
+}
+
+#[test]
+fn record_flags() -> Result<()> {
+    const PINNED: u8 = 1;
+    const ARCHIVED: u8 = 2;
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+
+        store.set_with_flags("key1", "value1", PINNED).await?;
+        store.set("key2", "value2").await?;
+        assert_eq!(
+            flags_of(store.get_with_metadata("key1").await?),
+            Some((b"value1".to_vec(), PINNED))
+        );
+        assert_eq!(
+            flags_of(store.get_with_metadata("key2").await?),
+            Some((b"value2".to_vec(), 0))
+        );
+        assert_eq!(store.get_with_metadata("key3").await?, None);
+
+        assert!(
+            !store
+                .compare_and_set_flags("key1", PINNED, 0, ARCHIVED)
+                .await?
+        );
+        assert!(
+            store
+                .compare_and_set_flags("key2", PINNED, 0, ARCHIVED)
+                .await?
+        );
+        assert!(!store.compare_and_set_flags("key3", 0, 0, ARCHIVED).await?);
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(
+            flags_of(store.get_with_metadata("key2").await?),
+            Some((b"value2".to_vec(), ARCHIVED))
+        );
+        assert_eq!(
+            store.get_with_metadata("key1").await?.unwrap().1.flags,
+            PINNED
+        );
+        store.set("key1", "value3").await?;
+        assert_eq!(store.get_with_metadata("key1").await?.unwrap().1.flags, 0);
+        Ok(())
+    })
+}
+
+// Should keep reading the values a scan started with, even after compaction
+#[test]
+fn scan_snapshot() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        let log_files = || {
+            fs::read_dir(temp_dir.path())
+                .unwrap()
+                .filter(|file| file.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+                .count()
+        };
+
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), "old").await?;
+        }
+        let mut scan = store.scan(..).await?;
+        for iter in 0..100 {
+            for key_id in 0..10 {
+                store
+                    .set(format!("key{}", key_id), format!("{}", iter))
+                    .await?;
+            }
+        }
+        // Compacted logs the scan reads from are kept.
+        assert!(store.stats().await?.log_files < log_files() as u64);
+
+        let mut scanned = 0;
+        while let Some(pair) = scan.next().await {
+            assert_eq!(pair?.1, b"old".to_vec());
+            scanned += 1;
+        }
+        assert_eq!(scanned, 10);
+        drop(scan);
+        assert_eq!(log_files() as u64, store.stats().await?.log_files);
+        assert_eq!(store.get("key0").await?, Some(b"99".to_vec()));
+        Ok(())
+    })
+}
+
+// Should scan in descending order within inclusive and exclusive bounds
+#[test]
+fn scan_rev() -> Result<()> {
+    async fn keys(
+        mut pairs: impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Unpin,
+        n: usize,
+    ) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        while keys.len() < n {
+            match pairs.next().await {
+                Some(pair) => keys.push(String::from_utf8(pair?.0).unwrap()),
+                None => break,
+            }
+        }
+        Ok(keys)
+    }
+
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        for i in 0..10 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+
+        let latest = keys(store.scan_rev(..).await?, 3).await?;
+        assert_eq!(latest, ["key9", "key8", "key7"]);
+        let range = b"key2".to_vec()..=b"key5".to_vec();
+        let pairs = keys(store.scan_rev(range).await?, usize::MAX).await?;
+        assert_eq!(pairs, ["key5", "key4", "key3", "key2"]);
+        let range = b"key2".to_vec()..b"key5".to_vec();
+        let pairs = keys(store.scan_rev(range).await?, usize::MAX).await?;
+        assert_eq!(pairs, ["key4", "key3", "key2"]);
+        let range = (Bound::Excluded(b"key7".to_vec()), Bound::Unbounded);
+        let pairs = keys(store.scan(range).await?, usize::MAX).await?;
+        assert_eq!(pairs, ["key8", "key9"]);
+
+        let mut pairs = store.scan_rev(..b"key3".to_vec()).await?;
+        let pair = pairs.next().await.unwrap()?;
+        assert_eq!(pair, (b"key2".to_vec(), b"value2".to_vec()));
+        Ok(())
+    })
+}
+
+// Should give equal digests to stores with the same keys and values
+#[test]
+fn digest() -> Result<()> {
+    task::block_on(async {
+        let temp_dir1 = TempDir::new().expect("unable to create temporary working directory");
+        let temp_dir2 = TempDir::new().expect("unable to create temporary working directory");
+        let store1 = KvStore::open(temp_dir1.path()).await?;
+        let store2 = KvStore::open(temp_dir2.path()).await?;
+        assert_eq!(store1.digest().await?, store2.digest().await?);
+
+        for key_id in 0..100 {
+            store1.set(format!("key{}", key_id), "value").await?;
+        }
+        for key_id in (0..100).rev() {
+            store2.set(format!("key{}", key_id), "other").await?;
+            store2.set(format!("key{}", key_id), "value").await?;
+        }
+        assert_eq!(store1.digest().await?, store2.digest().await?);
+
+        store2.set("key50", "changed").await?;
+        assert_ne!(store1.digest().await?, store2.digest().await?);
+        store2.set("key50", "value").await?;
+        store2.set("key100", "value").await?;
+        assert_ne!(store1.digest().await?, store2.digest().await?);
+        Ok(())
+    })
+}
+
+// Should parse maintenance windows and tell whether a time falls within them
+#[test]
+fn maintenance_window() {
+    // 2024-01-01 was a Monday.
+    let monday = UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+    let at = |days: u64, hours: u64| monday + Duration::from_secs(days * 86400 + hours * 3600);
+
+    let window: MaintenanceWindow = "mon-fri 01:00-05:00".parse().unwrap();
+    assert!(window.contains(at(0, 2)));
+    assert!(window.contains(at(4, 1)));
+    assert!(!window.contains(at(0, 5)));
+    assert!(!window.contains(at(5, 2)));
+
+    let window: MaintenanceWindow = "* 22:00-02:00".parse().unwrap();
+    assert!(window.contains(at(1, 1)));
+    assert!(window.contains(at(6, 23)));
+    assert!(!window.contains(at(3, 12)));
+
+    let window: MaintenanceWindow = "sat,sun 00:00-00:00".parse().unwrap();
+    assert!(window.contains(at(5, 12)));
+    assert!(!window.contains(at(0, 12)));
+
+    assert!("mon 25:00-01:00".parse::<MaintenanceWindow>().is_err());
+    assert!("someday 01:00-02:00".parse::<MaintenanceWindow>().is_err());
+}
+
+// Should put off compaction outside of maintenance windows
+#[test]
+fn compaction_outside_maintenance_window() -> Result<()> {
+    task::block_on(async {
+        // A window on a day other than today.
+        let days = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 86400
+            + 3;
+        let window = format!("{} 00:00-00:00", days[(today as usize + 3) % 7]);
+        let options = Options {
+            maintenance_windows: vec![window.parse().unwrap()],
+            ..Options::default()
+        };
+
+        let temp_dir1 = TempDir::new().expect("unable to create temporary working directory");
+        let temp_dir2 = TempDir::new().expect("unable to create temporary working directory");
+        let store1 = KvStore::open(temp_dir1.path()).await?;
+        let store2 = KvStore::open_with_options(temp_dir2.path(), options).await?;
+        for store in &[&store1, &store2] {
+            for key_id in 0..200 {
+                store.set(format!("key{}", key_id), "value").await?;
+                for _ in 0..3 {
+                    store.set("hot", "value").await?;
+                }
+            }
+        }
+
+        let (stats1, stats2) = (store1.stats().await?, store2.stats().await?);
+        assert!(stats2.log_files > stats1.log_files);
+        assert!(stats2.dead_bytes > stats1.dead_bytes);
+        assert_eq!(store2.get("key0").await?, Some(b"value".to_vec()));
+        Ok(())
+    })
+}
+
+#[test]
+fn versioned_values() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            versions: 2,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).await?;
+        for i in 0..4 {
+            store.set("key", format!("value{}", i)).await?;
+        }
+        let versions = |ids: &[usize]| -> Vec<Vec<u8>> {
+            ids.iter()
+                .map(|i| format!("value{}", i).into_bytes())
+                .collect()
+        };
+        assert_eq!(store.history("key").await?, versions(&[3, 2, 1]));
+        assert_eq!(store.get_version("key", 1).await?, Some(b"value2".to_vec()));
+        assert_eq!(store.get_version("key", 3).await?, None);
+        assert_eq!(store.history("missing").await?, versions(&[]));
+
+        // Compaction keeps the history of live keys.
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), "value").await?;
+            store.set(format!("key{}", key_id), "value").await?;
+        }
+        store.set("key", "value4").await?;
+        assert_eq!(store.history("key").await?, versions(&[4, 3, 2]));
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        assert_eq!(store.history("key").await?, versions(&[4, 3, 2]));
+        store.remove("key").await?;
+        store.set("key", "value5").await?;
+        assert_eq!(store.history("key").await?, versions(&[5]));
+        Ok(())
+    })
+}
+
+#[test]
+fn transforms() -> Result<()> {
+    let value = r#"{"users": [{"name": "alice"}]}"#;
+    assert_eq!(Transform::Length.apply(value)?, Some("30".to_owned()));
+    assert_eq!(
+        Transform::Sha256.apply("")?,
+        Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_owned())
+    );
+    let pointer: Transform = "json:/users/0/name".parse().unwrap();
+    assert_eq!(pointer.apply(value)?, Some(r#""alice""#.to_owned()));
+    let pointer: Transform = "json:/users/1".parse().unwrap();
+    assert_eq!(pointer.apply(value)?, None);
+    assert!(pointer.apply("not json").is_err());
+    assert!("json:users".parse::<Transform>().is_err());
+    assert!("gzip".parse::<Transform>().is_err());
+    Ok(())
+}
+
+#[test]
+fn write_metadata() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        let before = SystemTime::now() - Duration::from_millis(1);
+        store.set("key1", "value1").await?;
+        store.set("key2", "value2").await?;
+        let (_, meta1) = store.get_with_metadata("key1").await?.unwrap();
+        let (_, meta2) = store.get_with_metadata("key2").await?.unwrap();
+        assert!(meta1.seq < meta2.seq);
+        let written_at = meta1.written_at.unwrap();
+        assert!(before <= written_at && written_at <= SystemTime::now());
+
+        // Sequence numbers keep increasing after reopening, whether the keydir
+        // file is loaded or the logs are replayed.
+        store.close().await?;
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get_with_metadata("key2").await?.unwrap().1, meta2);
+        store.set("key1", "value3").await?;
+        let (_, meta3) = store.get_with_metadata("key1").await?.unwrap();
+        assert!(meta3.seq > meta2.seq);
+        store.close().await?;
+        fs::remove_file(temp_dir.path().join("keydir"))?;
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set("key2", "value4").await?;
+        let (_, meta4) = store.get_with_metadata("key2").await?.unwrap();
+        assert!(meta4.seq > meta3.seq);
+        Ok(())
+    })
+}
+
+#[test]
+fn reconfigure() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set_option("max_file_size", "4KiB")?;
+        store.set_option("compaction_ratio", "0.5")?;
+        let stats = store.stats().await?;
+        assert_eq!(stats.max_file_size, 4096);
+        assert_eq!(stats.compaction_threshold, 2048);
+
+        assert!(store.set_option("compaction_ratio", "2").is_err());
+        assert!(store.set_option("cache_size", "1MB").is_err());
+        assert_eq!(store.options().compaction_ratio, 0.5);
+
+        // Keeping versions takes effect from the next write.
+        store.set("key", "value1").await?;
+        store.reconfigure(Options {
+            versions: 1,
+            ..store.options()
+        });
+        store.set("key", "value2").await?;
+        assert_eq!(
+            store.history("key").await?,
+            vec![b"value2".to_vec(), b"value1".to_vec()]
+        );
+        Ok(())
+    })
+}
+
+// Should reject keys and values beyond the limits, or not fitting in a log file
+#[test]
+fn size_limits() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_key_size: Some(8),
+            max_value_size: Some(16),
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        store.set("key", "value").await?;
+        store.set("long key", "value").await?;
+        assert!(matches!(
+            store.set("longer key", "value").await,
+            Err(KvsError::TooLarge("key", 10, 8))
+        ));
+        assert!(store.set_nx("key2", "a value of limit").await?);
+        assert!(matches!(
+            store.set_xx("key", "a value too long!").await,
+            Err(KvsError::TooLarge("value", 17, 16))
+        ));
+        assert!(matches!(
+            store
+                .compare_and_set("key", Some(b"value"), Some(&[0; 17]))
+                .await,
+            Err(KvsError::TooLarge("value", 17, 16))
+        ));
+        assert_eq!(store.get("key").await?, Some(b"value".to_vec()));
+        assert_eq!(store.get("longer key").await?, None);
+
+        store.set_option("max_value_size", "none")?;
+        assert!(matches!(
+            store.set("key", vec![0; 2000]).await,
+            Err(KvsError::TooLarge("key and value", 2003, 1024))
+        ));
+        store.set("key", vec![0; 1000]).await?;
+        assert!(store.set_option("max_key_size", "big").is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn read_admission() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 1 << 20,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        let keys: Vec<_> = (0..1000).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            store.set(key, key).await?;
+        }
+        assert_eq!(store.stats().await?.queued_reads, 0);
+
+        // More reads are submitted at once than the queue holds.
+        let values = store.multi_get(&keys).await?;
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(value, Some(key.clone().into_bytes()));
+        }
+        assert!(store.stats().await?.queued_reads > 0);
+        Ok(())
+    })
+}
+
+#[test]
+fn manual_compaction() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 1 << 20,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        for iter in 0..100 {
+            for key_id in 0..10 {
+                store
+                    .set(format!("key{}", key_id), format!("{}", iter))
+                    .await?;
+            }
+        }
+        let dead_bytes = store.stats().await?.dead_bytes;
+        assert!(dead_bytes > 0);
+
+        let compaction = store.compact().await?;
+        assert_eq!(compaction.logs_compacted, 1);
+        assert_eq!(compaction.bytes_reclaimed, dead_bytes);
+        let stats = store.stats().await?;
+        assert_eq!(stats.dead_bytes, 0);
+        assert_eq!(stats.log_files, 1);
+        assert_eq!(store.compact().await?.logs_compacted, 0);
+        assert_eq!(store.get("key3").await?, Some(b"99".to_vec()));
+
+        assert!(store.compact_gen(0).await.is_err());
+        store.remove("key3").await?;
+        let compaction = store.compact_gen(1).await?;
+        assert_eq!(compaction.logs_compacted, 1);
+        assert_eq!(store.get("key3").await?, None);
+        assert_eq!(store.get("key4").await?, Some(b"99".to_vec()));
+        Ok(())
+    })
+}
+
+// Should merge small logs into the log written by a compaction
+#[test]
+fn compaction_merges_small_logs() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        // Logs only holding a couple of records each.
+        store.set_option("max_file_size", "48")?;
+        for i in 0..6 {
+            store.set(format!("small{}", i), "value").await?;
+        }
+        store.set_option("max_file_size", "1KiB")?;
+        let small_logs: Vec<_> = store
+            .segment_stats()
+            .await?
+            .iter()
+            .map(|segment| segment.gen)
+            .collect();
+        assert!(small_logs.len() > 2, "{:?}", small_logs);
+
+        for iter in 0..200 {
+            store.set("key", format!("{}", iter)).await?;
+        }
+        let gens: Vec<_> = store
+            .segment_stats()
+            .await?
+            .iter()
+            .map(|segment| segment.gen)
+            .collect();
+        let left: Vec<_> = small_logs.iter().filter(|gen| gens.contains(gen)).collect();
+        assert!(left.len() <= 1, "{:?} of {:?} left", left, small_logs);
+        for i in 0..6 {
+            assert_eq!(
+                store.get(format!("small{}", i)).await?,
+                Some(b"value".to_vec())
+            );
+        }
+        Ok(())
+    })
+}
+
+// Should create missing directories, or fail depending on whether a store exists
+#[test]
+fn open_options() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dir = temp_dir.path().join("a").join("b");
+        let options = Options {
+            create_if_missing: false,
+            ..Options::default()
+        };
+        assert!(matches!(
+            KvStore::open_with_options(&dir, options.clone()).await,
+            Err(KvsError::NoStore(_))
+        ));
+        assert!(!dir.exists());
+
+        let store = KvStore::open(&dir).await?;
+        store.set("key1", "value1").await?;
+        store.close().await?;
+
+        let store = KvStore::open_with_options(&dir, options).await?;
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        store.close().await?;
+        let options = Options {
+            error_if_exists: true,
+            ..Options::default()
+        };
+        assert!(matches!(
+            KvStore::open_with_options(&dir, options.clone()).await,
+            Err(KvsError::StoreExists(_))
+        ));
+        KvStore::open_with_options(temp_dir.path().join("c"), options).await?;
+        Ok(())
+    })
+}
+
+// Should remove the directory of an in-memory store once it's dropped
+#[test]
+fn in_memory() -> Result<()> {
+    task::block_on(async {
+        let store = KvStore::open_in_memory().await?;
+        let other = KvStore::open_in_memory().await?;
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        store.remove("key1").await?;
+        assert_eq!(store.get("key2").await?, Some(b"value2".to_vec()));
+        assert_eq!(store.get("key1").await?, None);
+        assert_eq!(other.get("key2").await?, None);
+        store.compact().await?;
+        assert_eq!(store.get("key99").await?, Some(b"value99".to_vec()));
+
+        let dir = store.dir().to_path_buf();
+        assert!(dir.exists());
+        store.clone().close().await?;
+        assert!(dir.exists());
+        drop(store);
+        assert!(!dir.exists());
+        Ok(())
+    })
+}
+
+// Should read from more logs than it keeps open at once
+#[test]
+fn max_open_files() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_open_files: 3,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        for i in 0..500 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        assert!(store.stats().await?.log_files > 10);
+
+        for i in (0..500).rev() {
+            let value = store.get(format!("key{}", i)).await?;
+            assert_eq!(value, Some(format!("value{}", i).into_bytes()));
+        }
+        let keys: Vec<_> = (0..500).step_by(7).map(|i| format!("key{}", i)).collect();
+        let values = store.multi_get(&keys).await?;
+        assert!(values.iter().all(Option::is_some));
+        assert!(store.verify().await?.is_ok());
+
+        // Files of the store the process has open, where that can be told.
+        if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+            let open = fds
+                .filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+                .filter(|path| path.starts_with(temp_dir.path()))
+                .count();
+            assert!(open <= 3, "{}", open);
+        }
+        Ok(())
+    })
+}
+
+// Should read the same values with direct I/O, across block boundaries
+#[test]
+fn direct_io() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 64 * 1024,
+            direct_io: true,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        let value = |i: usize| format!("{}:", i).repeat(i % 700);
+        for i in 0..300 {
+            store.set(format!("key{}", i), value(i)).await?;
+        }
+        for i in 0..300 {
+            assert_eq!(
+                store.get(format!("key{}", i)).await?,
+                Some(value(i).into_bytes())
+            );
+        }
+        let keys: Vec<_> = (0..300).map(|i| format!("key{}", i)).collect();
+        let values = store.multi_get(&keys).await?;
+        for (i, got) in values.into_iter().enumerate() {
+            assert_eq!(got, Some(value(i).into_bytes()));
+        }
+        let digest = store.digest().await?;
+        store.compact().await?;
+        assert_eq!(store.digest().await?, digest);
+        assert!(store.verify().await?.is_ok());
+        Ok(())
+    })
+}
+
+// Should save how far the logs are synced, and find it when reopening
+#[test]
+fn watermark() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            sync_interval: Some(Duration::from_millis(20)),
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).await?;
+        assert_eq!(store.recovery_watermark(), None);
+        for i in 0..100 {
+            store.set(format!("key{}", i), "value").await?;
+        }
+        let seq = store.get_with_metadata("key99").await?.unwrap().1.seq;
+        task::sleep(Duration::from_millis(500)).await;
+        let mark = store.durable_watermark().await.unwrap();
+        assert_eq!(mark.seq, seq);
+        assert_eq!(mark.gen, store.stats().await?.log_files - 1);
+
+        std::mem::forget(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        assert_eq!(store.recovery_watermark(), Some(mark));
+        assert_eq!(store.durable_watermark().await, Some(mark));
+        Ok(())
+    })
+}
+
+// Should keep every log in one file, reusing the regions of compacted logs
+#[test]
+fn single_file() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || Options {
+            single_file: true,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options()).await?;
+        for iter in 0..200 {
+            for key_id in 0..10 {
+                store
+                    .set(format!("key{}", key_id), format!("{}", iter))
+                    .await?;
+            }
+        }
+        // Larger than `max_file_size` once encoded.
+        store.set("large", "x".repeat(1000)).await?;
+        store.remove("key0").await?;
+        assert!(store.stats().await?.log_files > 1);
+        let digest = store.digest().await?;
+
+        let mut names: Vec<_> = fs::read_dir(temp_dir.path())?
+            .map(|file| file.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["MANIFEST", "kvs.data"]);
+        let size = fs::metadata(temp_dir.path().join("kvs.data"))?.len();
+        assert!(size < 80 * 1024, "{}", size);
+
+        std::mem::forget(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options()).await?;
+        assert_eq!(store.digest().await?, digest);
+        assert_eq!(store.get("key0").await?, None);
+        assert_eq!(store.get("key1").await?, Some(b"199".to_vec()));
+        store.close().await?;
+        let store = KvStore::open_with_options(temp_dir.path(), options()).await?;
+        assert_eq!(store.digest().await?, digest);
+        assert!(store.verify().await?.is_ok());
+        store.close().await?;
+
+        assert!(matches!(
+            KvStore::open(temp_dir.path()).await,
+            Err(KvsError::Config(_))
+        ));
+        Ok(())
+    })
+}
+
+// Writes should wait while frozen, and go on once thawed or timed out
+#[test]
+fn freeze_writes() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set("key1", "value1").await?;
+        store.freeze_writes(Duration::from_secs(60)).await?;
+        assert!(temp_dir.path().join("keydir").exists());
+        assert!(store.freeze_writes(Duration::from_secs(60)).await.is_err());
+
+        let writing = task::spawn({
+            let store = store.clone();
+            async move { store.set("key1", "value2").await }
+        });
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        assert!(store.thaw());
+        writing.await?;
+        assert_eq!(store.get("key1").await?, Some(b"value2".to_vec()));
+        assert!(!store.thaw());
+
+        store.freeze_writes(Duration::from_millis(100)).await?;
+        store.set("key1", "value3").await?;
+        assert_eq!(store.get("key1").await?, Some(b"value3".to_vec()));
+        assert!(!store.thaw());
+        Ok(())
+    })
+}
+
+// Damaged logs and stray files should be reported rather than failing
+#[test]
+fn verify() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), "value").await?;
+        }
+        let report = store.verify().await?;
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.keys_checked, 50);
+        store.close().await?;
+
+        // Garble the first record of the oldest log and cut off its last.
+        let log = temp_dir.path().join("0.log");
+        let mut data = fs::read(&log)?;
+        let len = data.len() as u64;
+        data[10..20].copy_from_slice(&[0xff; 10]);
+        data.truncate(data.len() - 10);
+        fs::write(&log, data)?;
+        fs::write(temp_dir.path().join("keydir.0000000000000000.tmp"), b"")?;
+
+        let store = KvStore::open(temp_dir.path()).await?;
+        fs::write(temp_dir.path().join("999.log"), b"")?;
+        let report = store.verify().await?;
+        assert!(!report.is_ok());
+        assert_eq!(report.keys_checked, 50);
+        assert_eq!(report.undecodable, vec![b"key0".to_vec()]);
+        assert_eq!(report.dangling.len(), 1);
+        assert!(report.mismatched.is_empty());
+        assert_eq!(report.corrupt_logs.len(), 1);
+        assert_eq!(report.corrupt_logs[0].gen, 0);
+        assert_eq!(report.corrupt_logs[0].valid_len, 0);
+        assert_eq!(report.corrupt_logs[0].len, len - 10);
+        assert_eq!(report.orphans.len(), 2);
+        Ok(())
+    })
+}
+
+#[test]
+fn segment_stats() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 1 << 20,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), "value").await?;
+        }
+        store.set("key1", "value").await?;
+
+        let segments = store.segment_stats().await?;
+        assert_eq!(segments.len(), 1);
+        let segment = &segments[0];
+        assert_eq!(segment.gen, 0);
+        assert_eq!(segment.live_records, 10);
+        assert_eq!(segment.live_bytes + segment.dead_bytes, segment.size);
+        assert_eq!(segment.dead_bytes * 11, segment.size);
+        assert!((segment.dead_ratio() - 1.0 / 11.0).abs() < 1e-9);
+
+        store.compact().await?;
+        let segments = store.segment_stats().await?;
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].gen, 1);
+        assert_eq!(segments[0].live_records, 10);
+        assert_eq!(segments[0].dead_bytes, 0);
+        assert_eq!(segments[0].dead_ratio(), 0.0);
+        Ok(())
+    })
+}
+
+#[derive(Default)]
+struct EventLog(Mutex<Vec<String>>);
+
+impl StoreListener for EventLog {
+    fn on_open(&self, _dir: &Path) {
+        self.0.lock().unwrap().push("open".to_owned());
+    }
+
+    fn on_rotation(&self, sealed: u64, active: u64) {
+        let event = format!("rotation {} {}", sealed, active);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn on_compaction_start(&self, gen: u64) {
+        let event = format!("compaction start {}", gen);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn on_compaction_finish(&self, gen: u64) {
+        let event = format!("compaction finish {}", gen);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn on_close(&self, _dir: &Path) {
+        self.0.lock().unwrap().push("close".to_owned());
+    }
+}
+
+#[test]
+fn lifecycle_listener() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let events = Arc::new(EventLog::default());
+        let mut options = Options {
+            max_file_size: 1 << 20,
+            ..Options::default()
+        };
+        options.listeners.push(events.clone());
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        store.set("key1", "value1").await?;
+        store.set("key1", "value2").await?;
+        store.compact().await?;
+        store.close().await?;
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            vec![
+                "open",
+                "rotation 0 1",
+                "compaction start 0",
+                "compaction finish 0",
+                "close"
+            ]
+        );
+        Ok(())
+    })
+}
+
+// Sessions are recorded with `kvs-server --record-session`.
+#[test]
+fn replay_recorded_session() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        let session = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/sessions/basic.session");
+        assert_eq!(replay_session(session, &store).await?, 7);
+        assert_eq!(store.get("user:1").await?, Some(b"carol".to_vec()));
+        assert_eq!(store.get("user:2").await?, None);
+        assert_eq!(store.get("doc").await?, Some(br#"{"a":1}"#.to_vec()));
+        Ok(())
+    })
+}
+
+// Recorded sessions should never hold the values of redacted keys
+#[test]
+fn record_redacted_session() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let session = temp_dir.path().join("recorded.session");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().join("server")),
+            redaction: Redaction::new(vec!["secret:"]),
+            record_session: Some(session.clone()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        client
+            .set("secret:1".to_owned(), "hunter2".to_owned())
+            .await?;
+        client
+            .set("public".to_owned(), "visible".to_owned())
+            .await?;
+        drop(client);
+        server.shutdown();
+        running.await?;
+
+        let recorded = fs::read(&session)?;
+        let contains = |value: &[u8]| recorded.windows(value.len()).any(|w| w == value);
+        assert!(!contains(b"hunter2"));
+        assert!(contains(b"visible"));
+        let store = KvStore::open(temp_dir.path().join("replayed")).await?;
+        assert_eq!(replay_session(&session, &store).await?, 2);
+        assert_eq!(store.get("secret:1").await?, Some(b"*******".to_vec()));
+        assert_eq!(store.get("public").await?, Some(b"visible".to_vec()));
+        Ok(())
+    })
+}
+
+// Should write the format version of a new store, and upgrade or refuse others
+#[test]
+fn format_version() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let manifest = temp_dir.path().join("MANIFEST");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set("key1", "value1").await?;
+        store.close().await?;
+        assert_eq!(fs::read_to_string(&manifest)?, "format-version = 2\n");
+
+        // A store from before manifests is upgraded.
+        fs::remove_file(&manifest)?;
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        store.close().await?;
+        assert_eq!(fs::read_to_string(&manifest)?, "format-version = 2\n");
+
+        fs::write(&manifest, "format-version = 99\n")?;
+        match KvStore::open(temp_dir.path()).await {
+            Err(KvsError::UnsupportedFormat(99, 2)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("opened a store of a newer format"),
+        }
+        Ok(())
+    })
+}
+
+// Should copy every key and value of a sled database
+#[cfg(feature = "sled")]
+#[test]
+fn migrate_from_sled() -> Result<()> {
+    task::block_on(async {
+        let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+        // Without the flusher thread, which releases the lock after the drop.
+        let db = sled::Config::new()
+            .path(sled_dir.path())
+            .flush_every_ms(None)
+            .open()
+            .unwrap();
+        for i in 0..100 {
+            db.insert(format!("key{}", i), format!("value{}", i).as_bytes())
+                .unwrap();
+        }
+        drop(db);
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(kvs::migrate_from_sled(sled_dir.path(), &store).await?, 100);
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", i)).await?,
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+        Ok(())
+    })
+}
+
+// Should serve keys from a sled database through the engine trait
+#[cfg(feature = "sled")]
+#[test]
+fn sled_engine() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = kvs::SledEngine::open(temp_dir.path())?;
+        engine.set(b"a1", b"1").await?;
+        engine.set(b"a2", b"2").await?;
+        engine.set(b"b1", b"3").await?;
+        assert_eq!(KvsEngine::get(&engine, b"a1").await?, Some(b"1".to_vec()));
+        assert_eq!(
+            engine.keys_with_prefix(b"a").await?,
+            vec![b"a1".to_vec(), b"a2".to_vec()]
+        );
+        assert!(!engine.compare_and_set(b"a1", Some(b"2"), None).await?);
+        assert!(
+            engine
+                .compare_and_set(b"a1", Some(b"1"), Some(b"4"))
+                .await?
+        );
+        assert_eq!(KvsEngine::get(&engine, b"a1").await?, Some(b"4".to_vec()));
+        KvsEngine::remove(&engine, b"a2").await?;
+        match KvsEngine::remove(&engine, b"a2").await {
+            Err(KvsError::KeyNotFound) => {}
+            res => panic!("removed a missing key: {:?}", res),
+        }
+        Ok(())
+    })
+}
+
+// Should encode and decode values of any serde type
+#[test]
+fn typed_values() -> Result<()> {
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        let user = User {
+            name: "alice".to_owned(),
+            age: 30,
+        };
+        store.set_typed("user1", &user).await?;
+        assert_eq!(store.get_typed::<_, User>("user1").await?, Some(user));
+        assert_eq!(store.get_typed::<_, User>("user2").await?, None);
+
+        store.set("user2", "x").await?;
+        match store.get_typed::<_, User>("user2").await {
+            Err(KvsError::Serde(_)) => {}
+            res => panic!("decoded a value of another type: {:?}", res),
+        }
+        Ok(())
+    })
+}
+
+// Should pass keys and values through the codecs of a codec store
+#[test]
+fn codecs() -> Result<()> {
+    use kvs::codec::{Bincode, KeyCodec, Raw};
+
+    /// Keys of one byte, counted down so scans go from the largest.
+    struct Descending;
+
+    impl KeyCodec for Descending {
+        type Key = u8;
+
+        fn encode_key(&self, key: &u8) -> Result<Vec<u8>> {
+            Ok(vec![255 - key])
+        }
+
+        fn decode_key(&self, bytes: Vec<u8>) -> Result<u8> {
+            Ok(255 - bytes[0])
+        }
+    }
+
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        let typed = store
+            .clone()
+            .with_codecs(Descending, Bincode::<Vec<u32>>::default());
+        for key in 0..10 {
+            typed.set(&key, &vec![u32::from(key); 3]).await?;
+        }
+        typed.remove(&9).await?;
+        assert_eq!(typed.get(&3).await?, Some(vec![3, 3, 3]));
+        assert_eq!(typed.get(&9).await?, None);
+        assert_eq!(store.get([255 - 3]).await?, Some(bincode_of(&[3u32; 3])));
+
+        let mut scan = typed.scan(..=5).await?;
+        let mut keys = Vec::new();
+        while let Some(pair) = scan.next().await {
+            keys.push(pair?.0);
+        }
+        assert_eq!(keys, [8, 7, 6, 5]);
+
+        let raw = store.with_codecs(Raw, Raw);
+        assert_eq!(raw.get(&vec![255 - 3]).await?, Some(bincode_of(&[3u32; 3])));
+        Ok(())
+    })
+}
+
+fn bincode_of(values: &[u32]) -> Vec<u8> {
+    let mut bytes = (values.len() as u64).to_le_bytes().to_vec();
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+// Should return the changes logged since a sequence number, then tail new ones
+#[test]
+fn changes_since() -> Result<()> {
+    use kvs::Change;
+
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set("key1", "value1").await?;
+        store.set("key2", "value2").await?;
+        store.remove("key1").await?;
+        store.delete_prefix("key").await?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path()).await?;
+        let mut changes = store.changes_since(1).await?;
+        assert_eq!(
+            changes.next().await,
+            Some(Change::Set {
+                seq: 2,
+                key: b"key2".to_vec(),
+                value: b"value2".to_vec()
+            })
+        );
+        assert_eq!(
+            changes.next().await,
+            Some(Change::Remove {
+                seq: 3,
+                key: b"key1".to_vec()
+            })
+        );
+        assert_eq!(
+            changes.next().await,
+            Some(Change::RemovePrefix {
+                seq: 4,
+                prefix: b"key".to_vec()
+            })
+        );
+
+        store.set("key3", "value3").await?;
+        assert_eq!(
+            changes.next().await,
+            Some(Change::Set {
+                seq: 5,
+                key: b"key3".to_vec(),
+                value: b"value3".to_vec()
+            })
+        );
+        drop(store);
+        assert_eq!(changes.next().await, None);
+        Ok(())
+    })
+}
+
+// Should record every mutation in the audit log, and rotate it
+#[test]
+fn audit_log() -> Result<()> {
+    use kvs::{read_audit_log, AuditQuery};
+
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let audit_dir = temp_dir.path().join("audit");
+        let options = Options {
+            audit_log: Some(audit_dir.clone()),
+            audit_max_size: 1024,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        let client = "10.0.0.1:4000".parse().unwrap();
+        for i in 0..20 {
+            store.set(format!("key{}", i), "value").await?;
+        }
+        store.with_client(client).remove("key1").await?;
+        store.delete_prefix("key2").await?;
+        drop(store);
+
+        let entries = read_audit_log(&audit_dir, &AuditQuery::default())?;
+        assert_eq!(entries.len(), 22);
+        assert!(entries[..20].iter().all(|entry| entry.op == "set"));
+        assert_eq!(entries[20].op, "remove");
+        assert_eq!(entries[20].client, Some(client));
+        assert_eq!(entries[21].op, "delete_prefix");
+        assert_eq!(entries[21].key, "key2");
+        assert!(audit_dir.join("audit.1.log").exists());
+
+        let query = AuditQuery {
+            client: Some(client.ip()),
+            ..AuditQuery::default()
+        };
+        let entries = read_audit_log(&audit_dir, &query)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "key1");
+
+        // An audit log in the store's own directory isn't one of its logs.
+        let options = Options {
+            audit_log: Some(temp_dir.path().to_path_buf()),
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).await?;
+        store.set("key1", "value1").await?;
+        drop(store);
+        assert!(temp_dir.path().join("audit.log").exists());
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        Ok(())
+    })
+}
+
+// Should open a checkpoint read-only as the store was when it was saved
+#[test]
+fn checkpoints() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 1024,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i % 10), format!("{}", i))
+                .await?;
+        }
+        store.checkpoint("first").await?;
+        match store.checkpoint("first").await {
+            Err(KvsError::StoreExists(_)) => {}
+            res => panic!("saved a checkpoint twice: {:?}", res),
+        }
+        match store.checkpoint("../escape").await {
+            Err(KvsError::Config(_)) => {}
+            res => panic!("saved a checkpoint outside the store: {:?}", res),
+        }
+
+        // Changes made later, even compacting every log, aren't in it.
+        for i in 0..10 {
+            store.set(format!("key{}", i), "new").await?;
+        }
+        store.remove("key0").await?;
+        store.compact().await?;
+
+        // Opening the checkpoint leaves its files as they were.
+        let contents = || -> Vec<_> {
+            let dir = temp_dir.path().join("checkpoints").join("first");
+            let mut files: Vec<_> = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    (
+                        path.file_name().unwrap().to_owned(),
+                        fs::read(&path).unwrap(),
+                    )
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let saved = contents();
+        assert!(saved.iter().any(|(name, _)| name == "keydir"));
+        let checkpoint = KvStore::open_checkpoint(temp_dir.path(), "first").await?;
+        assert_eq!(checkpoint.get("key0").await?, Some(b"90".to_vec()));
+        assert_eq!(checkpoint.get("key9").await?, Some(b"99".to_vec()));
+        match checkpoint.set("key0", "x").await {
+            Err(KvsError::ReadOnly) => {}
+            res => panic!("wrote to a read-only store: {:?}", res),
+        }
+        match checkpoint.remove("key0").await {
+            Err(KvsError::ReadOnly) => {}
+            res => panic!("wrote to a read-only store: {:?}", res),
+        }
+        assert!(checkpoint.compact().await.is_err());
+        assert!(checkpoint.verify().await?.is_ok());
+        drop(checkpoint);
+        assert!(contents() == saved, "opening the checkpoint changed it");
+
+        assert_eq!(store.get("key0").await?, None);
+        assert_eq!(store.get("key9").await?, Some(b"new".to_vec()));
+        assert!(store.verify().await?.is_ok());
+        Ok(())
+    })
+}
+
+// Should copy only the logs created since the previous backup
+#[test]
+fn backup_incremental() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 1024,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), "value").await?;
+        }
+        let first = store.backup_incremental(backup_dir.path(), None).await?;
+        assert!(first.logs_copied > 1);
+
+        store.set("key0", "new").await?;
+        store.remove("key1").await?;
+        let second = store
+            .backup_incremental(backup_dir.path(), Some(&first.checkpoint))
+            .await?;
+        // The log sealed by the first backup, which the sets went to.
+        assert_eq!(second.logs_copied, 1);
+        assert!(!temp_dir
+            .path()
+            .join("checkpoints")
+            .join(&first.checkpoint)
+            .exists());
+
+        store.compact().await?;
+        let third = store
+            .backup_incremental(backup_dir.path(), Some(&second.checkpoint))
+            .await?;
+        assert!(third.logs_removed > 0);
+        drop(store);
+
+        let backup = KvStore::open(backup_dir.path()).await?;
+        assert_eq!(backup.get("key0").await?, Some(b"new".to_vec()));
+        assert_eq!(backup.get("key1").await?, None);
+        assert_eq!(backup.get("key99").await?, Some(b"value".to_vec()));
+        assert!(backup.verify().await?.is_ok());
+        Ok(())
+    })
+}
+
+#[test]
+fn backup_to_target() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let target_dir = TempDir::new().expect("unable to create temporary working directory");
+        let target = DirTarget::new(target_dir.path());
+        let options = Options {
+            max_file_size: 1024,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).await?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), "value").await?;
+        }
+        let first = store.backup(&target).await?;
+        assert!(first.logs_copied > 1);
+        assert!(!temp_dir
+            .path()
+            .join("checkpoints")
+            .join(&first.checkpoint)
+            .exists());
+
+        store.set("key0", "new").await?;
+        store.remove("key1").await?;
+        let second = store.backup(&target).await?;
+        assert_eq!(second.logs_copied, 1);
+        store.compact().await?;
+        let third = store.backup(&target).await?;
+        assert!(third.logs_removed > 0);
+
+        let restored = temp_dir.path().join("restored");
+        KvStore::restore(&target, &restored).await?;
+        match KvStore::restore(&target, &restored).await {
+            Err(KvsError::StoreExists(_)) => {}
+            res => panic!("restored over a store: {:?}", res),
+        }
+        let backup = KvStore::open(&restored).await?;
+        assert_eq!(backup.get("key0").await?, Some(b"new".to_vec()));
+        assert_eq!(backup.get("key1").await?, None);
+        assert_eq!(backup.get("key99").await?, Some(b"value".to_vec()));
+        assert_eq!(backup.digest().await?, store.digest().await?);
+
+        // A corrupted object fails the restore, leaving nothing behind.
+        let log = fs::read_dir(target_dir.path())
+            .unwrap()
+            .map(|file| file.unwrap().path())
+            .find(|path| path.extension() == Some("log".as_ref()))
+            .unwrap();
+        let mut data = fs::read(&log)?;
+        data[0] ^= 1;
+        fs::write(&log, data)?;
+        let corrupt = temp_dir.path().join("corrupt");
+        match KvStore::restore(&target, &corrupt).await {
+            Err(KvsError::Backup(_)) => {}
+            res => panic!("restored a corrupted backup: {:?}", res),
+        }
+        assert!(!corrupt.exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn archives() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            max_file_size: 1024,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path().join("store"), options).await?;
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        store.remove("key1").await?;
+        let archive = temp_dir.path().join("store.kvsarch");
+        let len = store.export_archive(&archive).await?;
+        assert_eq!(fs::metadata(&archive)?.len(), len);
+        assert!(!temp_dir
+            .path()
+            .join("store")
+            .join("checkpoints")
+            .read_dir()?
+            .any(|_| true));
+
+        let imported = temp_dir.path().join("imported");
+        KvStore::import_archive(&archive, &imported).await?;
+        match KvStore::import_archive(&archive, &imported).await {
+            Err(KvsError::StoreExists(_)) => {}
+            res => panic!("imported over a store: {:?}", res),
+        }
+        let copy = KvStore::open(&imported).await?;
+        assert_eq!(copy.get("key1").await?, None);
+        assert_eq!(copy.get("key99").await?, Some(b"value99".to_vec()));
+        assert_eq!(copy.digest().await?, store.digest().await?);
+
+        let mut data = fs::read(&archive)?;
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        fs::write(&archive, &data)?;
+        let corrupt = temp_dir.path().join("corrupt");
+        match KvStore::import_archive(&archive, &corrupt).await {
+            Err(KvsError::InvalidArchive(..)) => {}
+            res => panic!("imported a corrupted archive: {:?}", res),
+        }
+        fs::write(&archive, &data[..middle])?;
+        match KvStore::import_archive(&archive, &corrupt).await {
+            Err(KvsError::InvalidArchive(..)) => {}
+            res => panic!("imported a truncated archive: {:?}", res),
+        }
+        assert!(!corrupt.exists());
+        Ok(())
+    })
+}
+
+// Should serve clients on the port it was bound to until shut down
+#[test]
+fn embedded_server() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        match client.remove("key2".to_owned()).await {
+            Err(KvsError::Server(ServerError::KeyNotFound)) => {}
+            res => panic!("removed a missing key: {:?}", res),
+        }
+        drop(client);
+
+        server.shutdown();
+        running.await?;
+        match server.run().await {
+            Err(KvsError::Config(_)) => {}
+            res => panic!("ran a server twice: {:?}", res),
+        }
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key1").await?, Some(b"value1".to_vec()));
+        Ok(())
+    })
+}
+
+// Values longer than a frame should be sent in chunks, within the limits
+#[test]
+fn chunked_values() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            max_frame_size: Some(2 << 20),
+            store: Options {
+                max_file_size: 16 << 20,
+                max_value_size: Some(4 << 20),
+                ..Options::default()
+            },
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        let value: String = (0..3 << 20)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        client.set("key1".to_owned(), value.clone()).await?;
+        assert_eq!(client.get("key1".to_owned()).await?, Some(value.clone()));
+
+        match client.set("key1".to_owned(), "x".repeat(5 << 20)).await {
+            Err(KvsError::Server(ServerError::TooLarge { max, .. })) => assert_eq!(max, 4 << 20),
+            res => panic!("set a value over the limit: {:?}", res),
+        }
+        assert_eq!(client.get("key1".to_owned()).await?, Some(value));
+        client.set("key2".to_owned(), "value2".to_owned()).await?;
+        assert_eq!(
+            client.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        drop(client);
+        server.shutdown();
+        running.await?;
+
+        // Values set in chunks are held whole, so they're limited too.
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            max_chunked_value_size: Some(2 << 20),
+            store: Options {
+                max_file_size: 16 << 20,
+                ..Options::default()
+            },
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        match client.set("key1".to_owned(), "x".repeat(3 << 20)).await {
+            Err(KvsError::Server(ServerError::TooLarge { max, .. })) => assert_eq!(max, 2 << 20),
+            res => panic!("set a chunked value over the limit: {:?}", res),
+        }
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Clients should only be allowed what their auth token does
+#[test]
+fn acl_tokens() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let acl = "# tokens\nreader read app1:\nwriter write app1: shared:\n".parse();
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            acl: Some(acl.expect("invalid ACL")),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let addr = server.local_addr();
+        let with_token = |token: &str| ClientConfig {
+            token: Some(token.to_owned()),
+            ..ClientConfig::default()
+        };
+
+        let mut anonymous = KvsClient::connect(addr, ClientConfig::default()).await?;
+        assert_eq!(anonymous.ping(b"hi").await?, b"hi".to_vec());
+        match anonymous.get("app1:key".to_owned()).await {
+            Err(KvsError::Server(ServerError::AuthRequired)) => {}
+            res => panic!("got a key unauthenticated: {:?}", res),
+        }
+        match KvsClient::connect(addr, with_token("unknown")).await {
+            Err(KvsError::Server(ServerError::AuthRequired)) => {}
+            res => panic!("authenticated with an unknown token: {:?}", res.err()),
+        }
+
+        let mut writer = KvsClient::connect(addr, with_token("writer")).await?;
+        writer
+            .set("app1:key".to_owned(), "value".to_owned())
+            .await?;
+        match writer.set("app2:key".to_owned(), "value".to_owned()).await {
+            Err(KvsError::Server(ServerError::Forbidden { op })) => assert_eq!(op, "set"),
+            res => panic!("set a key outside the token's prefixes: {:?}", res),
+        }
+        match writer.digest().await {
+            Err(KvsError::Server(ServerError::Forbidden { .. })) => {}
+            res => panic!("digested the store with a prefix token: {:?}", res.err()),
+        }
+
+        let mut reader = KvsClient::connect(addr, with_token("reader")).await?;
+        assert_eq!(
+            reader.get("app1:key".to_owned()).await?,
+            Some("value".to_owned())
+        );
+        match reader.remove("app1:key".to_owned()).await {
+            Err(KvsError::Server(ServerError::Forbidden { op })) => assert_eq!(op, "remove"),
+            res => panic!("removed a key with a read token: {:?}", res),
+        }
+        drop((anonymous, writer, reader));
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Connections beyond the worker queue should be refused as busy
+#[test]
+fn worker_pool_sheds_load() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            workers: Some(1),
+            worker_queue: Some(1),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let addr = server.local_addr();
+        let mut served = KvsClient::connect(addr, ClientConfig::default()).await?;
+        served.set("key1".to_owned(), "value1".to_owned()).await?;
+        // Waits for the worker, which serves the first connection.
+        let queued = task::spawn(async move {
+            let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+            client.get("key1".to_owned()).await
+        });
+        task::sleep(Duration::from_millis(200)).await;
+
+        let mut refused = KvsClient::connect(addr, ClientConfig::default()).await?;
+        match refused.get("key1".to_owned()).await {
+            Err(KvsError::Server(ServerError::RateLimited)) => {}
+            res => panic!("served a connection beyond the queue: {:?}", res),
+        }
+        drop(served);
+        assert_eq!(queued.await?, Some("value1".to_owned()));
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Requests sent at once on a multiplexed connection should each get their reply
+#[test]
+fn multiplexed_client() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let client = KvsClient::connect(server.local_addr(), ClientConfig::default())
+            .await?
+            .multiplex()
+            .await?;
+        let setting = (0..100).map(|i| {
+            let client = client.clone();
+            task::spawn(async move { client.set(format!("key{}", i), format!("value{}", i)).await })
+        });
+        for set in setting.collect::<Vec<_>>() {
+            set.await?;
+        }
+        let getting = (0..100).map(|i| {
+            let client = client.clone();
+            task::spawn(async move { client.get(format!("key{}", i)).await })
+        });
+        for (i, get) in getting.collect::<Vec<_>>().into_iter().enumerate() {
+            assert_eq!(get.await?, Some(format!("value{}", i)));
+        }
+        match client.remove("missing".to_owned()).await {
+            Err(KvsError::Server(ServerError::KeyNotFound)) => {}
+            res => panic!("removed a missing key: {:?}", res),
+        }
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Exists and Strlen should answer from the index and record headers alone
+#[test]
+fn exists_and_strlen() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set("key1", "value1").await?;
+        store.set("key2", "value2").await?;
+        store.set("key1", "longer value1").await?;
+        store.remove("key2").await?;
+        assert!(store.contains_key("key1").await?);
+        assert!(!store.contains_key("key2").await?);
+        assert_eq!(store.value_len("key1").await?, Some(13));
+        assert_eq!(store.value_len("key2").await?, None);
+        drop(store);
+
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        assert!(client.exists("key1".to_owned()).await?);
+        assert!(!client.exists("key2".to_owned()).await?);
+        assert_eq!(client.strlen("key1".to_owned()).await?, Some(13));
+        assert_eq!(client.strlen("key2".to_owned()).await?, None);
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Several keys should be set with one write and got with one request
+#[test]
+fn multi_get_and_set() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store
+            .set_many(vec![
+                ("key1", "value1"),
+                ("key2", "value2"),
+                ("key1", "value3"),
+            ])
+            .await?;
+        assert_eq!(
+            store.multi_get(&["key1", "key2", "key3"]).await?,
+            vec![Some(b"value3".to_vec()), Some(b"value2".to_vec()), None]
+        );
+        drop(store);
+
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        let pairs = (0..10).map(|i| (format!("key{}", i), format!("value{}", i)));
+        client.set_many(pairs.collect()).await?;
+        let keys = (0..12).map(|i| format!("key{}", i)).collect();
+        let values = client.get_many(keys).await?;
+        assert_eq!(values.len(), 12);
+        for (i, value) in values.into_iter().enumerate() {
+            assert_eq!(value, Some(format!("value{}", i)).filter(|_| i < 10));
+        }
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Removing several keys should count those that were set, skipping the rest
+#[test]
+fn remove_many_keys() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        client.set("key2".to_owned(), "value2".to_owned()).await?;
+        client.set("key3".to_owned(), "value3".to_owned()).await?;
+        let keys = ["key1", "key2", "key2", "key4"]
+            .iter()
+            .map(|key| key.to_string());
+        assert_eq!(client.remove_many(keys.collect()).await?, 2);
+        assert_eq!(client.get("key1".to_owned()).await?, None);
+        assert_eq!(client.get("key2".to_owned()).await?, None);
+        assert_eq!(
+            client.get("key3".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(client.remove_many(Vec::new()).await?, 0);
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// A renamed key should keep its value and flags under the new name, after
+// reopening too
+#[test]
+fn rename_key() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set_with_flags("key1", "value1", 7).await?;
+        store.set("key2", "value2").await?;
+        store.rename("key1", "key2").await?;
+        assert_eq!(store.get("key1").await?, None);
+        assert_eq!(store.get("key2").await?, Some(b"value1".to_vec()));
+        match store.rename("key1", "key3").await {
+            Err(KvsError::KeyNotFound) => {}
+            res => panic!("renamed a missing key: {:?}", res),
+        }
+        store.rename("key2", "key2").await?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key1").await?, None);
+        let (value, metadata) = store.get_with_metadata("key2").await?.unwrap();
+        assert_eq!((value, metadata.flags), (b"value1".to_vec(), 7));
+        drop(store);
+
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        client.rename("key2".to_owned(), "key4".to_owned()).await?;
+        assert_eq!(
+            client.get("key4".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        match client.rename("key2".to_owned(), "key5".to_owned()).await {
+            Err(KvsError::Server(ServerError::KeyNotFound)) => {}
+            res => panic!("renamed a missing key: {:?}", res),
+        }
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Copying should leave the key it copies from, and only overwrite if asked
+#[test]
+fn copy_key() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).await?;
+        store.set_with_flags("key1", "value1", 7).await?;
+        store.set("key2", "value2").await?;
+        assert!(!store.copy("key1", "key2", false).await?);
+        assert_eq!(store.get("key2").await?, Some(b"value2".to_vec()));
+        assert!(store.copy("key1", "key3", false).await?);
+        let (value, metadata) = store.get_with_metadata("key3").await?.unwrap();
+        assert_eq!((value, metadata.flags), (b"value1".to_vec(), 7));
+        match store.copy("key4", "key5", true).await {
+            Err(KvsError::KeyNotFound) => {}
+            res => panic!("copied a missing key: {:?}", res),
+        }
+        drop(store);
+
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        assert!(
+            client
+                .copy("key1".to_owned(), "key2".to_owned(), true)
+                .await?
+        );
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            client.get("key2".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// The writes of a transaction should be applied together on exec, or not
+// at all if one of them fails or it's discarded
+#[test]
+fn transactions() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        let mut other = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+
+        client.begin().await?;
+        client.set("key2".to_owned(), "value2".to_owned()).await?;
+        client.remove("key1".to_owned()).await?;
+        match client.get("key2".to_owned()).await {
+            Err(KvsError::Server(ServerError::Internal { .. })) => {}
+            res => panic!("got a key in a transaction: {:?}", res),
+        }
+        assert_eq!(other.get("key2".to_owned()).await?, None);
+        client.exec().await?;
+        assert_eq!(other.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            other.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+
+        client.begin().await?;
+        client.set("key3".to_owned(), "value3".to_owned()).await?;
+        client.remove("key1".to_owned()).await?;
+        match client.exec().await {
+            Err(KvsError::Server(ServerError::KeyNotFound)) => {}
+            res => panic!("removed a missing key in a transaction: {:?}", res),
+        }
+        assert_eq!(other.get("key3".to_owned()).await?, None);
+
+        client.begin().await?;
+        client.remove("key2".to_owned()).await?;
+        client.discard().await?;
+        assert_eq!(
+            client.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        assert!(client.exec().await.is_err());
+        drop((client, other));
+
+        server.shutdown();
+        running.await?;
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key1").await?, None);
+        assert_eq!(store.get("key2").await?, Some(b"value2".to_vec()));
+        assert_eq!(store.get("key3").await?, None);
+        Ok(())
+    })
+}
+
+/// Binds a server with `config` on a free port and runs it in a task.
+async fn start_server(
+    config: ServerConfig,
+) -> Result<(Arc<KvsServer>, task::JoinHandle<Result<()>>)> {
+    let server = Arc::new(KvsServer::bind("127.0.0.1:0", config).await?);
+    let running = task::spawn({
+        let server = Arc::clone(&server);
+        async move { server.run().await }
+    });
+    Ok((server, running))
+}
+
+/// Gets `key` through `client` until it has `expected`, for up to 10 seconds.
+async fn wait_for(client: &mut KvsClient, key: &str, expected: Option<&str>) -> Result<()> {
+    for _ in 0..100 {
+        if client.get(key.to_owned()).await?.as_deref() == expected {
+            return Ok(());
+        }
+        task::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{:?} didn't become {:?}", key, expected);
+}
+
+// A replica should apply the writes made to its primary, and refuse its own
+#[test]
+fn replica_follows_primary() -> Result<()> {
+    task::block_on(async {
+        let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+        let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (primary, primary_running) = start_server(ServerConfig {
+            dir: Some(primary_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let (replica, replica_running) = start_server(ServerConfig {
+            dir: Some(replica_dir.path().to_path_buf()),
+            replica_of: Some(primary.local_addr()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut writer = KvsClient::connect(primary.local_addr(), ClientConfig::default()).await?;
+        let mut reader = KvsClient::connect(replica.local_addr(), ClientConfig::default()).await?;
+
+        writer.set("key1".to_owned(), "value1".to_owned()).await?;
+        wait_for(&mut reader, "key1", Some("value1")).await?;
+        writer.set("key1".to_owned(), "value2".to_owned()).await?;
+        writer.set("key2".to_owned(), "value3".to_owned()).await?;
+        writer.remove("key2".to_owned()).await?;
+        wait_for(&mut reader, "key1", Some("value2")).await?;
+        assert_eq!(reader.get("key2".to_owned()).await?, None);
+
+        match reader.set("key3".to_owned(), "value4".to_owned()).await {
+            Err(KvsError::Server(ServerError::Internal { msg })) => {
+                assert!(msg.contains("replica"), "unexpected error: {}", msg)
+            }
+            res => panic!("wrote to a replica: {:?}", res),
+        }
+        match reader.remove("key1".to_owned()).await {
+            Err(KvsError::Server(ServerError::Internal { .. })) => {}
+            res => panic!("removed from a replica: {:?}", res),
+        }
+        drop((writer, reader));
+
+        replica.shutdown();
+        replica_running.await?;
+        primary.shutdown();
+        primary_running.await
+    })
+}
+
+/// Returns `count` addresses on free ports, for nodes that must know each
+/// other's addresses before they're bound.
+fn free_addrs(count: usize) -> Vec<SocketAddr> {
+    let listeners: Vec<_> = (0..count)
+        .map(|_| TcpListener::bind("127.0.0.1:0").expect("unable to bind a free port"))
+        .collect();
+    listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap())
+        .collect()
+}
+
+/// Starts node `id` of the cluster of `nodes`, keeping its store in `dir`.
+async fn start_node(
+    nodes: &[SocketAddr],
+    id: usize,
+    dir: &Path,
+) -> Result<(Arc<KvsServer>, task::JoinHandle<Result<()>>)> {
+    let server = Arc::new(
+        KvsServer::bind(
+            nodes[id],
+            ServerConfig {
+                dir: Some(dir.to_path_buf()),
+                cluster: Some(Cluster {
+                    nodes: nodes.to_vec(),
+                    id,
+                    max_log_entries: Some(20),
+                }),
+                ..ServerConfig::default()
+            },
+        )
+        .await?,
+    );
+    let running = task::spawn({
+        let server = Arc::clone(&server);
+        async move { server.run().await }
+    });
+    Ok((server, running))
+}
+
+/// Asks the nodes at `nodes` until one names a leader among them, for up to
+/// 10 seconds.
+async fn wait_for_leader(nodes: &[SocketAddr]) -> Result<SocketAddr> {
+    for _ in 0..100 {
+        for &node in nodes {
+            let mut client = KvsClient::connect(node, ClientConfig::default()).await?;
+            if let Some(leader) = client.leader().await? {
+                if nodes.contains(&leader) {
+                    return Ok(leader);
+                }
+            }
+        }
+        task::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("no leader was elected among {:?}", nodes);
+}
+
+// Writes acknowledged by the leader should survive it failing, and other
+// nodes should point clients to it
+#[test]
+fn cluster_failover() -> Result<()> {
+    task::block_on(async {
+        let dirs: Vec<_> = (0..3)
+            .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+            .collect();
+        let nodes = free_addrs(3);
+        let mut servers = Vec::new();
+        for (id, dir) in dirs.iter().enumerate() {
+            servers.push(Some(start_node(&nodes, id, dir.path()).await?));
+        }
+
+        let leader = wait_for_leader(&nodes).await?;
+        let mut client = KvsClient::connect(leader, ClientConfig::default()).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+
+        let follower = *nodes.iter().find(|&&node| node != leader).unwrap();
+        let mut client = KvsClient::connect(follower, ClientConfig::default()).await?;
+        match client.set("key2".to_owned(), "value2".to_owned()).await {
+            Err(KvsError::Server(ServerError::Internal { msg })) => assert!(
+                msg.contains(&leader.to_string()),
+                "the leader isn't named: {}",
+                msg
+            ),
+            res => panic!("a follower took a write: {:?}", res),
+        }
+        match client.get("key1".to_owned()).await {
+            Err(KvsError::Server(ServerError::Internal { .. })) => {}
+            res => panic!("a follower served a read: {:?}", res),
+        }
+        drop(client);
+
+        let id = nodes.iter().position(|&node| node == leader).unwrap();
+        let (server, running) = servers[id].take().unwrap();
+        server.shutdown();
+        running.await?;
+
+        let rest: Vec<_> = nodes
+            .iter()
+            .copied()
+            .filter(|&node| node != leader)
+            .collect();
+        let leader = wait_for_leader(&rest).await?;
+        let mut client = KvsClient::connect_leader(leader, ClientConfig::default()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        client.set("key2".to_owned(), "value2".to_owned()).await?;
+        assert_eq!(
+            client.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+
+        // Renames are replicated as one write.
+        client.rename("key1".to_owned(), "key3".to_owned()).await?;
+        assert_eq!(client.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            client.get("key3".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        match client.rename("key1".to_owned(), "key4".to_owned()).await {
+            Err(KvsError::Server(ServerError::KeyNotFound)) => {}
+            res => panic!("renamed a missing key: {:?}", res),
+        }
+        drop(client);
+
+        for (server, running) in servers.into_iter().flatten() {
+            server.shutdown();
+            running.await?;
+        }
+        Ok(())
+    })
+}
+
+// A node that missed entries dropped from the leader's log should catch up
+// from a snapshot of the leader's keys
+#[test]
+fn cluster_snapshot_catch_up() -> Result<()> {
+    task::block_on(async {
+        let dirs: Vec<_> = (0..3)
+            .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+            .collect();
+        let nodes = free_addrs(3);
+        let mut servers = Vec::new();
+        for (id, dir) in dirs.iter().enumerate() {
+            servers.push(Some(start_node(&nodes, id, dir.path()).await?));
+        }
+        let leader = wait_for_leader(&nodes).await?;
+        let leader_id = nodes.iter().position(|&node| node == leader).unwrap();
+        let lagging = (leader_id + 1) % 3;
+        let other = (leader_id + 2) % 3;
+
+        let (server, running) = servers[lagging].take().unwrap();
+        server.shutdown();
+        running.await?;
+
+        // Enough entries for the leader to drop them from its log.
+        let mut client = KvsClient::connect(leader, ClientConfig::default()).await?;
+        for i in 0..50 {
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        drop(client);
+
+        // With the other follower down, writes need the lagging node, which
+        // needs the snapshot to take them.
+        servers[lagging] = Some(start_node(&nodes, lagging, dirs[lagging].path()).await?);
+        let (server, running) = servers[other].take().unwrap();
+        server.shutdown();
+        running.await?;
+        let mut client = KvsClient::connect(leader, ClientConfig::default()).await?;
+        client.set("last".to_owned(), "value".to_owned()).await?;
+        drop(client);
+
+        // Only the lagging node has the last write, so it's elected once the
+        // leader is gone, and serves every key.
+        let (server, running) = servers[leader_id].take().unwrap();
+        server.shutdown();
+        running.await?;
+        servers[other] = Some(start_node(&nodes, other, dirs[other].path()).await?);
+        let leader = wait_for_leader(&[nodes[lagging], nodes[other]]).await?;
+        assert_eq!(leader, nodes[lagging]);
+        let mut client = KvsClient::connect(leader, ClientConfig::default()).await?;
+        assert_eq!(
+            client.get("last".to_owned()).await?,
+            Some("value".to_owned())
+        );
+        for i in 0..50 {
+            assert_eq!(
+                client.get(format!("key{}", i)).await?,
+                Some(format!("value{}", i))
+            );
+        }
+        drop(client);
+
+        for (server, running) in servers.into_iter().flatten() {
+            server.shutdown();
+            running.await?;
+        }
+        Ok(())
+    })
+}
+
+// Every node of a sharded server should serve every key, keeping only the
+// keys it owns
+#[test]
+fn sharded_servers() -> Result<()> {
+    task::block_on(async {
+        let dirs: Vec<_> = (0..3)
+            .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+            .collect();
+        let nodes = free_addrs(3);
+        let mut servers = Vec::new();
+        for (id, dir) in dirs.iter().enumerate() {
+            let server = Arc::new(
+                KvsServer::bind(
+                    nodes[id],
+                    ServerConfig {
+                        dir: Some(dir.path().to_path_buf()),
+                        sharding: Some(Sharding {
+                            nodes: nodes.clone(),
+                            id,
+                        }),
+                        ..ServerConfig::default()
+                    },
+                )
+                .await?,
+            );
+            let running = task::spawn({
+                let server = Arc::clone(&server);
+                async move { server.run().await }
+            });
+            servers.push((server, running));
+        }
+
+        let mut client = KvsClient::connect(nodes[0], ClientConfig::default()).await?;
+        let topology = client.topology().await?.expect("the server isn't sharded");
+        assert_eq!(topology, Topology::new(nodes.clone()));
+        for i in 0..30 {
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        client.set("other".to_owned(), "value".to_owned()).await?;
+
+        // Each node keeps the keys it owns, and forwards the others.
+        for &node in &nodes {
+            let mut client = KvsClient::connect(node, ClientConfig::default()).await?;
+            let owned = (0..30)
+                .filter(|i| topology.owner(format!("key{}", i).as_bytes()) == node)
+                .count()
+                + (topology.owner(b"other") == node) as usize;
+            assert_eq!(client.stats().await?.keys, owned as u64);
+            for i in 0..30 {
+                assert_eq!(
+                    client.get(format!("key{}", i)).await?,
+                    Some(format!("value{}", i))
+                );
+            }
+        }
+        let mut client = KvsClient::connect(nodes[1], ClientConfig::default()).await?;
+        client.remove("key0".to_owned()).await?;
+        client.set("key1".to_owned(), "value".to_owned()).await?;
+        let mut client = KvsClient::connect(nodes[2], ClientConfig::default()).await?;
+        assert_eq!(client.get("key0".to_owned()).await?, None);
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value".to_owned())
+        );
+
+        // Listing keys asks every node.
+        assert_eq!(client.count_prefix("key".to_owned()).await?, 29);
+        let mut keys: Vec<_> = (1..30).map(|i| format!("key{}", i)).collect();
+        keys.sort();
+        let mut scanned = Vec::new();
+        let mut start_after = None;
+        loop {
+            let page = client
+                .scan("key".to_owned(), start_after, 7, u64::MAX)
+                .await?;
+            scanned.extend(page.entries.into_iter().map(|(key, _)| key));
+            match page.cursor {
+                Some(cursor) => start_after = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(scanned, keys);
+
+        // Keys are renamed by their owner if it owns both, and moved between
+        // owners otherwise.
+        let owner = |key: &str| topology.owner(key.as_bytes());
+        let mut candidates = (0..).map(|i| format!("renamed{}", i));
+        let renamed = candidates.find(|key| owner(key) == owner("key1")).unwrap();
+        let moved = candidates.find(|key| owner(key) != owner("key2")).unwrap();
+        client.rename("key1".to_owned(), renamed.clone()).await?;
+        client.rename("key2".to_owned(), moved.clone()).await?;
+        match client.rename("key0".to_owned(), "key100".to_owned()).await {
+            Err(KvsError::Server(ServerError::KeyNotFound)) => {}
+            res => panic!("renamed a missing key: {:?}", res),
+        }
+        for &node in &nodes {
+            let mut client = KvsClient::connect(node, ClientConfig::default()).await?;
+            assert_eq!(client.get("key1".to_owned()).await?, None);
+            assert_eq!(client.get("key2".to_owned()).await?, None);
+            assert_eq!(client.get(renamed.clone()).await?, Some("value".to_owned()));
+            assert_eq!(client.get(moved.clone()).await?, Some("value2".to_owned()));
+        }
+        client.rename(renamed, "key1".to_owned()).await?;
+        client.rename(moved, "key2".to_owned()).await?;
+
+        // Keys owned by a node that's down fail instead of hanging.
+        let (server, running) = servers.pop().unwrap();
+        server.shutdown();
+        running.await?;
+        let key = (1..30)
+            .map(|i| format!("key{}", i))
+            .find(|key| topology.owner(key.as_bytes()) == nodes[2])
+            .expect("no key is owned by the last node");
+        let mut client = KvsClient::connect(nodes[0], ClientConfig::default()).await?;
+        assert!(client.get(key).await.is_err());
+        drop(client);
+
+        for (server, running) in servers {
+            server.shutdown();
+            running.await?;
+        }
+        Ok(())
+    })
+}
+
+// Requests up to the frame size limit should be served, and longer ones
+// refused, closing the connection
+#[test]
+fn max_frame_size() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            max_frame_size: Some(1024),
+            ..ServerConfig::default()
+        })
+        .await?;
+
+        // Setting `key` takes 23 bytes besides the value: the variant, and
+        // the lengths of the key and value.
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        client.set("key".to_owned(), "v".repeat(1001)).await?;
+        assert_eq!(client.get("key".to_owned()).await?, Some("v".repeat(1001)));
+        match client.set("key".to_owned(), "v".repeat(1002)).await {
+            Err(KvsError::Server(ServerError::TooLarge { what, len, max })) => {
+                assert_eq!((what.as_str(), len, max), ("frame", 1025, 1024))
+            }
+            res => panic!("an oversized frame was taken: {:?}", res),
+        }
+        assert!(client.get("key".to_owned()).await.is_err());
+        drop(client);
+
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        assert_eq!(client.get("key".to_owned()).await?, Some("v".repeat(1001)));
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+/// Sends `request` on `stream`, and asserts the reply is `expected`.
+async fn assert_reply(stream: &mut TcpStream, request: &[u8], expected: &[u8]) -> Result<()> {
+    stream.write_all(request).await?;
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).await?;
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        String::from_utf8_lossy(expected)
+    );
+    Ok(())
+}
+
+// RESP clients should get, set and remove keys, in multibulk or inline
+// commands
+#[test]
+fn resp_commands() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            protocol: Protocol::Resp,
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut stream = TcpStream::connect(server.local_addr()).await?;
+
+        assert_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n", b"$-1\r\n").await?;
+        assert_reply(
+            &mut stream,
+            b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nva\r\nl\r\n",
+            b"+OK\r\n",
+        )
+        .await?;
+        assert_reply(
+            &mut stream,
+            b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n",
+            b"$5\r\nva\r\nl\r\n",
+        )
+        .await?;
+        assert_reply(&mut stream, b"SET key2 value\r\n", b"+OK\r\n").await?;
+        assert_reply(&mut stream, b"GET key2\r\n", b"$5\r\nvalue\r\n").await?;
+        assert_reply(&mut stream, b"DEL key key2 key3\r\n", b":2\r\n").await?;
+        assert_reply(&mut stream, b"GET key\r\n", b"$-1\r\n").await?;
+        assert_reply(&mut stream, b"GET\r\n", b"-ERR").await?;
+        drop(stream);
+
+        // Lines longer than 64 KiB are refused, closing the connection.
+        let mut stream = TcpStream::connect(server.local_addr()).await?;
+        stream.write_all(&vec![b'a'; (64 << 10) + 2]).await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        assert!(reply.starts_with("-ERR Protocol error: line"), "{}", reply);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// memcached clients should get, set and delete keys, which can't expire
+#[test]
+fn memcached_commands() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let memcached_addr = free_addrs(1)[0];
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            memcached_addr: Some(memcached_addr),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut stream = TcpStream::connect(memcached_addr).await?;
+
+        assert_reply(&mut stream, b"get key\r\n", b"END\r\n").await?;
+        assert_reply(&mut stream, b"set key 0 0 5\r\nva\r\nl\r\n", b"STORED\r\n").await?;
+        assert_reply(&mut stream, b"set key2 0 0 5 noreply\r\nvalue\r\n", b"").await?;
+        assert_reply(
+            &mut stream,
+            b"get key key2 key3\r\n",
+            b"VALUE key 0 5\r\nva\r\nl\r\nVALUE key2 0 5\r\nvalue\r\nEND\r\n",
+        )
+        .await?;
+        assert_reply(&mut stream, b"delete key\r\n", b"DELETED\r\n").await?;
+        assert_reply(&mut stream, b"delete key\r\n", b"NOT_FOUND\r\n").await?;
+        assert_reply(
+            &mut stream,
+            b"set key2 0 60 5\r\nnewer\r\n",
+            b"CLIENT_ERROR keys can't expire\r\n",
+        )
+        .await?;
+        assert_reply(
+            &mut stream,
+            b"get key2\r\n",
+            b"VALUE key2 0 5\r\nvalue\r\nEND\r\n",
+        )
+        .await?;
+        drop(stream);
+
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        assert_eq!(client.get("key".to_owned()).await?, None);
+        assert_eq!(
+            client.get("key2".to_owned()).await?,
+            Some("value".to_owned())
+        );
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+/// Sends the HTTP `request` to `addr`, returning the status line and body of
+/// the response.
+async fn http(addr: SocketAddr, request: &str) -> Result<(String, String)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let (head, body) = response.split_at(response.find("\r\n\r\n").expect("no end of head"));
+    let status = head.lines().next().unwrap_or_default().to_owned();
+    Ok((status, body[4..].to_owned()))
+}
+
+// HTTP clients should get, put and delete keys, and read the stats
+#[test]
+fn http_api() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let http_addr = free_addrs(1)[0];
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            http_addr: Some(http_addr),
+            ..ServerConfig::default()
+        })
+        .await?;
+
+        let (status, body) = http(http_addr, "GET /keys/a%20key HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        assert_eq!(body, r#"{"error":"key not found"}"#);
+        let put = "PUT /keys/a%20key HTTP/1.1\r\nContent-Length: 17\r\n\r\n{\"value\":\"value\"}";
+        let (status, _) = http(http_addr, put).await?;
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        let (status, body) = http(http_addr, "GET /keys/a%20key HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, r#"{"key":"a key","value":"value"}"#);
+
+        let (status, body) = http(http_addr, "GET /stats HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains(r#""keys":1"#), "{}", body);
+
+        let (status, _) = http(http_addr, "DELETE /keys/a%20key HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        let (status, _) = http(http_addr, "DELETE /keys/a%20key HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+        // Bodies over 16 MiB are refused before they're read.
+        let put = format!(
+            "PUT /keys/key HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            (16 << 20) + 1
+        );
+        let (status, _) = http(http_addr, &put).await?;
+        assert_eq!(status, "HTTP/1.1 413 Payload Too Large");
+        let (status, _) = http(http_addr, "GET /keys/key HTTP/1.1\r\n\r\n").await?;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Connections beyond max_connections should be refused on their first
+// request, until another closes
+#[test]
+fn max_connections() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            max_connections: Some(2),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let addr = server.local_addr();
+        let mut first = KvsClient::connect(addr, ClientConfig::default()).await?;
+        first.set("key1".to_owned(), "value1".to_owned()).await?;
+        let mut second = KvsClient::connect(addr, ClientConfig::default()).await?;
+        assert_eq!(
+            second.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+
+        let mut third = KvsClient::connect(addr, ClientConfig::default()).await?;
+        match third.get("key1".to_owned()).await {
+            Err(KvsError::Server(ServerError::RateLimited)) => {}
+            res => panic!("a connection over the limit was served: {:?}", res),
+        }
+        assert_eq!(
+            first.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+
+        // The server notices the close soon after, freeing its slot.
+        drop(first);
+        let mut served = false;
+        for _ in 0..100 {
+            let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+            match client.get("key1".to_owned()).await {
+                Ok(value) => {
+                    assert_eq!(value, Some("value1".to_owned()));
+                    served = true;
+                    break;
+                }
+                Err(KvsError::Server(ServerError::RateLimited)) => {
+                    task::sleep(Duration::from_millis(100)).await
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        assert!(served, "closing a connection didn't free its slot");
+        drop((second, third));
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Subscribers should receive the sets and removes of keys with their prefix,
+// until they unsubscribe or the server shuts down
+#[test]
+fn subscribe_to_changes() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let addr = server.local_addr();
+        let client = KvsClient::connect(addr, ClientConfig::default()).await?;
+        let mut subscription = client.subscribe("user/".to_owned()).await?;
+
+        let mut writer = KvsClient::connect(addr, ClientConfig::default()).await?;
+        writer.set("user/1".to_owned(), "alice".to_owned()).await?;
+        writer.set("post/1".to_owned(), "hello".to_owned()).await?;
+        writer.remove("user/1".to_owned()).await?;
+        assert_eq!(
+            subscription.next().await?,
+            Some(WatchEvent::Set {
+                key: b"user/1".to_vec(),
+                value: b"alice".to_vec(),
+            })
+        );
+        assert_eq!(
+            subscription.next().await?,
+            Some(WatchEvent::Remove {
+                key: b"user/1".to_vec(),
+            })
+        );
+
+        // Unsubscribing waits for the end of the stream, so the connection
+        // takes requests again.
+        writer.set("user/2".to_owned(), "bob".to_owned()).await?;
+        let mut client = subscription.unsubscribe().await?;
+        assert_eq!(
+            client.get("user/2".to_owned()).await?,
+            Some("bob".to_owned())
+        );
+        let mut subscription = client.subscribe("user/".to_owned()).await?;
+        drop(writer);
+
+        server.shutdown();
+        assert_eq!(subscription.next().await?, None);
+        assert_eq!(subscription.next().await?, None);
+        drop(subscription);
+        running.await
+    })
+}
+
+// Pages of a snapshot scan should see the store as it was when the scan
+// started
+#[test]
+fn scan_snapshot_pages() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let addr = server.local_addr();
+        let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+        for i in 0..10 {
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        client.set("other".to_owned(), "value".to_owned()).await?;
+
+        let page = client
+            .scan_snapshot("key".to_owned(), None, 3, true)
+            .await?;
+        assert_eq!(page.keys, ["key0", "key1", "key2"]);
+        assert_eq!(
+            page.values,
+            Some(vec![
+                "value0".to_owned(),
+                "value1".to_owned(),
+                "value2".to_owned()
+            ])
+        );
+        let mut cursor = page.cursor.expect("the scan ended early");
+
+        let mut writer = KvsClient::connect(addr, ClientConfig::default()).await?;
+        writer.set("key5".to_owned(), "changed".to_owned()).await?;
+        writer.remove("key7".to_owned()).await?;
+        writer.set("key70".to_owned(), "value".to_owned()).await?;
+        match writer
+            .scan_snapshot(String::new(), Some(cursor), 3, false)
+            .await
+        {
+            Err(KvsError::Server(ServerError::Internal { .. })) => {}
+            res => panic!("a cursor was continued on another connection: {:?}", res),
+        }
+        drop(writer);
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        loop {
+            let page = client
+                .scan_snapshot(String::new(), Some(cursor), 3, true)
+                .await?;
+            keys.extend(page.keys);
+            values.extend(page.values.expect("values weren't returned"));
+            match page.cursor {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        let expected: Vec<_> = (3..10).map(|i| format!("key{}", i)).collect();
+        assert_eq!(keys, expected);
+        let expected: Vec<_> = (3..10).map(|i| format!("value{}", i)).collect();
+        assert_eq!(values, expected);
+
+        // The store is seen as it is now by the next scan.
+        let page = client
+            .scan_snapshot("key".to_owned(), None, 100, false)
+            .await?;
+        assert!(page.keys.contains(&"key70".to_owned()));
+        assert!(!page.keys.contains(&"key7".to_owned()));
+        assert_eq!(page.values, None);
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+/// Reads a frame of the kvs protocol: its length, big-endian, and payload.
+async fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len = [0; 8];
+    stream.read_exact(&mut len).await?;
+    let mut frame = vec![0; u64::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> Result<()> {
+    stream
+        .write_all(&(frame.len() as u64).to_be_bytes())
+        .await?;
+    stream.write_all(frame).await?;
+    Ok(())
+}
+
+// A batch should be answered in order, and refused whole if it holds a
+// request that can't be batched
+#[test]
+fn batch_requests() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        let owned = |s: &str| s.to_owned();
+        let replies = client
+            .batch(vec![
+                BatchOp::Set {
+                    key: owned("key1"),
+                    value: owned("value1"),
+                },
+                BatchOp::Get { key: owned("key1") },
+                BatchOp::Remove { key: owned("key2") },
+                BatchOp::Set {
+                    key: owned("key1"),
+                    value: owned("value2"),
+                },
+                BatchOp::Get { key: owned("key1") },
+                BatchOp::Remove { key: owned("key1") },
+                BatchOp::Get { key: owned("key1") },
+            ])
+            .await?;
+        assert_eq!(replies.len(), 7);
+        let mut replies = replies.into_iter();
+        assert_eq!(replies.next().unwrap()?, None);
+        assert_eq!(replies.next().unwrap()?, Some(owned("value1")));
+        match replies.next().unwrap() {
+            Err(KvsError::Server(ServerError::KeyNotFound)) => {}
+            res => panic!("removed a missing key: {:?}", res),
+        }
+        assert_eq!(replies.next().unwrap()?, None);
+        assert_eq!(replies.next().unwrap()?, Some(owned("value2")));
+        assert_eq!(replies.next().unwrap()?, None);
+        assert_eq!(replies.next().unwrap()?, None);
+        drop(client);
+
+        // `KvsClient` only batches gets, sets and removes, so a batch holding
+        // a leader request is sent by hand: `Request::Batch` is variant 16
+        // and `Request::Leader` 18.
+        let mut stream = TcpStream::connect(server.local_addr()).await?;
+        read_frame(&mut stream).await?;
+        let mut request = Vec::new();
+        request.extend_from_slice(&16u32.to_le_bytes());
+        request.extend_from_slice(&1u64.to_le_bytes());
+        request.extend_from_slice(&18u32.to_le_bytes());
+        write_frame(&mut stream, &request).await?;
+        let reply = read_frame(&mut stream).await?;
+        assert_eq!(reply[..4], 1u32.to_le_bytes(), "the batch wasn't refused");
+        let reply = String::from_utf8_lossy(&reply);
+        assert!(
+            reply.ends_with("leader requests can't be batched"),
+            "{}",
+            reply
+        );
+        drop(stream);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Scans should be paged by the client's and the server's limits, always
+// returning at least one key, and resume after the cursor
+#[test]
+fn scan_pages() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            max_scan_keys: Some(4),
+            max_scan_bytes: Some(64),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        for i in 0..10 {
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        client.set("other".to_owned(), "value".to_owned()).await?;
+        let keys = |page: &ScanPage| -> Vec<String> {
+            page.entries.iter().map(|(key, _)| key.clone()).collect()
+        };
+
+        // Each entry takes 10 bytes.
+        let page = client.scan("key".to_owned(), None, 100, u64::MAX).await?;
+        assert_eq!(keys(&page), ["key0", "key1", "key2", "key3"]);
+        assert_eq!(page.entries[0].1, "value0");
+        assert_eq!(page.cursor.as_deref(), Some("key3"));
+        let page = client.scan("key".to_owned(), None, 2, u64::MAX).await?;
+        assert_eq!(keys(&page), ["key0", "key1"]);
+        let page = client.scan("key".to_owned(), None, 100, 25).await?;
+        assert_eq!(keys(&page), ["key0", "key1"]);
+        assert_eq!(page.cursor.as_deref(), Some("key1"));
+
+        let page = client
+            .scan("key".to_owned(), Some("key3".to_owned()), 100, u64::MAX)
+            .await?;
+        assert_eq!(keys(&page), ["key4", "key5", "key6", "key7"]);
+        let page = client
+            .scan("key".to_owned(), page.cursor, 100, u64::MAX)
+            .await?;
+        assert_eq!(keys(&page), ["key8", "key9"]);
+        assert_eq!(page.cursor, None);
+
+        // A key too large for the limits is returned alone.
+        client.set("key5".to_owned(), "v".repeat(100)).await?;
+        let page = client
+            .scan("key".to_owned(), Some("key4".to_owned()), 100, 1)
+            .await?;
+        assert_eq!(keys(&page), ["key5"]);
+        assert_eq!(page.cursor.as_deref(), Some("key5"));
+        let page = client
+            .scan("key".to_owned(), Some("key4".to_owned()), 100, u64::MAX)
+            .await?;
+        assert_eq!(keys(&page), ["key5"]);
+        let page = client
+            .scan("key".to_owned(), page.cursor, 100, u64::MAX)
+            .await?;
+        assert_eq!(keys(&page), ["key6", "key7", "key8", "key9"]);
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// With a signing key, only requests signed with it should be served
+#[test]
+fn signed_requests() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let signing_key = SigningKey::new("secret");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            signing_key: Some(signing_key.clone()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let addr = server.local_addr();
+        let signed = ClientConfig {
+            signing_key: Some(signing_key),
+            ..ClientConfig::default()
+        };
+        let mut client = KvsClient::connect(addr, signed.clone()).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        drop(client);
+
+        let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+        match client.set("key1".to_owned(), "unsigned".to_owned()).await {
+            Err(KvsError::Server(ServerError::AuthRequired)) => {}
+            res => panic!("an unsigned request was served: {:?}", res),
+        }
+        drop(client);
+        let other_key = ClientConfig {
+            signing_key: Some(SigningKey::new("guess")),
+            ..ClientConfig::default()
+        };
+        let mut client = KvsClient::connect(addr, other_key).await?;
+        assert!(client
+            .set("key1".to_owned(), "other key".to_owned())
+            .await
+            .is_err());
+        drop(client);
+
+        // A client hello whose tag doesn't match is refused: it's the
+        // client's nonce and the tag.
+        let mut stream = TcpStream::connect(addr).await?;
+        read_frame(&mut stream).await?;
+        let mut hello = Vec::new();
+        hello.extend_from_slice(&1u64.to_le_bytes());
+        hello.extend_from_slice(&32u64.to_le_bytes());
+        hello.extend_from_slice(&[0; 32]);
+        write_frame(&mut stream, &hello).await?;
+        // `Err(ServerError::AuthRequired)`, unsigned.
+        assert_eq!(read_frame(&mut stream).await?, [1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(stream.read(&mut [0]).await?, 0);
+
+        let mut client = KvsClient::connect(addr, signed).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// What a signed server sent on one connection shouldn't be accepted by a
+// client on another
+#[test]
+fn replayed_signed_session() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let signing_key = SigningKey::new("secret");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            signing_key: Some(signing_key.clone()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let signed = ClientConfig {
+            signing_key: Some(signing_key),
+            ..ClientConfig::default()
+        };
+        let mut client = KvsClient::connect(server.local_addr(), signed.clone()).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        drop(client);
+
+        // Records what the server sends through a proxy.
+        let proxy = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+        let recording = task::spawn({
+            let addr = server.local_addr();
+            async move {
+                let (client, _) = proxy.accept().await?;
+                let server = TcpStream::connect(addr).await?;
+                let forwarding = task::spawn({
+                    let (mut from, mut to) = (client.clone(), server.clone());
+                    async move {
+                        async_std::io::copy(&mut from, &mut to).await?;
+                        to.shutdown(std::net::Shutdown::Write)
+                    }
+                });
+                let (mut from, mut to) = (&server, &client);
+                let mut recorded = Vec::new();
+                let mut buf = [0; 4096];
+                loop {
+                    let n = from.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    to.write_all(&buf[..n]).await?;
+                    recorded.extend_from_slice(&buf[..n]);
+                }
+                forwarding.await?;
+                Result::Ok(recorded)
+            }
+        });
+        let mut client = KvsClient::connect(proxy_addr, signed.clone()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        drop(client);
+        let recorded = recording.await?;
+
+        // The server's hello and reply, replayed to a client with its own
+        // nonce, don't verify.
+        let replaying = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+        let replaying_addr = replaying.local_addr()?;
+        let replay = task::spawn(async move {
+            let (mut stream, _) = replaying.accept().await?;
+            stream.write_all(&recorded).await?;
+            while stream.read(&mut [0; 4096]).await? > 0 {}
+            Result::Ok(())
+        });
+        let mut client = KvsClient::connect(replaying_addr, signed).await?;
+        match client.get("key1".to_owned()).await {
+            Err(KvsError::Signature(_)) => {}
+            res => panic!("a replayed reply was accepted: {:?}", res),
+        }
+        drop(client);
+        replay.await?;
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Writes made while the server is unreachable should be journaled, and
+// replayed in order once it's back, reporting those to changed keys
+#[test]
+fn offline_client() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let server_dir = temp_dir.path().join("server");
+        let journal_dir = temp_dir.path().join("journal");
+        let config = ServerConfig {
+            dir: Some(server_dir),
+            store: Options {
+                max_file_size: 1 << 20,
+                ..Options::default()
+            },
+            ..ServerConfig::default()
+        };
+        let (server, running) = start_server(config.clone()).await?;
+        let addr = server.local_addr();
+        let drop_reply = Arc::new(AtomicBool::new(false));
+        let proxy_addr = start_proxy(addr, Arc::clone(&drop_reply)).await?;
+
+        let mut offline =
+            OfflineClient::open(proxy_addr, ClientConfig::default(), &journal_dir).await?;
+        assert_eq!(offline.sync().await?, Vec::new());
+        offline.set("key1".to_owned(), "value1".to_owned()).await?;
+        offline.set("key2".to_owned(), "value2".to_owned()).await?;
+        let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+        client.set("key2".to_owned(), "changed".to_owned()).await?;
+        drop(client);
+
+        server.shutdown();
+        running.await?;
+        offline
+            .set("key1".to_owned(), "offline1".to_owned())
+            .await?;
+        offline
+            .set("key2".to_owned(), "offline2".to_owned())
+            .await?;
+        // Longer than the logs of a store by default.
+        let long = "x".repeat(2048);
+        offline.set("key3".to_owned(), long.clone()).await?;
+        assert_eq!(
+            offline.get("key1".to_owned()).await?,
+            Some("offline1".to_owned())
+        );
+        assert_eq!(offline.pending().await?, 3);
+        // Later writes are journaled after those from before reopening.
+        drop(offline);
+        let mut offline =
+            OfflineClient::open(proxy_addr, ClientConfig::default(), &journal_dir).await?;
+        offline
+            .set("key4".to_owned(), "offline4".to_owned())
+            .await?;
+        assert_eq!(offline.pending().await?, 4);
+
+        let server = Arc::new(KvsServer::bind(addr, config).await?);
+        let running = task::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        // The first write is applied, but its reply is lost. Writes made
+        // after that are journaled rather than sent ahead of the rest.
+        drop_reply.store(true, Ordering::SeqCst);
+        assert!(matches!(offline.sync().await, Err(KvsError::Io(_))));
+        drop_reply.store(false, Ordering::SeqCst);
+        offline
+            .set("key5".to_owned(), "offline5".to_owned())
+            .await?;
+        assert_eq!(offline.pending().await?, 5);
+        let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("offline1".to_owned())
+        );
+        assert_eq!(client.get("key5".to_owned()).await?, None);
+
+        // Replaying the applied write again doesn't conflict with itself.
+        let conflicts = offline.sync().await?;
+        assert_eq!(
+            conflicts,
+            vec![Conflict {
+                key: "key2".to_owned(),
+                expected: Some("value2".to_owned()),
+                actual: Some("changed".to_owned()),
+                value: Some("offline2".to_owned()),
+            }]
+        );
+        assert_eq!(offline.pending().await?, 0);
+        assert_eq!(
+            offline.get("key2".to_owned()).await?,
+            Some("changed".to_owned())
+        );
+        for (key, value) in [
+            ("key1", "offline1"),
+            ("key2", "changed"),
+            ("key3", long.as_str()),
+            ("key4", "offline4"),
+            ("key5", "offline5"),
+        ]
+        .iter()
+        {
+            assert_eq!(client.get(key.to_string()).await?.as_deref(), Some(*value));
+        }
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+/// Forwards connections to `addr`, closing those it can't. While
+/// `drop_reply` is set, the reply to the first request of a connection is
+/// dropped and the connection closed.
+async fn start_proxy(addr: SocketAddr, drop_reply: Arc<AtomicBool>) -> Result<SocketAddr> {
+    let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?;
+    task::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let mut server = match TcpStream::connect(addr).await {
+                Ok(server) => server,
+                Err(_) => continue,
+            };
+            let drop_reply = drop_reply.load(Ordering::SeqCst);
+            task::spawn(async move {
+                let (mut from, mut to) = (client.clone(), server.clone());
+                let forwarding = task::spawn(async move {
+                    let _ = async_std::io::copy(&mut from, &mut to).await;
+                    let _ = to.shutdown(std::net::Shutdown::Both);
+                });
+                if drop_reply {
+                    let hello = read_frame(&mut server).await?;
+                    write_frame(&mut client, &hello).await?;
+                    read_frame(&mut server).await?;
+                } else {
+                    async_std::io::copy(&mut server.clone(), &mut client.clone()).await?;
+                }
+                client.shutdown(std::net::Shutdown::Both)?;
+                forwarding.await;
+                Result::Ok(())
+            });
+        }
+    });
+    Ok(proxy_addr)
+}
+
+// Requests of an operation with an error rate of one should always fail,
+// leaving the others alone
+#[test]
+fn chaos_error_rate() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut chaos = Chaos::default();
+        chaos.add_error_rate("set", 1.0);
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            chaos,
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        let injected = ServerError::Internal {
+            msg: KvsError::InjectedFault.to_string(),
+        };
+        for _ in 0..5 {
+            match client.set("key1".to_owned(), "value1".to_owned()).await {
+                Err(KvsError::Server(e)) if e == injected => {}
+                res => panic!("a set wasn't failed: {:?}", res),
+            }
+        }
+        assert_eq!(client.get("key1".to_owned()).await?, None);
+        assert_eq!(client.ping(b"hi").await?, b"hi".to_vec());
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+/// Encodes a get of `key`, as variant 1 of `Request`.
+fn get_request(key: &str) -> Vec<u8> {
+    let mut request = 1u32.to_le_bytes().to_vec();
+    request.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    request.extend_from_slice(key.as_bytes());
+    request
+}
+
+// Pipelined requests should be answered in order, and those beyond
+// max_in_flight refused
+#[test]
+fn pipelined_requests() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut chaos = Chaos::default();
+        chaos.add_latency("get", Duration::from_millis(200));
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            max_in_flight: Some(2),
+            chaos,
+            ..ServerConfig::default()
+        })
+        .await?;
+        let addr = server.local_addr();
+        let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+        client.set("key1".to_owned(), "1".to_owned()).await?;
+        client.set("key2".to_owned(), "22".to_owned()).await?;
+        drop(client);
+
+        let mut stream = TcpStream::connect(addr).await?;
+        read_frame(&mut stream).await?;
+        for key in ["key2", "key1", "key2"].iter() {
+            write_frame(&mut stream, &get_request(key)).await?;
+        }
+        // `Ok(Some(value))`
+        let ok = |value: &str| {
+            let mut reply = vec![0, 0, 0, 0, 1];
+            reply.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            reply.extend_from_slice(value.as_bytes());
+            reply
+        };
+        assert_eq!(read_frame(&mut stream).await?, ok("22"));
+        assert_eq!(read_frame(&mut stream).await?, ok("1"));
+        // `Err(ServerError::RateLimited)`
+        assert_eq!(read_frame(&mut stream).await?, [1, 0, 0, 0, 2, 0, 0, 0]);
+
+        // Once replied to, requests no longer count.
+        write_frame(&mut stream, &get_request("key1")).await?;
+        assert_eq!(read_frame(&mut stream).await?, ok("1"));
+        drop(stream);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// A new replica should start from a copy of its primary's store, replacing
+// its own keys
+#[test]
+fn replica_bootstrap() -> Result<()> {
+    task::block_on(async {
+        let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+        let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(replica_dir.path()).await?;
+        store.set("stale", "value").await?;
+        drop(store);
+        let (primary, primary_running) = start_server(ServerConfig {
+            dir: Some(primary_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut writer = KvsClient::connect(primary.local_addr(), ClientConfig::default()).await?;
+        for i in 0..20 {
+            writer
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        writer.remove("key0".to_owned()).await?;
+
+        let (replica, replica_running) = start_server(ServerConfig {
+            dir: Some(replica_dir.path().to_path_buf()),
+            replica_of: Some(primary.local_addr()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut reader = KvsClient::connect(replica.local_addr(), ClientConfig::default()).await?;
+        wait_for(&mut reader, "key19", Some("value19")).await?;
+        for i in 1..20 {
+            assert_eq!(
+                reader.get(format!("key{}", i)).await?,
+                Some(format!("value{}", i))
+            );
+        }
+        assert_eq!(reader.get("key0".to_owned()).await?, None);
+        assert_eq!(reader.get("stale".to_owned()).await?, None);
+
+        // Changes made after the copy follow it.
+        writer.set("key1".to_owned(), "changed".to_owned()).await?;
+        wait_for(&mut reader, "key1", Some("changed")).await?;
+        drop((writer, reader));
+
+        replica.shutdown();
+        replica_running.await?;
+        primary.shutdown();
+        primary_running.await
+    })
+}
+
+// Reloaded limits should apply without a restart: max_connections to every
+// connection, and others to connections accepted after the reload
+#[test]
+fn reload_config() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        };
+        let (server, running) = start_server(config.clone()).await?;
+        let addr = server.local_addr();
+        let mut first = KvsClient::connect(addr, ClientConfig::default()).await?;
+        let mut second = KvsClient::connect(addr, ClientConfig::default()).await?;
+        first.set("key1".to_owned(), "v".repeat(100)).await?;
+
+        server
+            .reload(ServerConfig {
+                max_connections: Some(2),
+                max_frame_size: Some(64),
+                ..config.clone()
+            })
+            .await;
+        let mut third = KvsClient::connect(addr, ClientConfig::default()).await?;
+        match third.get("key1".to_owned()).await {
+            Err(KvsError::Server(ServerError::RateLimited)) => {}
+            res => panic!("a connection over the reloaded limit was served: {:?}", res),
+        }
+        drop(third);
+        // Open connections keep their frame size limit.
+        second.set("key2".to_owned(), "v".repeat(100)).await?;
+        drop(second);
+
+        let mut served = None;
+        for _ in 0..100 {
+            let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+            match client.set("key3".to_owned(), "v".repeat(100)).await {
+                Err(KvsError::Server(ServerError::RateLimited)) => {
+                    task::sleep(Duration::from_millis(100)).await
+                }
+                res => {
+                    served = Some(res);
+                    break;
+                }
+            }
+        }
+        match served {
+            Some(Err(KvsError::Server(ServerError::TooLarge { max: 64, .. }))) => {}
+            res => panic!("the reloaded frame size limit wasn't applied: {:?}", res),
+        }
+
+        server.reload(config).await;
+        let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+        client.set("key3".to_owned(), "v".repeat(100)).await?;
+        assert_eq!(first.get("key3".to_owned()).await?, Some("v".repeat(100)));
+        drop((first, client));
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Info should describe the server, its clients and its store
+#[test]
+fn server_info() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            max_connections: Some(10),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        client.set("key2".to_owned(), "value2".to_owned()).await?;
+        client.set("key1".to_owned(), "value3".to_owned()).await?;
+
+        let info = client.info().await?;
+        assert_eq!(info.server.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.server.engine, "kvs");
+        assert_eq!(info.server.role, "standalone");
+        assert_eq!(info.clients.connected, 1);
+        assert_eq!(info.clients.max_connections, Some(10));
+        assert_eq!(info.keyspace.keys, 2);
+        assert!(info.keyspace.live_bytes.is_some());
+        assert_eq!(info.persistence.expect("no persistence info").seq, 3);
+        let compaction = info.compaction.expect("no compaction info");
+        assert!(compaction.dead_bytes > 0);
+        assert_eq!(compaction.log_files, 1);
+        drop(client);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Pings should echo their payload, even when too many requests are in
+// flight
+#[test]
+fn ping() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut chaos = Chaos::default();
+        chaos.add_latency("get", Duration::from_millis(200));
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            max_in_flight: Some(1),
+            chaos,
+            ..ServerConfig::default()
+        })
+        .await?;
+        let mut client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        assert_eq!(client.ping(b"hello").await?, b"hello");
+        assert_eq!(client.ping(b"").await?, b"");
+        drop(client);
+
+        // A ping, as variant 23 of `Request`, pipelined after a slow get.
+        let mut stream = TcpStream::connect(server.local_addr()).await?;
+        read_frame(&mut stream).await?;
+        write_frame(&mut stream, &get_request("key")).await?;
+        let mut ping = 23u32.to_le_bytes().to_vec();
+        ping.extend_from_slice(&2u64.to_le_bytes());
+        ping.extend_from_slice(b"hi");
+        write_frame(&mut stream, &ping).await?;
+        // `Ok(None)`, then `Ok(payload)`
+        assert_eq!(read_frame(&mut stream).await?, [0, 0, 0, 0, 0]);
+        let mut pong = vec![0, 0, 0, 0];
+        pong.extend_from_slice(&2u64.to_le_bytes());
+        pong.extend_from_slice(b"hi");
+        assert_eq!(read_frame(&mut stream).await?, pong);
+        drop(stream);
+
+        server.shutdown();
+        running.await
+    })
+}
+
+// Clients should learn what the server supports as they connect, and
+// refuse requests servers too old to report it don't support
+#[test]
+fn capabilities_handshake() -> Result<()> {
+    task::block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (server, running) = start_server(ServerConfig {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        })
+        .await?;
+        let client = KvsClient::connect(server.local_addr(), ClientConfig::default()).await?;
+        let capabilities = client.capabilities();
+        assert_eq!(capabilities.version, env!("CARGO_PKG_VERSION"));
+        for op in ["get", "scan", "batch", "info", "ping", "rename"].iter() {
+            assert!(capabilities.supports(op), "{} isn't supported", op);
+        }
+        assert!(!capabilities.supports("ttl"));
+        drop(client);
+        server.shutdown();
+        running.await?;
+
+        // Older servers only send the session nonce, and the first ones
+        // nothing, then answer a get.
+        for &nonce in [true, false].iter() {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let old_server = std::thread::spawn(move || -> std::io::Result<()> {
+                use std::io::{Read, Write};
+                let (mut stream, _) = listener.accept()?;
+                if nonce {
+                    stream.write_all(&8u64.to_be_bytes())?;
+                    stream.write_all(&42u64.to_le_bytes())?;
+                }
+                let mut len = [0; 8];
+                stream.read_exact(&mut len)?;
+                let mut request = vec![0; u64::from_be_bytes(len) as usize];
+                stream.read_exact(&mut request)?;
+                stream.write_all(&5u64.to_be_bytes())?;
+                stream.write_all(&[0, 0, 0, 0, 0])?;
+                // A signing client gives up on the server as it connects.
+                let (mut stream, _) = listener.accept()?;
+                if nonce {
+                    stream.write_all(&8u64.to_be_bytes())?;
+                    stream.write_all(&42u64.to_le_bytes())?;
+                }
+                stream.read_to_end(&mut Vec::new())?;
+                Ok(())
+            });
+            let mut client = KvsClient::connect(addr, ClientConfig::default()).await?;
+            assert_eq!(client.capabilities().version, "");
+            assert!(!client.capabilities().supports("count_prefix"));
+            match client.ping(b"hello").await {
+                Err(KvsError::ServerTooOld("ping")) => {}
+                res => panic!("a ping was sent to an old server: {:?}", res),
+            }
+            assert_eq!(client.get("key".to_owned()).await?, None);
+            drop(client);
+            let signed = ClientConfig {
+                signing_key: Some(SigningKey::new("secret")),
+                ..ClientConfig::default()
+            };
+            match KvsClient::connect(addr, signed).await {
+                Err(KvsError::ServerTooOld("signing")) => {}
+                res => panic!("signed frames for an old server: {:?}", res.err()),
+            }
+            old_server.join().unwrap()?;
+        }
+        Ok(())
+    })
+}